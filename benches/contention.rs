@@ -0,0 +1,166 @@
+// Compares the limiter against a single `Mutex<HashMap>` fixed window limiter,
+// the way rate-gate stored its entities before sharding and lock-free consumes,
+// and against the same limiter on a `DashMap`. `rate_gate_one_shard` shows the
+// shards of the limiter turned off, `rate_gate_dashmap` the limiter storing its
+// entities in a `DashMap`.
+//
+// cargo bench --bench contention --features dashmap
+
+use std::collections::HashMap;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use dashmap::DashMap;
+#[cfg(feature = "dashmap")]
+use rate_gate::Storage;
+use rate_gate::{Limiter, LimiterBuilder};
+
+const THREADS: usize = 8;
+const CHECKS_PER_THREAD: u64 = 10_000;
+
+/// A fixed window limiter behind one global lock.
+#[derive(Default)]
+struct GlobalMutexLimiter {
+    requests: Mutex<HashMap<u64, (usize, Instant)>>,
+}
+
+impl GlobalMutexLimiter {
+    fn check(&self, entity: u64, max_limit: usize, refresh_rate: Duration) -> bool {
+        let mut requests = self.requests.lock().unwrap();
+        let now = Instant::now();
+        let (bucket, bucket_init) = requests.entry(entity).or_insert((max_limit, now));
+        if now.duration_since(*bucket_init) >= refresh_rate {
+            *bucket = max_limit;
+            *bucket_init = now;
+        }
+        if *bucket > 0 {
+            *bucket -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A fixed window limiter on a concurrent map, locking only the shard of the entity.
+#[derive(Default)]
+struct DashMapLimiter {
+    requests: DashMap<u64, (usize, Instant)>,
+}
+
+impl DashMapLimiter {
+    fn check(&self, entity: u64, max_limit: usize, refresh_rate: Duration) -> bool {
+        let now = Instant::now();
+        let mut request = self.requests.entry(entity).or_insert((max_limit, now));
+        let (bucket, bucket_init) = request.value_mut();
+        if now.duration_since(*bucket_init) >= refresh_rate {
+            *bucket = max_limit;
+            *bucket_init = now;
+        }
+        if *bucket > 0 {
+            *bucket -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Runs `check` on `THREADS` threads at once and returns the wall time taken.
+fn run_threads<F>(iters: u64, check: F) -> Duration
+where
+    F: Fn(usize, u64) + Send + Sync + 'static,
+{
+    let check = Arc::new(check);
+    let barrier = Arc::new(Barrier::new(THREADS + 1));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let check = Arc::clone(&check);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..iters * CHECKS_PER_THREAD {
+                    check(thread, i);
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn contention(c: &mut Criterion) {
+    let refresh_rate = Duration::from_secs(3600);
+    let max_limit = usize::MAX / 2;
+    let mut group = c.benchmark_group("contention");
+
+    for (name, keys) in [("hot_key", 1), ("distinct_keys", 1024)] {
+        group.bench_function(BenchmarkId::new("global_mutex", name), |b| {
+            b.iter_custom(|iters| {
+                let limiter = Arc::new(GlobalMutexLimiter::default());
+                run_threads(iters, move |thread, i| {
+                    let entity = (thread as u64 * 7919 + i) % keys;
+                    limiter.check(entity, max_limit, refresh_rate);
+                })
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("dashmap", name), |b| {
+            b.iter_custom(|iters| {
+                let limiter = Arc::new(DashMapLimiter::default());
+                run_threads(iters, move |thread, i| {
+                    let entity = (thread as u64 * 7919 + i) % keys;
+                    limiter.check(entity, max_limit, refresh_rate);
+                })
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("rate_gate_one_shard", name), |b| {
+            b.iter_custom(|iters| {
+                let limiter: Limiter<u64> = LimiterBuilder::new()
+                    .default_limit(max_limit, refresh_rate)
+                    .shards(1)
+                    .build();
+                run_threads(iters, move |thread, i| {
+                    let entity = (thread as u64 * 7919 + i) % keys;
+                    limiter.check(&entity);
+                })
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("rate_gate", name), |b| {
+            b.iter_custom(|iters| {
+                let limiter: Limiter<u64> = Limiter::with_default(max_limit, refresh_rate);
+                run_threads(iters, move |thread, i| {
+                    let entity = (thread as u64 * 7919 + i) % keys;
+                    limiter.check(&entity);
+                })
+            })
+        });
+
+        #[cfg(feature = "dashmap")]
+        group.bench_function(BenchmarkId::new("rate_gate_dashmap", name), |b| {
+            b.iter_custom(|iters| {
+                let limiter: Limiter<u64> = LimiterBuilder::new()
+                    .default_limit(max_limit, refresh_rate)
+                    .storage(Storage::DashMap)
+                    .build();
+                run_threads(iters, move |thread, i| {
+                    let entity = (thread as u64 * 7919 + i) % keys;
+                    limiter.check(&entity);
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, contention);
+criterion_main!(benches);
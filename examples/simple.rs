@@ -5,7 +5,7 @@ use rate_gate::Limiter;
 
 fn main() {
     // Create a new rate limiter
-    let mut rate_limiter: Limiter<&str> = Limiter::new();
+    let rate_limiter: Limiter<&str> = Limiter::new();
 
     // Add two users to the limiter with different limits
     rate_limiter.add_limited_entity("user1", 5, Duration::from_secs(5));
@@ -0,0 +1,152 @@
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use hashbrown::HashMap;
+
+use crate::sync::RwLock;
+
+/// How an entity is treated regardless of its bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// Every request is denied until `until`, for good without one.
+    Banned { until: Option<Instant> },
+    /// Every request is allowed without touching the bucket.
+    Unlimited,
+    /// Every request is denied until the entity is enabled again.
+    Disabled,
+}
+
+impl Access {
+    /// Returns `true` once the rule no longer applies.
+    fn expired(&self, now: Instant) -> bool {
+        match self {
+            Access::Banned { until } => until.is_some_and(|until| until <= now),
+            Access::Unlimited | Access::Disabled => false,
+        }
+    }
+}
+
+/// Entities with an `Access` rule, checked before their buckets.
+///
+/// Kept apart from the buckets so rules outlive eviction and can be set on
+/// entities the limiter doesn't track (yet). Limiters without any rules only pay
+/// for an atomic load per check.
+#[derive(Debug)]
+pub(crate) struct AccessList<T> {
+    rules: RwLock<HashMap<T, Access>>,
+    len: AtomicUsize,
+}
+
+impl<T> Default for AccessList<T> {
+    fn default() -> Self {
+        AccessList {
+            rules: RwLock::new(HashMap::new()),
+            len: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T> AccessList<T>
+where
+    T: Hash + Eq,
+{
+    /// Returns the rule for `entity`, dropping it if it expired.
+    pub(crate) fn get<Q>(&self, entity: &Q, now: Instant) -> Option<Access>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let access = *self.rules.read().get(entity)?;
+        if access.expired(now) {
+            let mut rules = self.rules.write();
+            // Someone may have set a new rule in the meantime.
+            if rules.get(entity).is_some_and(|access| access.expired(now)) {
+                rules.remove(entity);
+                self.len.store(rules.len(), Ordering::Relaxed);
+            }
+            return None;
+        }
+        Some(access)
+    }
+
+    /// Sets the rule for `entity`, replacing any previous one.
+    pub(crate) fn insert(&self, entity: T, access: Access) {
+        let mut rules = self.rules.write();
+        rules.insert(entity, access);
+        self.len.store(rules.len(), Ordering::Relaxed);
+    }
+
+    /// Removes the rule for `entity` if it matches `filter`, returns `true` if it did.
+    pub(crate) fn remove_if<Q>(&self, entity: &Q, filter: impl FnOnce(&Access) -> bool) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut rules = self.rules.write();
+        if !rules.get(entity).is_some_and(filter) {
+            return false;
+        }
+        rules.remove(entity);
+        self.len.store(rules.len(), Ordering::Relaxed);
+        true
+    }
+
+    /// Removes every expired rule, returns how many were removed.
+    pub(crate) fn remove_expired(&self, now: Instant) -> usize {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return 0;
+        }
+        let mut rules = self.rules.write();
+        let before = rules.len();
+        rules.retain(|_, access| !access.expired(now));
+        self.len.store(rules.len(), Ordering::Relaxed);
+        before - rules.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_expired_rules_are_dropped() {
+        let list = AccessList::default();
+        let now = Instant::now();
+        let until = now + Duration::from_secs(10);
+        list.insert("user1", Access::Banned { until: Some(until) });
+        list.insert("user2", Access::Banned { until: None });
+
+        assert_eq!(
+            list.get("user1", now),
+            Some(Access::Banned { until: Some(until) })
+        );
+        assert_eq!(list.get("user1", until), None);
+        assert!(list.get("user2", until).is_some());
+        assert_eq!(list.len.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let list = AccessList::default();
+        let now = Instant::now();
+        list.insert("user1", Access::Banned { until: Some(now) });
+        list.insert(
+            "user2",
+            Access::Banned {
+                until: Some(now + Duration::from_secs(10)),
+            },
+        );
+
+        list.insert("user3", Access::Unlimited);
+
+        assert_eq!(list.remove_expired(now), 1);
+        assert!(list.get("user2", now).is_some());
+        assert_eq!(list.get("user3", now), Some(Access::Unlimited));
+    }
+}
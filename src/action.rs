@@ -0,0 +1,133 @@
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hashbrown::HashMap;
+
+use crate::sync::RwLock;
+use crate::{Decision, Limiter};
+
+/// Limits the actions of an entity separately, e.g. 5 logins per minute and
+/// 100 API calls per minute for the same user.
+///
+/// Limits are set once per action, and every entity gets its own bucket per action
+/// on its first request. Underneath, buckets are tracked by a `Limiter<(T, A)>`,
+/// available through `limiter` for everything else, like bans or stats.
+#[derive(Debug)]
+pub struct ActionLimiter<T, A>
+where
+    T: Hash + Eq + Send + 'static,
+    A: Hash + Eq + Send + 'static,
+{
+    limiter: Limiter<(T, A)>,
+    actions: Arc<RwLock<HashMap<A, (usize, Duration)>>>,
+}
+
+// Not derived, clones share the buckets so `T` and `A` don't need to be `Clone`.
+impl<T, A> Clone for ActionLimiter<T, A>
+where
+    T: Hash + Eq + Send + 'static,
+    A: Hash + Eq + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        ActionLimiter {
+            limiter: self.limiter.clone(),
+            actions: Arc::clone(&self.actions),
+        }
+    }
+}
+
+impl<T, A> Default for ActionLimiter<T, A>
+where
+    T: Hash + Eq + Send + 'static,
+    A: Hash + Eq + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, A> ActionLimiter<T, A>
+where
+    T: Hash + Eq + Send + 'static,
+    A: Hash + Eq + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::from_limiter(Limiter::new())
+    }
+
+    /// Tracks the buckets in `limiter`, e.g. one configured with `LimiterBuilder`.
+    pub fn from_limiter(limiter: Limiter<(T, A)>) -> Self {
+        ActionLimiter {
+            limiter,
+            actions: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Sets the limit of `action`, entities get `max_limit` requests of it every
+    /// `refresh_rate`.
+    ///
+    /// Entities that already made a request of `action` keep the limit they started with,
+    /// change theirs with `limiter().update_limit`.
+    pub fn limit_action(&self, action: A, max_limit: usize, refresh_rate: Duration) {
+        self.actions
+            .write()
+            .insert(action, (max_limit, refresh_rate));
+    }
+
+    /// Checks whether `entity` has requests of `action` left to consume,
+    /// like `Limiter::is_entity_limited`.
+    ///
+    /// ### returns:
+    ///
+    /// `None` -> no limit was set for `action`, set one with `limit_action`.
+    ///
+    /// `Some(false)` -> entity is rate limited for `action`.
+    ///
+    /// `Some(true)` -> everything worked, entity had requests of `action` left.
+    pub fn is_action_limited(&self, entity: T, action: A) -> Option<bool> {
+        match self.check(entity, action) {
+            Decision::Allowed { .. } => Some(true),
+            Decision::Denied { .. } | Decision::Banned { .. } => Some(false),
+            Decision::Unknown => None,
+        }
+    }
+
+    /// Consumes a request of `action` for `entity`, like `is_action_limited`,
+    /// but returns a `Decision`. `Decision::Unknown` if no limit was set for `action`.
+    pub fn check(&self, entity: T, action: A) -> Decision {
+        let Some(&(max_limit, refresh_rate)) = self.actions.read().get(&action) else {
+            return Decision::Unknown;
+        };
+        self.limiter
+            .check_or_add((entity, action), max_limit, refresh_rate)
+    }
+
+    /// The limiter tracking a bucket per entity and action.
+    pub fn limiter(&self) -> &Limiter<(T, A)> {
+        &self.limiter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_actions_are_limited_separately() {
+        let limiter: ActionLimiter<&str, &str> = ActionLimiter::new();
+        limiter.limit_action("login", 1, Duration::from_secs(60));
+        limiter.limit_action("api", 2, Duration::from_secs(60));
+
+        assert_eq!(limiter.is_action_limited("user1", "login"), Some(true));
+        assert_eq!(limiter.is_action_limited("user1", "login"), Some(false));
+        assert_eq!(limiter.is_action_limited("user1", "api"), Some(true));
+        assert_eq!(limiter.is_action_limited("user2", "login"), Some(true));
+        assert_eq!(limiter.is_action_limited("user1", "upload"), None);
+
+        assert_eq!(
+            limiter.limiter().get_bucket_remaining(&("user1", "api")),
+            Some(1)
+        );
+    }
+}
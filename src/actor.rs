@@ -0,0 +1,181 @@
+use std::hash::{BuildHasher, Hash};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{Decision, Limiter, Policy};
+
+/// A limiter owned by a background task, which callers talk to through a channel.
+///
+/// Only the task ever touches the buckets, so request threads never wait on each
+/// other's locks, they only send a message and wait for the answer. Fits services
+/// built around message passing, or with so much contention on a few hot entities
+/// that handing every decision to one task is cheaper than fighting over the lock:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, LimiterHandle};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let limiter: Limiter<String> = Limiter::with_default(1, Duration::from_secs(60));
+/// let handle = LimiterHandle::spawn(limiter, 1024);
+///
+/// assert!(handle.check("alice".to_string()).await.is_allowed());
+/// assert!(!handle.check("alice".to_string()).await.is_allowed());
+/// # }
+/// ```
+///
+/// Handles are cheap to clone, the task stops once every one of them was dropped.
+#[derive(Debug)]
+pub struct LimiterHandle<T> {
+    sender: mpsc::Sender<Command<T>>,
+}
+
+#[derive(Debug)]
+enum Command<T> {
+    Consume {
+        entity: T,
+        cost: usize,
+        reply: oneshot::Sender<Decision>,
+    },
+    Remaining {
+        entity: T,
+        reply: oneshot::Sender<Option<usize>>,
+    },
+    Add {
+        entity: T,
+        policy: Policy,
+    },
+    Remove {
+        entity: T,
+        reply: oneshot::Sender<bool>,
+    },
+}
+
+// Not derived, clones share the channel so `T` doesn't need to be `Clone`.
+impl<T> Clone for LimiterHandle<T> {
+    fn clone(&self) -> Self {
+        LimiterHandle {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> LimiterHandle<T>
+where
+    T: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// Spawns a task on the current tokio runtime owning `limiter`, and returns a
+    /// handle to it. Up to `capacity` messages queue up before callers wait for room.
+    ///
+    /// Panics when called outside of a tokio runtime, or if `capacity` is zero.
+    pub fn spawn<S>(limiter: Limiter<T, S>, capacity: usize) -> Self
+    where
+        S: BuildHasher + Clone + Send + Sync + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel(capacity);
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                run(&limiter, command);
+            }
+        });
+        LimiterHandle { sender }
+    }
+
+    /// Consumes a request of `entity`, like `Limiter::check`.
+    ///
+    /// `Decision::Unknown` if the task is gone, e.g. because its runtime shut down.
+    pub async fn check(&self, entity: T) -> Decision {
+        self.consume(entity, 1).await
+    }
+
+    /// Consumes `cost` requests of `entity`, like `Limiter::consume`.
+    ///
+    /// Not cancel safe: once sent, the request is consumed even if the future is
+    /// dropped before the answer arrives.
+    pub async fn consume(&self, entity: T, cost: usize) -> Decision {
+        self.ask(|reply| Command::Consume {
+            entity,
+            cost,
+            reply,
+        })
+        .await
+        .unwrap_or(Decision::Unknown)
+    }
+
+    /// Returns how many requests `entity` has left, like `Limiter::get_bucket_remaining`.
+    pub async fn get_bucket_remaining(&self, entity: T) -> Option<usize> {
+        self.ask(|reply| Command::Remaining { entity, reply })
+            .await
+            .flatten()
+    }
+
+    /// Adds `entity` with the limits of `policy`, like
+    /// `Limiter::add_limited_entity_with_policy`, without waiting for the task to do so.
+    /// Requests sent afterwards through any handle see the entity.
+    pub async fn add_limited_entity_with_policy(&self, entity: T, policy: Policy) {
+        // A task that is gone has no entities to add to.
+        let _ = self.sender.send(Command::Add { entity, policy }).await;
+    }
+
+    /// Removes `entity`, returns `false` if it wasn't tracked.
+    pub async fn remove_limited_entity(&self, entity: T) -> bool {
+        self.ask(|reply| Command::Remove { entity, reply })
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Sends the command built by `command` and waits for its reply, `None` if the
+    /// task is gone.
+    async fn ask<R>(&self, command: impl FnOnce(oneshot::Sender<R>) -> Command<T>) -> Option<R> {
+        let (reply, answer) = oneshot::channel();
+        self.sender.send(command(reply)).await.ok()?;
+        answer.await.ok()
+    }
+}
+
+fn run<T, S>(limiter: &Limiter<T, S>, command: Command<T>)
+where
+    T: Hash + Eq + Clone + Send + Sync + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    // Callers that stopped waiting don't take their replies.
+    match command {
+        Command::Consume {
+            entity,
+            cost,
+            reply,
+        } => {
+            let _ = reply.send(limiter.consume(&entity, cost));
+        }
+        Command::Remaining { entity, reply } => {
+            let _ = reply.send(limiter.get_bucket_remaining(&entity));
+        }
+        Command::Add { entity, policy } => limiter.add_limited_entity_with_policy(entity, policy),
+        Command::Remove { entity, reply } => {
+            let _ = reply.send(limiter.remove_limited_entity(&entity).is_some());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handles_share_the_task() {
+        let handle = LimiterHandle::spawn(Limiter::new(), 16);
+        handle
+            .add_limited_entity_with_policy("user1", Policy::new(2, Duration::from_secs(60)))
+            .await;
+
+        let other = handle.clone();
+        assert!(other.check("user1").await.is_allowed());
+        assert_eq!(handle.get_bucket_remaining("user1").await, Some(1));
+        assert_eq!(handle.check("unknown_user").await, Decision::Unknown);
+
+        assert!(handle.remove_limited_entity("user1").await);
+        assert_eq!(other.get_bucket_remaining("user1").await, None);
+    }
+}
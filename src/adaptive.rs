@@ -0,0 +1,105 @@
+/// Additive-increase/multiplicative-decrease of an entity's limit, driven by how its
+/// requests went, set with `Limiter::make_adaptive` or `Policy::with_adaptive`.
+///
+/// Every success reported with `Limiter::report_success` raises the limit by
+/// `increase`, and every failure reported with `Limiter::report_failure` divides it
+/// by `factor`, always staying between `min_limit` and `max_limit`. The limit probes
+/// upwards while a downstream service keeps up, and backs off quickly once it doesn't.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Aimd, Limiter};
+/// let limiter: Limiter<&str> = Limiter::new();
+/// limiter.add_limited_entity("flaky-api", 10, Duration::from_secs(1));
+/// limiter.make_adaptive(&"flaky-api", Aimd::new(1, 100));
+///
+/// assert_eq!(limiter.report_success(&"flaky-api"), Some(11));
+/// assert_eq!(limiter.report_failure(&"flaky-api"), Some(5));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "Unchecked")
+)]
+pub struct Aimd {
+    min_limit: usize,
+    max_limit: usize,
+    increase: usize,
+    factor: u32,
+}
+
+impl Aimd {
+    /// Keeps the limit between `min_limit` and `max_limit`, raising it by one per
+    /// success and halving it per failure.
+    pub fn new(min_limit: usize, max_limit: usize) -> Self {
+        Aimd {
+            min_limit,
+            max_limit: max_limit.max(min_limit),
+            increase: 1,
+            factor: 2,
+        }
+    }
+
+    /// Sets how much the limit grows per success.
+    pub fn increase(mut self, increase: usize) -> Self {
+        self.increase = increase;
+        self
+    }
+
+    /// Sets what the limit gets divided by per failure.
+    pub fn factor(mut self, factor: u32) -> Self {
+        self.factor = factor.max(1);
+        self
+    }
+
+    /// `limit` moved into the bounds.
+    pub(crate) fn clamp(&self, limit: usize) -> usize {
+        limit.clamp(self.min_limit, self.max_limit)
+    }
+
+    /// The limit after a success at `limit`.
+    pub(crate) fn increased(&self, limit: usize) -> usize {
+        self.clamp(limit.saturating_add(self.increase))
+    }
+
+    /// The limit after a failure at `limit`.
+    pub(crate) fn decreased(&self, limit: usize) -> usize {
+        self.clamp(limit / self.factor as usize)
+    }
+}
+
+/// `Aimd` as it is deserialized, before the bounds of its setters are applied.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct Unchecked {
+    min_limit: usize,
+    max_limit: usize,
+    increase: usize,
+    factor: u32,
+}
+
+#[cfg(feature = "serde")]
+impl From<Unchecked> for Aimd {
+    fn from(unchecked: Unchecked) -> Self {
+        Aimd::new(unchecked.min_limit, unchecked.max_limit)
+            .increase(unchecked.increase)
+            .factor(unchecked.factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_increase_and_decrease() {
+        let aimd = Aimd::new(2, 10).increase(3);
+        assert_eq!(aimd.increased(5), 8);
+        assert_eq!(aimd.increased(8), 10);
+        assert_eq!(aimd.decreased(10), 5);
+        assert_eq!(aimd.decreased(3), 2);
+        assert_eq!(aimd.factor(4).decreased(10), 2);
+        assert_eq!(aimd.clamp(50), 10);
+    }
+}
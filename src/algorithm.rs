@@ -0,0 +1,47 @@
+/// How an entity's bucket gets refilled.
+///
+/// Selected per entity with `Limiter::add_limited_entity_with_algorithm`,
+/// `add_limited_entity` uses `Algorithm::FixedWindow`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Algorithm {
+    /// The whole bucket is refilled with `bucket_max` once every `refresh_rate`.
+    ///
+    /// Simple and cheap, but allows up to 2x `bucket_max` requests around a window boundary.
+    #[default]
+    FixedWindow,
+    /// Tokens trickle back one by one at `bucket_max / refresh_rate`,
+    /// so the sustained rate stays smooth while still allowing bursts of `bucket_max`.
+    TokenBucket,
+    /// Generic cell rate algorithm, requests are spaced `refresh_rate / bucket_max` apart
+    /// with a burst tolerance of `bucket_max`.
+    ///
+    /// Only keeps the theoretical arrival time of the next request,
+    /// which makes it precise for high-frequency entities.
+    Gcra,
+    /// Keeps the timestamp of every request and allows at most `bucket_max`
+    /// in any rolling `refresh_rate` window.
+    ///
+    /// Exact, with no bursts across window boundaries, but memory grows with `bucket_max`.
+    SlidingWindowLog,
+    /// Approximates a sliding window with two counters: the current window plus
+    /// the previous one, weighted by how much of it still overlaps the rolling window.
+    ///
+    /// Cheap on memory, with far smaller boundary bursts than `FixedWindow`.
+    SlidingWindowCounter,
+    /// Requests fill a queue of `bucket_max` that leaks at a constant
+    /// `bucket_max / refresh_rate`, requests that would overflow it are rejected.
+    ///
+    /// Smooths traffic towards a downstream service to a steady outflow.
+    LeakyBucket,
+}
+
+impl Algorithm {
+    /// Whether the bucket refills one request at a time rather than by window.
+    pub(crate) fn is_continuous(self) -> bool {
+        matches!(
+            self,
+            Algorithm::TokenBucket | Algorithm::Gcra | Algorithm::LeakyBucket
+        )
+    }
+}
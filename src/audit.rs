@@ -0,0 +1,607 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::sync::Mutex;
+use crate::Decision;
+
+/// A decision of a limiter as recorded by `Audit`.
+///
+/// Records are chained: `hash` is the SHA-256 of the hash of the record before and
+/// of this one, so a record that was changed, removed or inserted afterwards breaks
+/// the chain from there on, see `verify_chain`.
+///
+/// Written as a line of tab separated fields by `Display` and read back by `FromStr`,
+/// with times and durations as seconds, and the timestamp since the Unix epoch:
+///
+/// ```text
+/// 7\t1760000000.250000000\tdenied\t1\t0\t59.750000000\talice\t9f86d081884c7d65...
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// The position of the record in the log, from 0.
+    pub sequence: u64,
+    /// When the decision was made, by the clock of the limiter.
+    pub timestamp: SystemTime,
+    /// The entity, printed with its `Display` implementation.
+    pub key: String,
+    /// How many requests were asked for.
+    pub cost: usize,
+    pub decision: Decision,
+    /// How many requests the entity has left.
+    pub remaining: usize,
+    /// The SHA-256 of the hash of the previous record and the fields above.
+    pub hash: [u8; 32],
+}
+
+impl AuditRecord {
+    /// Checks that every record of `records` follows the one before it, without gaps
+    /// and with the hash it should have. Returns the sequence of the first one that
+    /// doesn't.
+    ///
+    /// The first record is trusted unless it is the first of the log, so keep the
+    /// hash of the last record verified somewhere safe and start from it next time.
+    /// Records removed from the end of the log can't be detected otherwise.
+    pub fn verify_chain<'a>(records: impl IntoIterator<Item = &'a AuditRecord>) -> Result<(), u64> {
+        let mut previous: Option<&AuditRecord> = None;
+        for record in records {
+            let expected = match previous {
+                Some(previous) if record.sequence != previous.sequence + 1 => {
+                    return Err(record.sequence)
+                }
+                Some(previous) => Some(record.digest(&previous.hash)),
+                None if record.sequence == 0 => Some(record.digest(&[0; 32])),
+                None => None,
+            };
+            if expected.is_some_and(|hash| hash != record.hash) {
+                return Err(record.sequence);
+            }
+            previous = Some(record);
+        }
+        Ok(())
+    }
+
+    /// The hash the record has after `previous`.
+    fn digest(&self, previous: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(previous);
+        hasher.update(self.fields().as_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Every field but the hash, as written in the log.
+    fn fields(&self) -> String {
+        let (decision, wait) = match self.decision {
+            Decision::Allowed { reset_in, .. } => ("allowed", reset_in),
+            Decision::Denied { retry_after } => ("denied", retry_after),
+            Decision::Banned { retry_after } => ("banned", retry_after),
+            Decision::Unknown => ("unknown", Duration::ZERO),
+        };
+        let timestamp = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.sequence,
+            Secs(timestamp),
+            decision,
+            self.cost,
+            self.remaining,
+            Secs(wait),
+            escape(&self.key),
+        )
+    }
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.fields())?;
+        f.write_str("\t")?;
+        for byte in self.hash {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AuditRecord {
+    type Err = ParseAuditRecordError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseAuditRecordError(line.to_string());
+        let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+        let [sequence, timestamp, decision, cost, remaining, wait, key, hash] = fields[..] else {
+            return Err(invalid());
+        };
+        let wait = parse_secs(wait).ok_or_else(invalid)?;
+        let remaining = remaining.parse().map_err(|_| invalid())?;
+        let decision = match decision {
+            "allowed" => Decision::Allowed {
+                remaining,
+                reset_in: wait,
+            },
+            "denied" => Decision::Denied { retry_after: wait },
+            "banned" => Decision::Banned { retry_after: wait },
+            "unknown" => Decision::Unknown,
+            _ => return Err(invalid()),
+        };
+        if hash.len() != 64 || !hash.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hash[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(AuditRecord {
+            sequence: sequence.parse().map_err(|_| invalid())?,
+            timestamp: UNIX_EPOCH + parse_secs(timestamp).ok_or_else(invalid)?,
+            key: unescape(key).ok_or_else(invalid)?,
+            cost: cost.parse().map_err(|_| invalid())?,
+            decision,
+            remaining,
+            hash: bytes,
+        })
+    }
+}
+
+/// Why a line couldn't be parsed as an `AuditRecord`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseAuditRecordError(String);
+
+impl fmt::Display for ParseAuditRecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid audit record `{}`", self.0)
+    }
+}
+
+impl std::error::Error for ParseAuditRecordError {}
+
+/// A duration written as seconds with nine decimals, exact to the nanosecond.
+struct Secs(Duration);
+
+impl fmt::Display for Secs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:09}", self.0.as_secs(), self.0.subsec_nanos())
+    }
+}
+
+fn parse_secs(secs: &str) -> Option<Duration> {
+    let (secs, nanos) = secs.split_once('.')?;
+    if nanos.len() != 9 {
+        return None;
+    }
+    Some(Duration::new(secs.parse().ok()?, nanos.parse().ok()?))
+}
+
+/// Escapes the characters that would end a field or a line.
+fn escape(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for c in key.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(key: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(key.len());
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(unescaped)
+}
+
+/// Where `Audit` sends its records.
+///
+/// Implemented for closures taking the record, `FileSink`, and with the `tokio`
+/// feature, the senders of `tokio::sync::mpsc` channels of `AuditRecord`s.
+///
+/// Records are sent one at a time in the order of the chain, on the thread that made
+/// the decision while the shard of the entity is locked for reading, so sinks should
+/// be quick and must not check entities of the same limiter.
+pub trait AuditSink: Send + Sync {
+    /// Stores `record`.
+    fn record(&self, record: &AuditRecord);
+
+    /// The last record the sink already holds, for the chain to continue from it
+    /// instead of starting over, e.g. after a restart.
+    fn last(&self) -> Option<AuditRecord> {
+        None
+    }
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(&AuditRecord) + Send + Sync,
+{
+    fn record(&self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+impl<S> AuditSink for Arc<S>
+where
+    S: AuditSink + ?Sized,
+{
+    fn record(&self, record: &AuditRecord) {
+        (**self).record(record)
+    }
+
+    fn last(&self) -> Option<AuditRecord> {
+        (**self).last()
+    }
+}
+
+/// Sends every record, never blocking the limiter.
+#[cfg(feature = "tokio")]
+impl AuditSink for tokio::sync::mpsc::UnboundedSender<AuditRecord> {
+    fn record(&self, record: &AuditRecord) {
+        // Nobody is listening anymore.
+        let _ = self.send(record.clone());
+    }
+}
+
+/// Drops records while the channel is full, instead of blocking the limiter. The
+/// gaps they leave show up in `AuditRecord::verify_chain`.
+#[cfg(feature = "tokio")]
+impl AuditSink for tokio::sync::mpsc::Sender<AuditRecord> {
+    fn record(&self, record: &AuditRecord) {
+        let _ = self.try_send(record.clone());
+    }
+}
+
+/// Appends records to a file, a line each, continuing the chain of the records
+/// already in it.
+///
+/// Every record is written as soon as it is made, without buffering. Records that
+/// couldn't be written are counted by `failures`, keep an `Arc` of the sink to check.
+#[derive(Debug)]
+pub struct FileSink {
+    file: Mutex<File>,
+    last: Option<AuditRecord>,
+    failures: AtomicU64,
+}
+
+impl FileSink {
+    /// Opens the log at `path` for appending, creating it if it doesn't exist.
+    ///
+    /// Fails if the last line of the file isn't a record, since the chain couldn't
+    /// be continued from it.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let last = last_line(&mut file)?
+            .map(|line| line.parse::<AuditRecord>())
+            .transpose()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(FileSink {
+            file: Mutex::new(file),
+            last,
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    /// How many records couldn't be written.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+impl AuditSink for FileSink {
+    fn record(&self, record: &AuditRecord) {
+        let line = format!("{}\n", record);
+        if self.file.lock().write_all(line.as_bytes()).is_err() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn last(&self) -> Option<AuditRecord> {
+        self.last.clone()
+    }
+}
+
+/// The last line of `file`, if it has any, read from the end.
+fn last_line(file: &mut File) -> io::Result<Option<String>> {
+    const TAIL: u64 = 64 * 1024; // Far longer than a record
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL)))?;
+    let mut tail = String::new();
+    file.read_to_string(&mut tail)?;
+    let line = tail
+        .trim_end_matches('\n')
+        .rsplit('\n')
+        .next()
+        .unwrap_or("");
+    Ok(Some(line.to_string()).filter(|line| !line.is_empty()))
+}
+
+/// An audit log of the decisions of a limiter, set with `Limiter::with_audit`.
+///
+/// Every request the limiter decides is recorded in the sink as an `AuditRecord`,
+/// or only the denied ones with `denials_only`:
+///
+/// ```
+/// # use std::sync::{Arc, Mutex};
+/// # use std::time::Duration;
+/// # use rate_gate::{Audit, AuditRecord, Limiter};
+/// let log = Arc::new(Mutex::new(Vec::new()));
+/// let sink = log.clone();
+/// let limiter: Limiter<String> = Limiter::with_default(1, Duration::from_secs(60))
+///     .with_audit(Audit::new(move |record: &AuditRecord| {
+///         sink.lock().unwrap().push(record.clone())
+///     }));
+///
+/// limiter.check("alice");
+/// limiter.check("alice");
+/// let log = log.lock().unwrap();
+/// assert_eq!(log.len(), 2);
+/// assert!(!log[1].decision.is_allowed());
+/// assert_eq!(AuditRecord::verify_chain(log.iter()), Ok(()));
+/// ```
+///
+/// That includes requests decided without a bucket: those of banned, disabled or
+/// unlimited entities, and everything while the limiter runs in another `Mode` than
+/// `Mode::Enforce`. Only requests of entities the limiter doesn't know aren't recorded.
+///
+/// Records are chained, so they are made one at a time over every thread, which
+/// costs throughput on busy limiters. Recording only denials keeps that small.
+pub struct Audit {
+    sink: Box<dyn AuditSink>,
+    denials_only: bool,
+    chain: Mutex<(u64, [u8; 32])>, // The sequence and previous hash of the next record
+}
+
+impl fmt::Debug for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Audit")
+            .field("denials_only", &self.denials_only)
+            .field("chain", &self.chain)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Audit {
+    /// Records decisions in `sink`, after the last record it holds if any.
+    pub fn new(sink: impl AuditSink + 'static) -> Self {
+        let chain = match sink.last() {
+            Some(last) => (last.sequence + 1, last.hash),
+            None => (0, [0; 32]),
+        };
+        Audit {
+            sink: Box::new(sink),
+            denials_only: false,
+            chain: Mutex::new(chain),
+        }
+    }
+
+    /// Only records denied requests.
+    pub fn denials_only(mut self) -> Self {
+        self.denials_only = true;
+        self
+    }
+
+    /// Records a decision made for the entity printed by `key`.
+    pub(crate) fn record(
+        &self,
+        key: impl FnOnce() -> String,
+        cost: usize,
+        decision: &Decision,
+        timestamp: SystemTime,
+    ) {
+        let remaining = match decision {
+            Decision::Allowed { remaining, .. } => *remaining,
+            Decision::Denied { .. } | Decision::Banned { .. } | Decision::Unknown => 0,
+        };
+        if self.denials_only && decision.is_allowed() {
+            return;
+        }
+        let mut record = AuditRecord {
+            sequence: 0,
+            timestamp,
+            key: key(),
+            cost,
+            decision: *decision,
+            remaining,
+            hash: [0; 32],
+        };
+        // Held while the sink stores the record, so it gets them in order.
+        let mut chain = self.chain.lock();
+        record.sequence = chain.0;
+        record.hash = record.digest(&chain.1);
+        *chain = (chain.0 + 1, record.hash);
+        self.sink.record(&record);
+    }
+}
+
+/// The `Audit` of a limiter, shared by its clones, and how it prints entities.
+pub(crate) struct Audited<T> {
+    pub(crate) audit: Arc<Audit>,
+    pub(crate) key: fn(&T) -> String,
+}
+
+impl<T> Clone for Audited<T> {
+    fn clone(&self) -> Self {
+        Audited {
+            audit: self.audit.clone(),
+            key: self.key,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Audited<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.audit.fmt(f)
+    }
+}
+
+pub(crate) fn to_key<T: fmt::Display>(entity: &T) -> String {
+    entity.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, Limiter, LimiterBuilder, ManualClock, Mode};
+
+    #[test]
+    fn test_records_roundtrip() {
+        let record = AuditRecord {
+            sequence: 3,
+            timestamp: UNIX_EPOCH + Duration::new(1_760_000_000, 250_000_000),
+            key: "tenant\tal\\ice\n".to_string(),
+            cost: 2,
+            decision: Decision::Denied {
+                retry_after: Duration::from_millis(59_750),
+            },
+            remaining: 0,
+            hash: [7; 32],
+        };
+        let line = record.to_string();
+        assert_eq!(line.lines().count(), 1);
+        assert!(line.starts_with("3\t1760000000.250000000\tdenied\t2\t0\t59.750000000\t"));
+        assert_eq!(line.parse::<AuditRecord>(), Ok(record));
+        assert!("3\tdenied".parse::<AuditRecord>().is_err());
+    }
+
+    #[test]
+    fn test_chain() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        let limiter: Limiter<&str> = Limiter::new().with_audit(
+            Audit::new(move |record: &AuditRecord| sink.lock().push(record.clone())).denials_only(),
+        );
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+        for _ in 0..4 {
+            limiter.check(&"user1");
+        }
+
+        let mut log = log.lock().clone();
+        assert_eq!(log.len(), 3);
+        assert_eq!((log[0].key.as_str(), log[2].sequence), ("user1", 2));
+        assert_eq!(AuditRecord::verify_chain(&log), Ok(()));
+        // Starting from any record that was verified before.
+        assert_eq!(AuditRecord::verify_chain(&log[1..]), Ok(()));
+
+        log[1].cost = 0;
+        assert_eq!(AuditRecord::verify_chain(&log), Err(1));
+        log.remove(1);
+        assert_eq!(AuditRecord::verify_chain(&log), Err(2));
+    }
+
+    #[test]
+    fn test_file_sink_continues_the_chain() {
+        let path = std::env::temp_dir().join(format!("rate-gate-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = ManualClock::new();
+        for _ in 0..2 {
+            let sink = Arc::new(FileSink::open(&path).unwrap());
+            let limiter: Limiter<String> = LimiterBuilder::new()
+                .default_limit(5, Duration::from_secs(60))
+                .clock(clock.clone())
+                .build()
+                .with_audit(Audit::new(sink.clone()));
+            limiter.check("alice");
+            limiter.check("bob");
+            assert_eq!(sink.failures(), 0);
+        }
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<AuditRecord> = log.lines().map(|line| line.parse().unwrap()).collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!((records[3].sequence, records[3].key.as_str()), (3, "bob"));
+        assert_eq!(records[3].remaining, 4);
+        assert_eq!(records[0].timestamp, clock.system_time());
+        assert_eq!(AuditRecord::verify_chain(&records), Ok(()));
+    }
+
+    #[test]
+    fn test_records_decisions_without_bucket() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        let limiter: Limiter<&str> = Limiter::with_default(5, Duration::from_secs(60)).with_audit(
+            Audit::new(move |record: &AuditRecord| sink.lock().push(record.clone())),
+        );
+        limiter.ban("banned", Duration::from_secs(60));
+        limiter.disable("disabled");
+
+        limiter.check(&"banned");
+        limiter.check_many(&[&"disabled"]);
+        assert!(!limiter
+            .consume_all(&[(&"alice", 1), (&"banned", 2)])
+            .is_allowed());
+        limiter.set_mode(Mode::AllowAll);
+        limiter.consume(&"alice", 3);
+        limiter.set_mode(Mode::DenyAll);
+        limiter.check_or_add("bob", 5, Duration::from_secs(60));
+
+        let log = log.lock();
+        let logged: Vec<_> = log
+            .iter()
+            .map(|record| {
+                (
+                    record.key.as_str(),
+                    record.cost,
+                    record.decision.is_allowed(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            logged,
+            [
+                ("banned", 1, false),
+                ("disabled", 1, false),
+                ("banned", 2, false),
+                ("alice", 3, true),
+                ("bob", 1, false),
+            ]
+        );
+        assert_eq!(AuditRecord::verify_chain(log.iter()), Ok(()));
+    }
+
+    #[test]
+    fn test_rejected_batches_record_no_unlimited_entities() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        let limiter: Limiter<&str> = Limiter::with_default(1, Duration::from_secs(60)).with_audit(
+            Audit::new(move |record: &AuditRecord| sink.lock().push(record.clone())),
+        );
+        limiter.allow_unlimited("admin");
+
+        assert!(limiter
+            .consume_all(&[(&"admin", 1), (&"alice", 1)])
+            .is_allowed());
+        assert!(!limiter
+            .consume_all(&[(&"admin", 1), (&"alice", 1)])
+            .is_allowed());
+
+        let keys: Vec<_> = log.lock().iter().map(|record| record.key.clone()).collect();
+        assert_eq!(keys, ["alice", "admin", "alice"]);
+    }
+}
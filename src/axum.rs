@@ -0,0 +1,192 @@
+//! Rate limiting for `axum` by client IP, see `limit_by_ip`, and `admin_router`, showing
+//! what a limiter tracks with the `admin` feature.
+
+use std::net::IpAddr;
+
+use ::axum::body::Body;
+use ::axum::extract::{Request, State};
+use ::axum::middleware::Next;
+use ::axum::response::Response;
+
+use crate::extract::{ForwardedFor, KeyExtractor, PeerIp};
+use crate::respond::{DeniedResponder, TooManyRequests};
+use crate::{Decision, Limiter};
+
+#[cfg(feature = "admin")]
+mod admin;
+
+#[cfg(feature = "admin")]
+pub use self::admin::admin_router;
+
+/// The limiter `limit_by_ip` checks clients against, and how it finds their IP.
+///
+/// Every route can have its own, e.g. a stricter one for logins:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use axum::{middleware, routing::get, routing::post, Router};
+/// # use rate_gate::{axum::{limit_by_ip, IpRateLimit}, Limiter};
+/// let default = IpRateLimit::new(Limiter::with_default(100, Duration::from_secs(60)));
+/// let login = IpRateLimit::new(Limiter::with_default(5, Duration::from_secs(60)));
+///
+/// let app: Router = Router::new()
+///     .route("/login", post(|| async {}))
+///     .route_layer(middleware::from_fn_with_state(login, limit_by_ip))
+///     .route("/", get(|| async {}))
+///     .layer(middleware::from_fn_with_state(default, limit_by_ip));
+/// ```
+///
+/// Layers added later wrap the earlier ones, so `/login` counts against both.
+/// To limit by anything but the client IP, use `RateLimitLayer` with a `KeyExtractor`.
+#[derive(Debug, Clone)]
+pub struct IpRateLimit<R = TooManyRequests> {
+    limiter: Limiter<IpAddr>,
+    proxies: usize,
+    responder: R,
+}
+
+impl IpRateLimit {
+    /// Finds the IP of clients in `ConnectInfo`, so the app has to be served with
+    /// `into_make_service_with_connect_info::<SocketAddr>`.
+    pub fn new(limiter: Limiter<IpAddr>) -> Self {
+        IpRateLimit {
+            limiter,
+            proxies: 0,
+            responder: TooManyRequests,
+        }
+    }
+}
+
+impl<R> IpRateLimit<R> {
+    /// Takes the IP of clients from the `X-Forwarded-For` header set by the `proxies`
+    /// reverse proxies in front of the app, e.g. 1 behind a single load balancer.
+    ///
+    /// Only the addresses the proxies appended are used, the ones before them
+    /// were sent by the client and could be anything.
+    pub fn trust_forwarded_for(mut self, proxies: usize) -> Self {
+        self.proxies = proxies;
+        self
+    }
+
+    /// Sets how denied requests are answered, see `DeniedResponder`.
+    pub fn responder<R2>(self, responder: R2) -> IpRateLimit<R2> {
+        IpRateLimit {
+            limiter: self.limiter,
+            proxies: self.proxies,
+            responder,
+        }
+    }
+
+    /// The limiter clients are checked against.
+    pub fn limiter(&self) -> &Limiter<IpAddr> {
+        &self.limiter
+    }
+
+    /// The IP of the client that sent `request`, if it can be found.
+    pub fn client_ip<B>(&self, request: &::axum::http::Request<B>) -> Option<IpAddr> {
+        if self.proxies > 0 {
+            return ForwardedFor {
+                proxies: self.proxies,
+            }
+            .extract(request);
+        }
+        PeerIp.extract(request)
+    }
+}
+
+/// Middleware answering with `429 Too Many Requests` and a `Retry-After` header once
+/// the client IP is out of requests, added with `axum::middleware::from_fn_with_state`.
+///
+/// Requests without a client IP, and clients the limiter doesn't know, are let through.
+pub async fn limit_by_ip<R>(
+    State(limit): State<IpRateLimit<R>>,
+    request: Request,
+    next: Next,
+) -> Response
+where
+    R: DeniedResponder<Body>,
+{
+    let decision = match limit.client_ip(&request) {
+        Some(ip) => limit.limiter.check(&ip),
+        None => Decision::Unknown,
+    };
+    match decision {
+        Decision::Denied { .. } | Decision::Banned { .. } => limit.responder.respond(&decision),
+        Decision::Allowed { .. } | Decision::Unknown => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::axum::extract::ConnectInfo;
+    use ::axum::http::StatusCode;
+    use ::axum::routing::get;
+    use ::axum::{middleware, Router};
+    use ::tower::ServiceExt;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_limit_by_ip() {
+        let limit = IpRateLimit::new(Limiter::with_default(1, Duration::from_secs(60)));
+        let app = Router::new()
+            .route("/", get(|| async { "hello" }))
+            .layer(middleware::from_fn_with_state(limit.clone(), limit_by_ip));
+        let get = || {
+            let request = ::axum::http::Request::builder()
+                .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 1234))))
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        assert_eq!(get().await.unwrap().status(), StatusCode::OK);
+        let response = get().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "60");
+        assert_eq!(
+            limit
+                .limiter()
+                .get_bucket_remaining(&IpAddr::from([10, 0, 0, 1])),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_forwarded_for() {
+        let limit = IpRateLimit::new(Limiter::new()).trust_forwarded_for(1);
+        let request = ::axum::http::Request::builder()
+            .header("x-forwarded-for", "1.1.1.1, 2.2.2.2")
+            .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 1234))))
+            .body(())
+            .unwrap();
+        assert_eq!(limit.client_ip(&request), Some(IpAddr::from([2, 2, 2, 2])));
+
+        let request = ::axum::http::Request::builder()
+            .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 1234))))
+            .body(())
+            .unwrap();
+        assert_eq!(limit.client_ip(&request), Some(IpAddr::from([10, 0, 0, 1])));
+    }
+
+    #[tokio::test]
+    async fn test_responder() {
+        let limit = IpRateLimit::new(Limiter::with_default(0, Duration::from_secs(60)))
+            .responder(crate::ProblemJson);
+        let app = Router::new()
+            .route("/", get(|| async { "hello" }))
+            .layer(middleware::from_fn_with_state(limit, limit_by_ip));
+        let request = ::axum::http::Request::builder()
+            .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 1234))))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/problem+json"
+        );
+    }
+}
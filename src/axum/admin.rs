@@ -0,0 +1,257 @@
+use std::fmt::Display;
+use std::hash::Hash;
+use std::str::FromStr;
+use std::time::Duration;
+
+use ::axum::extract::{Path, Query, State};
+use ::axum::http::StatusCode;
+use ::axum::response::{IntoResponse, Response};
+use ::axum::routing::get;
+use ::axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+
+use crate::{EntityState, Limiter};
+
+/// Read-only JSON endpoints showing what `limiter` tracks, for applications to mount
+/// under a path of their own:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use axum::{routing::get, Router};
+/// # use rate_gate::{axum::admin_router, Limiter};
+/// let limiter: Limiter<String> = Limiter::with_default(100, Duration::from_secs(60));
+/// let app: Router = Router::new()
+///     .route("/", get(|| async { "hello" }))
+///     .nest("/internal/rate-limits", admin_router(limiter.clone()));
+/// ```
+///
+/// - `GET /stats`: what was checked and denied over every entity, see `GlobalStats`.
+/// - `GET /entities?limited=true&limit=100`: the entities, sorted by key, with what is
+///   left of their buckets and when they reset. Only the limited ones with
+///   `limited=true`, at most 1000 unless `limit` says otherwise.
+/// - `GET /entities/{entity}`: a single entity, `404` if the limiter doesn't track it.
+/// - `GET /offenders?limit=10`: the entities denied the most requests.
+///
+/// Durations are in milliseconds. Nothing can be changed through the endpoints, but
+/// they list every key of the limiter, like IPs or API keys, so keep them private.
+pub fn admin_router<T, S>(limiter: Limiter<T>) -> Router<S>
+where
+    T: Hash + Eq + Clone + Display + FromStr + Send + Sync + 'static,
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/stats", get(stats::<T>))
+        .route("/entities", get(entities::<T>))
+        .route("/entities/{entity}", get(entity::<T>))
+        .route("/offenders", get(offenders::<T>))
+        .with_state(limiter)
+}
+
+#[derive(Serialize)]
+struct StatsJson {
+    checks: u64,
+    allowed: u64,
+    denied: u64,
+    evicted: u64,
+    entities: usize,
+    denial_rate: f64,
+    elapsed_secs: u64, // Since the limiter was created, or its stats reset
+}
+
+async fn stats<T>(State(limiter): State<Limiter<T>>) -> Json<StatsJson>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    let stats = limiter.global_stats();
+    Json(StatsJson {
+        checks: stats.checks,
+        allowed: stats.allowed,
+        denied: stats.denied,
+        evicted: stats.evicted,
+        entities: stats.entities,
+        denial_rate: stats.denial_rate(),
+        elapsed_secs: stats.since.elapsed().as_secs(),
+    })
+}
+
+#[derive(Serialize)]
+struct EntityJson {
+    entity: String,
+    remaining: usize,
+    max: usize,
+    refresh_rate_ms: u64,
+    reset_in_ms: u64,
+    retry_after_ms: u64,
+    limited: bool,
+    allowed: u64,
+    denied: u64,
+}
+
+impl EntityJson {
+    fn new<T>(limiter: &Limiter<T>, entity: &T, state: EntityState) -> Self
+    where
+        T: Hash + Eq + Display + Send + 'static,
+    {
+        // Removed since its state was taken, if missing.
+        let (allowed, denied) = limiter
+            .stats(entity)
+            .map_or((0, 0), |stats| (stats.allowed, stats.denied));
+        EntityJson {
+            entity: entity.to_string(),
+            remaining: state.remaining,
+            max: state.max,
+            refresh_rate_ms: millis(state.refresh_rate),
+            reset_in_ms: millis(state.reset_in),
+            retry_after_ms: millis(state.retry_after),
+            limited: state.is_limited(),
+            allowed,
+            denied,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EntitiesQuery {
+    #[serde(default)]
+    limited: bool,
+    #[serde(default = "thousand")]
+    limit: usize,
+}
+
+fn thousand() -> usize {
+    1000
+}
+
+async fn entities<T>(
+    State(limiter): State<Limiter<T>>,
+    Query(query): Query<EntitiesQuery>,
+) -> Json<Vec<EntityJson>>
+where
+    T: Hash + Eq + Clone + Display + Send + 'static,
+{
+    let mut entities: Vec<_> = limiter
+        .snapshot()
+        .into_iter()
+        .filter(|(_, state)| !query.limited || state.is_limited())
+        .map(|(entity, state)| EntityJson::new(&limiter, &entity, state))
+        .collect();
+    entities.sort_by(|a, b| a.entity.cmp(&b.entity));
+    entities.truncate(query.limit);
+    Json(entities)
+}
+
+async fn entity<T>(State(limiter): State<Limiter<T>>, Path(entity): Path<String>) -> Response
+where
+    T: Hash + Eq + Display + FromStr + Send + 'static,
+{
+    let state = entity
+        .parse::<T>()
+        .ok()
+        .and_then(|key| Some((limiter.state(&key)?, key)));
+    match state {
+        Some((state, key)) => Json(EntityJson::new(&limiter, &key, state)).into_response(),
+        None => {
+            let message = format!("unknown entity `{}`", entity);
+            (StatusCode::NOT_FOUND, Json(ErrorJson { error: message })).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OffendersQuery {
+    #[serde(default = "ten")]
+    limit: usize,
+}
+
+fn ten() -> usize {
+    10
+}
+
+#[derive(Serialize)]
+struct OffenderJson {
+    entity: String,
+    allowed: u64,
+    denied: u64,
+}
+
+async fn offenders<T>(
+    State(limiter): State<Limiter<T>>,
+    Query(query): Query<OffendersQuery>,
+) -> Json<Vec<OffenderJson>>
+where
+    T: Hash + Eq + Clone + Display + Send + 'static,
+{
+    let offenders = limiter
+        .top_offenders(query.limit)
+        .into_iter()
+        .map(|(entity, stats)| OffenderJson {
+            entity: entity.to_string(),
+            allowed: stats.allowed,
+            denied: stats.denied,
+        })
+        .collect();
+    Json(offenders)
+}
+
+#[derive(Serialize)]
+struct ErrorJson {
+    error: String,
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::axum::body::Body;
+    use ::axum::http::Request;
+    use ::tower::ServiceExt;
+    use http_body_util::BodyExt;
+
+    async fn get(app: &Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_admin_router() {
+        let limiter: Limiter<String> = Limiter::new();
+        limiter.add_limited_entity("alice".to_string(), 1, Duration::from_secs(60));
+        limiter.add_limited_entity("bob".to_string(), 5, Duration::from_secs(60));
+        limiter.check("alice");
+        limiter.check("alice");
+        let app = Router::new().nest("/internal/rate-limits", admin_router(limiter));
+
+        let (_, stats) = get(&app, "/internal/rate-limits/stats").await;
+        assert_eq!(
+            (stats["checks"].as_u64(), stats["denied"].as_u64()),
+            (Some(2), Some(1))
+        );
+
+        let (_, entities) = get(&app, "/internal/rate-limits/entities").await;
+        assert_eq!(entities.as_array().unwrap().len(), 2);
+        assert_eq!(entities[1]["entity"], "bob");
+        assert_eq!(entities[1]["remaining"], 5);
+        let (_, limited) = get(&app, "/internal/rate-limits/entities?limited=true").await;
+        assert_eq!(limited.as_array().unwrap().len(), 1);
+
+        let (status, alice) = get(&app, "/internal/rate-limits/entities/alice").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            (alice["limited"].as_bool(), alice["denied"].as_u64()),
+            (Some(true), Some(1))
+        );
+        assert_eq!(alice["refresh_rate_ms"], 60_000);
+        let (status, _) = get(&app, "/internal/rate-limits/entities/carol").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, offenders) = get(&app, "/internal/rate-limits/offenders").await;
+        assert_eq!(offenders[0]["entity"], "alice");
+        assert_eq!(offenders.as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,318 @@
+//! A limiter served over HTTP, for services that aren't written in Rust.
+//!
+//! ```text
+//! rate-gate [--listen 127.0.0.1:8080] [--config limits.toml] [--default 100/min] [--idle-ttl 600]
+//! ```
+//!
+//! Entities are strings. The config has the policies and entities of `Config`,
+//! entities nobody added get the `--default` rate, if any, and are forgotten after
+//! `--idle-ttl` seconds without a request.
+//!
+//! - `POST /check/{entity}?cost=1`: consumes requests, `200` if allowed and `429` if not.
+//! - `PUT /entities/{entity}`: adds the entity, with `{"policy": "free"}` or `{"rate": "10/min"}`.
+//! - `DELETE /entities/{entity}`: removes the entity.
+//! - The read-only endpoints of `rate_gate::axum::admin_router`, like `GET /entities/{entity}`,
+//!   `GET /offenders` and `GET /stats`.
+//!
+//! Unknown entities are answered with `404`, errors with `{"error": "..."}`.
+
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{post, put};
+use axum::{Json, Router};
+use rate_gate::axum::admin_router;
+use rate_gate::{Config, Decision, Limiter, LimiterBuilder, Policy};
+use serde::{Deserialize, Serialize};
+
+const USAGE: &str =
+    "usage: rate-gate [--listen ADDR] [--config FILE] [--default RATE] [--idle-ttl SECS]";
+
+/// What the daemon was started with.
+#[derive(Debug)]
+struct Options {
+    listen: SocketAddr,
+    config: Option<String>,
+    default: Option<Policy>,
+    idle_ttl: Option<Duration>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            listen: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            config: None,
+            default: None,
+            idle_ttl: None,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value of {}", arg));
+            match arg.as_str() {
+                "--listen" => {
+                    let listen = value()?;
+                    options.listen = listen
+                        .parse()
+                        .map_err(|_| format!("invalid address `{}`", listen))?;
+                }
+                "--config" => options.config = Some(value()?),
+                "--default" => {
+                    options.default = Some(Policy::parse(&value()?).map_err(|err| err.to_string())?)
+                }
+                "--idle-ttl" => {
+                    let secs = value()?;
+                    let secs = secs
+                        .parse()
+                        .map_err(|_| format!("invalid idle TTL `{}`", secs))?;
+                    options.idle_ttl = Some(Duration::from_secs(secs));
+                }
+                _ => return Err(format!("unknown argument `{}`", arg)),
+            }
+        }
+        Ok(options)
+    }
+
+    fn limiter(&self) -> Result<Limiter<String>, String> {
+        let mut builder = LimiterBuilder::new();
+        if let Some(default) = &self.default {
+            builder = builder.default_limit(default.max_limit, default.refresh_rate);
+        }
+        if let Some(idle_ttl) = self.idle_ttl {
+            builder = builder.idle_ttl(idle_ttl);
+        }
+        let limiter = builder.build();
+        if let Some(path) = &self.config {
+            let config = Config::load(path).map_err(|err| err.to_string())?;
+            limiter
+                .load_config(&config)
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(limiter)
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    let limiter = match options.limiter() {
+        Ok(limiter) => limiter,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(idle_ttl) = options.idle_ttl {
+        limiter.spawn_cleanup(idle_ttl);
+    }
+
+    let listener = match tokio::net::TcpListener::bind(options.listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("failed to listen on {}: {}", options.listen, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    eprintln!("listening on http://{}", options.listen);
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    if let Err(err) = axum::serve(listener, app(limiter))
+        .with_graceful_shutdown(shutdown)
+        .await
+    {
+        eprintln!("server error: {}", err);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn app(limiter: Limiter<String>) -> Router {
+    Router::new()
+        .route("/check/{entity}", post(check))
+        .route("/entities/{entity}", put(add).delete(remove))
+        .merge(admin_router(limiter.clone()))
+        .with_state(limiter)
+}
+
+#[derive(Deserialize)]
+struct CheckQuery {
+    #[serde(default = "one")]
+    cost: usize,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Serialize)]
+struct CheckResponse {
+    allowed: bool,
+    remaining: usize,
+    reset_in_ms: u64,
+    retry_after_ms: u64,
+}
+
+async fn check(
+    State(limiter): State<Limiter<String>>,
+    Path(entity): Path<String>,
+    Query(query): Query<CheckQuery>,
+) -> Response {
+    let (status, response) = match limiter.consume(&entity, query.cost) {
+        Decision::Allowed {
+            remaining,
+            reset_in,
+        } => (
+            StatusCode::OK,
+            CheckResponse {
+                allowed: true,
+                remaining,
+                reset_in_ms: millis(reset_in),
+                retry_after_ms: 0,
+            },
+        ),
+        Decision::Denied { retry_after } | Decision::Banned { retry_after } => (
+            StatusCode::TOO_MANY_REQUESTS,
+            CheckResponse {
+                allowed: false,
+                remaining: 0,
+                reset_in_ms: millis(retry_after),
+                retry_after_ms: millis(retry_after),
+            },
+        ),
+        Decision::Unknown => return unknown(&entity),
+    };
+    (status, Json(response)).into_response()
+}
+
+/// How an entity is added, by the name of a policy of the config or by a rate.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AddRequest {
+    Policy(String),
+    Rate(String),
+}
+
+async fn add(
+    State(limiter): State<Limiter<String>>,
+    Path(entity): Path<String>,
+    Json(request): Json<AddRequest>,
+) -> Response {
+    match request {
+        AddRequest::Policy(name) => {
+            if !limiter.add_limited_entity_with_named_policy(entity, &name) {
+                let message = format!("unknown policy `{}`", name);
+                return error(StatusCode::BAD_REQUEST, message);
+            }
+        }
+        AddRequest::Rate(rate) => match Policy::parse(&rate) {
+            Ok(policy) => limiter.add_limited_entity_with_policy(entity, policy),
+            Err(err) => return error(StatusCode::BAD_REQUEST, err.to_string()),
+        },
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn remove(State(limiter): State<Limiter<String>>, Path(entity): Path<String>) -> Response {
+    match limiter.remove_limited_entity(&entity) {
+        Some(_) => StatusCode::NO_CONTENT.into_response(),
+        None => unknown(&entity),
+    }
+}
+
+fn unknown(entity: &str) -> Response {
+    error(
+        StatusCode::NOT_FOUND,
+        format!("unknown entity `{}`", entity),
+    )
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    #[derive(Serialize)]
+    struct Error {
+        error: String,
+    }
+    (status, Json(Error { error: message })).into_response()
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    async fn call(app: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let app = app(Limiter::new());
+        let (status, _) = call(&app, "POST", "/check/alice", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(&app, "PUT", "/entities/alice", r#"{"rate": "2/min"}"#).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = call(&app, "POST", "/check/alice?cost=2", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""remaining":0"#), "{}", body);
+        let (status, _) = call(&app, "POST", "/check/alice", "").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let (_, body) = call(&app, "GET", "/entities/alice", "").await;
+        assert!(body.contains(r#""denied":1"#), "{}", body);
+        let (_, body) = call(&app, "GET", "/offenders", "").await;
+        assert!(body.contains(r#""entity":"alice""#), "{}", body);
+        let (_, body) = call(&app, "GET", "/stats", "").await;
+        assert!(body.contains(r#""checks":3"#), "{}", body);
+
+        let (status, _) = call(&app, "PUT", "/entities/bob", r#"{"policy": "pro"}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&app, "DELETE", "/entities/alice", "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&app, "GET", "/entities/alice", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_options() {
+        let args = [
+            "--listen",
+            "0.0.0.0:9000",
+            "--default",
+            "5/s",
+            "--idle-ttl",
+            "60",
+        ];
+        let options = Options::parse(args.iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!(options.listen.port(), 9000);
+        assert_eq!(options.default.unwrap().max_limit, 5);
+        assert_eq!(options.idle_ttl, Some(Duration::from_secs(60)));
+
+        assert!(Options::parse(["--default".to_string()].into_iter()).is_err());
+        assert!(Options::parse(["--verbose".to_string()].into_iter()).is_err());
+    }
+}
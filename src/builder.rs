@@ -0,0 +1,156 @@
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::shards::{default_shard_count, Shards, Storage};
+use crate::stats::Counters;
+use crate::sync::RwLock;
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashMap;
+
+use crate::{Clock, Escalation, Limiter, Policy};
+
+/// Configures a `Limiter` before creating it.
+#[derive(Debug, Clone)]
+pub struct LimiterBuilder {
+    shards: usize,
+    storage: Storage,
+    default: Option<(usize, Duration)>,
+    idle_ttl: Option<Duration>,
+    max_entities: Option<usize>,
+    capacity: usize,
+    escalation: Option<Escalation>,
+    policies: HashMap<String, Policy>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for LimiterBuilder {
+    fn default() -> Self {
+        LimiterBuilder {
+            shards: default_shard_count(),
+            storage: Storage::default(),
+            default: None,
+            idle_ttl: None,
+            max_entities: None,
+            capacity: 0,
+            escalation: None,
+            policies: HashMap::new(),
+            #[cfg(not(feature = "tokio"))]
+            clock: Arc::new(crate::SystemClock),
+            #[cfg(feature = "tokio")]
+            clock: Arc::new(crate::TokioClock),
+        }
+    }
+}
+
+impl LimiterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many independently locked shards the entities are spread over,
+    /// rounded up to the next power of two.
+    ///
+    /// More shards means less contention between entities, defaults to four per core.
+    /// Every shard is a locked map of its own, so checks and updates of entities in
+    /// different shards proceed in parallel. One shard puts every entity behind a single lock.
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.shards = shards;
+        self
+    }
+
+    /// Sets how the entities are stored, `Storage::Sharded` by default.
+    /// `Storage::DashMap` takes the `dashmap` feature.
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Limits entities that were never added with `max_limit` and `refresh_rate`,
+    /// see `Limiter::with_default`.
+    pub fn default_limit(mut self, max_limit: usize, refresh_rate: Duration) -> Self {
+        self.default = Some((max_limit, refresh_rate));
+        self
+    }
+
+    /// Entities that haven't consumed any requests for `idle_ttl` get removed
+    /// by `Limiter::evict_idle`.
+    pub fn idle_ttl(mut self, idle_ttl: Duration) -> Self {
+        self.idle_ttl = Some(idle_ttl);
+        self
+    }
+
+    /// Caps how many entities the limiter tracks, see `Limiter::with_max_entities`.
+    pub fn max_entities(mut self, max_entities: usize) -> Self {
+        self.max_entities = Some(max_entities);
+        self
+    }
+
+    /// Allocates room for `capacity` entities up front, so a limiter expecting that many
+    /// doesn't grow its maps while it fills up, see `Limiter::reserve`.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Escalates the penalty of entities that keep getting denied, see `Escalation`.
+    pub fn escalation(mut self, escalation: Escalation) -> Self {
+        self.escalation = Some(escalation);
+        self
+    }
+
+    /// Registers `policy` as `name`, see `Limiter::define_policy`.
+    pub fn policy(mut self, name: impl Into<String>, policy: Policy) -> Self {
+        self.policies.insert(name.into(), policy);
+        self
+    }
+
+    /// Sets where the limiter reads the time from, e.g. a `ManualClock` in tests.
+    /// Defaults to `SystemClock`, or `TokioClock` with the `tokio` feature.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn build<T>(self) -> Limiter<T>
+    where
+        T: Hash + Eq + Send + 'static,
+    {
+        self.build_with_hasher(DefaultHashBuilder::default())
+    }
+
+    /// Builds a limiter hashing entities with `hasher` instead of the default one,
+    /// which resists collisions crafted by clients. A faster hasher like FxHash is
+    /// only safe for entities the clients don't choose.
+    pub fn build_with_hasher<T, S>(self, hasher: S) -> Limiter<T, S>
+    where
+        T: Hash + Eq + Send + 'static,
+        S: BuildHasher + Clone,
+    {
+        Limiter {
+            requests: Arc::new(Shards::new(
+                self.shards,
+                self.max_entities,
+                self.capacity,
+                hasher,
+                self.storage,
+            )),
+            default: self.default,
+            idle_ttl: self.idle_ttl,
+            escalation: self.escalation,
+            access: Arc::default(),
+            mode: Arc::default(),
+            policies: Arc::new(RwLock::new(self.policies)),
+            tiers: None,
+            clock: self.clock,
+            hooks: Arc::default(),
+            #[cfg(feature = "audit")]
+            audit: None,
+            counters: Arc::new(Counters::new(self.shards)),
+            #[cfg(feature = "tokio")]
+            waiters: Arc::new(crate::queue::WaitQueues::new(self.shards)),
+            #[cfg(feature = "tracing")]
+            debug: None,
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use hashbrown::HashMap;
+
+/// A range of IP addresses sharing their first `prefix` bits, like `10.0.0.0/8`,
+/// see `IpLimiter::limit_range`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The range of the first `prefix` bits of `ip`, cut to the length of the address.
+    pub fn new(ip: IpAddr, prefix: u8) -> Self {
+        let prefix = prefix.min(max_prefix(ip));
+        Cidr {
+            network: mask(ip, prefix),
+            prefix,
+        }
+    }
+
+    /// The first address of the range.
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    /// How many leading bits the addresses of the range share.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Returns `true` if `ip` is in the range. IPv4 addresses are never in IPv6 ranges
+    /// and the other way around.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.network.is_ipv4() && mask(ip, self.prefix) == self.network
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    /// Parses a range like `10.0.0.0/8` or `2001:db8::/32`, or a single address.
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseCidrError(cidr.to_string());
+        let (ip, prefix) = match cidr.trim().split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (cidr.trim(), None),
+        };
+        let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix(ip),
+        };
+        if prefix > max_prefix(ip) {
+            return Err(invalid());
+        }
+        Ok(Cidr::new(ip, prefix))
+    }
+}
+
+/// Why a range couldn't be parsed as a `Cidr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCidrError(String);
+
+impl fmt::Display for ParseCidrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected a range like `10.0.0.0/8`, got `{}`", self.0)
+    }
+}
+
+impl std::error::Error for ParseCidrError {}
+
+fn max_prefix(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// `ip` with every bit past the first `prefix` cleared.
+pub(crate) fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+/// How the addresses of a range are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RangeRule {
+    /// Limited by the policy named after the range.
+    Limited,
+    /// Allowed without touching any bucket.
+    Unlimited,
+}
+
+/// Ranges with a rule, resolved by longest prefix match.
+///
+/// Looks up one map entry per prefix length in use, so even thousands of ranges,
+/// like the published ranges of a cloud provider, take at most 33 or 129 lookups.
+#[derive(Debug, Default)]
+pub(crate) struct CidrRules {
+    rules: HashMap<Cidr, RangeRule>,
+    v4: Vec<u8>, // Prefix lengths in use, longest first
+    v6: Vec<u8>,
+}
+
+impl CidrRules {
+    /// Sets the rule of `cidr`, replacing any previous one.
+    pub(crate) fn insert(&mut self, cidr: Cidr, rule: RangeRule) {
+        self.rules.insert(cidr, rule);
+        self.index();
+    }
+
+    /// Returns `false` if `cidr` had no rule.
+    pub(crate) fn remove(&mut self, cidr: &Cidr) -> bool {
+        let removed = self.rules.remove(cidr).is_some();
+        self.index();
+        removed
+    }
+
+    /// The narrowest range containing `ip` and its rule.
+    pub(crate) fn lookup(&self, ip: IpAddr) -> Option<(Cidr, RangeRule)> {
+        let prefixes = match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        prefixes.iter().find_map(|&prefix| {
+            let cidr = Cidr::new(ip, prefix);
+            self.rules.get(&cidr).map(|&rule| (cidr, rule))
+        })
+    }
+
+    fn index(&mut self) {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for cidr in self.rules.keys() {
+            match cidr.network {
+                IpAddr::V4(_) => v4.push(cidr.prefix),
+                IpAddr::V6(_) => v6.push(cidr.prefix),
+            }
+        }
+        for prefixes in [&mut v4, &mut v6] {
+            prefixes.sort_unstable_by(|a, b| b.cmp(a));
+            prefixes.dedup();
+        }
+        self.v4 = v4;
+        self.v6 = v6;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+        assert!(cidr.contains(ip("10.255.0.1")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert_eq!("2001:db8::1".parse::<Cidr>().unwrap().prefix(), 128);
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!(!"0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("::1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let mut rules = CidrRules::default();
+        rules.insert("0.0.0.0/0".parse().unwrap(), RangeRule::Limited);
+        rules.insert("10.0.0.0/8".parse().unwrap(), RangeRule::Unlimited);
+        rules.insert("10.1.0.0/16".parse().unwrap(), RangeRule::Limited);
+
+        let lookup = |rules: &CidrRules, ip: &str| {
+            rules
+                .lookup(ip.parse().unwrap())
+                .map(|(cidr, _)| cidr.to_string())
+        };
+        assert_eq!(lookup(&rules, "10.1.2.3").as_deref(), Some("10.1.0.0/16"));
+        assert_eq!(lookup(&rules, "10.2.0.1").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(lookup(&rules, "192.168.0.1").as_deref(), Some("0.0.0.0/0"));
+        assert_eq!(lookup(&rules, "::1"), None);
+
+        assert!(rules.remove(&"10.1.0.0/16".parse().unwrap()));
+        assert_eq!(lookup(&rules, "10.1.2.3").as_deref(), Some("10.0.0.0/8"));
+    }
+}
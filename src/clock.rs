@@ -0,0 +1,252 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::sync::Mutex;
+
+/// Where the limiter reads the time from, set with `LimiterBuilder::clock`.
+///
+/// Defaults to `SystemClock`, or `TokioClock` with the `tokio` feature. Tests can use
+/// a `ManualClock` to refill buckets without sleeping.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The wall-clock time, read when windows are aligned to it, see
+    /// `Policy::with_aligned_windows`.
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The monotonic clock of the system, `Instant::now`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The clock of the tokio runtime, `tokio::time::Instant::now`.
+///
+/// It stands still while the time is paused with `tokio::time::pause` and moves with
+/// `tokio::time::advance`, so async tests control the limiter like the rest of the app.
+/// Pausing needs tokio's `test-util` feature, without it or outside a runtime this is
+/// the system clock.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+#[cfg(feature = "tokio")]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+/// A clock that is only read every `precision` by a background thread, so reading it
+/// is a single atomic load instead of a call to `Instant::now`.
+///
+/// For limiters checking millions of requests a second, where being up to `precision`
+/// late doesn't matter, e.g. 1ms for limits of a second or longer:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{CoarseClock, Limiter, LimiterBuilder};
+/// let limiter: Limiter<u64> = LimiterBuilder::new()
+///     .clock(CoarseClock::new(Duration::from_millis(1)))
+///     .build();
+/// ```
+///
+/// The thread stops once every clone of the clock has been dropped.
+#[derive(Debug, Clone)]
+pub struct CoarseClock {
+    inner: Arc<Coarse>,
+}
+
+#[derive(Debug)]
+struct Coarse {
+    start: Instant,
+    elapsed: AtomicU64, // Nanoseconds since start when the clock was last read
+}
+
+impl CoarseClock {
+    /// Starts a thread reading the system clock every `precision`.
+    ///
+    /// Panics if `precision` is zero.
+    pub fn new(precision: Duration) -> Self {
+        assert!(!precision.is_zero(), "precision must not be zero");
+        let inner = Arc::new(Coarse {
+            start: Instant::now(),
+            elapsed: AtomicU64::new(0),
+        });
+        let coarse = Arc::downgrade(&inner);
+        thread::Builder::new()
+            .name("rate-gate-clock".to_string())
+            .spawn(move || loop {
+                thread::sleep(precision);
+                let Some(coarse) = coarse.upgrade() else {
+                    return; // the clock is gone
+                };
+                let elapsed = coarse.start.elapsed().as_nanos() as u64;
+                coarse.elapsed.store(elapsed, Ordering::Relaxed);
+            })
+            .expect("failed to spawn the clock thread");
+        CoarseClock { inner }
+    }
+}
+
+impl Clock for CoarseClock {
+    fn now(&self) -> Instant {
+        let elapsed = self.inner.elapsed.load(Ordering::Relaxed);
+        self.inner.start + Duration::from_nanos(elapsed)
+    }
+}
+
+/// A clock that only moves when told to, for deterministic tests.
+///
+/// Clones share the time, so the clock can be advanced after handing it to the limiter:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{LimiterBuilder, ManualClock};
+/// let clock = ManualClock::new();
+/// let limiter = LimiterBuilder::new().clock(clock.clone()).build();
+/// limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+///
+/// assert!(limiter.check(&"user1").is_allowed());
+/// assert!(!limiter.check(&"user1").is_allowed());
+/// clock.advance(Duration::from_secs(60));
+/// assert!(limiter.check(&"user1").is_allowed());
+/// ```
+///
+/// Its wall-clock time moves along with it, and can be set to e.g. just before
+/// midnight to test windows aligned to days.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    time: Arc<Mutex<ManualTime>>,
+}
+
+#[derive(Debug)]
+struct ManualTime {
+    now: Instant,
+    system_time: SystemTime,
+}
+
+impl ManualClock {
+    /// A clock standing at the current time.
+    pub fn new() -> Self {
+        ManualClock {
+            time: Arc::new(Mutex::new(ManualTime {
+                now: Instant::now(),
+                system_time: SystemTime::now(),
+            })),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.time.lock();
+        time.now += duration;
+        time.system_time += duration;
+    }
+
+    /// Moves the clock to `now`, which may be in the past of the clock.
+    pub fn set(&self, now: Instant) {
+        let mut time = self.time.lock();
+        time.system_time = match now.checked_duration_since(time.now) {
+            Some(forward) => time.system_time + forward,
+            None => time.system_time - time.now.duration_since(now),
+        };
+        time.now = now;
+    }
+
+    /// Sets the wall-clock time the clock stands at, without moving `now`.
+    pub fn set_system_time(&self, system_time: SystemTime) {
+        self.time.lock().system_time = system_time;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.time.lock().now
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.time.lock().system_time
+    }
+}
+
+/// The wall-clock time at an instant, to tell the one at other instants.
+#[derive(Debug, Clone, Copy, Hash)]
+pub(crate) struct WallTime {
+    instant: Instant,
+    system_time: SystemTime,
+}
+
+impl WallTime {
+    pub(crate) fn new(instant: Instant, system_time: SystemTime) -> Self {
+        WallTime {
+            instant,
+            system_time,
+        }
+    }
+
+    /// The wall-clock time at `at`.
+    pub(crate) fn at(&self, at: Instant) -> SystemTime {
+        match at.checked_duration_since(self.instant) {
+            Some(elapsed) => self.system_time + elapsed,
+            None => self.system_time - self.instant.duration_since(at),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let shared = clock.clone();
+        shared.advance(Duration::from_secs(5));
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+        clock.set(start);
+        assert_eq!(shared.now(), start);
+
+        let midnight = SystemTime::UNIX_EPOCH + Duration::from_secs(86_400);
+        clock.set_system_time(midnight);
+        shared.advance(Duration::from_secs(5));
+        assert_eq!(clock.system_time(), midnight + Duration::from_secs(5));
+        clock.set(start);
+        assert_eq!(clock.system_time(), midnight);
+    }
+
+    #[test]
+    fn test_coarse_clock() {
+        let clock = CoarseClock::new(Duration::from_millis(5));
+        let start = clock.now();
+        assert!(start <= Instant::now());
+        thread::sleep(Duration::from_millis(50));
+        let later = clock.now();
+        assert!(later > start && later <= Instant::now());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_tokio_clock() {
+        let start = TokioClock.now();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(TokioClock.now() - start, Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,96 @@
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The requests of an entity in progress, at most `max` at once,
+/// see `Limiter::limit_concurrency`.
+#[derive(Debug, Clone)]
+pub(crate) struct InFlight {
+    pub(crate) max: usize,
+    count: Arc<AtomicUsize>, // Shared with the guards, which outlive any lock on the entity
+}
+
+impl Hash for InFlight {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.max.hash(state);
+        Arc::as_ptr(&self.count).hash(state);
+    }
+}
+
+impl InFlight {
+    pub(crate) fn new(max: usize) -> Self {
+        InFlight {
+            max,
+            count: Arc::default(),
+        }
+    }
+
+    /// The same requests in progress, capped at `max` from now on.
+    pub(crate) fn with_max(&self, max: usize) -> Self {
+        InFlight {
+            max,
+            count: self.count.clone(),
+        }
+    }
+
+    /// How many requests are in progress.
+    pub(crate) fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Starts a request, `None` if `max` are in progress already.
+    pub(crate) fn acquire(&self) -> Option<InFlightGuard> {
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < self.max).then_some(count + 1)
+            })
+            .ok()?;
+        Some(InFlightGuard {
+            count: Some(self.count.clone()),
+        })
+    }
+}
+
+/// A request in progress, counted against the concurrency limit of its entity until
+/// the guard is dropped. Created with `Limiter::acquire_in_flight`.
+#[must_use = "dropping an InFlightGuard ends the request right away"]
+#[derive(Debug)]
+pub struct InFlightGuard {
+    count: Option<Arc<AtomicUsize>>, // None for entities without a concurrency limit
+}
+
+impl InFlightGuard {
+    /// A guard for an entity without a concurrency limit.
+    pub(crate) fn unlimited() -> Self {
+        InFlightGuard { count: None }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Some(count) = &self.count {
+            count.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight() {
+        let in_flight = InFlight::new(2);
+        let first = in_flight.acquire().unwrap();
+        let second = in_flight.acquire().unwrap();
+        assert!(in_flight.acquire().is_none());
+        assert_eq!(in_flight.count(), 2);
+
+        drop(first);
+        let raised = in_flight.with_max(3);
+        let third = raised.acquire().unwrap();
+        assert!(raised.acquire().is_some());
+        drop((second, third));
+        assert_eq!(in_flight.count(), 0);
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::policy::parse_window;
+use crate::{Algorithm, Limiter, ParsePolicyError, Policy};
+
+#[cfg(feature = "watch")]
+mod watch;
+
+#[cfg(feature = "watch")]
+pub use self::watch::ConfigWatcher;
+
+/// Named policies and the entities added with them, loaded from a TOML or YAML file
+/// and applied with `Limiter::load_config`.
+///
+/// ```toml
+/// [policies]
+/// free = "100/min"
+/// pro = { rate = "10k/h", algorithm = "TokenBucket", max_in_flight = 8 }
+/// api = { rate = "5/s", burst = 20, warm_up = "30s", overdraft = 10 }
+/// daily = { rate = "1000/day", aligned = true }
+/// scraper = { rate = "60/min", cooldown = "5m", jitter = 10 }
+/// upstream = { rate = "5000/day", reset = "0 8 * * *" } # with the `cron` feature
+///
+/// [entities]
+/// "user1" = "free"
+/// "user2" = "pro"
+/// ```
+///
+/// Rates are parsed with `Policy::parse`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawConfig")]
+pub struct Config {
+    /// Policies by name.
+    pub policies: BTreeMap<String, Policy>,
+    /// The name of the policy of every entity.
+    pub entities: BTreeMap<String, String>,
+}
+
+/// `Config` as written in the file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    policies: BTreeMap<String, RawPolicy>,
+    #[serde(default)]
+    entities: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPolicy {
+    Rate(String),
+    Table {
+        rate: String,
+        #[serde(default)]
+        algorithm: Algorithm,
+        burst: Option<usize>,
+        warm_up: Option<String>,
+        #[serde(default)]
+        aligned: bool,
+        #[cfg(feature = "cron")]
+        reset: Option<String>,
+        max_in_flight: Option<usize>,
+        #[serde(default)]
+        overdraft: usize,
+        cooldown: Option<String>,
+        #[serde(default)]
+        jitter: u8,
+    },
+}
+
+impl TryFrom<RawConfig> for Config {
+    type Error = ParsePolicyError;
+
+    fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
+        let policies = raw
+            .policies
+            .into_iter()
+            .map(|(name, policy)| {
+                let policy = match policy {
+                    RawPolicy::Rate(rate) => Policy::parse(&rate)?,
+                    RawPolicy::Table {
+                        rate,
+                        algorithm,
+                        burst,
+                        warm_up,
+                        aligned,
+                        #[cfg(feature = "cron")]
+                        reset,
+                        max_in_flight,
+                        overdraft,
+                        cooldown,
+                        jitter,
+                    } => {
+                        let mut policy = Policy::parse(&rate)?.with_algorithm(algorithm);
+                        if let Some(burst) = burst {
+                            policy = policy.with_burst(burst);
+                        }
+                        if let Some(warm_up) = warm_up {
+                            let period = parse_window(warm_up.trim())
+                                .ok_or(ParsePolicyError::InvalidWindow(warm_up))?;
+                            policy = policy.with_warm_up(period);
+                        }
+                        if let Some(cooldown) = cooldown {
+                            let period = parse_window(cooldown.trim())
+                                .ok_or(ParsePolicyError::InvalidWindow(cooldown))?;
+                            policy = policy.with_cooldown(period);
+                        }
+                        if aligned {
+                            policy = policy.with_aligned_windows();
+                        }
+                        if jitter > 0 {
+                            policy = policy.with_jitter(jitter);
+                        }
+                        #[cfg(feature = "cron")]
+                        if let Some(reset) = reset {
+                            policy = policy.with_reset_schedule(reset.parse()?);
+                        }
+                        Policy {
+                            max_in_flight,
+                            overdraft,
+                            ..policy
+                        }
+                    }
+                };
+                Ok((name, policy))
+            })
+            .collect::<Result<_, ParsePolicyError>>()?;
+        Ok(Config {
+            policies,
+            entities: raw.entities,
+        })
+    }
+}
+
+impl Config {
+    /// Parses a config written in TOML.
+    pub fn from_toml(config: &str) -> Result<Self, ConfigError> {
+        toml::from_str(config).map_err(ConfigError::Toml)
+    }
+
+    /// Parses a config written in YAML.
+    pub fn from_yaml(config: &str) -> Result<Self, ConfigError> {
+        serde_yaml::from_str(config).map_err(ConfigError::Yaml)
+    }
+
+    /// Reads a config from `path`, as TOML or YAML depending on its extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let parse = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Config::from_toml,
+            Some("yaml" | "yml") => Config::from_yaml,
+            _ => return Err(ConfigError::UnknownFormat(path.to_path_buf())),
+        };
+        parse(&std::fs::read_to_string(path).map_err(ConfigError::Io)?)
+    }
+}
+
+/// Why a config couldn't be loaded or applied.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read.
+    Io(std::io::Error),
+    /// The file is neither `.toml` nor `.yaml`.
+    UnknownFormat(PathBuf),
+    /// The TOML is malformed, or has an invalid rate.
+    Toml(toml::de::Error),
+    /// The YAML is malformed, or has an invalid rate.
+    Yaml(serde_yaml::Error),
+    /// An entity couldn't be parsed into the limiter's entity type.
+    InvalidEntity(String),
+    /// An entity refers to a policy the config doesn't define.
+    UnknownPolicy { entity: String, policy: String },
+    /// The config file couldn't be watched.
+    #[cfg(feature = "watch")]
+    Watch(notify::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "failed to read config: {}", err),
+            ConfigError::UnknownFormat(path) => {
+                write!(f, "unknown config format: {}", path.display())
+            }
+            ConfigError::Toml(err) => write!(f, "invalid config: {}", err),
+            ConfigError::Yaml(err) => write!(f, "invalid config: {}", err),
+            ConfigError::InvalidEntity(entity) => write!(f, "invalid entity `{}`", entity),
+            ConfigError::UnknownPolicy { entity, policy } => {
+                write!(f, "entity `{}` has unknown policy `{}`", entity, policy)
+            }
+            #[cfg(feature = "watch")]
+            ConfigError::Watch(err) => write!(f, "failed to watch config: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(err) => Some(err),
+            ConfigError::Toml(err) => Some(err),
+            ConfigError::Yaml(err) => Some(err),
+            #[cfg(feature = "watch")]
+            ConfigError::Watch(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl<T, S> Limiter<T, S>
+where
+    T: Hash + Eq + Send + FromStr + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    /// Registers the policies of `config` with `define_policy` and adds its entities
+    /// with them. Entities the limiter already tracks start over with a full bucket.
+    ///
+    /// Nothing is applied if an entity can't be parsed or has an unknown policy.
+    pub fn load_config(&self, config: &Config) -> Result<(), ConfigError> {
+        let entities = parse_entities(config)?;
+        for (name, policy) in &config.policies {
+            self.define_policy(name.clone(), policy.clone());
+        }
+        for (entity, name, _) in entities {
+            self.add_limited_entity_with_named_policy(entity, name);
+        }
+        Ok(())
+    }
+
+    /// Replaces the policies of the limiter with the ones of `config`, without
+    /// dropping what entities consumed so far, e.g. after the config file changed.
+    ///
+    /// - Entities added with a named policy get its new limits, as with `update_limit`.
+    ///   If its algorithm changed, they start over with a full bucket.
+    /// - Entities of the config are added, or switched to their policy if already tracked.
+    /// - Entities whose policy is gone keep their limits.
+    ///
+    /// Nothing is applied if an entity can't be parsed or has an unknown policy.
+    pub fn apply_config(&self, config: &Config) -> Result<(), ConfigError> {
+        let entities = parse_entities(config)?;
+        *self.policies.write() = config
+            .policies
+            .iter()
+            .map(|(name, policy)| (name.clone(), policy.clone()))
+            .collect();
+
+        let now = self.now();
+        let system_time = self.clock.system_time();
+        self.requests.for_each(|key, entry| {
+            let mut entity = entry.lock();
+            let Some(name) = entity.policy.clone() else {
+                return;
+            };
+            match config.policies.get(&*name) {
+                Some(policy) => entity.apply_policy(now, system_time, policy, key),
+                None => entity.policy = None,
+            }
+        });
+
+        for (entity, name, policy) in entities {
+            let updated = self.update(&entity, |state, now| {
+                state.policy = Some(name.into());
+                state.apply_policy(now, system_time, &policy, &entity);
+            });
+            if updated.is_none() {
+                self.add_limited_entity_with_named_policy(entity, name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the entities of `config` along with the name of their policy and the policy.
+fn parse_entities<T: FromStr>(config: &Config) -> Result<Vec<(T, &str, Policy)>, ConfigError> {
+    config
+        .entities
+        .iter()
+        .map(|(entity, name)| {
+            let Some(policy) = config.policies.get(name) else {
+                return Err(ConfigError::UnknownPolicy {
+                    entity: entity.clone(),
+                    policy: name.clone(),
+                });
+            };
+            let parsed = entity
+                .parse()
+                .map_err(|_| ConfigError::InvalidEntity(entity.clone()))?;
+            Ok((parsed, name.as_str(), policy.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_toml_and_yaml_agree() {
+        let toml = Config::from_toml(
+            r#"
+            [policies]
+            free = "1/min"
+            pro = { rate = "10k/h", algorithm = "TokenBucket", max_in_flight = 8 }
+
+            [entities]
+            "user1" = "free"
+            "user2" = "pro"
+            "#,
+        )
+        .unwrap();
+        let yaml = Config::from_yaml(
+            "
+            policies:
+              free: 1/min
+              pro:
+                rate: 10k/h
+                algorithm: TokenBucket
+                max_in_flight: 8
+            entities:
+              user1: free
+              user2: pro
+            ",
+        )
+        .unwrap();
+        assert_eq!(toml, yaml);
+        assert_eq!(
+            toml.policies["pro"],
+            Policy::new(10_000, Duration::from_secs(3600))
+                .with_algorithm(Algorithm::TokenBucket)
+                .with_max_in_flight(8)
+        );
+    }
+
+    #[test]
+    fn test_load_config() {
+        let config = Config::from_toml(
+            r#"
+            policies = { free = "1/min" }
+            entities = { "1" = "free", "2" = "free" }
+            "#,
+        )
+        .unwrap();
+        let limiter: Limiter<u64> = Limiter::new();
+        limiter.load_config(&config).unwrap();
+        assert!(limiter.check(&1).is_allowed());
+        assert!(!limiter.check(&1).is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&2), Some(1));
+        assert!(limiter.policy("free").is_some());
+
+        let limiter: Limiter<u64> = Limiter::new();
+        let mut invalid = config.clone();
+        invalid.entities.insert("3".into(), "pro".into());
+        assert!(matches!(
+            limiter.load_config(&invalid),
+            Err(ConfigError::UnknownPolicy { .. })
+        ));
+        assert_eq!(limiter.get_bucket_remaining(&1), None);
+
+        assert!(matches!(
+            Config::from_toml(r#"policies = { free = "1/fortnight" }"#),
+            Err(ConfigError::Toml(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_config_keeps_consumption() {
+        let config = |free: &str, pro: &str| {
+            let toml = format!(
+                "policies = {{ free = \"{}\", pro = \"{}\" }}\nentities = {{ \"1\" = \"free\" }}",
+                free, pro
+            );
+            Config::from_toml(&toml).unwrap()
+        };
+        let limiter: Limiter<u64> = Limiter::new();
+        limiter.load_config(&config("3/min", "5/min")).unwrap();
+        assert!(limiter.add_limited_entity_with_named_policy(2, "pro"));
+        assert!(limiter.consume(&1, 2).is_allowed());
+        assert!(limiter.consume(&2, 2).is_allowed());
+
+        limiter.apply_config(&config("4/min", "10/min")).unwrap();
+        assert_eq!(limiter.get_bucket_remaining(&1), Some(2));
+        assert_eq!(limiter.get_bucket_remaining(&2), Some(8));
+        assert_eq!(limiter.policy("pro").unwrap().max_limit, 10);
+
+        // Entities move to the policy the config gives them.
+        let mut moved = config("4/min", "10/min");
+        moved.entities.insert("1".into(), "pro".into());
+        moved.entities.insert("3".into(), "free".into());
+        limiter.apply_config(&moved).unwrap();
+        assert_eq!(limiter.get_bucket_remaining(&1), Some(8));
+        assert_eq!(limiter.get_bucket_remaining(&3), Some(4));
+    }
+}
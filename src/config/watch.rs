@@ -0,0 +1,100 @@
+use std::hash::{BuildHasher, Hash};
+use std::path::Path;
+use std::str::FromStr;
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{Config, ConfigError, Limiter};
+
+/// Keeps applying a config file to a limiter while it changes, see
+/// `Limiter::watch_config`. Stops watching once dropped.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl<T, S> Limiter<T, S>
+where
+    T: Hash + Eq + Send + Sync + FromStr + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    /// Applies the config at `path` with `apply_config`, and again every time the file
+    /// changes, until the returned watcher is dropped.
+    ///
+    /// Errors while reloading, like a config with a typo, are handed to `on_error`
+    /// and leave the last applied config in place.
+    pub fn watch_config(
+        &self,
+        path: impl AsRef<Path>,
+        on_error: impl Fn(ConfigError) + Send + 'static,
+    ) -> Result<ConfigWatcher, ConfigError> {
+        let path = path.as_ref().to_path_buf();
+        self.apply_config(&Config::load(&path)?)?;
+
+        let limiter = self.clone();
+        let watched = path.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<_>| {
+            let event: notify::Event = match event {
+                Ok(event) => event,
+                Err(err) => return on_error(ConfigError::Watch(err)),
+            };
+            let changed = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                && event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == watched.file_name());
+            if !changed {
+                return;
+            }
+            let applied = Config::load(&watched).and_then(|config| limiter.apply_config(&config));
+            if let Err(err) = applied {
+                on_error(err);
+            }
+        })
+        .map_err(ConfigError::Watch)?;
+
+        // Editors tend to replace the file rather than write to it, so the
+        // directory is watched instead, which sees the new file as well.
+        let directory = match path.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .map_err(ConfigError::Watch)?;
+        Ok(ConfigWatcher { _watcher: watcher })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_reloads_on_change() {
+        let directory =
+            std::env::temp_dir().join(format!("rate-gate-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("limits.toml");
+        let write = |rate: &str| {
+            let config = format!("policies = {{ free = \"{}\" }}", rate);
+            std::fs::write(&path, config).unwrap();
+        };
+
+        write("1/min");
+        let limiter: Limiter<String> = Limiter::new();
+        let watcher = limiter.watch_config(&path, |_| {}).unwrap();
+        assert_eq!(limiter.policy("free").unwrap().max_limit, 1);
+
+        write("2/min");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while limiter.policy("free").unwrap().max_limit != 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(limiter.policy("free").unwrap().max_limit, 2);
+
+        drop(watcher);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,1773 @@
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::concurrency::InFlight;
+use crate::escalation::Violations;
+use crate::headers::ceil_secs;
+use crate::jitter::Jitter;
+#[cfg(feature = "cron")]
+use crate::schedule::{ResetSchedule, ScheduledReset};
+use crate::snapshot::{before, EntitySnapshot, MergeStrategy, StateSnapshot, ViolationsSnapshot};
+use crate::sync::Mutex;
+use crate::time_of_day::TimeOfDay;
+use crate::warm_up::WarmUp;
+use crate::{Aimd, Algorithm, Decision, Escalation, RateLimitHeaders};
+
+#[derive(Debug, Clone, Hash)]
+pub struct AssociatedEntity {
+    pub(crate) bucket: usize, // How many requests are left in the bucket, 0 means the hard limit.
+    pub(crate) bucket_init: Instant, // When was the last bucket refreshed
+    pub(crate) bucket_max: usize, // set by user, this is the value the bucket will get refilled with.
+    pub(crate) refresh_rate: Duration, // Every refresh_rate tick bucket gets filled with bucket_max
+    pub(crate) algorithm: Algorithm, // How the bucket gets refilled
+    state: State,                 // Extra bookkeeping some algorithms need
+    pub(crate) violations: Option<Violations>, // Penalty under the limiter's Escalation, if any
+    pub(crate) limited: bool,     // Was the last request denied
+    pub(crate) windows: Vec<AssociatedEntity>, // Further limits requests have to pass as well
+    pub(crate) parent: Option<Parent>, // Shared bucket requests count against as well
+    pub(crate) policy: Option<Arc<str>>, // Name of the policy it was added with, if any
+    pub(crate) in_flight: Option<InFlight>, // Requests in progress, if their number is limited
+    pub(crate) warm_up: Option<WarmUp>, // Slow start after idling, if any
+    pub(crate) adaptive: Option<Aimd>, // How reported outcomes move bucket_max, if at all
+    pub(crate) aligned: bool, // Do fixed windows start at multiples of refresh_rate since the Unix epoch
+    #[cfg(feature = "cron")]
+    pub(crate) scheduled: Option<ScheduledReset>, // When fixed windows reset, if not every refresh_rate
+    pub(crate) time_of_day: Option<TimeOfDay>, // Limits switched to over the day, if any
+    pub(crate) overdraft: usize, // How many requests can be borrowed beyond an empty bucket
+    pub(crate) debt: usize,      // Requests borrowed, repaid from refills before anything else
+    pub(crate) cooldown: Option<Duration>, // How long everything is denied once the limit is hit
+    pub(crate) cooling_until: Option<Instant>, // When the current cooldown ends, if any
+    pub(crate) jitter: Option<Jitter>, // Random fixed window lengths, if any
+}
+
+/// The state of a parent entity, shared with the `Entry` it is tracked by.
+#[derive(Debug, Clone)]
+pub(crate) struct Parent(pub(crate) Arc<Mutex<AssociatedEntity>>);
+
+impl Hash for Parent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).hash(state);
+    }
+}
+
+/// A point in time view of an entity, see `Limiter::snapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntityState {
+    /// How many requests are left in the bucket.
+    pub remaining: usize,
+    /// The value the bucket gets refilled with.
+    pub max: usize,
+    /// The timeframe after which the entity gets a renewed limit.
+    pub refresh_rate: Duration,
+    /// How the bucket gets refilled.
+    pub algorithm: Algorithm,
+    /// Time until the bucket is completely refilled.
+    pub reset_in: Duration,
+    /// Time until the next request is allowed, zero if the entity isn't limited.
+    pub retry_after: Duration,
+}
+
+impl EntityState {
+    /// Returns `true` if the next request of the entity would be denied.
+    pub fn is_limited(&self) -> bool {
+        !self.retry_after.is_zero()
+    }
+}
+
+/// How often an entity was allowed and denied, see `Limiter::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntityStats {
+    /// Requests allowed since the entity was added.
+    pub allowed: u64,
+    /// Requests denied for hitting the limit since the entity was added.
+    /// Requests of banned or unlimited entities aren't counted.
+    pub denied: u64,
+    /// When the entity last made a request, or was added.
+    pub last_access: Instant,
+}
+
+/// Algorithm specific state, on top of `bucket` and `bucket_init`.
+#[derive(Debug, Clone, Hash)]
+enum State {
+    Bucket,
+    Gcra { tat: Instant }, // Theoretical arrival time of the next request
+    Log(VecDeque<(Instant, usize)>), // When requests were consumed, and their cost
+    Counter { previous: usize, current: usize }, // Requests in the previous and current window
+    Leaky { level: usize }, // Queued requests, drained since bucket_init
+}
+
+impl AssociatedEntity {
+    /// The value the bucket gets refilled with.
+    pub fn bucket_max(&self) -> usize {
+        self.bucket_max
+    }
+
+    /// The timeframe after which the entity gets a renewed limit.
+    pub fn refresh_rate(&self) -> Duration {
+        self.refresh_rate
+    }
+
+    /// How the bucket gets refilled.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// How many times the refresh rate was escalated for being denied repeatedly,
+    /// see `Escalation`.
+    pub fn penalty_level(&self) -> u32 {
+        self.violations
+            .as_ref()
+            .map_or(0, |violations| violations.level)
+    }
+
+    /// How many requests are left in the bucket right now.
+    /// With several windows, what is left in the tightest one.
+    /// Reads the system clock, not the one of the limiter.
+    pub fn remaining(&self) -> usize {
+        self.refreshed(Instant::now()).available()
+    }
+
+    /// Time since the bucket was last refreshed.
+    pub fn time_since_init(&self) -> Duration {
+        self.bucket_init.elapsed()
+    }
+
+    /// Time left until the bucket is completely refilled.
+    /// With several windows, the bucket of the tightest one.
+    /// Reads the system clock, not the one of the limiter.
+    pub fn time_until_reset(&self) -> Duration {
+        let now = Instant::now();
+        self.refreshed(now).binding().reset_in(now)
+    }
+
+    /// A refreshed copy, for reading without a `&mut`.
+    fn refreshed(&self, now: Instant) -> AssociatedEntity {
+        let mut entity = self.clone();
+        entity.refresh(now);
+        entity
+    }
+
+    pub(crate) fn new(
+        max_limit: usize,
+        refresh_rate: Duration,
+        algorithm: Algorithm,
+        now: Instant,
+    ) -> Self {
+        let state = match algorithm {
+            Algorithm::FixedWindow | Algorithm::TokenBucket => State::Bucket,
+            Algorithm::Gcra => State::Gcra { tat: now },
+            Algorithm::SlidingWindowLog => State::Log(VecDeque::new()),
+            Algorithm::SlidingWindowCounter => State::Counter {
+                previous: 0,
+                current: 0,
+            },
+            Algorithm::LeakyBucket => State::Leaky { level: 0 },
+        };
+        AssociatedEntity {
+            bucket: max_limit,
+            bucket_init: now,
+            bucket_max: max_limit,
+            refresh_rate,
+            algorithm,
+            state,
+            violations: None,
+            limited: false,
+            windows: Vec::new(),
+            parent: None,
+            policy: None,
+            in_flight: None,
+            warm_up: None,
+            adaptive: None,
+            aligned: false,
+            #[cfg(feature = "cron")]
+            scheduled: None,
+            time_of_day: None,
+            overdraft: 0,
+            debt: 0,
+            cooldown: None,
+            cooling_until: None,
+            jitter: None,
+        }
+    }
+
+    /// An entity with the limits of `policy`, `system_time` being the wall-clock time at `now`.
+    pub(crate) fn from_policy<K: Hash + ?Sized>(
+        policy: &crate::Policy,
+        entity_key: &K,
+        now: Instant,
+        system_time: SystemTime,
+    ) -> Self {
+        let (max_limit, refresh_rate) = policy.bucket();
+        let mut entity = AssociatedEntity::new(max_limit, refresh_rate, policy.algorithm, now);
+        entity.limit_in_flight(policy.max_in_flight);
+        entity.set_warm_up(now, policy.warm_up);
+        entity.adaptive = policy.adaptive;
+        entity.overdraft = policy.overdraft;
+        entity.cooldown = policy.cooldown;
+        entity.start_windows(now, system_time, policy, entity_key);
+        entity.jitter_window(now);
+        entity.set_time_of_day(now, system_time, policy);
+        entity
+    }
+
+    /// Switches between the limits of `policy` over the day, if it has any for parts of it.
+    /// `system_time` is the wall-clock time at `now`.
+    fn set_time_of_day(&mut self, now: Instant, system_time: SystemTime, policy: &crate::Policy) {
+        self.time_of_day = (!policy.time_of_day.is_empty()).then(|| {
+            TimeOfDay::new(
+                policy.time_of_day.clone(),
+                policy.bucket(),
+                now,
+                system_time,
+            )
+        });
+        self.follow_time_of_day(now);
+    }
+
+    /// Switches to the limits of the time of day, if they changed.
+    fn follow_time_of_day(&mut self, now: Instant) {
+        let Some((max_limit, refresh_rate)) = self.time_of_day.as_mut().and_then(|t| t.switch(now))
+        else {
+            return;
+        };
+        self.violations = None;
+        self.update_limit(now, max_limit, refresh_rate, false);
+    }
+
+    /// Aligns, schedules or jitters the fixed windows as `policy` says, for the entity
+    /// `entity_key`. `system_time` is the wall-clock time at `now`.
+    fn start_windows<K: Hash + ?Sized>(
+        &mut self,
+        now: Instant,
+        system_time: SystemTime,
+        policy: &crate::Policy,
+        entity_key: &K,
+    ) {
+        self.jitter =
+            (policy.jitter > 0).then(|| Jitter::new(policy.jitter, policy.jitter_seed, entity_key));
+        self.aligned = false;
+        if policy.aligned {
+            self.align(now, system_time);
+        }
+        #[cfg(feature = "cron")]
+        {
+            self.scheduled = None;
+            if let Some(schedule) = &policy.reset {
+                self.schedule_reset(now, system_time, schedule.clone());
+            }
+        }
+    }
+
+    /// Starts fixed windows at multiples of `refresh_rate` since the Unix epoch, so daily
+    /// windows start at midnight UTC and hourly ones on the hour. What was consumed in the
+    /// current window is kept. `system_time` is the wall-clock time at `now`.
+    pub(crate) fn align(&mut self, now: Instant, system_time: SystemTime) {
+        if self.algorithm != Algorithm::FixedWindow || self.refresh_rate.is_zero() {
+            return;
+        }
+        let since_epoch = system_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let into_window = since_epoch.as_nanos() % self.refresh_rate.as_nanos();
+        self.bucket_init = before(now, nanos(into_window));
+        self.aligned = true;
+    }
+
+    /// Resets fixed windows whenever `schedule` fires, rather than every `refresh_rate`,
+    /// which becomes the length of the current window. What was consumed in it is kept.
+    /// `system_time` is the wall-clock time at `now`.
+    #[cfg(feature = "cron")]
+    pub(crate) fn schedule_reset(
+        &mut self,
+        now: Instant,
+        system_time: SystemTime,
+        schedule: ResetSchedule,
+    ) {
+        if self.algorithm != Algorithm::FixedWindow {
+            return;
+        }
+        self.scheduled = Some(ScheduledReset::new(schedule, now, system_time));
+        self.start_window(now);
+    }
+
+    /// Starts the fixed window `now` falls into: the one of the schedule, the aligned one,
+    /// or one starting right away.
+    fn start_window(&mut self, now: Instant) {
+        #[cfg(feature = "cron")]
+        if let Some((start, length)) = self.scheduled.as_ref().and_then(|s| s.window(now)) {
+            self.bucket_init = start;
+            self.refresh_rate = length;
+            return;
+        }
+        self.bucket_init = match (self.aligned, &mut self.jitter) {
+            (true, _) => self.window_start(now),
+            (false, Some(jitter)) => jitter.start(now, self.refresh_rate),
+            (false, None) => now,
+        };
+    }
+
+    /// Gives the current fixed window a random length if the entity has jitter,
+    /// see `Policy::with_jitter`. Aligned and scheduled windows keep theirs.
+    fn jitter_window(&mut self, now: Instant) {
+        if self.jitter.is_some() && self.algorithm == Algorithm::FixedWindow {
+            self.start_window(now);
+        }
+    }
+
+    /// The start of the fixed window `now` falls into, counting whole windows since `bucket_init`.
+    fn window_start(&self, now: Instant) -> Instant {
+        let rate = self.refresh_rate.as_nanos();
+        if rate == 0 {
+            return now;
+        }
+        let elapsed = now.saturating_duration_since(self.bucket_init).as_nanos();
+        before(now, nanos(elapsed % rate))
+    }
+
+    /// Keeps the limit within the bounds of `aimd` from now on.
+    pub(crate) fn set_adaptive(&mut self, now: Instant, aimd: Aimd) {
+        self.adaptive = Some(aimd);
+        self.adapt_to(now, aimd.clamp(self.bucket_max));
+    }
+
+    /// Moves the limit according to the reported outcome of a request,
+    /// returns the new limit if the entity is adaptive.
+    pub(crate) fn adapt(&mut self, now: Instant, success: bool) -> Option<usize> {
+        let aimd = self.adaptive?;
+        let limit = match success {
+            true => aimd.increased(self.bucket_max),
+            false => aimd.decreased(self.bucket_max),
+        };
+        self.adapt_to(now, limit);
+        Some(limit)
+    }
+
+    fn adapt_to(&mut self, now: Instant, limit: usize) {
+        if limit != self.bucket_max {
+            self.update_limit(now, limit, self.refresh_rate, false);
+        }
+    }
+
+    /// Slows down the refill after idling for `period`, keeping an ongoing warm-up.
+    /// Only token buckets warm up.
+    pub(crate) fn set_warm_up(&mut self, now: Instant, period: Option<Duration>) {
+        let period = period.filter(|_| self.algorithm == Algorithm::TokenBucket);
+        match (period, &mut self.warm_up) {
+            (Some(period), Some(warm_up)) => warm_up.period = period,
+            (period, warm_up) => *warm_up = period.map(|period| WarmUp::new(period, now)),
+        }
+    }
+
+    /// Caps the requests in progress at `max`, keeping those already in progress.
+    pub(crate) fn limit_in_flight(&mut self, max: Option<usize>) {
+        self.in_flight = match (max, &self.in_flight) {
+            (Some(max), Some(in_flight)) => Some(in_flight.with_max(max)),
+            (Some(max), None) => Some(InFlight::new(max)),
+            (None, _) => None,
+        };
+    }
+
+    /// Locks the parent, if any, and hands it to `f` refreshed.
+    fn with_parent<R>(
+        &self,
+        now: Instant,
+        f: impl FnOnce(&mut AssociatedEntity) -> R,
+    ) -> Option<R> {
+        let mut parent = self.parent.as_ref()?.0.lock();
+        parent.refresh(now);
+        Some(f(&mut parent))
+    }
+
+    /// How many requests are left, counting the parent's bucket and its own parents.
+    /// Expects `refresh` to have been called with the same `now`.
+    pub(crate) fn total_available(&self, now: Instant) -> usize {
+        if self.cooling(now) {
+            return 0;
+        }
+        let available = self.available();
+        self.with_parent(now, |parent| parent.total_available(now))
+            .map_or(available, |parent| available.min(parent))
+    }
+
+    /// Adds another limit the entity's requests have to pass, using its algorithm.
+    pub(crate) fn add_window(&mut self, now: Instant, max_limit: usize, refresh_rate: Duration) {
+        let window = AssociatedEntity::new(max_limit, refresh_rate, self.algorithm, now);
+        self.windows.push(window);
+    }
+
+    /// How many requests are left in the tightest window.
+    pub(crate) fn available(&self) -> usize {
+        self.windows
+            .iter()
+            .map(|window| window.bucket)
+            .fold(self.bucket, usize::min)
+    }
+
+    /// The window with the fewest requests left, the entity itself without windows.
+    fn binding(&self) -> &AssociatedEntity {
+        self.windows.iter().fold(self, |binding, window| {
+            if window.bucket < binding.bucket {
+                window
+            } else {
+                binding
+            }
+        })
+    }
+
+    /// Refills the bucket of every window according to the entity's algorithm.
+    pub(crate) fn refresh(&mut self, now: Instant) {
+        self.follow_time_of_day(now);
+        self.refill(now);
+        self.repay(now);
+        for window in &mut self.windows {
+            window.refill(now);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        match self.algorithm {
+            Algorithm::FixedWindow => {
+                if now.saturating_duration_since(self.bucket_init) >= self.refresh_rate {
+                    self.bucket = self.bucket_max;
+                    self.start_window(now);
+                }
+            }
+            Algorithm::TokenBucket => self.refill_continuous(now),
+            Algorithm::Gcra => {
+                // Every outstanding emission interval before the TAT is a used up request.
+                let used = div_ceil(self.gcra_backlog(now), self.token_interval());
+                self.bucket = self.bucket_max.saturating_sub(used);
+            }
+            Algorithm::SlidingWindowLog => {
+                let State::Log(log) = &mut self.state else {
+                    return;
+                };
+                // Requests older than the window are given back.
+                while let Some(&(at, cost)) = log.front() {
+                    if now.saturating_duration_since(at) < self.refresh_rate {
+                        break;
+                    }
+                    log.pop_front();
+                    self.bucket = (self.bucket + cost).min(self.bucket_max);
+                }
+            }
+            Algorithm::SlidingWindowCounter => {
+                let State::Counter { previous, current } = &mut self.state else {
+                    return;
+                };
+                let rate = self.refresh_rate.as_nanos();
+                let elapsed = now.saturating_duration_since(self.bucket_init).as_nanos();
+                let windows = elapsed.checked_div(rate).unwrap_or(0);
+                if windows >= 2 {
+                    *previous = 0;
+                    *current = 0;
+                } else if windows == 1 {
+                    *previous = *current;
+                    *current = 0;
+                }
+                // bucket_init tracks the start of the current window.
+                self.bucket_init += nanos(windows * rate);
+
+                let into_window = elapsed - windows * rate;
+                let estimate = *current + decayed(*previous, rate, into_window);
+                self.bucket = self.bucket_max.saturating_sub(estimate);
+            }
+            Algorithm::LeakyBucket => self.drain(now),
+        }
+    }
+
+    /// Takes `cost` requests out of every window if all of them have enough left.
+    /// Expects `refresh` to have been called with the same `now`.
+    pub(crate) fn try_consume(&mut self, now: Instant, cost: usize) -> bool {
+        let borrowed = cost.saturating_sub(self.bucket);
+        if borrowed > self.overdraft.saturating_sub(self.debt)
+            || self.windows.iter().any(|window| window.bucket < cost)
+        {
+            return false;
+        }
+        // Holding on to this entity, the parent takes its share atomically or not at all.
+        if self.with_parent(now, |parent| parent.try_consume(now, cost)) == Some(false) {
+            return false;
+        }
+        self.take(now, cost - borrowed);
+        self.debt += borrowed;
+        for window in &mut self.windows {
+            window.take(now, cost);
+        }
+        true
+    }
+
+    /// Denies everything for `cooldown` once the limit is hit, `None` also ends
+    /// the cooldown in progress.
+    pub(crate) fn set_cooldown(&mut self, cooldown: Option<Duration>) {
+        self.cooldown = cooldown;
+        if cooldown.is_none() {
+            self.cooling_until = None;
+        }
+    }
+
+    /// Is the entity cooling down at `now` after hitting its limit.
+    fn cooling(&self, now: Instant) -> bool {
+        self.cooling_until.is_some_and(|until| now < until)
+    }
+
+    /// Lets up to `overdraft` requests beyond an empty bucket be allowed, as a debt that is
+    /// repaid from refills before new requests can use them. A lower overdraft leaves the
+    /// debt already taken on to be repaid.
+    pub(crate) fn set_overdraft(&mut self, overdraft: usize) {
+        self.overdraft = overdraft;
+    }
+
+    /// Repays the debt from what was refilled.
+    fn repay(&mut self, now: Instant) {
+        let repaid = self.debt.min(self.bucket);
+        if repaid > 0 {
+            self.take(now, repaid);
+            self.debt -= repaid;
+        }
+    }
+
+    /// Takes `cost` requests out of the bucket, which must have enough left.
+    fn take(&mut self, now: Instant, cost: usize) {
+        self.bucket -= cost;
+        if let Some(warm_up) = &mut self.warm_up {
+            warm_up.record(now);
+        }
+        let increment = nanos(
+            self.token_interval()
+                .as_nanos()
+                .saturating_mul(cost as u128),
+        );
+        match &mut self.state {
+            State::Gcra { tat } => *tat = (*tat).max(now) + increment,
+            State::Log(log) => log.push_back((now, cost)),
+            State::Counter { current, .. } => *current += cost,
+            State::Leaky { level } => {
+                if *level == 0 {
+                    // Draining starts with the first queued request.
+                    self.bucket_init = now;
+                }
+                *level += cost;
+            }
+            State::Bucket => {}
+        }
+    }
+
+    /// Changes the limit while keeping track of what was already consumed.
+    ///
+    /// Without `scale`, the entity keeps the number of requests it used so far.
+    /// With `scale`, that number is scaled to the new `max_limit`, so an entity that
+    /// used half of its old limit has used half of its new one.
+    pub(crate) fn update_limit(
+        &mut self,
+        now: Instant,
+        max_limit: usize,
+        refresh_rate: Duration,
+        scale: bool,
+    ) {
+        self.refresh(now);
+        let used = self.bucket_max.saturating_sub(self.bucket);
+        let used = if scale && self.bucket_max > 0 {
+            (used as u128 * max_limit as u128).div_ceil(self.bucket_max as u128) as usize
+        } else {
+            used
+        };
+        let old_max = self.bucket_max;
+        self.bucket_max = max_limit;
+        self.refresh_rate = refresh_rate;
+        let used = used.min(max_limit);
+
+        let interval = self.token_interval();
+        match &mut self.state {
+            State::Bucket => {}
+            State::Gcra { tat } => {
+                *tat = now + nanos(used as u128 * interval.as_nanos());
+            }
+            State::Log(log) => {
+                // Logged requests only move to the new window, the oldest are dropped
+                // if they no longer fit in it.
+                let mut logged: usize = log.iter().map(|(_, cost)| cost).sum();
+                while logged > max_limit {
+                    let Some((_, cost)) = log.front_mut() else {
+                        break;
+                    };
+                    let dropped = (*cost).min(logged - max_limit);
+                    *cost -= dropped;
+                    logged -= dropped;
+                    if *cost == 0 {
+                        log.pop_front();
+                    }
+                }
+                self.bucket = max_limit - logged;
+                return;
+            }
+            State::Counter { previous, current } => {
+                if scale && old_max > 0 {
+                    *previous = (*previous * max_limit).div_ceil(old_max);
+                    *current = (*current * max_limit).div_ceil(old_max);
+                }
+            }
+            State::Leaky { level } => *level = used,
+        }
+        // Algorithms with their own state recompute the bucket from it on refresh.
+        self.bucket = max_limit - used;
+        self.refresh(now);
+    }
+
+    /// Forgets everything consumed so far and starts over with full buckets.
+    /// Escalated penalties are forgiven as well.
+    pub(crate) fn reset(&mut self, now: Instant) {
+        let refresh_rate = self
+            .violations
+            .take()
+            .map_or(self.refresh_rate, |violations| violations.base_rate);
+        let mut windows = std::mem::take(&mut self.windows);
+        for window in &mut windows {
+            window.reset(now);
+        }
+        let parent = self.parent.take();
+        let policy = self.policy.take();
+        let in_flight = self.in_flight.take();
+        let warm_up = self.warm_up.take();
+        let adaptive = self.adaptive.take();
+        let aligned_start = self.aligned.then(|| self.window_start(now));
+        #[cfg(feature = "cron")]
+        let scheduled = self.scheduled.take();
+        let time_of_day = self.time_of_day.take();
+        let overdraft = self.overdraft;
+        let cooldown = self.cooldown;
+        let jitter = self.jitter.take();
+        *self = AssociatedEntity::new(self.bucket_max, refresh_rate, self.algorithm, now);
+        if let Some(start) = aligned_start {
+            self.bucket_init = start;
+            self.aligned = true;
+        }
+        #[cfg(feature = "cron")]
+        if scheduled.is_some() {
+            self.scheduled = scheduled;
+            self.start_window(now);
+        }
+        self.windows = windows;
+        self.parent = parent;
+        self.policy = policy;
+        self.in_flight = in_flight;
+        self.warm_up = warm_up;
+        self.adaptive = adaptive;
+        self.time_of_day = time_of_day;
+        self.overdraft = overdraft;
+        self.cooldown = cooldown;
+        self.jitter = jitter;
+        if !self.aligned {
+            self.jitter_window(now);
+        }
+    }
+
+    /// Switches to the limits of `policy`. What was consumed is kept, unless the
+    /// algorithm changes and the entity starts over with a full bucket.
+    /// An adaptive entity keeps the limit it adapted to, within the policy's bounds.
+    /// `system_time` is the wall-clock time at `now`, for aligned and scheduled windows,
+    /// and `entity_key` the entity, for jittered ones.
+    #[cfg(feature = "config")]
+    pub(crate) fn apply_policy<K: Hash + ?Sized>(
+        &mut self,
+        now: Instant,
+        system_time: SystemTime,
+        policy: &crate::Policy,
+        entity_key: &K,
+    ) {
+        let (mut max_limit, refresh_rate) = policy.bucket();
+        if let (Some(aimd), Some(_)) = (policy.adaptive, self.adaptive) {
+            max_limit = aimd.clamp(self.bucket_max);
+        }
+        self.adaptive = policy.adaptive;
+        self.set_overdraft(policy.overdraft);
+        self.cooldown = policy.cooldown;
+        if policy.algorithm != self.algorithm {
+            let mut entity = AssociatedEntity::new(max_limit, refresh_rate, policy.algorithm, now);
+            entity.windows = std::mem::take(&mut self.windows);
+            entity.parent = self.parent.take();
+            entity.policy = self.policy.take();
+            entity.in_flight = self.in_flight.take();
+            entity.adaptive = self.adaptive;
+            entity.overdraft = self.overdraft;
+            entity.debt = self.debt;
+            entity.cooldown = self.cooldown;
+            entity.cooling_until = self.cooling_until;
+            *self = entity;
+            self.limit_in_flight(policy.max_in_flight);
+            self.set_warm_up(now, policy.warm_up);
+            self.start_windows(now, system_time, policy, entity_key);
+            self.jitter_window(now);
+            self.set_time_of_day(now, system_time, policy);
+            return;
+        }
+        self.limit_in_flight(policy.max_in_flight);
+        self.set_warm_up(now, policy.warm_up);
+        let base_rate = self
+            .violations
+            .as_ref()
+            .map_or(self.refresh_rate, |violations| violations.base_rate);
+        if (max_limit, refresh_rate) != (self.bucket_max, base_rate) {
+            self.violations = None;
+            self.update_limit(now, max_limit, refresh_rate, false);
+        }
+        self.start_windows(now, system_time, policy, entity_key);
+        self.set_time_of_day(now, system_time, policy);
+    }
+
+    /// Counts a denied request under `escalation`, lengthening the refresh rate
+    /// if the penalty escalates.
+    pub(crate) fn escalate(&mut self, now: Instant, escalation: &Escalation) {
+        let base_rate = self.refresh_rate;
+        let violations = self
+            .violations
+            .get_or_insert_with(|| Violations::new(now, base_rate));
+        if violations.escalate(now, escalation) {
+            let refresh_rate = escalation.refresh_rate(violations.base_rate, violations.level);
+            self.update_limit(now, self.bucket_max, refresh_rate, false);
+        }
+    }
+
+    /// Lets the penalty under `escalation` decay, shortening the refresh rate again.
+    pub(crate) fn deescalate(&mut self, now: Instant, escalation: &Escalation) {
+        let Some(violations) = &mut self.violations else {
+            return;
+        };
+        if violations.decay(now, escalation) {
+            let refresh_rate = escalation.refresh_rate(violations.base_rate, violations.level);
+            if violations.level == 0 {
+                self.violations = None;
+            }
+            self.update_limit(now, self.bucket_max, refresh_rate, false);
+        }
+    }
+
+    /// Takes up to `tokens` requests out of the bucket as if they had been consumed,
+    /// stopping once the bucket is empty.
+    pub(crate) fn penalize(&mut self, now: Instant, tokens: usize) {
+        self.refresh(now);
+        self.take(now, tokens.min(self.bucket));
+        for window in &mut self.windows {
+            window.take(now, tokens.min(window.bucket));
+        }
+    }
+
+    /// Gives back `cost` requests taken by `try_consume`, including the parents' share.
+    pub(crate) fn give_back(&mut self, now: Instant, cost: usize) {
+        self.refund(now, cost);
+        self.with_parent(now, |parent| parent.give_back(now, cost));
+    }
+
+    /// Gives up to `tokens` consumed requests back, never filling the bucket past `bucket_max`.
+    pub(crate) fn refund(&mut self, now: Instant, tokens: usize) {
+        for window in &mut self.windows {
+            window.refund(now, tokens);
+        }
+        self.refund_own(now, tokens);
+    }
+
+    /// Refunds the entity's own bucket, leaving the windows alone.
+    fn refund_own(&mut self, now: Instant, tokens: usize) {
+        self.refresh(now);
+        // Whatever was borrowed last is given back first.
+        let forgiven = tokens.min(self.debt);
+        self.debt -= forgiven;
+        let tokens = (tokens - forgiven).min(self.bucket_max - self.bucket);
+        let interval = self.token_interval();
+        match &mut self.state {
+            State::Bucket => self.bucket += tokens,
+            State::Gcra { tat } => {
+                let refunded = nanos(tokens as u128 * interval.as_nanos());
+                *tat = tat.checked_sub(refunded).unwrap_or(now).max(now);
+            }
+            State::Log(log) => {
+                // The most recent requests are the ones being refunded.
+                let mut tokens = tokens;
+                while let Some((_, cost)) = log.back_mut() {
+                    if *cost > tokens {
+                        *cost -= tokens;
+                        break;
+                    }
+                    tokens -= *cost;
+                    log.pop_back();
+                }
+                let logged = log.iter().map(|(_, cost)| cost).sum::<usize>();
+                self.bucket = self.bucket_max.saturating_sub(logged);
+            }
+            State::Counter { current, .. } => *current = current.saturating_sub(tokens),
+            State::Leaky { level } => *level = level.saturating_sub(tokens),
+        }
+        self.refresh(now);
+    }
+
+    /// Matches the entity's own bucket to what a server reported, see
+    /// `Limiter::sync_from_headers`. Its windows and parent are left alone.
+    #[cfg(feature = "http")]
+    pub(crate) fn sync(&mut self, now: Instant, server: &crate::headers::ServerLimit) {
+        self.refresh(now);
+        let empty_until = server
+            .retry_after
+            .or(server.reset.filter(|_| server.remaining == Some(0)));
+        if let Some(wait) = empty_until {
+            self.empty_until(now + wait);
+            return;
+        }
+        if let Some(remaining) = server.remaining {
+            let remaining = remaining.min(self.bucket_max);
+            if remaining < self.bucket {
+                self.take(now, self.bucket - remaining);
+            } else if remaining > self.bucket {
+                self.refund_own(now, remaining - self.bucket);
+            }
+        }
+        if let Some(reset) = server.reset {
+            // Only a fixed window resets all at once, at a point in time the server knows.
+            if self.algorithm == Algorithm::FixedWindow && reset <= self.refresh_rate {
+                self.bucket_init = before(now + reset, self.refresh_rate);
+            }
+        }
+    }
+
+    /// Empties the bucket so that the next request is allowed at `until`.
+    #[cfg(feature = "http")]
+    fn empty_until(&mut self, until: Instant) {
+        let delay = match self.algorithm.is_continuous() {
+            true => self.token_interval(),
+            false => self.refresh_rate,
+        };
+        // Emptied all at once `delay` before, the first request comes back at `until`.
+        let start = before(until, delay);
+        let mut empty =
+            AssociatedEntity::new(self.bucket_max, self.refresh_rate, self.algorithm, start);
+        empty.take(start, self.bucket_max);
+        self.bucket = empty.bucket;
+        self.bucket_init = empty.bucket_init;
+        self.state = empty.state;
+    }
+
+    /// Refreshes the bucket and tries to consume `cost` requests from it.
+    /// Hitting the limit starts the cooldown, if any, denying everything until it ends.
+    pub(crate) fn decide(&mut self, now: Instant, cost: usize) -> Decision {
+        self.refresh(now);
+
+        if !self.cooling(now) && self.try_consume(now, cost) {
+            // request allowed
+            Decision::Allowed {
+                remaining: self.total_available(now),
+                reset_in: self.binding().reset_in(now),
+            }
+        } else {
+            // entity is limited, request denied.
+            if let Some(cooldown) = self.cooldown.filter(|_| !self.cooling(now)) {
+                self.cooling_until = Some(now + cooldown);
+            }
+            Decision::Denied {
+                retry_after: self.retry_after(now, cost),
+            }
+        }
+    }
+
+    /// Combines `other`, the same entity as tracked by another limiter, into this one.
+    pub(crate) fn merge(
+        &mut self,
+        now: Instant,
+        mut other: AssociatedEntity,
+        strategy: MergeStrategy,
+    ) {
+        self.refresh(now);
+        other.refresh(now);
+        match strategy {
+            MergeStrategy::MinRemaining => {
+                if other.available() < self.available() {
+                    other.parent = self.parent.take();
+                    other.policy = self.policy.take();
+                    other.in_flight = self.in_flight.take();
+                    other.warm_up = self.warm_up.take();
+                    other.adaptive = self.adaptive.take();
+                    #[cfg(feature = "cron")]
+                    {
+                        other.scheduled = self.scheduled.take();
+                    }
+                    other.time_of_day = self.time_of_day.take();
+                    other.overdraft = self.overdraft;
+                    other.cooldown = self.cooldown;
+                    other.cooling_until = other.cooling_until.max(self.cooling_until);
+                    other.jitter = self.jitter.take();
+                    *self = other;
+                }
+            }
+            MergeStrategy::SumConsumption => {
+                self.penalize(now, other.bucket_max.saturating_sub(other.bucket))
+            }
+        }
+    }
+
+    /// Converts the entity into a form without instants, relative to `now`.
+    pub(crate) fn snapshot(&self, now: Instant) -> EntitySnapshot {
+        let age = |at: Instant| now.saturating_duration_since(at);
+        let state = match &self.state {
+            State::Bucket => StateSnapshot::Bucket,
+            State::Gcra { tat } => StateSnapshot::Gcra {
+                tat_in: tat.saturating_duration_since(now),
+            },
+            State::Log(log) => {
+                StateSnapshot::Log(log.iter().map(|&(at, cost)| (age(at), cost)).collect())
+            }
+            &State::Counter { previous, current } => StateSnapshot::Counter { previous, current },
+            &State::Leaky { level } => StateSnapshot::Leaky { level },
+        };
+        EntitySnapshot {
+            bucket: self.bucket,
+            bucket_age: age(self.bucket_init),
+            bucket_max: self.bucket_max,
+            refresh_rate: self.refresh_rate,
+            algorithm: self.algorithm,
+            state,
+            violations: self
+                .violations
+                .as_ref()
+                .map(|violations| ViolationsSnapshot {
+                    count: violations.count,
+                    level: violations.level,
+                    last_age: age(violations.last),
+                    base_rate: violations.base_rate,
+                }),
+            limited: self.limited,
+            windows: self
+                .windows
+                .iter()
+                .map(|window| window.snapshot(now))
+                .collect(),
+            aligned: self.aligned,
+            debt: self.debt,
+            cooldown_in: self
+                .cooling_until
+                .and_then(|until| until.checked_duration_since(now))
+                .filter(|cooldown_in| !cooldown_in.is_zero()),
+        }
+    }
+
+    /// Turns a snapshot taken at `taken_at` back into an entity.
+    pub(crate) fn restore(snapshot: &EntitySnapshot, taken_at: Instant) -> Self {
+        let state = match &snapshot.state {
+            StateSnapshot::Bucket => State::Bucket,
+            StateSnapshot::Gcra { tat_in } => State::Gcra {
+                tat: taken_at + *tat_in,
+            },
+            StateSnapshot::Log(log) => State::Log(
+                log.iter()
+                    .map(|&(age, cost)| (before(taken_at, age), cost))
+                    .collect(),
+            ),
+            &StateSnapshot::Counter { previous, current } => State::Counter { previous, current },
+            &StateSnapshot::Leaky { level } => State::Leaky { level },
+        };
+        AssociatedEntity {
+            bucket: snapshot.bucket,
+            bucket_init: before(taken_at, snapshot.bucket_age),
+            bucket_max: snapshot.bucket_max,
+            refresh_rate: snapshot.refresh_rate,
+            algorithm: snapshot.algorithm,
+            state,
+            violations: snapshot.violations.as_ref().map(|violations| Violations {
+                count: violations.count,
+                level: violations.level,
+                last: before(taken_at, violations.last_age),
+                base_rate: violations.base_rate,
+            }),
+            limited: snapshot.limited,
+            windows: snapshot
+                .windows
+                .iter()
+                .map(|window| AssociatedEntity::restore(window, taken_at))
+                .collect(),
+            parent: None,
+            policy: None,
+            in_flight: None,
+            warm_up: None,
+            adaptive: None,
+            aligned: snapshot.aligned,
+            #[cfg(feature = "cron")]
+            scheduled: None,
+            time_of_day: None,
+            overdraft: 0,
+            debt: snapshot.debt,
+            cooldown: None,
+            cooling_until: snapshot
+                .cooldown_in
+                .map(|cooldown_in| taken_at + cooldown_in),
+            jitter: None,
+        }
+    }
+
+    /// Describes the entity, expects `refresh` to have been called with the same `now`.
+    pub(crate) fn state(&self, now: Instant) -> EntityState {
+        EntityState {
+            remaining: self.total_available(now),
+            max: self.bucket_max,
+            refresh_rate: self.refresh_rate,
+            algorithm: self.algorithm,
+            reset_in: self.binding().reset_in(now),
+            retry_after: self.retry_after(now, 1),
+        }
+    }
+
+    /// The rate limit headers of the tightest window, with `system_time` as the
+    /// wall-clock time of `now`.
+    /// Expects `refresh` to have been called with the same `now`.
+    pub(crate) fn headers(&self, now: Instant, system_time: SystemTime) -> RateLimitHeaders {
+        let binding = self.binding();
+        let reset = binding.reset_in(now);
+        let since_epoch = system_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        RateLimitHeaders {
+            limit: binding.bucket_max,
+            remaining: self.total_available(now),
+            reset,
+            reset_at: ceil_secs(since_epoch.saturating_add(reset)),
+            window: binding.refresh_rate,
+        }
+    }
+
+    /// Time left until the bucket is completely refilled.
+    pub(crate) fn reset_in(&self, now: Instant) -> Duration {
+        match self.algorithm {
+            Algorithm::FixedWindow => self.time_until_window_end(now),
+            Algorithm::TokenBucket => self.time_until_tokens(now, self.bucket_max),
+            Algorithm::Gcra => self.gcra_backlog(now),
+            Algorithm::SlidingWindowLog => self.time_until_logged(now, self.bucket_max),
+            Algorithm::SlidingWindowCounter => match self.state {
+                State::Counter { current, .. } if current > 0 => {
+                    self.time_until_window_end(now) + self.refresh_rate
+                }
+                State::Counter { previous, .. } if previous > 0 => self.time_until_window_end(now),
+                _ => Duration::ZERO,
+            },
+            Algorithm::LeakyBucket => self.time_until_drained(now, 0),
+        }
+    }
+
+    /// Time left until every window can take `cost` requests, zero if they can right away.
+    /// Includes the wait for the parent, if any.
+    pub(crate) fn retry_after(&self, now: Instant, cost: usize) -> Duration {
+        let parent = self
+            .with_parent(now, |parent| parent.retry_after(now, cost))
+            .unwrap_or_default();
+        self.windows
+            .iter()
+            .map(|window| window.wait(now, cost))
+            .fold(self.wait(now, cost), Duration::max)
+            .max(parent)
+            .max(
+                self.cooling_until
+                    .map_or(Duration::ZERO, |until| until.saturating_duration_since(now)),
+            )
+    }
+
+    /// Time left until the bucket can take `cost` requests, borrowing what the overdraft allows.
+    /// `Duration::MAX` if it never can, as `cost` is more than even a full bucket holds.
+    fn wait(&self, now: Instant, cost: usize) -> Duration {
+        let cost = cost.saturating_sub(self.overdraft.saturating_sub(self.debt));
+        if self.bucket >= cost {
+            return Duration::ZERO;
+        }
+        if cost > self.bucket_max {
+            return Duration::MAX;
+        }
+        match self.algorithm {
+            Algorithm::FixedWindow => self.time_until_window_end(now),
+            Algorithm::TokenBucket => self.time_until_tokens(now, cost),
+            Algorithm::Gcra => {
+                // The backlog has to shrink until `cost` more intervals fit under the limit.
+                let allowed = (self.bucket_max - cost) as u128;
+                let allowed_backlog = nanos(allowed * self.token_interval().as_nanos());
+                self.gcra_backlog(now).saturating_sub(allowed_backlog)
+            }
+            Algorithm::SlidingWindowLog => self.time_until_logged(now, cost),
+            Algorithm::SlidingWindowCounter => self.time_until_counted(now, cost),
+            Algorithm::LeakyBucket => self.time_until_drained(now, self.bucket_max - cost),
+        }
+    }
+
+    fn time_until_window_end(&self, now: Instant) -> Duration {
+        self.refresh_rate
+            .saturating_sub(now.saturating_duration_since(self.bucket_init))
+            .saturating_add(self.pending(now))
+    }
+
+    /// Time until `bucket_init` if it's in the future, as set by `empty_until`.
+    fn pending(&self, now: Instant) -> Duration {
+        self.bucket_init.saturating_duration_since(now)
+    }
+
+    /// Time between two tokens trickling back into the bucket.
+    fn token_interval(&self) -> Duration {
+        if self.bucket_max == 0 {
+            return Duration::MAX;
+        }
+        nanos(self.refresh_rate.as_nanos() / self.bucket_max as u128)
+    }
+
+    /// Time between two tokens refilled since `bucket_init`, longer while warming up.
+    fn refill_interval(&self, now: Instant) -> u128 {
+        match &self.warm_up {
+            Some(warm_up) => {
+                // Warmth grows linearly, the middle of the span is its average.
+                let midpoint =
+                    self.bucket_init + now.saturating_duration_since(self.bucket_init) / 2;
+                warm_up.interval(self.token_interval(), midpoint)
+            }
+            None => self.token_interval().as_nanos(),
+        }
+    }
+
+    fn refill_continuous(&mut self, now: Instant) {
+        let mut capacity = self.bucket_max;
+        if let Some(warm_up) = &mut self.warm_up {
+            warm_up.cool(now);
+            capacity = warm_up.capacity(self.bucket_max, now);
+        }
+        if self.bucket >= capacity {
+            // A full bucket doesn't accumulate more, keep the refill clock current.
+            self.bucket = capacity;
+            self.bucket_init = now;
+            return;
+        }
+        let interval = self.refill_interval(now);
+        let elapsed = now.saturating_duration_since(self.bucket_init).as_nanos();
+        let missing = (capacity - self.bucket) as u128;
+        let tokens = elapsed.checked_div(interval).unwrap_or(missing);
+
+        if tokens >= missing {
+            self.bucket = capacity;
+            self.bucket_init = now;
+        } else if tokens > 0 {
+            self.bucket += tokens as usize;
+            // Keep the partial progress towards the next token.
+            self.bucket_init += nanos(tokens * interval);
+        }
+    }
+
+    /// Leaks queued requests out of the bucket at a constant `bucket_max / refresh_rate`.
+    fn drain(&mut self, now: Instant) {
+        let interval = self.token_interval().as_nanos();
+        let State::Leaky { level } = &mut self.state else {
+            return;
+        };
+        let elapsed = now.saturating_duration_since(self.bucket_init).as_nanos();
+        let leaked = elapsed.checked_div(interval).unwrap_or(*level as u128);
+
+        if leaked >= *level as u128 {
+            *level = 0;
+            self.bucket_init = now;
+        } else if leaked > 0 {
+            *level -= leaked as usize;
+            // Keep the partial progress towards the next leak.
+            self.bucket_init += nanos(leaked * interval);
+        }
+        self.bucket = self.bucket_max.saturating_sub(*level);
+    }
+
+    /// Time until the queue has drained down to `level`.
+    fn time_until_drained(&self, now: Instant, level: usize) -> Duration {
+        let State::Leaky { level: queued } = self.state else {
+            return Duration::ZERO;
+        };
+        let excess = queued.saturating_sub(level) as u128;
+        let elapsed = now.saturating_duration_since(self.bucket_init);
+        nanos(excess.saturating_mul(self.token_interval().as_nanos()))
+            .saturating_sub(elapsed)
+            .saturating_add(self.pending(now))
+    }
+
+    /// How far the theoretical arrival time is ahead of `now`.
+    fn gcra_backlog(&self, now: Instant) -> Duration {
+        match self.state {
+            State::Gcra { tat } => tat.saturating_duration_since(now),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Time until enough logged requests leave the window to free up `tokens`.
+    fn time_until_logged(&self, now: Instant, tokens: usize) -> Duration {
+        let State::Log(log) = &self.state else {
+            return Duration::ZERO;
+        };
+        let mut available = self.bucket;
+        let mut expires = now;
+        for &(at, cost) in log {
+            if available >= tokens {
+                break;
+            }
+            available += cost;
+            expires = at + self.refresh_rate;
+        }
+        expires.saturating_duration_since(now)
+    }
+
+    /// Time until the weighted count leaves room for `tokens` more requests.
+    fn time_until_counted(&self, now: Instant, tokens: usize) -> Duration {
+        let State::Counter { previous, current } = self.state else {
+            return Duration::ZERO;
+        };
+        let rate = self.refresh_rate.as_nanos();
+        let into_window = now.saturating_duration_since(self.bucket_init).as_nanos();
+        let tokens = tokens.min(self.bucket_max);
+
+        if current + tokens <= self.bucket_max {
+            // Enough room once the previous window has decayed a bit more.
+            let budget = self.bucket_max - current - tokens;
+            nanos(decay_point(previous, budget, rate).saturating_sub(into_window))
+        } else {
+            // The current window first has to become the previous one.
+            let budget = self.bucket_max - tokens;
+            self.time_until_window_end(now) + nanos(decay_point(current, budget, rate))
+        }
+    }
+
+    fn time_until_tokens(&self, now: Instant, tokens: usize) -> Duration {
+        if self.bucket >= tokens {
+            return Duration::ZERO;
+        }
+        let missing = (tokens - self.bucket) as u128;
+        let elapsed = now.saturating_duration_since(self.bucket_init);
+        nanos(missing.saturating_mul(self.refill_interval(now)))
+            .saturating_sub(elapsed)
+            .saturating_add(self.pending(now))
+    }
+}
+
+/// The part of `count` still weighing on a window that is `into_window` out of `rate` done.
+fn decayed(count: usize, rate: u128, into_window: u128) -> usize {
+    match (count as u128 * rate.saturating_sub(into_window)).checked_div(rate) {
+        Some(weighted) => weighted as usize,
+        None => 0,
+    }
+}
+
+/// How far into a window of `rate` a `count` has decayed to at most `budget`.
+fn decay_point(count: usize, budget: usize, rate: u128) -> u128 {
+    if count <= budget {
+        return 0;
+    }
+    // floor(count * (rate - t) / rate) <= budget
+    // holds once rate - t < (budget + 1) * rate / count.
+    let max_left = ((budget as u128 + 1) * rate).div_ceil(count as u128) - 1;
+    rate - max_left
+}
+
+/// How many whole `interval`s are needed to cover `duration`.
+fn div_ceil(duration: Duration, interval: Duration) -> usize {
+    let interval = interval.as_nanos();
+    if interval == 0 {
+        return 0;
+    }
+    duration
+        .as_nanos()
+        .div_ceil(interval)
+        .min(usize::MAX as u128) as usize
+}
+
+/// Converts nanoseconds into a `Duration`, saturating at `Duration::MAX`.
+pub(crate) fn nanos(nanos: u128) -> Duration {
+    let secs = nanos / 1_000_000_000;
+    if secs > u64::MAX as u128 {
+        return Duration::MAX;
+    }
+    Duration::new(secs as u64, (nanos % 1_000_000_000) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consume(entity: &mut AssociatedEntity, now: Instant, cost: usize) -> bool {
+        entity.refresh(now);
+        entity.try_consume(now, cost)
+    }
+
+    #[test]
+    fn test_cost_above_max_is_never_allowed() {
+        let start = Instant::now();
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::TokenBucket,
+            Algorithm::Gcra,
+            Algorithm::SlidingWindowLog,
+            Algorithm::SlidingWindowCounter,
+            Algorithm::LeakyBucket,
+        ] {
+            let mut entity = AssociatedEntity::new(3, Duration::from_secs(1), algorithm, start);
+            assert_eq!(
+                entity.decide(start, 4),
+                Decision::Denied {
+                    retry_after: Duration::MAX
+                },
+                "{:?}",
+                algorithm
+            );
+            assert!(entity.decide(start, 3).is_allowed(), "{:?}", algorithm);
+        }
+
+        // Unless the overdraft covers it.
+        let mut entity =
+            AssociatedEntity::new(3, Duration::from_secs(1), Algorithm::TokenBucket, start);
+        entity.set_overdraft(1);
+        assert!(entity.decide(start, 4).is_allowed());
+    }
+
+    #[test]
+    fn test_fixed_window_refills_at_once() {
+        let start = Instant::now();
+        let mut entity =
+            AssociatedEntity::new(2, Duration::from_secs(1), Algorithm::FixedWindow, start);
+
+        assert!(consume(&mut entity, start, 2));
+        assert!(!consume(&mut entity, start + Duration::from_millis(999), 1));
+        assert_eq!(
+            entity.retry_after(start + Duration::from_millis(999), 1),
+            Duration::from_millis(1)
+        );
+        assert!(consume(&mut entity, start + Duration::from_secs(1), 2));
+    }
+
+    #[test]
+    fn test_token_bucket_refills_continuously() {
+        let start = Instant::now();
+        let mut entity =
+            AssociatedEntity::new(4, Duration::from_secs(4), Algorithm::TokenBucket, start);
+
+        assert!(consume(&mut entity, start, 4));
+        assert!(!consume(&mut entity, start + Duration::from_millis(500), 1));
+        assert_eq!(
+            entity.retry_after(start + Duration::from_millis(500), 1),
+            Duration::from_millis(500)
+        );
+
+        // One token per second trickles back.
+        assert!(consume(&mut entity, start + Duration::from_millis(1500), 1));
+        assert!(!consume(
+            &mut entity,
+            start + Duration::from_millis(1500),
+            1
+        ));
+        assert!(consume(&mut entity, start + Duration::from_millis(2000), 1));
+        assert_eq!(entity.bucket, 0);
+        assert_eq!(
+            entity.reset_in(start + Duration::from_millis(2000)),
+            Duration::from_secs(4)
+        );
+    }
+
+    #[test]
+    fn test_token_bucket_caps_at_max() {
+        let start = Instant::now();
+        let mut entity =
+            AssociatedEntity::new(3, Duration::from_secs(3), Algorithm::TokenBucket, start);
+
+        assert!(consume(&mut entity, start, 1));
+        entity.refresh(start + Duration::from_secs(60));
+        assert_eq!(entity.bucket, 3);
+        assert!(!consume(&mut entity, start + Duration::from_secs(60), 4));
+        assert_eq!(
+            entity.reset_in(start + Duration::from_secs(60)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_gcra_spaces_requests() {
+        let start = Instant::now();
+        let mut entity = AssociatedEntity::new(4, Duration::from_secs(4), Algorithm::Gcra, start);
+
+        // The whole burst is available up front.
+        assert!(consume(&mut entity, start, 3));
+        assert_eq!(entity.bucket, 1);
+        assert!(consume(&mut entity, start, 1));
+        assert!(!consume(&mut entity, start + Duration::from_millis(500), 1));
+        assert_eq!(
+            entity.retry_after(start + Duration::from_millis(500), 1),
+            Duration::from_millis(500)
+        );
+
+        // After that, one request per emission interval.
+        assert!(consume(&mut entity, start + Duration::from_secs(1), 1));
+        assert!(!consume(&mut entity, start + Duration::from_secs(1), 1));
+        assert_eq!(
+            entity.reset_in(start + Duration::from_secs(1)),
+            Duration::from_secs(4)
+        );
+
+        entity.refresh(start + Duration::from_secs(10));
+        assert_eq!(entity.bucket, 4);
+        assert!(!consume(&mut entity, start + Duration::from_secs(10), 5));
+        assert_eq!(
+            entity.retry_after(start + Duration::from_secs(10), 5),
+            Duration::MAX
+        );
+    }
+
+    #[test]
+    fn test_sliding_window_log_rolls() {
+        let start = Instant::now();
+        let mut entity = AssociatedEntity::new(
+            3,
+            Duration::from_secs(10),
+            Algorithm::SlidingWindowLog,
+            start,
+        );
+
+        assert!(consume(&mut entity, start, 1));
+        assert!(consume(&mut entity, start + Duration::from_secs(4), 2));
+        assert!(!consume(&mut entity, start + Duration::from_secs(9), 1));
+        assert_eq!(
+            entity.retry_after(start + Duration::from_secs(9), 1),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            entity.retry_after(start + Duration::from_secs(9), 3),
+            Duration::from_secs(5)
+        );
+
+        // A fixed window would refill everything here, the log only frees the first request.
+        assert!(consume(&mut entity, start + Duration::from_secs(10), 1));
+        assert!(!consume(&mut entity, start + Duration::from_secs(13), 1));
+        assert_eq!(
+            entity.reset_in(start + Duration::from_secs(13)),
+            Duration::from_secs(7)
+        );
+        assert!(consume(&mut entity, start + Duration::from_secs(14), 2));
+    }
+
+    #[test]
+    fn test_sliding_window_counter_weights_previous_window() {
+        let start = Instant::now();
+        let mut entity = AssociatedEntity::new(
+            10,
+            Duration::from_secs(10),
+            Algorithm::SlidingWindowCounter,
+            start,
+        );
+
+        assert!(consume(&mut entity, start + Duration::from_secs(5), 10));
+        assert!(!consume(&mut entity, start + Duration::from_secs(9), 1));
+
+        // 2.5s into the next window, 75% of the previous 10 requests still count.
+        let now = start + Duration::from_millis(12_500);
+        entity.refresh(now);
+        assert_eq!(entity.bucket, 3);
+        assert!(consume(&mut entity, now, 3));
+        assert!(!consume(&mut entity, now, 1));
+        // 1 more request fits once the previous window weighs less than 7, just after 3s.
+        assert_eq!(
+            entity.retry_after(now, 1),
+            Duration::from_millis(500) + Duration::from_nanos(1)
+        );
+        assert_eq!(entity.reset_in(now), Duration::from_millis(17_500));
+
+        // Both windows have passed.
+        assert!(consume(&mut entity, start + Duration::from_secs(30), 10));
+    }
+
+    #[test]
+    fn test_sliding_window_counter_waits_for_rollover() {
+        let start = Instant::now();
+        let mut entity = AssociatedEntity::new(
+            4,
+            Duration::from_secs(10),
+            Algorithm::SlidingWindowCounter,
+            start,
+        );
+
+        assert!(consume(&mut entity, start, 4));
+        // The current window has to roll over and weigh less than 3 before 2 fit again.
+        let now = start + Duration::from_secs(2);
+        assert!(!consume(&mut entity, now, 2));
+        assert_eq!(
+            entity.retry_after(now, 2),
+            Duration::from_millis(10_500) + Duration::from_nanos(1)
+        );
+        assert!(!consume(
+            &mut entity,
+            start + Duration::from_millis(12_500),
+            2
+        ));
+        assert!(consume(&mut entity, start + Duration::from_secs(15), 2));
+    }
+
+    #[test]
+    fn test_leaky_bucket_drains_at_constant_rate() {
+        let start = Instant::now();
+        let mut entity =
+            AssociatedEntity::new(2, Duration::from_secs(2), Algorithm::LeakyBucket, start);
+
+        assert!(consume(&mut entity, start, 2));
+        assert!(!consume(&mut entity, start + Duration::from_millis(400), 1));
+        assert_eq!(
+            entity.retry_after(start + Duration::from_millis(400), 1),
+            Duration::from_millis(600)
+        );
+        assert_eq!(
+            entity.reset_in(start + Duration::from_millis(400)),
+            Duration::from_millis(1600)
+        );
+
+        // One request leaks out per second.
+        assert!(consume(&mut entity, start + Duration::from_secs(1), 1));
+        assert!(!consume(
+            &mut entity,
+            start + Duration::from_millis(1500),
+            1
+        ));
+        entity.refresh(start + Duration::from_secs(10));
+        assert_eq!(entity.bucket, 2);
+    }
+
+    #[test]
+    fn test_accessors() {
+        let mut entity = AssociatedEntity::new(
+            5,
+            Duration::from_secs(60),
+            Algorithm::FixedWindow,
+            Instant::now(),
+        );
+        assert!(consume(&mut entity, Instant::now(), 2));
+
+        assert_eq!(entity.bucket_max(), 5);
+        assert_eq!(entity.refresh_rate(), Duration::from_secs(60));
+        assert_eq!(entity.algorithm(), Algorithm::FixedWindow);
+        assert_eq!(entity.remaining(), 3);
+        assert!(entity.time_since_init() < Duration::from_secs(60));
+        assert!(entity.time_until_reset() <= Duration::from_secs(60));
+        assert!(entity.time_until_reset() + entity.time_since_init() <= Duration::from_secs(61));
+    }
+
+    #[test]
+    fn test_remaining_accounts_for_refresh() {
+        let start = Instant::now() - Duration::from_secs(2);
+        let mut entity =
+            AssociatedEntity::new(2, Duration::from_secs(1), Algorithm::FixedWindow, start);
+        assert!(consume(&mut entity, start, 2));
+
+        // The window has passed since, so the whole bucket is back.
+        assert_eq!(entity.remaining(), 2);
+        assert!(entity.time_since_init() >= Duration::from_secs(2));
+        assert!(entity.time_until_reset() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_update_limit_keeps_consumption() {
+        let start = Instant::now();
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::TokenBucket,
+            Algorithm::Gcra,
+            Algorithm::SlidingWindowLog,
+            Algorithm::SlidingWindowCounter,
+            Algorithm::LeakyBucket,
+        ] {
+            let mut entity = AssociatedEntity::new(10, Duration::from_secs(60), algorithm, start);
+            assert!(consume(&mut entity, start, 4));
+
+            entity.update_limit(start, 20, Duration::from_secs(60), false);
+            assert_eq!(entity.bucket, 16, "{:?}", algorithm);
+            assert_eq!(entity.bucket_max, 20);
+            assert!(consume(&mut entity, start, 16), "{:?}", algorithm);
+            assert!(!consume(&mut entity, start, 1), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_update_limit_scaled() {
+        let start = Instant::now();
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::TokenBucket,
+            Algorithm::Gcra,
+            Algorithm::SlidingWindowCounter,
+            Algorithm::LeakyBucket,
+        ] {
+            let mut entity = AssociatedEntity::new(10, Duration::from_secs(60), algorithm, start);
+            assert!(consume(&mut entity, start, 5));
+
+            entity.update_limit(start, 4, Duration::from_secs(30), true);
+            assert_eq!(entity.bucket, 2, "{:?}", algorithm);
+            assert_eq!(entity.refresh_rate, Duration::from_secs(30));
+        }
+    }
+
+    #[test]
+    fn test_update_limit_below_consumption() {
+        let start = Instant::now();
+        let mut entity =
+            AssociatedEntity::new(10, Duration::from_secs(60), Algorithm::FixedWindow, start);
+        assert!(consume(&mut entity, start, 8));
+
+        entity.update_limit(start, 5, Duration::from_secs(60), false);
+        assert_eq!(entity.bucket, 0);
+        // The window isn't restarted.
+        assert!(consume(&mut entity, start + Duration::from_secs(60), 5));
+    }
+
+    #[test]
+    fn test_update_limit_trims_log() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut entity = AssociatedEntity::new(
+            10,
+            Duration::from_secs(60),
+            Algorithm::SlidingWindowLog,
+            start,
+        );
+        assert!(consume(&mut entity, start, 6));
+        assert!(consume(&mut entity, start + second, 4));
+
+        entity.update_limit(start + second, 2, Duration::from_secs(60), false);
+        assert_eq!(entity.bucket, 0);
+        entity.refund(start + second, 1);
+        assert_eq!(entity.bucket, 1);
+        // Only the latest requests were kept, they leave the window a second later.
+        assert!(!consume(&mut entity, start + Duration::from_secs(60), 2));
+        assert!(consume(&mut entity, start + Duration::from_secs(61), 2));
+    }
+
+    #[test]
+    fn test_reset_and_penalize() {
+        let start = Instant::now();
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::TokenBucket,
+            Algorithm::Gcra,
+            Algorithm::SlidingWindowLog,
+            Algorithm::SlidingWindowCounter,
+            Algorithm::LeakyBucket,
+        ] {
+            let mut entity = AssociatedEntity::new(10, Duration::from_secs(60), algorithm, start);
+            entity.penalize(start, 3);
+            assert_eq!(entity.bucket, 7, "{:?}", algorithm);
+            entity.penalize(start, 100);
+            assert_eq!(entity.bucket, 0, "{:?}", algorithm);
+            assert!(!consume(&mut entity, start, 1), "{:?}", algorithm);
+
+            entity.reset(start + Duration::from_secs(1));
+            assert!(
+                consume(&mut entity, start + Duration::from_secs(1), 10),
+                "{:?}",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn test_refund() {
+        let start = Instant::now();
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::TokenBucket,
+            Algorithm::Gcra,
+            Algorithm::SlidingWindowLog,
+            Algorithm::SlidingWindowCounter,
+            Algorithm::LeakyBucket,
+        ] {
+            let mut entity = AssociatedEntity::new(10, Duration::from_secs(60), algorithm, start);
+            assert!(consume(&mut entity, start, 3));
+            assert!(consume(&mut entity, start, 5));
+
+            entity.refund(start, 6);
+            assert_eq!(entity.bucket, 8, "{:?}", algorithm);
+            entity.refund(start, 100);
+            assert_eq!(entity.bucket, 10, "{:?}", algorithm);
+            assert!(consume(&mut entity, start, 10), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_escalation() {
+        let escalation = Escalation::new(2, Duration::from_secs(600));
+        let start = Instant::now();
+        let mut entity =
+            AssociatedEntity::new(1, Duration::from_secs(60), Algorithm::FixedWindow, start);
+        assert!(consume(&mut entity, start, 1));
+
+        entity.escalate(start, &escalation);
+        assert_eq!(entity.refresh_rate, Duration::from_secs(60));
+        entity.escalate(start, &escalation);
+        assert_eq!(entity.penalty_level(), 1);
+        assert_eq!(entity.refresh_rate, Duration::from_secs(120));
+        // The current window got longer as well.
+        assert!(!consume(&mut entity, start + Duration::from_secs(90), 1));
+
+        entity.deescalate(start + Duration::from_secs(601), &escalation);
+        assert_eq!(entity.penalty_level(), 0);
+        assert_eq!(entity.refresh_rate, Duration::from_secs(60));
+        assert!(entity.violations.is_none());
+    }
+
+    #[test]
+    fn test_reset_forgives_escalation() {
+        let escalation = Escalation::new(1, Duration::from_secs(600));
+        let start = Instant::now();
+        let mut entity =
+            AssociatedEntity::new(1, Duration::from_secs(60), Algorithm::TokenBucket, start);
+        entity.escalate(start, &escalation);
+        assert_eq!(entity.refresh_rate, Duration::from_secs(120));
+
+        entity.reset(start);
+        assert_eq!(entity.refresh_rate, Duration::from_secs(60));
+        assert_eq!(entity.penalty_level(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(10);
+        for algorithm in [
+            Algorithm::FixedWindow,
+            Algorithm::TokenBucket,
+            Algorithm::Gcra,
+            Algorithm::SlidingWindowLog,
+            Algorithm::SlidingWindowCounter,
+            Algorithm::LeakyBucket,
+        ] {
+            let mut entity = AssociatedEntity::new(10, Duration::from_secs(60), algorithm, start);
+            assert!(consume(&mut entity, start + Duration::from_secs(5), 4));
+
+            let snapshot = entity.snapshot(now);
+            // Restored an hour later, as if no time passed since the snapshot.
+            let later = now + Duration::from_secs(3600);
+            let mut restored = AssociatedEntity::restore(&snapshot, later);
+            assert_eq!(restored.snapshot(later), snapshot, "{:?}", algorithm);
+
+            entity.refresh(now);
+            restored.refresh(later);
+            assert_eq!(entity.bucket, restored.bucket, "{:?}", algorithm);
+            assert_eq!(
+                entity.reset_in(now),
+                restored.reset_in(later),
+                "{:?}",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn test_every_window_must_pass() {
+        let start = Instant::now();
+        let mut entity =
+            AssociatedEntity::new(2, Duration::from_secs(1), Algorithm::FixedWindow, start);
+        entity.add_window(start, 3, Duration::from_secs(60));
+
+        assert!(consume(&mut entity, start, 2));
+        assert!(!consume(&mut entity, start, 1));
+        assert_eq!(entity.retry_after(start, 1), Duration::from_secs(1));
+
+        // The second refills, the minute only has one request left.
+        let later = start + Duration::from_secs(1);
+        assert!(!consume(&mut entity, later, 2));
+        assert!(consume(&mut entity, later, 1));
+        assert_eq!(entity.available(), 0);
+        assert_eq!(entity.retry_after(later, 1), Duration::from_secs(59));
+        assert_eq!(entity.bucket, 1);
+    }
+}
@@ -0,0 +1,345 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::sync::{Mutex, MutexGuard};
+use crate::{Algorithm, AssociatedEntity, Decision, EntityStats, Escalation};
+
+/// Marks the fast path tokens as unpublished, `state` holds the real bucket.
+const UNPUBLISHED: usize = usize::MAX;
+
+/// An entity as stored by the limiter.
+///
+/// While the current window of a fixed window entity is open, its bucket is published
+/// to `tokens` so requests can be consumed with a single atomic operation,
+/// without locking `state`. Locking `state` takes the tokens back first,
+/// and publishes them again once the lock is released.
+#[derive(Debug)]
+pub(crate) struct Entry {
+    tokens: AtomicUsize,                 // Published bucket, or UNPUBLISHED
+    window_end: AtomicU64,               // Nanos after `epoch` at which the published window closes
+    last_access: AtomicU64,              // Nanos after `epoch` of the last consume
+    allowed: AtomicU64,                  // Allowed requests, for stats
+    denied: AtomicU64,                   // Denied requests, for stats
+    epoch: Instant,                      // Reference point for `window_end` and `last_access`
+    state: Arc<Mutex<AssociatedEntity>>, // Shared with the children of a parent entity
+}
+
+impl Entry {
+    /// An entry for `entity`, added at `now`.
+    pub(crate) fn new(entity: AssociatedEntity, now: Instant) -> Self {
+        Entry::shared(Arc::new(Mutex::new(entity)), now)
+    }
+
+    /// An entry for an entity whose state is shared with other entries, see `share`.
+    ///
+    /// Idle since `now` rather than since its `bucket_init`, which is backdated to the
+    /// start of the window for aligned windows.
+    pub(crate) fn shared(state: Arc<Mutex<AssociatedEntity>>, now: Instant) -> Self {
+        let entry = Entry {
+            tokens: AtomicUsize::new(UNPUBLISHED),
+            window_end: AtomicU64::new(0),
+            last_access: AtomicU64::new(0),
+            allowed: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+            epoch: now,
+            state,
+        };
+        drop(entry.lock()); // publishes the bucket
+        entry
+    }
+
+    /// Locks the entity, the returned guard publishes the bucket again when dropped.
+    pub(crate) fn lock(&self) -> EntryGuard<'_> {
+        let mut state = self.state.lock();
+        let tokens = self.tokens.swap(UNPUBLISHED, Ordering::AcqRel);
+        if tokens != UNPUBLISHED {
+            state.bucket = tokens;
+        }
+        EntryGuard { entry: self, state }
+    }
+
+    /// Consumes `cost` requests, lock-free when the bucket is published.
+    /// Denied requests count towards `escalation`, if any.
+    ///
+    /// Also returns `true` if the entity was allowed again after being denied.
+    pub(crate) fn decide(
+        &self,
+        now: Instant,
+        cost: usize,
+        escalation: Option<&Escalation>,
+    ) -> (Decision, bool) {
+        self.last_access
+            .fetch_max(self.nanos_since_epoch(now), Ordering::Relaxed);
+        if let Some(decision) = self.try_consume_published(now, cost) {
+            self.allowed.fetch_add(1, Ordering::Relaxed);
+            return (decision, false);
+        }
+
+        let mut entity = self.lock();
+        if let Some(escalation) = escalation {
+            entity.deescalate(now, escalation);
+        }
+        let mut decision = entity.decide(now, cost);
+        if let Decision::Denied { .. } = decision {
+            if let Some(escalation) = escalation {
+                entity.escalate(now, escalation);
+                // The escalated refresh rate pushes back the retry.
+                decision = Decision::Denied {
+                    retry_after: entity.retry_after(now, cost),
+                };
+            }
+            entity.limited = true;
+            self.denied.fetch_add(1, Ordering::Relaxed);
+            return (decision, false);
+        }
+        self.allowed.fetch_add(1, Ordering::Relaxed);
+        (decision, std::mem::take(&mut entity.limited))
+    }
+
+    /// Gives back the `cost` of a request `decide` allowed, which then no longer counts
+    /// as allowed, see `AssociatedEntity::give_back`.
+    pub(crate) fn give_back(&self, now: Instant, cost: usize) {
+        self.lock().give_back(now, cost);
+        self.allowed.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> EntityStats {
+        let last_access = Duration::from_nanos(self.last_access.load(Ordering::Relaxed));
+        EntityStats {
+            allowed: self.allowed.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            last_access: self.epoch + last_access,
+        }
+    }
+
+    /// When requests were last consumed, as an opaque stamp that only changes when
+    /// they are, see `EvictionQueue`.
+    pub(crate) fn accessed(&self) -> u64 {
+        self.last_access.load(Ordering::Relaxed)
+    }
+
+    /// How long ago requests were last consumed, or the entity was added.
+    pub(crate) fn idle_for(&self, now: Instant) -> Duration {
+        let last_access = Duration::from_nanos(self.last_access.load(Ordering::Relaxed));
+        now.saturating_duration_since(self.epoch + last_access)
+    }
+
+    /// Shares the entity's state with a child entity, which then counts its requests
+    /// against it, or with entries of a group. Shared entities stay off the fast path.
+    pub(crate) fn share(&self) -> Arc<Mutex<AssociatedEntity>> {
+        let shared = Arc::clone(&self.state);
+        drop(self.lock()); // takes back the published bucket, for good
+        shared
+    }
+
+    pub(crate) fn into_inner(self) -> AssociatedEntity {
+        let tokens = self.tokens.load(Ordering::Acquire);
+        let mut state = match Arc::try_unwrap(self.state) {
+            Ok(state) => state.into_inner(),
+            Err(shared) => shared.lock().clone(),
+        };
+        if tokens != UNPUBLISHED {
+            state.bucket = tokens;
+        }
+        state
+    }
+
+    /// Only handles the common case of an allowed request within the open window,
+    /// everything else (refreshing, denying) goes through the lock.
+    fn try_consume_published(&self, now: Instant, cost: usize) -> Option<Decision> {
+        let now = self.nanos_since_epoch(now);
+        let window_end = self.window_end.load(Ordering::Acquire);
+        if now >= window_end {
+            return None;
+        }
+
+        let tokens = self
+            .tokens
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| {
+                if tokens != UNPUBLISHED && tokens >= cost {
+                    Some(tokens - cost)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+
+        Some(Decision::Allowed {
+            remaining: tokens - cost,
+            reset_in: Duration::from_nanos(window_end - now),
+        })
+    }
+}
+
+impl Entry {
+    fn nanos_since_epoch(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch)
+            .as_nanos()
+            .min(u64::MAX as u128) as u64
+    }
+}
+
+/// Exclusive access to an entity's state, see `Entry::lock`.
+pub(crate) struct EntryGuard<'a> {
+    entry: &'a Entry,
+    state: MutexGuard<'a, AssociatedEntity>,
+}
+
+impl Deref for EntryGuard<'_> {
+    type Target = AssociatedEntity;
+
+    fn deref(&self) -> &AssociatedEntity {
+        &self.state
+    }
+}
+
+impl DerefMut for EntryGuard<'_> {
+    fn deref_mut(&mut self) -> &mut AssociatedEntity {
+        &mut self.state
+    }
+}
+
+impl Drop for EntryGuard<'_> {
+    fn drop(&mut self) {
+        // Limited entities stay on the slow path so the refill gets noticed,
+        // entities with several windows or a parent so every bucket gets checked,
+        // parents so their children see what was consumed, and entities switching
+        // limits over the day so the switch gets noticed.
+        if self.state.algorithm != Algorithm::FixedWindow
+            || self.state.limited
+            || self.state.time_of_day.is_some()
+            || !self.state.windows.is_empty()
+            || self.state.parent.is_some()
+            || Arc::strong_count(&self.entry.state) > 1
+        {
+            return;
+        }
+        let window_end = (self.state.bucket_init + self.state.refresh_rate)
+            .saturating_duration_since(self.entry.epoch)
+            .as_nanos()
+            .min(u64::MAX as u128) as u64;
+        // The window first, a consumer that sees the tokens must see the window they belong to.
+        self.entry.window_end.store(window_end, Ordering::Release);
+        self.entry
+            .tokens
+            .store(self.state.bucket.min(UNPUBLISHED - 1), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn fixed_window(max_limit: usize, refresh_rate: Duration) -> Entry {
+        let now = Instant::now();
+        Entry::new(
+            AssociatedEntity::new(max_limit, refresh_rate, Algorithm::FixedWindow, now),
+            now,
+        )
+    }
+
+    #[test]
+    fn test_published_bucket_is_consumed_without_lock() {
+        let entry = fixed_window(3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        let _state = entry.state.lock();
+        assert!(matches!(
+            entry.try_consume_published(now, 2),
+            Some(Decision::Allowed { remaining: 1, .. })
+        ));
+        // Denials fall back to the lock.
+        assert_eq!(entry.try_consume_published(now, 2), None);
+    }
+
+    #[test]
+    fn test_lock_takes_back_published_tokens() {
+        let entry = fixed_window(3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(entry.decide(now, 1, None).0.is_allowed());
+        {
+            let guard = entry.lock();
+            assert_eq!(guard.bucket, 2);
+            assert_eq!(entry.try_consume_published(now, 1), None);
+        }
+        assert!(entry.try_consume_published(now, 1).is_some());
+        assert_eq!(entry.into_inner().bucket, 1);
+    }
+
+    #[test]
+    fn test_closed_window_refreshes_through_lock() {
+        let entry = fixed_window(1, Duration::from_millis(10));
+        let start = Instant::now();
+
+        assert!(entry.decide(start, 1, None).0.is_allowed());
+        assert!(!entry.decide(start, 1, None).0.is_allowed());
+        let later = start + Duration::from_millis(20);
+        assert_eq!(entry.try_consume_published(later, 1), None);
+        assert!(entry.decide(later, 1, None).0.is_allowed());
+    }
+
+    #[test]
+    fn test_other_algorithms_are_not_published() {
+        let now = Instant::now();
+        let entry = Entry::new(
+            AssociatedEntity::new(3, Duration::from_secs(60), Algorithm::TokenBucket, now),
+            now,
+        );
+        assert_eq!(entry.try_consume_published(Instant::now(), 1), None);
+        assert!(entry.decide(Instant::now(), 1, None).0.is_allowed());
+    }
+
+    #[test]
+    fn test_idle_for_tracks_last_consume() {
+        let start = Instant::now();
+        let entry = Entry::new(
+            AssociatedEntity::new(3, Duration::from_secs(60), Algorithm::FixedWindow, start),
+            start,
+        );
+
+        assert_eq!(
+            entry.idle_for(start + Duration::from_secs(5)),
+            Duration::from_secs(5)
+        );
+        entry.decide(start + Duration::from_secs(5), 1, None);
+        entry.decide(start + Duration::from_secs(70), 1, None);
+        // An older, late arriving consume doesn't move the last access back.
+        entry.decide(start + Duration::from_secs(6), 1, None);
+        assert_eq!(
+            entry.idle_for(start + Duration::from_secs(80)),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn test_concurrent_fast_and_locked_paths() {
+        let entry = Arc::new(fixed_window(20_000, Duration::from_secs(60)));
+
+        let handles: Vec<_> = (0..8)
+            .map(|thread| {
+                let entry = Arc::clone(&entry);
+                thread::spawn(move || {
+                    for i in 0..1_500 {
+                        if (thread + i) % 10 == 0 {
+                            // Interleave locked reads with lock-free consumes.
+                            drop(entry.lock());
+                        } else {
+                            entry.decide(Instant::now(), 1, None);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // 8 threads * 1350 consumes, none lost or counted twice.
+        assert_eq!(entry.lock().bucket, 20_000 - 8 * 1_350);
+    }
+}
@@ -0,0 +1,537 @@
+//! Envoy's rate limit service, see `RateLimitServer`.
+//!
+//! Implements `envoy.service.ratelimit.v3.RateLimitService`, the protocol Envoy's
+//! `envoy.filters.http.ratelimit` filter and Istio speak to a global rate limit service.
+//! The messages only carry the fields the server reads or answers with, the others
+//! are skipped when decoding.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use ::tonic::codegen::{Body, BoxFuture, Service, StdError};
+use ::tonic::server::{Grpc, NamedService, UnaryService};
+use ::tonic::{Request, Response, Status};
+use tonic_prost::ProstCodec;
+
+use crate::{Decision, Key, KeyParts, Limiter, Policy};
+
+/// The full name of the gRPC service, as Envoy calls it.
+pub const SERVICE_NAME: &str = "envoy.service.ratelimit.v3.RateLimitService";
+
+const SHOULD_RATE_LIMIT: &str = "/envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit";
+
+/// Limits the descriptors Envoy sends for its requests, answering `ShouldRateLimit`
+/// calls with a `Limiter<Key>`.
+///
+/// Every descriptor is limited by a key of the domain, followed by the key and value
+/// of each of its entries, so the descriptor `[("remote_address", "10.0.0.1")]` of the
+/// domain `edge` is counted as `edge:remote_address:10.0.0.1`. Unknown descriptors
+/// get the policy of their rule, see `DescriptorRules`:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::envoy::{DescriptorRules, RateLimitServer};
+/// # use rate_gate::Policy;
+/// let minute = Duration::from_secs(60);
+/// let server = RateLimitServer::new(
+///     DescriptorRules::new()
+///         .rule("edge", &["path=/login", "remote_address"], Policy::new(5, minute))
+///         .rule("edge", &["remote_address"], Policy::new(100, minute)),
+/// );
+/// ```
+///
+/// The server is a `tonic` service, added to a server like generated ones:
+///
+/// ```ignore
+/// Server::builder()
+///     .add_service(server)
+///     .serve("0.0.0.0:8081".parse()?)
+///     .await?;
+/// ```
+///
+/// A request is over the limit if any of its descriptors is. Like Envoy's reference
+/// implementation, the hits of every descriptor are consumed even if another one is
+/// over the limit. Descriptors without a rule, when the limiter has no default limit,
+/// are always `OK`. Overrides of the limit sent along with a descriptor are ignored.
+#[derive(Debug, Clone)]
+pub struct RateLimitServer {
+    limiter: Limiter<Key>,
+    rules: Arc<DescriptorRules>,
+}
+
+impl RateLimitServer {
+    pub fn new(rules: DescriptorRules) -> Self {
+        Self::from_limiter(Limiter::new(), rules)
+    }
+
+    /// Tracks the descriptors in `limiter`, e.g. one configured with `LimiterBuilder`.
+    /// Descriptors without a rule get its default limit.
+    ///
+    /// The policy of every rule is registered with the limiter under the name of the
+    /// rule, like `edge:path=/login:remote_address`, and the limiter's `TierResolver`
+    /// is replaced by one finding the rule of each descriptor.
+    pub fn from_limiter(limiter: Limiter<Key>, rules: DescriptorRules) -> Self {
+        for rule in &rules.rules {
+            limiter.define_policy(rule.to_string(), rule.policy.clone());
+        }
+        let rules = Arc::new(rules);
+        let resolver = Arc::clone(&rules);
+        let limiter = limiter
+            .with_tier_resolver(move |key: &Key| resolver.find(key).map(|rule| rule.to_string()));
+        RateLimitServer { limiter, rules }
+    }
+
+    /// The limiter tracking a bucket per descriptor.
+    pub fn limiter(&self) -> &Limiter<Key> {
+        &self.limiter
+    }
+
+    /// Consumes the hits of every descriptor of `request`, what the server answers
+    /// `ShouldRateLimit` calls with.
+    pub fn should_rate_limit(&self, request: &RateLimitRequest) -> RateLimitResponse {
+        // Envoy leaves it at zero for a single hit.
+        let hits = request.hits_addend.max(1) as u64;
+        let statuses: Vec<_> = request
+            .descriptors
+            .iter()
+            .map(|descriptor| {
+                let key = DescriptorKey {
+                    domain: &request.domain,
+                    entries: &descriptor.entries,
+                };
+                let cost = descriptor.hits_addend.unwrap_or(hits);
+                let cost = usize::try_from(cost).unwrap_or(usize::MAX);
+                let decision = self.limiter.consume(key.as_key(), cost);
+                descriptor_status(decision, self.rules.find(key.as_key()))
+            })
+            .collect();
+        let over_limit = statuses
+            .iter()
+            .any(|status| status.code() == Code::OverLimit);
+        RateLimitResponse {
+            overall_code: if over_limit {
+                Code::OverLimit
+            } else {
+                Code::Ok
+            } as i32,
+            statuses,
+        }
+    }
+}
+
+impl NamedService for RateLimitServer {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+impl<B> Service<http::Request<B>> for RateLimitServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<::tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let server = self.clone();
+        Box::pin(async move {
+            if request.uri().path() != SHOULD_RATE_LIMIT {
+                return Ok(Status::unimplemented("unknown method").into_http());
+            }
+            let mut grpc = Grpc::new(ProstCodec::default());
+            Ok(grpc.unary(ShouldRateLimit(server), request).await)
+        })
+    }
+}
+
+/// The `ShouldRateLimit` method of a `RateLimitServer`.
+struct ShouldRateLimit(RateLimitServer);
+
+impl UnaryService<RateLimitRequest> for ShouldRateLimit {
+    type Response = RateLimitResponse;
+    type Future = std::future::Ready<Result<Response<RateLimitResponse>, Status>>;
+
+    fn call(&mut self, request: Request<RateLimitRequest>) -> Self::Future {
+        let response = self.0.should_rate_limit(request.get_ref());
+        std::future::ready(Ok(Response::new(response)))
+    }
+}
+
+fn descriptor_status(decision: Decision, rule: Option<&DescriptorRule>) -> DescriptorStatus {
+    let current_limit = rule.map(|rule| RateLimit {
+        name: rule.to_string(),
+        requests_per_unit: u32::try_from(rule.policy.max_limit).unwrap_or(u32::MAX),
+        unit: Unit::of(rule.policy.refresh_rate) as i32,
+    });
+    let (code, limit_remaining, reset) = match decision {
+        Decision::Allowed {
+            remaining,
+            reset_in,
+        } => (Code::Ok, remaining, Some(reset_in)),
+        Decision::Denied { retry_after } => (Code::OverLimit, 0, Some(retry_after)),
+        // Bans without an end never reset.
+        Decision::Banned { retry_after } => (
+            Code::OverLimit,
+            0,
+            Some(retry_after).filter(|d| *d != Duration::MAX),
+        ),
+        Decision::Unknown => {
+            return DescriptorStatus {
+                code: Code::Ok as i32,
+                ..DescriptorStatus::default()
+            }
+        }
+    };
+    DescriptorStatus {
+        code: code as i32,
+        current_limit,
+        limit_remaining: u32::try_from(limit_remaining).unwrap_or(u32::MAX),
+        duration_until_reset: reset.map(|reset| prost_types::Duration {
+            seconds: reset.as_secs() as i64,
+            nanos: reset.subsec_nanos() as i32,
+        }),
+    }
+}
+
+/// A descriptor as the borrowed form of its `Key`, the domain followed by the key
+/// and value of every entry.
+struct DescriptorKey<'a> {
+    domain: &'a str,
+    entries: &'a [DescriptorEntry],
+}
+
+impl KeyParts for DescriptorKey<'_> {
+    fn len(&self) -> usize {
+        1 + 2 * self.entries.len()
+    }
+
+    fn part(&self, index: usize) -> &str {
+        match index {
+            0 => self.domain,
+            _ if index % 2 == 1 => &self.entries[index / 2].key,
+            _ => &self.entries[index / 2 - 1].value,
+        }
+    }
+}
+
+/// Policies for the descriptors of a `RateLimitServer`, like the descriptors of the
+/// configuration of Envoy's reference implementation.
+///
+/// Rules take precedence in the order they were added, the first one matching a
+/// descriptor applies.
+#[derive(Debug, Clone, Default)]
+pub struct DescriptorRules {
+    rules: Vec<DescriptorRule>,
+}
+
+#[derive(Debug, Clone)]
+struct DescriptorRule {
+    domain: String,
+    entries: Vec<(String, Option<String>)>, // Keys, and the value they must have if any
+    policy: Policy,
+}
+
+impl DescriptorRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits descriptors of `domain` with `policy`, unless an earlier rule matches.
+    ///
+    /// Descriptors match if they have exactly the entries of `entries`, in order.
+    /// Entries are written `key=value` to only match that value, or `key` to match any,
+    /// in which case every value gets a bucket of its own.
+    pub fn rule(mut self, domain: &str, entries: &[&str], policy: Policy) -> Self {
+        let entries = entries
+            .iter()
+            .map(|entry| match entry.split_once('=') {
+                Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                None => (entry.to_string(), None),
+            })
+            .collect();
+        self.rules.push(DescriptorRule {
+            domain: domain.to_string(),
+            entries,
+            policy,
+        });
+        self
+    }
+
+    /// The first rule matching the key of a descriptor.
+    fn find(&self, key: &dyn KeyParts) -> Option<&DescriptorRule> {
+        self.rules.iter().find(|rule| rule.matches(key))
+    }
+}
+
+impl DescriptorRule {
+    fn matches(&self, key: &dyn KeyParts) -> bool {
+        key.len() == 1 + 2 * self.entries.len()
+            && key.part(0) == self.domain
+            && self.entries.iter().enumerate().all(|(i, (name, value))| {
+                key.part(1 + 2 * i) == name
+                    && value
+                        .as_ref()
+                        .is_none_or(|value| key.part(2 + 2 * i) == value)
+            })
+    }
+}
+
+/// Like `edge:path=/login:remote_address`, the name its policy is registered under.
+impl fmt::Display for DescriptorRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.domain)?;
+        for (key, value) in &self.entries {
+            write!(f, ":{}", key)?;
+            if let Some(value) = value {
+                write!(f, "={}", value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitRequest {
+    /// The domain of the descriptors, keeping apart those of different services.
+    #[prost(string, tag = "1")]
+    pub domain: String,
+    /// What to limit the request by, each descriptor a bucket.
+    #[prost(message, repeated, tag = "2")]
+    pub descriptors: Vec<RateLimitDescriptor>,
+    /// How many hits the request costs, zero for one.
+    #[prost(uint32, tag = "3")]
+    pub hits_addend: u32,
+}
+
+/// `envoy.extensions.common.ratelimit.v3.RateLimitDescriptor`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitDescriptor {
+    #[prost(message, repeated, tag = "1")]
+    pub entries: Vec<DescriptorEntry>,
+    /// How many hits the descriptor costs, instead of those of the request.
+    #[prost(message, optional, tag = "3")]
+    pub hits_addend: Option<u64>,
+}
+
+/// `envoy.extensions.common.ratelimit.v3.RateLimitDescriptor.Entry`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DescriptorEntry {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimitResponse {
+    /// `OVER_LIMIT` if any descriptor is.
+    #[prost(enumeration = "Code", tag = "1")]
+    pub overall_code: i32,
+    /// A status for every descriptor of the request, in the same order.
+    #[prost(message, repeated, tag = "2")]
+    pub statuses: Vec<DescriptorStatus>,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse.DescriptorStatus`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DescriptorStatus {
+    #[prost(enumeration = "Code", tag = "1")]
+    pub code: i32,
+    /// The limit of the rule matching the descriptor, if any.
+    #[prost(message, optional, tag = "2")]
+    pub current_limit: Option<RateLimit>,
+    #[prost(uint32, tag = "3")]
+    pub limit_remaining: u32,
+    #[prost(message, optional, tag = "4")]
+    pub duration_until_reset: Option<prost_types::Duration>,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse.RateLimit`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RateLimit {
+    #[prost(string, tag = "3")]
+    pub name: String,
+    #[prost(uint32, tag = "1")]
+    pub requests_per_unit: u32,
+    #[prost(enumeration = "Unit", tag = "2")]
+    pub unit: i32,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse.Code`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Code {
+    Unknown = 0,
+    Ok = 1,
+    OverLimit = 2,
+}
+
+/// `envoy.service.ratelimit.v3.RateLimitResponse.RateLimit.Unit`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Unit {
+    Unknown = 0,
+    Second = 1,
+    Minute = 2,
+    Hour = 3,
+    Day = 4,
+    Month = 5,
+    Year = 6,
+    Week = 7,
+}
+
+impl Unit {
+    /// The unit lasting `refresh_rate`, `Unknown` for refresh rates that aren't a unit.
+    fn of(refresh_rate: Duration) -> Self {
+        if refresh_rate.subsec_nanos() != 0 {
+            return Unit::Unknown;
+        }
+        match refresh_rate.as_secs() {
+            1 => Unit::Second,
+            60 => Unit::Minute,
+            3_600 => Unit::Hour,
+            86_400 => Unit::Day,
+            604_800 => Unit::Week,
+            _ => Unit::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LimiterBuilder;
+    use http_body_util::{BodyExt, Full};
+    use prost::Message;
+    use tower::ServiceExt;
+
+    fn request(domain: &str, descriptors: &[&[(&str, &str)]]) -> RateLimitRequest {
+        let descriptors = descriptors
+            .iter()
+            .map(|entries| RateLimitDescriptor {
+                entries: entries
+                    .iter()
+                    .map(|(key, value)| DescriptorEntry {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    })
+                    .collect(),
+                hits_addend: None,
+            })
+            .collect();
+        RateLimitRequest {
+            domain: domain.to_string(),
+            descriptors,
+            hits_addend: 0,
+        }
+    }
+
+    #[test]
+    fn test_descriptors() {
+        let minute = Duration::from_secs(60);
+        let server = RateLimitServer::new(
+            DescriptorRules::new()
+                .rule(
+                    "edge",
+                    &["path=/login", "remote_address"],
+                    Policy::new(1, minute),
+                )
+                .rule("edge", &["remote_address"], Policy::new(3, minute)),
+        );
+        let login = [("path", "/login"), ("remote_address", "10.0.0.1")];
+        let ip = [("remote_address", "10.0.0.1")];
+
+        let response = server.should_rate_limit(&request("edge", &[&login, &ip]));
+        assert_eq!(response.overall_code(), Code::Ok);
+        let status = &response.statuses[1];
+        assert_eq!((status.code(), status.limit_remaining), (Code::Ok, 2));
+        let limit = status.current_limit.as_ref().unwrap();
+        assert_eq!(limit.name, "edge:remote_address");
+        assert_eq!((limit.requests_per_unit, limit.unit()), (3, Unit::Minute));
+
+        let response = server.should_rate_limit(&request("edge", &[&login, &ip]));
+        assert_eq!(response.overall_code(), Code::OverLimit);
+        assert_eq!(response.statuses[0].code(), Code::OverLimit);
+        assert_eq!(response.statuses[1].limit_remaining, 1);
+
+        // Every value of an entry without one in the rule gets its own bucket.
+        let other = [("path", "/login"), ("remote_address", "10.0.0.2")];
+        let response = server.should_rate_limit(&request("edge", &[&other]));
+        assert_eq!(response.overall_code(), Code::Ok);
+
+        let unknown = server.should_rate_limit(&request("internal", &[&ip]));
+        assert_eq!(unknown.overall_code(), Code::Ok);
+        assert_eq!(unknown.statuses[0].current_limit, None);
+        assert_eq!(server.limiter().len(), 3);
+    }
+
+    #[test]
+    fn test_hits_addend() {
+        let limiter = LimiterBuilder::new()
+            .default_limit(10, Duration::from_secs(1))
+            .build();
+        let server = RateLimitServer::from_limiter(limiter, DescriptorRules::new());
+        let mut request = request("edge", &[&[("user", "alice")], &[("user", "bob")]]);
+        request.hits_addend = 4;
+        request.descriptors[1].hits_addend = Some(11);
+
+        let response = server.should_rate_limit(&request);
+        assert_eq!(response.overall_code(), Code::OverLimit);
+        assert_eq!(response.statuses[0].limit_remaining, 6);
+        assert_eq!(response.statuses[0].current_limit, None);
+        assert_eq!(response.statuses[1].code(), Code::OverLimit);
+    }
+
+    #[tokio::test]
+    async fn test_grpc() {
+        let server = RateLimitServer::new(DescriptorRules::new().rule(
+            "edge",
+            &["remote_address"],
+            Policy::new(1, Duration::from_secs(1)),
+        ));
+        let call = |method: &str| {
+            let message = request("edge", &[&[("remote_address", "10.0.0.1")]]).encode_to_vec();
+            let mut body = vec![0];
+            body.extend((message.len() as u32).to_be_bytes());
+            body.extend(message);
+            http::Request::post(method)
+                .header("content-type", "application/grpc")
+                .body(Full::new(::tonic::codegen::Bytes::from(body)))
+                .unwrap()
+        };
+
+        let response = server
+            .clone()
+            .oneshot(call(SHOULD_RATE_LIMIT))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let response = RateLimitResponse::decode(&body[5..]).unwrap();
+        assert_eq!(response.overall_code(), Code::Ok);
+        assert_eq!(
+            response.statuses[0].current_limit.as_ref().unwrap().unit(),
+            Unit::Second
+        );
+
+        let response = server
+            .clone()
+            .oneshot(call(SHOULD_RATE_LIMIT))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let response = RateLimitResponse::decode(&body[5..]).unwrap();
+        assert_eq!(response.overall_code(), Code::OverLimit);
+
+        let response = server.oneshot(call("/envoy.Unknown/Method")).await.unwrap();
+        assert_eq!(response.headers()["grpc-status"], "12");
+    }
+}
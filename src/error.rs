@@ -0,0 +1,119 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::Decision;
+
+/// Why a request wasn't allowed, see `Limiter::try_consume`.
+#[derive(Debug)]
+pub enum RateGateError {
+    /// The entity was not found by the limiter.
+    EntityNotFound,
+    /// The entity is rate limited.
+    LimitExceeded {
+        /// Time until the request would be allowed.
+        retry_after: Duration,
+    },
+    /// The entity is banned.
+    Banned {
+        /// Time until the ban expires.
+        retry_after: Duration,
+    },
+    /// The entity has as many requests in progress as it may, see `Limiter::check_in_flight`.
+    TooManyInFlight,
+    /// No request could be consumed within the timeout, see `Limiter::acquire_timeout`.
+    TimedOut {
+        /// Time until the request would have been allowed, as far as was known when
+        /// giving up.
+        retry_after: Duration,
+    },
+    /// The store of a `StoreLimiter` couldn't be reached.
+    StoreUnavailable(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl RateGateError {
+    /// Time until the request would be allowed, `None` if waiting won't help.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            RateGateError::LimitExceeded { retry_after }
+            | RateGateError::Banned { retry_after }
+            | RateGateError::TimedOut { retry_after } => Some(*retry_after),
+            RateGateError::EntityNotFound
+            | RateGateError::TooManyInFlight
+            | RateGateError::StoreUnavailable(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for RateGateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateGateError::EntityNotFound => write!(f, "entity not found"),
+            RateGateError::LimitExceeded { retry_after } => {
+                write!(f, "rate limit exceeded, retry after {:?}", retry_after)
+            }
+            RateGateError::Banned { retry_after } => {
+                write!(f, "entity is banned for {:?}", retry_after)
+            }
+            RateGateError::TooManyInFlight => write!(f, "too many requests in flight"),
+            RateGateError::TimedOut { retry_after } => {
+                write!(f, "timed out, retry after {:?}", retry_after)
+            }
+            RateGateError::StoreUnavailable(err) => write!(f, "store unavailable: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RateGateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RateGateError::StoreUnavailable(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// What is left after an allowed request, see `Limiter::try_consume`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allowance {
+    /// How many requests are left in the bucket.
+    pub remaining: usize,
+    /// Time until the bucket gets refilled.
+    pub reset_in: Duration,
+}
+
+impl Decision {
+    /// The decision as a `Result`, so it can be passed on with `?`.
+    pub fn into_result(self) -> Result<Allowance, RateGateError> {
+        match self {
+            Decision::Allowed {
+                remaining,
+                reset_in,
+            } => Ok(Allowance {
+                remaining,
+                reset_in,
+            }),
+            Decision::Denied { retry_after } => Err(RateGateError::LimitExceeded { retry_after }),
+            Decision::Banned { retry_after } => Err(RateGateError::Banned { retry_after }),
+            Decision::Unknown => Err(RateGateError::EntityNotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_result() {
+        let denied = Decision::Denied {
+            retry_after: Duration::from_secs(1),
+        };
+        let err = denied.into_result().unwrap_err();
+        assert!(matches!(err, RateGateError::LimitExceeded { .. }));
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(1)));
+        assert!(matches!(
+            Decision::Unknown.into_result(),
+            Err(RateGateError::EntityNotFound)
+        ));
+    }
+}
@@ -0,0 +1,169 @@
+use std::time::{Duration, Instant};
+
+/// Escalating penalties for entities that keep hitting their limit,
+/// set with `LimiterBuilder::escalation`.
+///
+/// Every `violations` denied requests raise the penalty level of an entity by one,
+/// and every level multiplies its refresh rate by `factor`, so a fixed window
+/// of a minute becomes two, then four, and so on. Once an entity behaves for
+/// `decay`, the level drops by one again, until its own refresh rate is restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "Unchecked")
+)]
+pub struct Escalation {
+    violations: usize,
+    decay: Duration,
+    factor: u32,
+    max_level: u32,
+}
+
+impl Escalation {
+    /// Escalates after `violations` denied requests, and decays one level
+    /// for every `decay` without a denied request.
+    /// Doubles the refresh rate per level, up to 4 levels.
+    pub fn new(violations: usize, decay: Duration) -> Self {
+        Escalation {
+            violations: violations.max(1),
+            decay,
+            factor: 2,
+            max_level: 4,
+        }
+    }
+
+    /// Sets what the refresh rate gets multiplied with per level.
+    pub fn factor(mut self, factor: u32) -> Self {
+        self.factor = factor.max(1);
+        self
+    }
+
+    /// Sets the highest level the penalty can escalate to.
+    pub fn max_level(mut self, max_level: u32) -> Self {
+        self.max_level = max_level;
+        self
+    }
+
+    /// The refresh rate of an entity with its own rate `base` at `level`.
+    pub(crate) fn refresh_rate(&self, base: Duration, level: u32) -> Duration {
+        base.saturating_mul(self.factor.saturating_pow(level))
+    }
+}
+
+/// `Escalation` as it is deserialized, before the bounds of its setters are applied.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct Unchecked {
+    violations: usize,
+    decay: Duration,
+    factor: u32,
+    max_level: u32,
+}
+
+#[cfg(feature = "serde")]
+impl From<Unchecked> for Escalation {
+    fn from(unchecked: Unchecked) -> Self {
+        Escalation::new(unchecked.violations, unchecked.decay)
+            .factor(unchecked.factor)
+            .max_level(unchecked.max_level)
+    }
+}
+
+/// How an entity is doing under an `Escalation`.
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct Violations {
+    pub(crate) count: usize,  // Denied requests since the level last changed
+    pub(crate) level: u32,    // Current penalty level
+    pub(crate) last: Instant, // Last denied request, or decay
+    pub(crate) base_rate: Duration, // The entity's own refresh rate
+}
+
+impl Violations {
+    pub(crate) fn new(now: Instant, base_rate: Duration) -> Self {
+        Violations {
+            count: 0,
+            level: 0,
+            last: now,
+            base_rate,
+        }
+    }
+
+    /// Counts a denied request, returns `true` if the level went up.
+    pub(crate) fn escalate(&mut self, now: Instant, escalation: &Escalation) -> bool {
+        self.count += 1;
+        self.last = now;
+        if self.count < escalation.violations || self.level >= escalation.max_level {
+            return false;
+        }
+        self.count = 0;
+        self.level += 1;
+        true
+    }
+
+    /// Drops a level for every `decay` since the last denied request,
+    /// returns `true` if the level went down.
+    pub(crate) fn decay(&mut self, now: Instant, escalation: &Escalation) -> bool {
+        let elapsed = now.saturating_duration_since(self.last);
+        let levels = elapsed
+            .as_nanos()
+            .checked_div(escalation.decay.as_nanos())
+            .unwrap_or(u128::MAX);
+        if levels == 0 {
+            return false;
+        }
+        self.count = 0;
+        if levels >= self.level as u128 {
+            let decayed = self.level > 0;
+            self.level = 0;
+            return decayed;
+        }
+        self.level -= levels as u32;
+        self.last += escalation.decay * levels as u32;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escalate_and_decay() {
+        let escalation = Escalation::new(2, Duration::from_secs(60)).max_level(2);
+        let start = Instant::now();
+        let mut violations = Violations::new(start, Duration::from_secs(1));
+
+        assert!(!violations.escalate(start, &escalation));
+        assert!(violations.escalate(start, &escalation));
+        assert!(!violations.escalate(start, &escalation));
+        assert!(violations.escalate(start, &escalation));
+        // Capped at max_level.
+        assert!(!violations.escalate(start, &escalation));
+        assert!(!violations.escalate(start, &escalation));
+        assert_eq!(violations.level, 2);
+        assert_eq!(
+            escalation.refresh_rate(violations.base_rate, violations.level),
+            Duration::from_secs(4)
+        );
+
+        assert!(!violations.decay(start + Duration::from_secs(59), &escalation));
+        assert!(violations.decay(start + Duration::from_secs(61), &escalation));
+        assert_eq!(violations.level, 1);
+        assert!(violations.decay(start + Duration::from_secs(500), &escalation));
+        assert_eq!(violations.level, 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_deserialize_applies_bounds() {
+        let json = r#"{"violations":0,"decay":{"secs":60,"nanos":0},"factor":0,"max_level":3}"#;
+        let escalation: Escalation = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            escalation,
+            Escalation::new(1, Duration::from_secs(60))
+                .factor(1)
+                .max_level(3)
+        );
+    }
+}
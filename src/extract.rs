@@ -0,0 +1,200 @@
+//! Finding what to limit an HTTP request by, see `KeyExtractor`.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+
+use http::{header, HeaderName, Request};
+
+/// Finds the key a request is limited by, e.g. the client IP or an API key,
+/// for the HTTP middleware like `RateLimitLayer`.
+///
+/// Implemented by closures taking a `&http::Request<B>`, by the extractors of this
+/// module, and by pairs of extractors, which combine both keys:
+///
+/// ```
+/// # use rate_gate::extract::{KeyExtractor, Path, PeerIp};
+/// // Every client gets its own limit per path.
+/// let key = (Path, PeerIp);
+/// ```
+pub trait KeyExtractor<B> {
+    type Key;
+
+    /// Returns the key of `request`, `None` if it has none and shouldn't be limited.
+    fn extract(&self, request: &Request<B>) -> Option<Self::Key>;
+}
+
+impl<B, K, F> KeyExtractor<B> for F
+where
+    F: Fn(&Request<B>) -> Option<K>,
+{
+    type Key = K;
+
+    fn extract(&self, request: &Request<B>) -> Option<K> {
+        self(request)
+    }
+}
+
+impl<B, E1, E2> KeyExtractor<B> for (E1, E2)
+where
+    E1: KeyExtractor<B>,
+    E2: KeyExtractor<B>,
+{
+    type Key = (E1::Key, E2::Key);
+
+    fn extract(&self, request: &Request<B>) -> Option<Self::Key> {
+        Some((self.0.extract(request)?, self.1.extract(request)?))
+    }
+}
+
+/// The IP of the peer that sent the request, from a `SocketAddr` in the request's
+/// extensions, or the `ConnectInfo<SocketAddr>` of axum.
+///
+/// Behind a reverse proxy that is the proxy's IP, see `ForwardedFor`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerIp;
+
+impl<B> KeyExtractor<B> for PeerIp {
+    type Key = IpAddr;
+
+    fn extract(&self, request: &Request<B>) -> Option<IpAddr> {
+        let extensions = request.extensions();
+        #[cfg(feature = "axum")]
+        if let Some(info) = extensions.get::<::axum::extract::ConnectInfo<SocketAddr>>() {
+            return Some(info.0.ip());
+        }
+        extensions.get::<SocketAddr>().map(SocketAddr::ip)
+    }
+}
+
+/// The IP of the client from the `X-Forwarded-For` header set by the `proxies` reverse
+/// proxies in front of the app, e.g. 1 behind a single load balancer.
+/// Falls back to `PeerIp` for requests that didn't come through enough proxies.
+///
+/// Only the addresses the proxies appended are used, the ones before them
+/// were sent by the client and could be anything.
+#[derive(Debug, Clone, Copy)]
+pub struct ForwardedFor {
+    pub proxies: usize,
+}
+
+impl<B> KeyExtractor<B> for ForwardedFor {
+    type Key = IpAddr;
+
+    fn extract(&self, request: &Request<B>) -> Option<IpAddr> {
+        if self.proxies > 0 {
+            // Every proxy appends the address it got the request from.
+            let forwarded = request
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .collect::<Vec<_>>();
+            let client = forwarded
+                .len()
+                .checked_sub(self.proxies)
+                .and_then(|client| forwarded[client].trim().parse().ok());
+            if client.is_some() {
+                return client;
+            }
+        }
+        PeerIp.extract(request)
+    }
+}
+
+/// The value of a header, e.g. `x-api-key`.
+#[derive(Debug, Clone)]
+pub struct Header(pub HeaderName);
+
+impl<B> KeyExtractor<B> for Header {
+    type Key = String;
+
+    fn extract(&self, request: &Request<B>) -> Option<String> {
+        let value = request.headers().get(&self.0)?;
+        value.to_str().ok().map(str::to_string)
+    }
+}
+
+/// A hash of the token in an `Authorization: Bearer` header, so the limiter
+/// doesn't keep the tokens themselves around.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BearerToken;
+
+impl<B> KeyExtractor<B> for BearerToken {
+    type Key = u64;
+
+    fn extract(&self, request: &Request<B>) -> Option<u64> {
+        let authorization = request
+            .headers()
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?;
+        let (scheme, token) = authorization.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        token.trim().hash(&mut hasher);
+        Some(hasher.finish())
+    }
+}
+
+/// The path of the request, without the query.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Path;
+
+impl<B> KeyExtractor<B> for Path {
+    type Key = String;
+
+    fn extract(&self, request: &Request<B>) -> Option<String> {
+        Some(request.uri().path().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut request = Request::builder().uri("/login?next=/");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request.body(()).unwrap();
+        request
+            .extensions_mut()
+            .insert(SocketAddr::from(([10, 0, 0, 1], 1234)));
+        request
+    }
+
+    #[test]
+    fn test_ips() {
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        let forwarded = request(&[("x-forwarded-for", "1.1.1.1, 2.2.2.2")]);
+        assert_eq!(PeerIp.extract(&forwarded), Some(peer));
+        assert_eq!(
+            ForwardedFor { proxies: 1 }.extract(&forwarded),
+            Some(IpAddr::from([2, 2, 2, 2]))
+        );
+        assert_eq!(
+            ForwardedFor { proxies: 2 }.extract(&forwarded),
+            Some(IpAddr::from([1, 1, 1, 1]))
+        );
+        assert_eq!(ForwardedFor { proxies: 3 }.extract(&forwarded), Some(peer));
+    }
+
+    #[test]
+    fn test_headers() {
+        let request = request(&[("x-api-key", "key1"), ("authorization", "Bearer abc")]);
+        let api_key = Header(HeaderName::from_static("x-api-key"));
+        assert_eq!(api_key.extract(&request), Some("key1".to_string()));
+        assert!(BearerToken.extract(&request).is_some());
+        assert_eq!(
+            (Path, PeerIp).extract(&request),
+            Some(("/login".to_string(), IpAddr::from([10, 0, 0, 1])))
+        );
+
+        let closure = |request: &Request<()>| request.headers().get("x-missing").cloned();
+        assert_eq!(closure.extract(&request), None);
+    }
+}
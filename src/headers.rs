@@ -0,0 +1,300 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The rate limit headers of an entity, see `Limiter::rate_limit_headers`.
+///
+/// Both the common `X-RateLimit-*` headers and the `RateLimit-*` ones of the IETF
+/// draft are written, clients tend to know one or the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitHeaders {
+    /// How many requests the entity gets per window.
+    pub limit: usize,
+    /// How many requests are left.
+    pub remaining: usize,
+    /// Time until the bucket is completely refilled.
+    pub reset: Duration,
+    /// The Unix time in seconds at which the bucket is completely refilled, by the
+    /// clock of the limiter.
+    pub reset_at: u64,
+    /// The timeframe after which the entity gets a renewed limit.
+    pub window: Duration,
+}
+
+impl RateLimitHeaders {
+    /// The headers as name and value pairs, for servers without the `http` crate:
+    /// - `X-RateLimit-Limit`, `X-RateLimit-Remaining`
+    /// - `X-RateLimit-Reset`, `reset_at`
+    /// - `RateLimit-Limit`, `RateLimit-Remaining`
+    /// - `RateLimit-Reset`, the seconds until the bucket is refilled
+    /// - `RateLimit-Policy`, like `100;w=60`
+    pub fn pairs(&self) -> [(&'static str, String); 7] {
+        let reset = ceil_secs(self.reset);
+        [
+            ("x-ratelimit-limit", self.limit.to_string()),
+            ("x-ratelimit-remaining", self.remaining.to_string()),
+            ("x-ratelimit-reset", self.reset_at.to_string()),
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", reset.to_string()),
+            (
+                "ratelimit-policy",
+                format!("{};w={}", self.limit, ceil_secs(self.window)),
+            ),
+        ]
+    }
+
+    /// Adds the headers of `pairs` to `headers`, replacing ones of the same name.
+    #[cfg(feature = "http")]
+    pub fn apply(&self, headers: &mut http::HeaderMap) {
+        for (name, value) in self.pairs() {
+            if let Ok(value) = http::HeaderValue::try_from(value) {
+                headers.insert(name, value);
+            }
+        }
+    }
+
+    /// The headers of `pairs` as a `HeaderMap`.
+    #[cfg(feature = "http")]
+    pub fn to_header_map(&self) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        self.apply(&mut headers);
+        headers
+    }
+}
+
+/// The value of a `Retry-After` header telling clients to come back after a duration,
+/// e.g. from `Limiter::retry_after` or `Decision::retry_after`.
+///
+/// Formats as whole seconds, rounded up so clients never come back too early.
+/// `http_date` gives the point in time instead, for clients that expect a date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RetryAfter(pub Duration);
+
+impl RetryAfter {
+    /// The seconds to wait, rounded up.
+    pub fn seconds(&self) -> u64 {
+        ceil_secs(self.0)
+    }
+
+    /// The point in time to retry at as an HTTP date, like `Sun, 06 Nov 1994 08:49:37 GMT`.
+    pub fn http_date(&self) -> String {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        http_date(since_epoch.saturating_add(self.seconds()))
+    }
+
+    /// The seconds to wait as a header value.
+    #[cfg(feature = "http")]
+    pub fn header_value(&self) -> http::HeaderValue {
+        http::HeaderValue::from(self.seconds())
+    }
+}
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.seconds())
+    }
+}
+
+/// Resets past this many seconds are read as Unix time rather than seconds from now.
+#[cfg(feature = "http")]
+const UNIX_TIME_RESETS: f64 = 100_000_000.0;
+
+/// The longest reset or retry a server is taken by its word for, longer ones are cut
+/// to this so a bogus header can't push the bucket past what an `Instant` can hold.
+#[cfg(feature = "http")]
+const MAX_SERVER_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The limit a server reported in the headers of a response,
+/// see `Limiter::sync_from_headers`.
+#[cfg(feature = "http")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ServerLimit {
+    pub(crate) remaining: Option<usize>,      // Requests left
+    pub(crate) reset: Option<Duration>,       // Time until the window resets
+    pub(crate) retry_after: Option<Duration>, // Time until requests are accepted again
+}
+
+#[cfg(feature = "http")]
+impl ServerLimit {
+    /// Reads the `X-RateLimit-*` or `RateLimit-*` and `Retry-After` headers,
+    /// resolving points in time against `now`. Waits are capped at `MAX_SERVER_WAIT`.
+    pub(crate) fn from_headers(headers: &http::HeaderMap, now: SystemTime) -> Self {
+        let get = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| headers.get(*name)?.to_str().ok())
+                .map(str::trim)
+        };
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let reset = get(&["x-ratelimit-reset", "ratelimit-reset"])
+            .and_then(|reset| reset.parse::<f64>().ok())
+            .and_then(|reset| Duration::try_from_secs_f64(reset).ok())
+            .map(|reset| match reset.as_secs_f64() >= UNIX_TIME_RESETS {
+                true => reset.saturating_sub(since_epoch),
+                false => reset,
+            })
+            .map(|reset| reset.min(MAX_SERVER_WAIT));
+        let retry_after = get(&["retry-after"])
+            .and_then(|retry_after| match retry_after.parse::<u64>() {
+                Ok(secs) => Some(Duration::from_secs(secs)),
+                Err(_) => Some(
+                    Duration::from_secs(parse_http_date(retry_after)?).saturating_sub(since_epoch),
+                ),
+            })
+            .map(|retry_after| retry_after.min(MAX_SERVER_WAIT));
+        ServerLimit {
+            remaining: get(&["x-ratelimit-remaining", "ratelimit-remaining"])
+                .and_then(|remaining| remaining.parse().ok()),
+            reset,
+            retry_after,
+        }
+    }
+}
+
+/// Parses an IMF-fixdate like `Sun, 06 Nov 1994 08:49:37 GMT` into seconds since
+/// the Unix epoch, the inverse of `http_date`.
+#[cfg(feature = "http")]
+fn parse_http_date(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let [_, day, month, year, time, "GMT"] = date.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    let days = days_from_civil(year.parse().ok()?, month, day.parse().ok()?)?;
+    Some(days * 86_400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+/// The number of days from 1970-01-01 to a date after it, the inverse of `civil_from_days`.
+pub(crate) fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146_097 + doe - 719_468)
+}
+
+/// Formats seconds since the Unix epoch as an IMF-fixdate, see RFC 9110.
+fn http_date(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = secs / 86_400;
+    let (year, month, day) = civil_from_days(days);
+    let time = secs % 86_400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// The year, month and day of the `days`th day since 1970-01-01.
+/// Howard Hinnant's `civil_from_days`, restricted to dates after the epoch.
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Whole seconds, rounded up so clients never come back too early.
+pub(crate) fn ceil_secs(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_add(u64::from(duration.subsec_nanos() > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(RetryAfter(Duration::from_millis(1500)).to_string(), "2");
+        assert_eq!(RetryAfter(Duration::ZERO).seconds(), 0);
+        assert_eq!(http_date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_pairs() {
+        let headers = RateLimitHeaders {
+            limit: 100,
+            remaining: 40,
+            reset: Duration::from_millis(29_500),
+            reset_at: 1_760_000_030,
+            window: Duration::from_secs(60),
+        };
+        let pairs = headers.pairs();
+        let get = |name| pairs.iter().find(|(n, _)| *n == name).unwrap().1.as_str();
+        assert_eq!(get("x-ratelimit-limit"), "100");
+        assert_eq!(get("ratelimit-remaining"), "40");
+        assert_eq!(get("ratelimit-reset"), "30");
+        assert_eq!(get("ratelimit-policy"), "100;w=60");
+        assert_eq!(get("x-ratelimit-reset"), "1760000030");
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_server_limit() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", "12".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "784111807".parse().unwrap());
+        assert_eq!(
+            ServerLimit::from_headers(&headers, now),
+            ServerLimit {
+                remaining: Some(12),
+                reset: Some(Duration::from_secs(30)),
+                retry_after: None,
+            }
+        );
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("ratelimit-remaining", "0".parse().unwrap());
+        headers.insert("ratelimit-reset", "1.5".parse().unwrap());
+        headers.insert(
+            "retry-after",
+            "Sun, 06 Nov 1994 08:50:37 GMT".parse().unwrap(),
+        );
+        let limit = ServerLimit::from_headers(&headers, now);
+        assert_eq!(limit.reset, Some(Duration::from_millis(1500)));
+        assert_eq!(limit.retry_after, Some(Duration::from_secs(60)));
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("retry-after", "18446744073709551615".parse().unwrap());
+        headers.insert("ratelimit-reset", "1e15".parse().unwrap());
+        let limit = ServerLimit::from_headers(&headers, now);
+        assert_eq!(limit.retry_after, Some(MAX_SERVER_WAIT));
+        assert_eq!(limit.reset, Some(MAX_SERVER_WAIT));
+
+        for secs in [0, 784_111_777, 951_782_400, 4_102_444_800] {
+            assert_eq!(parse_http_date(&http_date(secs)), Some(secs));
+        }
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::entry::Entry;
+use crate::{AssociatedEntity, Decision};
+
+/// Details about a denied request, passed to `Hooks::on_denied`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DenialInfo {
+    /// How many requests the denied request would have consumed.
+    pub cost: usize,
+    /// Time until the request would be allowed.
+    pub retry_after: Duration,
+}
+
+type OnDenied<T> = Box<dyn Fn(&T, DenialInfo) + Send + Sync>;
+type OnRefill<T> = Box<dyn Fn(&T) + Send + Sync>;
+type OnEvicted<T> = Box<dyn Fn(T, AssociatedEntity) + Send + Sync>;
+
+/// Callbacks for notable events of a limiter, set with `Limiter::with_hooks`.
+///
+/// `on_denied` and `on_refill` run on the thread that checked the entity, while
+/// the shard the entity lives in is locked for reading. They should be quick and
+/// must not add, remove or check entities of the same limiter, banning is fine.
+pub struct Hooks<T> {
+    pub(crate) on_denied: Option<OnDenied<T>>,
+    pub(crate) on_refill: Option<OnRefill<T>>,
+    pub(crate) on_evicted: Option<OnEvicted<T>>,
+}
+
+impl<T> Default for Hooks<T> {
+    fn default() -> Self {
+        Hooks {
+            on_denied: None,
+            on_refill: None,
+            on_evicted: None,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Hooks<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_denied", &self.on_denied.is_some())
+            .field("on_refill", &self.on_refill.is_some())
+            .field("on_evicted", &self.on_evicted.is_some())
+            .finish()
+    }
+}
+
+impl<T> Hooks<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called whenever an entity hits its limit and a request is denied.
+    /// Requests denied by a ban don't count, see `Limiter::ban`.
+    pub fn on_denied(mut self, f: impl Fn(&T, DenialInfo) + Send + Sync + 'static) -> Self {
+        self.on_denied = Some(Box::new(f));
+        self
+    }
+
+    /// Called on the first allowed request of an entity after it was denied,
+    /// i.e. once its bucket refilled.
+    pub fn on_refill(mut self, f: impl Fn(&T) + Send + Sync + 'static) -> Self {
+        self.on_refill = Some(Box::new(f));
+        self
+    }
+
+    /// Called with every entity the limiter removed by itself, because it was idle
+    /// or to make room for another one. Not called for `Limiter::remove_limited_entity`
+    /// or `Limiter::clear`.
+    pub fn on_evicted(mut self, f: impl Fn(T, AssociatedEntity) + Send + Sync + 'static) -> Self {
+        self.on_evicted = Some(Box::new(f));
+        self
+    }
+}
+
+impl<T> Hooks<T> {
+    /// Runs the hooks for a decision made for `entity`, see `Entry::decide`.
+    pub(crate) fn decided(&self, entity: &T, cost: usize, decided: (Decision, bool)) -> Decision {
+        match decided {
+            (Decision::Denied { retry_after }, _) => {
+                if let Some(on_denied) = &self.on_denied {
+                    on_denied(entity, DenialInfo { cost, retry_after });
+                }
+            }
+            (_, true) => {
+                if let Some(on_refill) = &self.on_refill {
+                    on_refill(entity);
+                }
+            }
+            _ => {}
+        }
+        decided.0
+    }
+
+    /// Runs the hook for an evicted entity, if any.
+    pub(crate) fn evicted(&self, evicted: Option<(T, Entry)>) {
+        if let (Some(on_evicted), Some((entity, entry))) = (&self.on_evicted, evicted) {
+            on_evicted(entity, entry.into_inner());
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cidr::{mask, CidrRules, RangeRule};
+use crate::sync::RwLock;
+use crate::{Cidr, Decision, Limiter, Policy};
+
+/// Limits clients by IP address, counting addresses of the same network together.
+///
+/// An IPv6 client usually gets a whole /64 to itself and can pick a new address out
+/// of it for every request, so limiting single IPv6 addresses limits nothing. By
+/// default, IPv6 addresses are limited per /64 and IPv4 addresses one by one, IPv4
+/// addresses mapped into IPv6 (`::ffff:1.2.3.4`) counting as IPv4:
+///
+/// ```
+/// # use std::net::IpAddr;
+/// # use std::time::Duration;
+/// # use rate_gate::IpLimiter;
+/// let limiter = IpLimiter::new(1, Duration::from_secs(60));
+/// let first: IpAddr = "2001:db8::1".parse().unwrap();
+/// let second: IpAddr = "2001:db8::2".parse().unwrap();
+///
+/// assert!(limiter.check(first).is_allowed());
+/// assert!(!limiter.check(second).is_allowed());
+/// ```
+///
+/// With `with_aggregate`, requests also count against the wider network around the
+/// address, like a /48 handed to a single site, and ranges like corporate NATs get
+/// limits of their own with `limit_range`. Underneath, buckets are tracked by a
+/// `Limiter<IpAddr>` of the network addresses, available through `limiter` for
+/// everything else, like bans or stats.
+#[derive(Debug, Clone)]
+pub struct IpLimiter {
+    limiter: Limiter<IpAddr>,
+    prefixes: Prefixes,
+    aggregate: Option<(Limiter<IpAddr>, Prefixes)>,
+    ranges: Arc<RwLock<CidrRules>>, // Shared with the tier resolver of `limiter`
+}
+
+/// How many leading bits of an address pick its network.
+#[derive(Debug, Clone, Copy)]
+struct Prefixes {
+    v4: u8,
+    v6: u8,
+}
+
+impl Prefixes {
+    fn new(v4: u8, v6: u8) -> Self {
+        Prefixes {
+            v4: v4.min(32),
+            v6: v6.min(128),
+        }
+    }
+
+    /// The network address of `ip`.
+    fn network(self, ip: IpAddr) -> IpAddr {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        match ip {
+            IpAddr::V4(_) => mask(ip, self.v4),
+            IpAddr::V6(_) => mask(ip, self.v6),
+        }
+    }
+}
+
+impl IpLimiter {
+    /// Allows every IPv4 address and every IPv6 /64 `max_limit` requests every
+    /// `refresh_rate`.
+    pub fn new(max_limit: usize, refresh_rate: Duration) -> Self {
+        Self::from_limiter(Limiter::with_default(max_limit, refresh_rate))
+    }
+
+    /// Tracks the networks in `limiter`, e.g. one configured with `LimiterBuilder`.
+    /// Networks it doesn't know get the limit of their range, or else its default
+    /// limit, and are `Decision::Unknown` without either.
+    ///
+    /// The ranges are resolved with the limiter's `TierResolver`, replacing any it had.
+    pub fn from_limiter(limiter: Limiter<IpAddr>) -> Self {
+        let ranges = Arc::new(RwLock::new(CidrRules::default()));
+        let resolver = Arc::clone(&ranges);
+        let limiter = limiter.with_tier_resolver(move |network: &IpAddr| {
+            match resolver.read().lookup(*network)? {
+                (cidr, RangeRule::Limited) => Some(cidr.to_string()),
+                (_, RangeRule::Unlimited) => None,
+            }
+        });
+        IpLimiter {
+            limiter,
+            prefixes: Prefixes::new(32, 64),
+            aggregate: None,
+            ranges,
+        }
+    }
+
+    /// Limits networks of `v4` and `v6` leading bits instead of single IPv4 addresses
+    /// and IPv6 /64s, e.g. `24` and `56`. Prefixes longer than the address are cut
+    /// to its length.
+    pub fn with_prefixes(mut self, v4: u8, v6: u8) -> Self {
+        self.prefixes = Prefixes::new(v4, v6);
+        self
+    }
+
+    /// Also limits the wider networks of `v4` and `v6` leading bits, with a bucket per
+    /// network in `limiter` shared by every address in it:
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use rate_gate::{IpLimiter, Limiter};
+    /// let minute = Duration::from_secs(60);
+    /// // 100 per address or /64, and 1000 per /24 or /48.
+    /// let limiter =
+    ///     IpLimiter::new(100, minute).with_aggregate(24, 48, Limiter::with_default(1000, minute));
+    /// ```
+    ///
+    /// A request is only allowed if both its network and the wider one have room for it.
+    /// Wider networks get the default limit of `limiter`, configure it with the same
+    /// `LimiterBuilder` as the one of `from_limiter` to share its clock, shards or
+    /// idle TTL.
+    pub fn with_aggregate(mut self, v4: u8, v6: u8, limiter: Limiter<IpAddr>) -> Self {
+        self.aggregate = Some((limiter, Prefixes::new(v4, v6)));
+        self
+    }
+
+    /// Limits the networks in `cidr` with `policy` instead of the default limit,
+    /// e.g. a corporate NAT that many users share:
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use rate_gate::{IpLimiter, Policy};
+    /// let limiter = IpLimiter::new(60, Duration::from_secs(60));
+    /// limiter.limit_range("203.0.113.0/24".parse().unwrap(), Policy::parse("6000/min").unwrap());
+    /// limiter.allow_unlimited_range("10.0.0.0/8".parse().unwrap());
+    /// ```
+    ///
+    /// A network gets the rule of the narrowest range containing its address. The
+    /// policy is registered with the limiter under the name of the range, like
+    /// `203.0.113.0/24`. Networks already tracked keep the limits they started with,
+    /// until they are evicted or removed. Ranges narrower than the networks match
+    /// the first address of a network only.
+    pub fn limit_range(&self, cidr: Cidr, policy: Policy) {
+        self.limiter.define_policy(cidr.to_string(), policy);
+        self.ranges.write().insert(cidr, RangeRule::Limited);
+    }
+
+    /// Allows every request of the networks in `cidr`, without consuming anything
+    /// or counting against the wider network, see `limit_range`.
+    pub fn allow_unlimited_range(&self, cidr: Cidr) {
+        self.ranges.write().insert(cidr, RangeRule::Unlimited);
+    }
+
+    /// Removes the rule of `cidr` set with `limit_range` or `allow_unlimited_range`.
+    /// Returns `false` if it had none.
+    pub fn remove_range(&self, cidr: &Cidr) -> bool {
+        self.ranges.write().remove(cidr)
+    }
+
+    /// The narrowest range with a rule that `ip` falls into, if any.
+    pub fn range(&self, ip: IpAddr) -> Option<Cidr> {
+        let (cidr, _) = self.ranges.read().lookup(self.network(ip))?;
+        Some(cidr)
+    }
+
+    /// The network `ip` is limited as, its address with every bit past the prefix cleared.
+    pub fn network(&self, ip: IpAddr) -> IpAddr {
+        self.prefixes.network(ip)
+    }
+
+    /// Consumes a request of `ip`, like `Limiter::check`.
+    pub fn check(&self, ip: IpAddr) -> Decision {
+        self.consume(ip, 1)
+    }
+
+    /// Consumes `cost` requests of `ip` from its network, and from the wider network
+    /// of `with_aggregate` if any. Nothing is consumed unless both allow it.
+    pub fn consume(&self, ip: IpAddr, cost: usize) -> Decision {
+        let network = self.prefixes.network(ip);
+        if let Some((_, RangeRule::Unlimited)) = self.ranges.read().lookup(network) {
+            return Decision::Allowed {
+                remaining: usize::MAX,
+                reset_in: Duration::ZERO,
+            };
+        }
+        let Some((aggregate, prefixes)) = &self.aggregate else {
+            return self.limiter.consume(&network, cost);
+        };
+        // Denied by the wider network, the request is given back and counted as denied.
+        self.limiter.consume_confirmed(&network, cost, |decision| {
+            // Allowed, the tighter of both buckets is reported.
+            match (decision, aggregate.consume(&prefixes.network(ip), cost)) {
+                (
+                    Decision::Allowed { remaining: own, .. },
+                    wider @ Decision::Allowed { remaining, .. },
+                ) if remaining < own => wider,
+                (decision, Decision::Allowed { .. }) => decision,
+                (_, denied) => denied,
+            }
+        })
+    }
+
+    /// The limiter tracking a bucket per network.
+    pub fn limiter(&self) -> &Limiter<IpAddr> {
+        &self.limiter
+    }
+
+    /// The limiter tracking a bucket per wider network, see `with_aggregate`.
+    pub fn aggregate_limiter(&self) -> Option<&Limiter<IpAddr>> {
+        self.aggregate.as_ref().map(|(limiter, _)| limiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LimiterBuilder, ManualClock};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_network() {
+        let limiter = IpLimiter::new(1, Duration::from_secs(60));
+        assert_eq!(limiter.network(ip("10.1.2.3")), ip("10.1.2.3"));
+        assert_eq!(
+            limiter.network(ip("2001:db8:1:2:3::4")),
+            ip("2001:db8:1:2::")
+        );
+        assert_eq!(limiter.network(ip("::ffff:10.1.2.3")), ip("10.1.2.3"));
+
+        let limiter = limiter.with_prefixes(0, 200);
+        assert_eq!(limiter.network(ip("10.1.2.3")), ip("0.0.0.0"));
+        assert_eq!(limiter.network(ip("2001:db8::1")), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let minute = Duration::from_secs(60);
+        let limiter =
+            IpLimiter::new(2, minute).with_aggregate(24, 48, Limiter::with_default(3, minute));
+
+        assert!(limiter.check(ip("10.0.0.1")).is_allowed());
+        assert!(limiter.check(ip("10.0.0.1")).is_allowed());
+        assert!(!limiter.check(ip("10.0.0.1")).is_allowed());
+        assert!(limiter.check(ip("10.0.0.2")).is_allowed());
+
+        // The /24 is used up, the address isn't charged for the denied request.
+        assert!(!limiter.check(ip("10.0.0.3")).is_allowed());
+        assert_eq!(
+            limiter.limiter().get_bucket_remaining(&ip("10.0.0.3")),
+            Some(2)
+        );
+        assert!(limiter.check(ip("10.0.1.1")).is_allowed());
+
+        // Counted once, as denied.
+        let stats = limiter.limiter().global_stats();
+        assert_eq!((stats.allowed, stats.denied), (4, 2));
+        let stats = limiter.limiter().stats(&ip("10.0.0.3")).unwrap();
+        assert_eq!(stats.allowed, 0);
+    }
+
+    #[test]
+    fn test_aggregate_shares_the_clock() {
+        let minute = Duration::from_secs(60);
+        let clock = ManualClock::new();
+        let builder = LimiterBuilder::new().clock(clock.clone());
+        let limiter = IpLimiter::from_limiter(builder.clone().default_limit(5, minute).build())
+            .with_aggregate(24, 48, builder.default_limit(1, minute).build());
+
+        assert!(limiter.check(ip("10.0.0.1")).is_allowed());
+        assert!(!limiter.check(ip("10.0.0.2")).is_allowed());
+        clock.advance(minute);
+        assert!(limiter.check(ip("10.0.0.2")).is_allowed());
+    }
+
+    #[test]
+    fn test_ranges() {
+        let minute = Duration::from_secs(60);
+        let limiter = IpLimiter::from_limiter(Limiter::new());
+        limiter.limit_range("0.0.0.0/0".parse().unwrap(), Policy::new(1, minute));
+        limiter.limit_range("203.0.113.0/24".parse().unwrap(), Policy::new(3, minute));
+        limiter.allow_unlimited_range("10.0.0.0/8".parse().unwrap());
+
+        assert!(limiter.check(ip("198.51.100.1")).is_allowed());
+        assert!(!limiter.check(ip("198.51.100.1")).is_allowed());
+        for _ in 0..3 {
+            assert!(limiter.check(ip("203.0.113.7")).is_allowed());
+        }
+        assert!(!limiter.check(ip("203.0.113.7")).is_allowed());
+        for _ in 0..10 {
+            assert!(limiter.check(ip("10.1.2.3")).is_allowed());
+        }
+        assert_eq!(limiter.check(ip("2001:db8::1")), Decision::Unknown);
+        assert_eq!(
+            limiter.range(ip("203.0.113.7")),
+            Some("203.0.113.0/24".parse().unwrap())
+        );
+
+        assert!(limiter.remove_range(&"10.0.0.0/8".parse().unwrap()));
+        assert!(limiter.check(ip("10.1.2.3")).is_allowed());
+        assert!(!limiter.check(ip("10.1.2.3")).is_allowed());
+    }
+}
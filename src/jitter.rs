@@ -0,0 +1,103 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::entity::nanos;
+use crate::snapshot::before;
+
+/// Random lengths for the fixed windows of an entity, see `Policy::with_jitter`.
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct Jitter {
+    percent: u8, // How far windows stray from `refresh_rate`, in percent of it
+    state: u64,  // SplitMix64, seeded at random unless a seed was given
+}
+
+impl Jitter {
+    /// Jitter for the windows of `entity`. A `seed` is mixed with the bytes the entity
+    /// hashes to by SplitMix64 itself, so every entity draws windows of its own and
+    /// draws the same ones on every run, as long as it hashes the same bytes.
+    pub(crate) fn new<K: Hash + ?Sized>(percent: u8, seed: Option<u64>, entity: &K) -> Self {
+        let mut jitter = Jitter {
+            percent: percent.min(100),
+            state: seed.unwrap_or_else(|| RandomState::new().build_hasher().finish()),
+        };
+        if seed.is_some() {
+            entity.hash(&mut jitter);
+        }
+        jitter
+    }
+
+    /// When a window starting at `now` should be treated as started, so it ends
+    /// anywhere within `percent` of `refresh_rate` around `now + refresh_rate`.
+    /// Past `now` for longer windows, before it for shorter ones.
+    pub(crate) fn start(&mut self, now: Instant, refresh_rate: Duration) -> Instant {
+        let spread = refresh_rate.as_nanos() * self.percent as u128 / 100;
+        if spread == 0 {
+            return now;
+        }
+        let offset = self.next() as u128 % (2 * spread + 1);
+        match offset.checked_sub(spread) {
+            Some(later) => now + nanos(later),
+            None => before(now, nanos(spread - offset)),
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Mixes the bytes of an entity into the state, see `Jitter::new`.
+impl Hasher for Jitter {
+    fn finish(&self) -> u64 {
+        self.state
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.state ^= u64::from_le_bytes(word);
+            self.state = self.next();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_windows_stay_within_the_spread() {
+        let now = Instant::now() + Duration::from_secs(60);
+        let rate = Duration::from_secs(10);
+        let mut jitter = Jitter::new(10, Some(7), "user1");
+        let starts: Vec<_> = (0..100).map(|_| jitter.start(now, rate)).collect();
+        for &start in &starts {
+            assert!(start >= now - Duration::from_secs(1));
+            assert!(start <= now + Duration::from_secs(1));
+        }
+        assert!(starts.iter().any(|&start| start < now));
+        assert!(starts.iter().any(|&start| start > now));
+
+        // The same seed draws the same windows for the same entity.
+        let mut again = Jitter::new(10, Some(7), "user1");
+        assert_eq!(again.start(now, rate), starts[0]);
+        let mut other = Jitter::new(10, Some(7), "user2");
+        assert_ne!(other.start(now, rate), starts[0]);
+        assert_eq!(Jitter::new(0, None, "user1").start(now, rate), now);
+    }
+
+    #[test]
+    fn test_seeded_state_is_stable() {
+        // Pinned, a seeded limiter must draw the same windows with every Rust release.
+        assert_eq!(
+            Jitter::new(10, Some(7), "user1").state,
+            16_609_221_799_637_404_855
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// A key of several parts, like `(tenant, user, route)`, for limiting along more
+/// than one dimension.
+///
+/// Limiters of `Key`s are looked up by anything implementing `KeyParts`, like tuples
+/// or arrays of `&str`, without allocating a key on every request. Only entities
+/// seen for the first time are copied into a `Key`:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Key, KeyParts, Limiter};
+/// let limiter: Limiter<Key> = Limiter::with_default(10, Duration::from_secs(60));
+///
+/// let (tenant, user) = ("acme", String::from("alice"));
+/// assert!(limiter.check((tenant, &user, "/search").as_key()).is_allowed());
+///
+/// let key = Key::new().part("acme").part("alice").part("/search");
+/// assert_eq!(limiter.get_bucket_remaining(&key), Some(9));
+/// ```
+///
+/// Keys only equal keys with the same parts in the same order, `("a", "bc")` and
+/// `("ab", "c")` are different keys.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct Key {
+    parts: Vec<Box<str>>,
+}
+
+impl Key {
+    /// A key without any parts, add them with `part`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `part` to the key.
+    pub fn part(mut self, part: impl Into<Box<str>>) -> Self {
+        self.parts.push(part.into());
+        self
+    }
+
+    /// The parts of the key in order.
+    pub fn parts(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().map(|part| &**part)
+    }
+}
+
+impl<P> FromIterator<P> for Key
+where
+    P: Into<Box<str>>,
+{
+    fn from_iter<I: IntoIterator<Item = P>>(parts: I) -> Self {
+        Key {
+            parts: parts.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Key").field(&self.parts).finish()
+    }
+}
+
+/// Joins the parts with `:`, like `acme:alice:/search`.
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, part) in self.parts().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            f.write_str(part)?;
+        }
+        Ok(())
+    }
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Hashed like its borrowed form, or lookups would miss.
+        (self as &dyn KeyParts).hash(state);
+    }
+}
+
+/// The parts of a `Key`, borrowed from wherever they are, see `as_key`.
+///
+/// Implemented for `Key`, and tuples of up to four and arrays of anything that is
+/// `AsRef<str>`, like `&str` or `String`.
+pub trait KeyParts {
+    /// How many parts the key has.
+    fn len(&self) -> usize;
+
+    /// The part at `index`, below `len`.
+    fn part(&self, index: usize) -> &str;
+
+    /// Returns `true` if the key has no parts.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The parts as the borrowed form of a `Key`, to look them up in a `Limiter<Key>`.
+    fn as_key(&self) -> &dyn KeyParts
+    where
+        Self: Sized,
+    {
+        self
+    }
+}
+
+impl KeyParts for Key {
+    fn len(&self) -> usize {
+        self.parts.len()
+    }
+
+    fn part(&self, index: usize) -> &str {
+        &self.parts[index]
+    }
+}
+
+impl<P, const N: usize> KeyParts for [P; N]
+where
+    P: AsRef<str>,
+{
+    fn len(&self) -> usize {
+        N
+    }
+
+    fn part(&self, index: usize) -> &str {
+        self[index].as_ref()
+    }
+}
+
+macro_rules! tuple_key_parts {
+    ($len:literal => $($part:ident $index:tt),+) => {
+        impl<$($part),+> KeyParts for ($($part,)+)
+        where
+            $($part: AsRef<str>),+
+        {
+            fn len(&self) -> usize {
+                $len
+            }
+
+            fn part(&self, index: usize) -> &str {
+                match index {
+                    $($index => self.$index.as_ref(),)+
+                    _ => panic!("part {} of a key of {} parts", index, $len),
+                }
+            }
+        }
+    };
+}
+
+tuple_key_parts!(1 => A 0);
+tuple_key_parts!(2 => A 0, B 1);
+tuple_key_parts!(3 => A 0, B 1, C 2);
+tuple_key_parts!(4 => A 0, B 1, C 2, D 3);
+
+impl Hash for dyn KeyParts + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        for index in 0..self.len() {
+            self.part(index).hash(state);
+        }
+    }
+}
+
+impl PartialEq for dyn KeyParts + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && (0..self.len()).all(|index| self.part(index) == other.part(index))
+    }
+}
+
+impl Eq for dyn KeyParts + '_ {}
+
+impl<'a> Borrow<dyn KeyParts + 'a> for Key {
+    fn borrow(&self) -> &(dyn KeyParts + 'a) {
+        self
+    }
+}
+
+impl ToOwned for dyn KeyParts + '_ {
+    type Owned = Key;
+
+    fn to_owned(&self) -> Key {
+        (0..self.len()).map(|index| self.part(index)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::BuildHasher;
+
+    #[test]
+    fn test_borrowed_parts_match_keys() {
+        let key = Key::new().part("acme").part("alice");
+        let state = std::collections::hash_map::RandomState::new();
+        let borrowed = ("acme", String::from("alice"));
+
+        let borrowed_key: &dyn KeyParts = key.borrow();
+        assert!(borrowed_key == borrowed.as_key());
+        assert_eq!(
+            state.hash_one(&key),
+            state.hash_one(["acme", "alice"].as_key())
+        );
+        assert_eq!(borrowed.as_key().to_owned(), key);
+        assert!(("ab", "c").as_key() != ("a", "bc").as_key());
+        assert_eq!(key.to_string(), "acme:alice");
+    }
+}
@@ -0,0 +1,242 @@
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use http::{Request, Response};
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::extract::KeyExtractor;
+use crate::respond::{DeniedResponder, TooManyRequests};
+use crate::{Decision, Limiter};
+
+/// Rate limits the requests to a `tower` service, answering with
+/// `429 Too Many Requests` and a `Retry-After` header once the key of a request is
+/// out of requests.
+///
+/// `key` extracts what to limit a request by, e.g. an API key header, see `KeyExtractor`.
+/// Requests it finds no key for, and keys the limiter doesn't know, are let through.
+/// Use `Limiter::with_default` to limit every key without adding them first, and
+/// `responder` to answer with something other than an empty 429.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, RateLimitLayer};
+/// let limiter: Limiter<String> = Limiter::with_default(100, Duration::from_secs(60));
+/// let layer = RateLimitLayer::new(limiter, |request: &http::Request<()>| {
+///     let key = request.headers().get("x-api-key")?;
+///     key.to_str().ok().map(str::to_string)
+/// });
+/// ```
+pub struct RateLimitLayer<T, F, R = TooManyRequests>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    limiter: Limiter<T>,
+    key: F,
+    responder: R,
+}
+
+impl<T, F> RateLimitLayer<T, F>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    pub fn new(limiter: Limiter<T>, key: F) -> Self {
+        RateLimitLayer {
+            limiter,
+            key,
+            responder: TooManyRequests,
+        }
+    }
+}
+
+impl<T, F, R> RateLimitLayer<T, F, R>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    /// Sets how denied requests are answered, see `DeniedResponder`.
+    pub fn responder<R2>(self, responder: R2) -> RateLimitLayer<T, F, R2> {
+        RateLimitLayer {
+            limiter: self.limiter,
+            key: self.key,
+            responder,
+        }
+    }
+}
+
+impl<T, F, R> Clone for RateLimitLayer<T, F, R>
+where
+    T: Hash + Eq + Send + 'static,
+    F: Clone,
+    R: Clone,
+{
+    fn clone(&self) -> Self {
+        RateLimitLayer {
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+            responder: self.responder.clone(),
+        }
+    }
+}
+
+impl<S, T, F, R> Layer<S> for RateLimitLayer<T, F, R>
+where
+    T: Hash + Eq + Send + 'static,
+    F: Clone,
+    R: Clone,
+{
+    type Service = RateLimitService<S, T, F, R>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+            responder: self.responder.clone(),
+        }
+    }
+}
+
+/// A service rate limited by a `RateLimitLayer`.
+pub struct RateLimitService<S, T, F, R = TooManyRequests>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    inner: S,
+    limiter: Limiter<T>,
+    key: F,
+    responder: R,
+}
+
+impl<S, T, F, R> Clone for RateLimitService<S, T, F, R>
+where
+    S: Clone,
+    T: Hash + Eq + Send + 'static,
+    F: Clone,
+    R: Clone,
+{
+    fn clone(&self) -> Self {
+        RateLimitService {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+            responder: self.responder.clone(),
+        }
+    }
+}
+
+impl<S, T, F, R, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimitService<S, T, F, R>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    T: Hash + Eq + Send + Clone + 'static,
+    F: KeyExtractor<ReqBody, Key = T>,
+    R: DeniedResponder<ResBody>,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future, ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let decision = match self.key.extract(&request) {
+            Some(key) => self.limiter.check(&key),
+            None => Decision::Unknown,
+        };
+        match decision {
+            Decision::Denied { .. } | Decision::Banned { .. } => ResponseFuture::Denied {
+                response: Some(self.responder.respond(&decision)),
+            },
+            Decision::Allowed { .. } | Decision::Unknown => ResponseFuture::Allowed {
+                future: self.inner.call(request),
+            },
+        }
+    }
+}
+
+pin_project! {
+    /// The response of a `RateLimitService`.
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F, B> {
+        Allowed {
+            #[pin]
+            future: F,
+        },
+        Denied {
+            response: Option<Response<B>>,
+        },
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F, B>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<B>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Allowed { future } => future.poll(cx),
+            ResponseFutureProj::Denied { response } => {
+                Poll::Ready(Ok(response.take().expect("polled after completion")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::tower::{service_fn, ServiceExt};
+    use http::{header, StatusCode};
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_denied_requests_get_429() {
+        let limiter: Limiter<String> = Limiter::new();
+        limiter.add_limited_entity("key1".to_string(), 1, Duration::from_secs(60));
+        let layer = RateLimitLayer::new(limiter, |request: &Request<String>| {
+            let key = request.headers().get("x-api-key")?;
+            key.to_str().ok().map(str::to_string)
+        });
+        let service = layer.layer(service_fn(|_: Request<String>| async {
+            Ok::<_, Infallible>(Response::new("hello".to_string()))
+        }));
+        let request = |key: &str| {
+            Request::builder()
+                .header("x-api-key", key)
+                .body(String::new())
+                .unwrap()
+        };
+
+        let response = service.clone().oneshot(request("key1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "hello");
+
+        let response = service.clone().oneshot(request("key1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+
+        // Unknown keys are let through.
+        let response = service.oneshot(request("key2")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_responder() {
+        let limiter = Limiter::with_default(0, Duration::from_secs(60));
+        let layer = RateLimitLayer::new(limiter, |_: &Request<String>| Some(()))
+            .responder(|_: &Decision| Response::new("slow down".to_string()));
+        let service = layer.layer(service_fn(|_: Request<String>| async {
+            Ok::<_, Infallible>(Response::new("hello".to_string()))
+        }));
+
+        let response = service.oneshot(Request::new(String::new())).await.unwrap();
+        assert_eq!(response.body(), "slow down");
+    }
+}
@@ -1,23 +1,253 @@
-use std::hash::Hash;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+mod access;
+mod action;
+#[cfg(feature = "tokio")]
+mod actor;
+mod adaptive;
+mod algorithm;
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(feature = "axum")]
+pub mod axum;
+mod builder;
+mod cidr;
+mod clock;
+mod concurrency;
+#[cfg(feature = "config")]
+mod config;
+mod entity;
+mod entry;
+#[cfg(feature = "envoy")]
+pub mod envoy;
+mod error;
+mod escalation;
+#[cfg(feature = "http")]
+pub mod extract;
+mod headers;
+mod hooks;
+mod ip;
+mod jitter;
+mod key;
+#[cfg(feature = "tower")]
+mod layer;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod mode;
+mod namespace;
+mod pattern;
+mod permit;
+mod policy;
+mod prehashed;
+#[cfg(feature = "tokio")]
+mod queue;
+mod quota;
+#[cfg(feature = "reqwest")]
+pub mod reqwest;
+mod reservation;
+#[cfg(feature = "http")]
+mod respond;
+#[cfg(feature = "cron")]
+mod schedule;
+mod shards;
+mod snapshot;
+mod stats;
+mod store;
+mod sync;
+mod throttle;
+mod tier;
+mod time_of_day;
+#[cfg(feature = "tonic")]
+pub mod tonic;
+#[cfg(feature = "tracing")]
+mod trace;
+mod warm_up;
+#[cfg(feature = "warp")]
+pub mod warp;
+
+pub use action::ActionLimiter;
+#[cfg(feature = "tokio")]
+pub use actor::LimiterHandle;
+pub use adaptive::Aimd;
+pub use algorithm::Algorithm;
+#[cfg(feature = "audit")]
+pub use audit::{Audit, AuditRecord, AuditSink, FileSink, ParseAuditRecordError};
+pub use builder::LimiterBuilder;
+pub use cidr::{Cidr, ParseCidrError};
+#[cfg(feature = "tokio")]
+pub use clock::TokioClock;
+pub use clock::{Clock, CoarseClock, ManualClock, SystemClock};
+pub use concurrency::InFlightGuard;
+#[cfg(feature = "watch")]
+pub use config::ConfigWatcher;
+#[cfg(feature = "config")]
+pub use config::{Config, ConfigError};
+pub use entity::{AssociatedEntity, EntityState, EntityStats};
+pub use error::{Allowance, RateGateError};
+pub use escalation::Escalation;
+pub use headers::{RateLimitHeaders, RetryAfter};
+pub use hooks::{DenialInfo, Hooks};
+pub use ip::IpLimiter;
+pub use key::{Key, KeyParts};
+#[cfg(feature = "tower")]
+pub use layer::{RateLimitLayer, RateLimitService, ResponseFuture};
+#[cfg(feature = "prometheus")]
+pub use metrics::PrometheusCollector;
+pub use mode::Mode;
+pub use namespace::{Namespace, NamespacedLimiter};
+pub use pattern::{KeyPattern, PatternRules};
+pub use permit::Permit;
+pub use policy::{ParsePolicyError, Policy};
+pub use prehashed::{KeyInterner, PrehashedHasher, PrehashedKey, PrehashedState};
+pub use quota::{Period, Quota, QuotaLimiter};
+pub use reservation::Reservation;
+#[cfg(feature = "http")]
+pub use respond::{DeniedResponder, ProblemJson, TooManyRequests};
+#[cfg(feature = "cron")]
+pub use schedule::ResetSchedule;
+pub use shards::Storage;
+pub use snapshot::{EntitySnapshot, LimiterSnapshot, MergeStrategy};
+pub use stats::GlobalStats;
+#[cfg(feature = "sqlx")]
+pub use store::PostgresStore;
+#[cfg(feature = "redis")]
+pub use store::RedisStore;
+#[cfg(feature = "sled")]
+pub use store::SledStore;
+pub use store::{AsyncStore, Store, StoreLimiter};
+#[cfg(feature = "tokio")]
+pub use throttle::Throttled;
+pub use throttle::{RateLimitIteratorExt, RateLimitedIter};
+#[cfg(feature = "stream")]
+pub use throttle::{RateLimitStreamExt, RateLimitedStream};
+pub use tier::TierResolver;
+pub use time_of_day::TimeOfDayLimit;
+
+use access::{Access, AccessList};
+use concurrency::InFlight;
+use entity::Parent;
+use entry::Entry;
+use hashbrown::hash_map::DefaultHashBuilder;
 use hashbrown::HashMap;
+use mode::ModeSwitch;
+#[cfg(feature = "tokio")]
+use queue::WaitQueues;
+use shards::Shards;
+use stats::Counters;
+use sync::{Mutex, RwLock};
+use tier::Tiers;
+
+#[derive(Debug)]
+pub struct Limiter<T, S = DefaultHashBuilder>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    requests: Arc<Shards<T, S>>,
+    default: Option<(usize, Duration)>, // (max_limit, refresh_rate) for entities that were never added
+    idle_ttl: Option<Duration>,         // Entities idle for longer get removed by evict_idle
+    escalation: Option<Escalation>,     // Penalties for entities that keep getting denied
+    access: Arc<AccessList<T>>,         // Bans and unlimited entities, checked before the buckets
+    mode: Arc<ModeSwitch>, // Fails open or closed for everyone, checked before all else
+    policies: Arc<RwLock<HashMap<String, Policy>>>, // Named policies, see define_policy
+    tiers: Option<Tiers<T>>, // Policies of entities that were never added, see with_tier_resolver
+    clock: Arc<dyn Clock>, // Where all time is read from, see LimiterBuilder::clock
+    hooks: Arc<Hooks<T>>,
+    #[cfg(feature = "audit")]
+    audit: Option<audit::Audited<T>>, // Where decisions are recorded, see with_audit
+    counters: Arc<Counters>,
+    #[cfg(feature = "tokio")]
+    waiters: Arc<WaitQueues<T>>, // Tasks waiting in acquire, served in order
+    #[cfg(feature = "tracing")]
+    debug: Option<trace::DebugFn<T>>, // Prints entities in events, see trace_entities
+}
+
+// Not derived, clones share the entities so `T` doesn't need to be `Clone` for the limiter
+// to be. Checking an entity by `&T` does need it, see `Limiter::check`.
+impl<T, S> Clone for Limiter<T, S>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        Limiter {
+            requests: self.requests.clone(),
+            default: self.default,
+            idle_ttl: self.idle_ttl,
+            escalation: self.escalation,
+            access: self.access.clone(),
+            mode: self.mode.clone(),
+            policies: self.policies.clone(),
+            tiers: self.tiers.clone(),
+            clock: self.clock.clone(),
+            hooks: self.hooks.clone(),
+            #[cfg(feature = "audit")]
+            audit: self.audit.clone(),
+            counters: self.counters.clone(),
+            #[cfg(feature = "tokio")]
+            waiters: self.waiters.clone(),
+            #[cfg(feature = "tracing")]
+            debug: self.debug,
+        }
+    }
+}
 
-#[derive(Default, Debug, Clone)]
-pub struct Limiter<T>
+impl<T> Default for Limiter<T>
 where
     T: Hash + Eq + Send + 'static,
 {
-    requests: Arc<Mutex<HashMap<T, AssociatedEntity>>>,
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-#[derive(Debug, Clone, Hash)]
-pub struct AssociatedEntity {
-    bucket: usize, // How many requests are left in the bucket, 0 means the hard limit.
-    bucket_init: Instant, // When was the last bucket refreshed
-    bucket_max: usize, // set by user, this is the value the bucket will get refilled with.
-    refresh_rate: Duration, // Every refresh_rate tick bucket gets filled with bucket_max
+/// The outcome of checking an entity against the limiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Decision {
+    /// The request was allowed and consumed a token.
+    Allowed {
+        /// How many requests are left in the bucket.
+        remaining: usize,
+        /// Time until the bucket gets refilled.
+        reset_in: Duration,
+    },
+    /// The entity is rate limited, the request was denied.
+    Denied {
+        /// Time until the bucket gets refilled and requests are allowed again,
+        /// `Duration::MAX` if never, as more was asked for than the bucket holds.
+        retry_after: Duration,
+    },
+    /// The entity was not found by the limiter, create one with `add_limited_entity`.
+    /// Never returned by limiters created with `Limiter::with_default`.
+    Unknown,
+    /// The entity is banned with `Limiter::ban` or disabled with `Limiter::disable`, or
+    /// the limiter denies everything with `Mode::DenyAll`. The request was denied no
+    /// matter what is left in its bucket.
+    Banned {
+        /// Time until the ban expires, `Duration::MAX` if it has no end, as for disabled
+        /// entities and `Mode::DenyAll`.
+        retry_after: Duration,
+    },
+}
+
+impl Decision {
+    /// Returns `true` if the request was allowed.
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Decision::Allowed { .. })
+    }
+
+    /// Returns how long to wait before retrying a denied or banned request,
+    /// `None` if the request wasn't denied. See `RetryAfter` for the header.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Decision::Denied { retry_after } | Decision::Banned { retry_after } => {
+                Some(*retry_after)
+            }
+            Decision::Allowed { .. } | Decision::Unknown => None,
+        }
+    }
 }
 
 impl<T> Limiter<T>
@@ -25,9 +255,130 @@ where
     T: Hash + Eq + Send + 'static,
 {
     pub fn new() -> Self {
-        Limiter {
-            requests: Arc::new(Mutex::new(HashMap::new())),
+        LimiterBuilder::new().build()
+    }
+
+    /// Creates a limiter that limits every entity, even ones never added with `add_limited_entity`.
+    ///
+    /// Unknown entities get added with `max_limit` and `refresh_rate` on their first request,
+    /// so checks never return `None`/`Decision::Unknown`. Handy when the entities are
+    /// arbitrary client IPs that can't be registered up front.
+    pub fn with_default(max_limit: usize, refresh_rate: Duration) -> Self {
+        LimiterBuilder::new()
+            .default_limit(max_limit, refresh_rate)
+            .build()
+    }
+
+    /// Creates a limiter that tracks at most about `max_entities` entities, the least
+    /// recently used entity gets removed to make room for a new one.
+    ///
+    /// Protects against memory growing with key cardinality, e.g. spoofed identifiers.
+    /// Entities are capped per shard, so the real cap is `max_entities` rounded up
+    /// to a multiple of the shard count. The evicted entity is picked among those of its
+    /// shard in constant time, approximating the least recently used one: the entity
+    /// tracked the longest, unless it made a request since it was last considered.
+    pub fn with_max_entities(max_entities: usize) -> Self {
+        LimiterBuilder::new().max_entities(max_entities).build()
+    }
+}
+
+impl<T, S> Limiter<T, S>
+where
+    T: Hash + Eq + Send + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    /// Creates a limiter hashing entities with `hasher`, with room for `capacity`
+    /// entities allocated up front:
+    ///
+    /// ```
+    /// # use std::hash::BuildHasherDefault;
+    /// # use std::collections::hash_map::DefaultHasher;
+    /// # use rate_gate::Limiter;
+    /// let hasher = BuildHasherDefault::<DefaultHasher>::default();
+    /// let limiter: Limiter<u64, _> = Limiter::with_hasher_and_capacity(hasher, 10_000);
+    /// ```
+    ///
+    /// The default hasher resists collisions crafted by clients. A faster one, like
+    /// FxHash or aHash with a fixed seed, is only safe for entities the clients don't
+    /// choose, e.g. internal service names. See `LimiterBuilder::build_with_hasher`
+    /// to configure the rest as well.
+    pub fn with_hasher_and_capacity(hasher: S, capacity: usize) -> Self {
+        LimiterBuilder::new()
+            .capacity(capacity)
+            .build_with_hasher(hasher)
+    }
+
+    /// Makes room for at least `additional` more entities, so adding them doesn't grow
+    /// the maps on the way. The room is spread evenly over the shards.
+    pub fn reserve_capacity(&self, additional: usize) {
+        self.requests.reserve(additional);
+    }
+
+    /// Frees the memory that isn't needed for the entities tracked right now, e.g. after
+    /// `evict_idle` removed most of them.
+    pub fn shrink_to_fit(&self) {
+        self.requests.shrink_to_fit();
+    }
+
+    /// Sets callbacks for notable events like denied requests, see `Hooks`.
+    ///
+    /// Clones share the hooks of the limiter they were cloned from, so set them
+    /// before cloning or spawning the cleanup task.
+    pub fn with_hooks(mut self, hooks: Hooks<T>) -> Self {
+        self.hooks = Arc::new(hooks);
+        self
+    }
+
+    /// Records the decisions of the limiter in an audit log, see `Audit`. Entities
+    /// are recorded with their `Display` implementation.
+    ///
+    /// Clones share the audit log of the limiter they were cloned from, so set it
+    /// before cloning or spawning the cleanup task.
+    #[cfg(feature = "audit")]
+    pub fn with_audit(mut self, audit: Audit) -> Self
+    where
+        T: std::fmt::Display,
+    {
+        self.audit = Some(audit::Audited {
+            audit: Arc::new(audit),
+            key: audit::to_key,
+        });
+        self
+    }
+
+    /// Adds entities that were never added with the policy of their tier, as found
+    /// by `resolver`, on their first request. See `TierResolver`.
+    ///
+    /// Reading an entity that was never added, e.g. with `get_bucket_remaining`,
+    /// doesn't consult the resolver and reads the default limit.
+    pub fn with_tier_resolver(mut self, resolver: impl TierResolver<T> + 'static) -> Self {
+        self.tiers = Some(Tiers(Arc::new(resolver)));
+        self
+    }
+
+    /// Adds entities that were never added with the policy of the first rule of `rules`
+    /// matching them, see `PatternRules`. Replaces any `TierResolver`.
+    ///
+    /// The policies are registered with `define_policy` under their pattern, like `bot-*`.
+    pub fn with_pattern_rules(self, rules: PatternRules) -> Self
+    where
+        T: AsRef<str>,
+    {
+        for (pattern, policy) in rules.iter() {
+            self.define_policy(pattern.to_string(), policy.clone());
         }
+        self.with_tier_resolver(rules)
+    }
+
+    /// Prints entities with their `Debug` implementation in the events emitted
+    /// with the `tracing` feature, instead of as `_`.
+    #[cfg(feature = "tracing")]
+    pub fn trace_entities(mut self) -> Self
+    where
+        T: std::fmt::Debug,
+    {
+        self.debug = Some(trace::as_debug);
+        self
     }
 
     /// Adds a entity to the limiter
@@ -37,16 +388,392 @@ where
     ///
     /// `refresh_rate` is the timeframe after which the entity gets a renewed limit
     pub fn add_limited_entity(&self, entity: T, max_limit: usize, refresh_rate: Duration) {
-        let mut requests = self.requests.lock().unwrap();
-        requests.insert(
+        self.add_limited_entity_with_algorithm(
             entity,
-            AssociatedEntity {
-                bucket: max_limit,
-                bucket_init: Instant::now(),
-                bucket_max: max_limit,
-                refresh_rate,
-            },
+            max_limit,
+            refresh_rate,
+            Algorithm::FixedWindow,
+        );
+    }
+
+    /// Adds a entity to the limiter, like `add_limited_entity`,
+    /// but with the `algorithm` used to refill its bucket.
+    pub fn add_limited_entity_with_algorithm(
+        &self,
+        entity: T,
+        max_limit: usize,
+        refresh_rate: Duration,
+        algorithm: Algorithm,
+    ) {
+        let now = self.now();
+        let entry = Entry::new(
+            AssociatedEntity::new(max_limit, refresh_rate, algorithm, now),
+            now,
         );
+        #[cfg(feature = "tracing")]
+        trace::inserted(self.debug, &entity, max_limit);
+        let mut requests = self.requests.write(&entity);
+        let evicted = self.requests.insert(&mut requests, entity, entry);
+        drop(requests);
+        self.evicted(evicted);
+    }
+
+    /// Adds `child` to the limiter like `add_limited_entity`, counting its requests
+    /// against the bucket of `parent` as well, e.g. an API key that is limited on its
+    /// own and also shares the quota of its organization. A request of the child is
+    /// only allowed if both buckets have room for it, and then counts against both.
+    ///
+    /// Parents can have any number of children, and parents of their own.
+    /// Children keep sharing the bucket of their parent even once the parent
+    /// is removed from the limiter, snapshots don't keep track of parents.
+    /// Returns `false` without adding the child if `parent` was not found by the limiter.
+    pub fn add_child_entity<Q>(
+        &self,
+        parent: &Q,
+        child: T,
+        max_limit: usize,
+        refresh_rate: Duration,
+    ) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(shared) = self.requests.read(parent).get(parent).map(Entry::share) else {
+            return false;
+        };
+        let now = self.now();
+        let mut entity =
+            AssociatedEntity::new(max_limit, refresh_rate, Algorithm::FixedWindow, now);
+        entity.parent = Some(Parent(shared));
+        #[cfg(feature = "tracing")]
+        trace::inserted(self.debug, &child, max_limit);
+        let mut requests = self.requests.write(&child);
+        let evicted = self
+            .requests
+            .insert(&mut requests, child, Entry::new(entity, now));
+        drop(requests);
+        self.evicted(evicted);
+        true
+    }
+
+    /// Adds every entity of `entities` to the limiter with one bucket they all share,
+    /// e.g. all sessions of a user or all addresses of a subnet. A request of any of
+    /// them counts against the shared `max_limit`.
+    ///
+    /// Entities of the group are otherwise tracked on their own: they have their own
+    /// stats and can be evicted, banned or removed without affecting the others.
+    /// Adding an entity of the group again takes it out of the group.
+    pub fn add_group(
+        &self,
+        entities: impl IntoIterator<Item = T>,
+        max_limit: usize,
+        refresh_rate: Duration,
+    ) {
+        let now = self.now();
+        let shared = Arc::new(Mutex::new(AssociatedEntity::new(
+            max_limit,
+            refresh_rate,
+            Algorithm::FixedWindow,
+            now,
+        )));
+        for entity in entities {
+            #[cfg(feature = "tracing")]
+            trace::inserted(self.debug, &entity, max_limit);
+            let entry = Entry::shared(Arc::clone(&shared), now);
+            let mut requests = self.requests.write(&entity);
+            let evicted = self.requests.insert(&mut requests, entity, entry);
+            drop(requests);
+            self.evicted(evicted);
+        }
+    }
+
+    /// Adds another limit to `entity` on top of the one it was added with, e.g. 10 per
+    /// second and also 1000 per day. A request is only allowed if every limit has room
+    /// for it, and then counts against all of them.
+    ///
+    /// The window uses the entity's algorithm. `update_limit` and escalated penalties
+    /// only change the limit the entity was added with.
+    /// Returns `false` if the entity was not found by the limiter.
+    pub fn add_window<Q>(&self, entity: &Q, max_limit: usize, refresh_rate: Duration) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| {
+            entry.add_window(now, max_limit, refresh_rate)
+        })
+        .is_some()
+    }
+
+    /// Adds a entity to the limiter, like `add_limited_entity`, with the limits of `policy`.
+    pub fn add_limited_entity_with_policy(&self, entity: T, policy: Policy) {
+        let now = self.now();
+        let state = AssociatedEntity::from_policy(&policy, &entity, now, self.clock.system_time());
+        #[cfg(feature = "tracing")]
+        trace::inserted(self.debug, &entity, policy.max_limit);
+        let mut requests = self.requests.write(&entity);
+        let evicted = self
+            .requests
+            .insert(&mut requests, entity, Entry::new(state, now));
+        drop(requests);
+        self.evicted(evicted);
+    }
+
+    /// Adds every entity of `entities` with the limits of its policy, like
+    /// `add_limited_entity_with_policy`.
+    ///
+    /// Every shard is locked once for all of its entities, with room reserved for them
+    /// up front, which makes loading e.g. all API keys from a database at startup much
+    /// cheaper than adding them one by one.
+    pub fn add_limited_entities(&self, entities: impl IntoIterator<Item = (T, Policy)>) {
+        let now = self.now();
+        let system_time = self.clock.system_time();
+        let mut by_shard: Vec<Vec<(T, Entry)>> =
+            (0..self.requests.count()).map(|_| Vec::new()).collect();
+        for (entity, policy) in entities {
+            #[cfg(feature = "tracing")]
+            trace::inserted(self.debug, &entity, policy.max_limit);
+            let state = AssociatedEntity::from_policy(&policy, &entity, now, system_time);
+            let entry = Entry::new(state, now);
+            by_shard[self.requests.index(&entity)].push((entity, entry));
+        }
+
+        for (index, entities) in by_shard.into_iter().enumerate() {
+            if entities.is_empty() {
+                continue;
+            }
+            let mut requests = self.requests.write_shard(index);
+            requests.reserve(entities.len());
+            let evicted: Vec<_> = entities
+                .into_iter()
+                .filter_map(|(entity, entry)| self.requests.insert(&mut requests, entity, entry))
+                .collect();
+            drop(requests);
+            for evicted in evicted {
+                self.evicted(Some(evicted));
+            }
+        }
+    }
+
+    /// Adds a entity to the limiter with the policy registered as `name`,
+    /// see `define_policy`. Returns `false` without adding the entity if there is none.
+    ///
+    /// Entities added this way follow changes to the policy made by `apply_config`.
+    pub fn add_limited_entity_with_named_policy(&self, entity: T, name: &str) -> bool {
+        let Some(policy) = self.policy(name) else {
+            return false;
+        };
+        let now = self.now();
+        let mut state =
+            AssociatedEntity::from_policy(&policy, &entity, now, self.clock.system_time());
+        state.policy = Some(name.into());
+        #[cfg(feature = "tracing")]
+        trace::inserted(self.debug, &entity, policy.max_limit);
+        let mut requests = self.requests.write(&entity);
+        let evicted = self
+            .requests
+            .insert(&mut requests, entity, Entry::new(state, now));
+        drop(requests);
+        self.evicted(evicted);
+        true
+    }
+
+    /// Registers `policy` as `name`, replacing any policy registered under it before.
+    ///
+    /// Entities added with the previous policy keep their limits.
+    pub fn define_policy(&self, name: impl Into<String>, policy: Policy) {
+        self.policies.write().insert(name.into(), policy);
+    }
+
+    /// Returns the policy registered as `name`, if any.
+    pub fn policy(&self, name: &str) -> Option<Policy> {
+        self.policies.read().get(name).cloned()
+    }
+
+    /// Changes the limit of an existing entity without resetting its bucket.
+    ///
+    /// Unlike adding the entity again, requests it already used stay used,
+    /// so a plan change can't be abused to reset a limit. An escalated penalty is
+    /// replaced by the new limit. Returns `false` if the entity was not found by the limiter.
+    pub fn update_limit<Q>(&self, entity: &Q, max_limit: usize, refresh_rate: Duration) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| {
+            entry.violations = None;
+            entry.update_limit(now, max_limit, refresh_rate, false)
+        })
+        .is_some()
+    }
+
+    /// Changes the limit of an existing entity like `update_limit`, but scales what it
+    /// already used to the new limit, e.g. having used half of the old limit means
+    /// having used half of the new one.
+    pub fn update_limit_scaled<Q>(
+        &self,
+        entity: &Q,
+        max_limit: usize,
+        refresh_rate: Duration,
+    ) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| {
+            entry.violations = None;
+            entry.update_limit(now, max_limit, refresh_rate, true)
+        })
+        .is_some()
+    }
+
+    /// Refills the bucket of `entity` immediately, forgiving everything it used so far,
+    /// including an escalated penalty.
+    /// Returns `false` if the entity was not found by the limiter.
+    pub fn reset_bucket<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| entry.reset(now)).is_some()
+    }
+
+    /// Removes `tokens` requests from the bucket of `entity`, saturating at zero.
+    /// The entity recovers from the penalty like from any other consumption.
+    /// Returns `false` if the entity was not found by the limiter.
+    pub fn penalize<Q>(&self, entity: &Q, tokens: usize) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| entry.penalize(now, tokens))
+            .is_some()
+    }
+
+    /// Gives `tokens` consumed requests back to `entity`, capped at its maximum.
+    /// Useful when the work a request was charged for never happened, e.g. a
+    /// downstream call failed before doing anything.
+    /// Returns `false` if the entity was not found by the limiter.
+    pub fn refund<Q>(&self, entity: &Q, tokens: usize) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| entry.refund(now, tokens))
+            .is_some()
+    }
+
+    /// Bans `entity` for `duration`, every request it makes until then is answered
+    /// with `Decision::Banned`, regardless of what is left in its bucket.
+    ///
+    /// The bucket and its limits are kept as they are and apply again once the ban
+    /// expires. Entities don't need to be added to be banned, and banning an entity
+    /// again replaces its previous ban. A ban too long to end, like `Duration::MAX`,
+    /// lasts until `unban` is called.
+    pub fn ban(&self, entity: T, duration: Duration) {
+        let until = self.now().checked_add(duration);
+        self.access.insert(entity, Access::Banned { until });
+    }
+
+    /// Lifts the ban of `entity` before it expires.
+    /// Returns `false` if the entity wasn't banned.
+    pub fn unban<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.access
+            .remove_if(entity, |access| matches!(access, Access::Banned { .. }))
+    }
+
+    /// Returns how long `entity` stays banned, `None` if it isn't banned and
+    /// `Duration::MAX` if its ban has no end.
+    pub fn banned_for<Q>(&self, entity: &Q) -> Option<Duration>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.now();
+        match self.access.get(entity, now)? {
+            Access::Banned { until } => {
+                Some(until.map_or(Duration::MAX, |until| until.saturating_duration_since(now)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Exempts `entity` from rate limiting, every request it makes is allowed
+    /// without consuming anything, until `revoke_unlimited` is called.
+    ///
+    /// Meant for health checkers and trusted partners that should go through the
+    /// same code path as everyone else. Entities don't need to be added first,
+    /// and banning an unlimited entity replaces its exemption with the ban.
+    pub fn allow_unlimited(&self, entity: T) {
+        self.access.insert(entity, Access::Unlimited);
+    }
+
+    /// Subjects `entity` to its limits again after `allow_unlimited`.
+    /// Returns `false` if the entity wasn't unlimited.
+    pub fn revoke_unlimited<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.access
+            .remove_if(entity, |access| *access == Access::Unlimited)
+    }
+
+    /// Returns `true` if `entity` was exempted with `allow_unlimited`.
+    pub fn is_unlimited<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.access.get(entity, self.now()) == Some(Access::Unlimited)
+    }
+
+    /// Denies every request of `entity` with `Decision::Banned`, until `enable` is called.
+    ///
+    /// Like a ban without an end, e.g. to pause a tenant during maintenance. The bucket
+    /// is kept as it is, and entities don't need to be added to be disabled. Banning or
+    /// exempting a disabled entity replaces the toggle.
+    pub fn disable(&self, entity: T) {
+        self.access.insert(entity, Access::Disabled);
+    }
+
+    /// Lets requests of `entity` through again after `disable`.
+    /// Returns `false` if the entity wasn't disabled.
+    pub fn enable<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.access
+            .remove_if(entity, |access| *access == Access::Disabled)
+    }
+
+    /// Returns `true` if `entity` was disabled with `disable`.
+    pub fn is_disabled<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.access.get(entity, self.now()) == Some(Access::Disabled)
+    }
+
+    /// Switches how every request is decided, for all clones of the limiter, see `Mode`.
+    ///
+    /// `Mode::AllowAll` fails open and `Mode::DenyAll` fails closed, overriding bans,
+    /// exemptions and buckets alike, until `Mode::Enforce` switches back. Nothing is
+    /// consumed meanwhile. Denied requests get a `retry_after` of `Duration::MAX`, as
+    /// there is no telling when the mode changes back.
+    pub fn set_mode(&self, mode: Mode) {
+        self.mode.set(mode);
+    }
+
+    /// Returns how requests are decided, see `set_mode`.
+    pub fn mode(&self) -> Mode {
+        self.mode.get()
     }
 
     /// Removes a entity from the limiter
@@ -56,9 +783,120 @@ where
     ///
     /// The key may be any borrowed form of the map's key type,
     /// but Hash and Eq on the borrowed form must match those for the key type.
-    pub fn remove_limited_entity(&self, entity: T) -> Option<AssociatedEntity> {
-        let mut requests = self.requests.lock().unwrap();
-        requests.remove(&entity)
+    /// This goes for every method that looks up an entity, e.g. a `Limiter<String>`
+    /// can be queried with a `&str`. Methods that may add the entity also need the
+    /// borrowed form to be `ToOwned`, see `check`.
+    pub fn remove_limited_entity<Q>(&self, entity: &Q) -> Option<AssociatedEntity>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut requests = self.requests.write(entity);
+        requests.remove(entity).map(Entry::into_inner)
+    }
+
+    /// Returns how many entities the limiter tracks.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Returns `true` if the limiter tracks no entities.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Removes every entity from the limiter, e.g. after a config change.
+    ///
+    /// Keeps the allocated memory for reuse.
+    pub fn clear(&self) {
+        self.requests.clear();
+    }
+
+    /// Removes every entity that hasn't consumed a request for longer than the limiter's
+    /// idle TTL, set with `LimiterBuilder::idle_ttl`. Returns how many were removed.
+    /// Expired bans are removed as well.
+    ///
+    /// Keeps a long running limiter keyed by e.g. client IPs from growing forever.
+    /// Removed entities are gone for good, unless the limiter has a default limit
+    /// they are unknown on their next request.
+    pub fn evict_idle(&self) -> usize {
+        self.access.remove_expired(self.now());
+        match self.idle_ttl {
+            Some(idle_ttl) => self.requests.evict_idle(
+                |_| Some(idle_ttl),
+                self.now(),
+                |entity, entry| self.evicted(Some((entity, entry))),
+            ),
+            None => 0,
+        }
+    }
+
+    /// Like `evict_idle`, but with the idle TTL of every entity picked by `idle_ttl`,
+    /// entities it returns `None` for are kept. `removed` is called with every removed
+    /// entity before the hooks are.
+    pub(crate) fn evict_idle_by(
+        &self,
+        idle_ttl: impl Fn(&T) -> Option<Duration>,
+        mut removed: impl FnMut(&T),
+    ) -> usize {
+        self.access.remove_expired(self.now());
+        self.requests
+            .evict_idle(idle_ttl, self.now(), |entity, entry| {
+                removed(&entity);
+                self.evicted(Some((entity, entry)))
+            })
+    }
+
+    /// Removes every entity `remove` returns `true` for, like `remove_limited_entity`,
+    /// returns how many were removed.
+    pub(crate) fn remove_where(&self, remove: impl FnMut(&T) -> bool) -> usize {
+        self.requests.remove_where(remove)
+    }
+
+    /// Spawns a task on the current tokio runtime that calls `evict_idle` every `interval`.
+    ///
+    /// The task stops by itself once every clone of the limiter has been dropped,
+    /// or when the returned handle is aborted. Does nothing useful without an idle TTL,
+    /// see `LimiterBuilder::idle_ttl`.
+    ///
+    /// Panics when called outside of a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_cleanup(&self, interval: Duration) -> tokio::task::JoinHandle<()>
+    where
+        T: Sync,
+    {
+        let requests = Arc::downgrade(&self.requests);
+        let access = Arc::downgrade(&self.access);
+        let hooks = self.hooks.clone();
+        let counters = self.counters.clone();
+        #[cfg(feature = "tracing")]
+        let debug = self.debug;
+        let idle_ttl = self.idle_ttl;
+        let clock = self.clock.clone();
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let (Some(requests), Some(access)) = (requests.upgrade(), access.upgrade()) else {
+                    return; // the limiter is gone
+                };
+                access.remove_expired(clock.now());
+                if let Some(idle_ttl) = idle_ttl {
+                    let evicted = requests.evict_idle(
+                        |_| Some(idle_ttl),
+                        clock.now(),
+                        |entity, entry| {
+                            #[cfg(feature = "tracing")]
+                            trace::evicted(debug, &entity);
+                            hooks.evicted(Some((entity, entry)))
+                        },
+                    );
+                    counters.record_evicted(evicted);
+                }
+            }
+        })
     }
 
     /// Checks whether a entity has requests left to consume.
@@ -72,188 +910,2972 @@ where
     /// `Some(false)` -> entity is rate limited, no requests to consume.
     ///
     /// `Some(true)` -> everything worked, entity had requests left.
-    pub fn is_entity_limited(&mut self, entity: &T) -> Option<bool> {
-        let mut requests = self.requests.lock().unwrap();
-        let now = Instant::now();
-
-        if let Some(entry) = requests.get_mut(entity) {
-            if now.duration_since(entry.bucket_init) >= entry.refresh_rate {
-                entry.bucket = entry.bucket_max;
-                entry.bucket_init = now;
-            }
-
-            if entry.bucket > 0 {
-                entry.bucket -= 1; // request allowed
-                Some(true)
-            } else {
-                // entity is limited, request denied.
-                Some(false)
-            }
-        } else {
-            None
+    pub fn is_entity_limited<Q>(&self, entity: &Q) -> Option<bool>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        match self.check(entity) {
+            Decision::Allowed { .. } => Some(true),
+            Decision::Denied { .. } | Decision::Banned { .. } => Some(false),
+            Decision::Unknown => None,
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::thread;
-    use std::time::Duration;
+    /// Consumes a request for `entity`, like `is_entity_limited`, but returns a `Decision`
+    /// carrying the remaining quota and time until the bucket refreshes.
+    ///
+    /// Unknown entities are added with the default limit, or the policy of their tier,
+    /// which takes an owned key: `entity` has to be `ToOwned` into a `T`, so checking by
+    /// `&T` takes `T: Clone`. This goes for every method that checks or consumes, and broke
+    /// checking keys that aren't `Clone` when default limits were added. Those can still be
+    /// checked with `check_or_add`, which takes the key by value.
+    pub fn check<Q>(&self, entity: &Q) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        self.consume(entity, 1)
+    }
 
-    #[test]
-    fn test_add_limited_entity() {
-        let limiter: Limiter<&str> = Limiter::new();
-        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+    /// Checks every entity of `entities` like `check`, returning their decisions in order.
+    ///
+    /// Entities sharing a shard are checked under one lock, so checking e.g. the IP, the
+    /// user and the API key of a request costs fewer lock round trips than three `check`s.
+    /// Every entity is consumed from on its own, a request denied for one entity still
+    /// counts against the others, see `consume_all` for all or nothing.
+    pub fn check_many<Q>(&self, entities: &[&Q]) -> Vec<Decision>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let now = self.now();
+        let mut decisions: Vec<Option<Decision>> = entities
+            .iter()
+            .map(|entity| {
+                let decision = self.overridden(*entity, now)?;
+                Some(self.overruled(|key| key(&(*entity).to_owned()), 1, decision))
+            })
+            .collect();
+        let mut by_shard: Vec<(usize, usize)> = entities
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| decisions[*i].is_none())
+            .map(|(i, entity)| (self.requests.index(*entity), i))
+            .collect();
+        by_shard.sort_unstable();
+        for shard in by_shard.chunk_by(|a, b| a.0 == b.0) {
+            let requests = self.requests.read_shard(shard[0].0);
+            for &(_, i) in shard {
+                if let Some((key, entry)) = requests.get_key_value(entities[i]) {
+                    let decided = entry.decide(now, 1, self.escalation.as_ref());
+                    decisions[i] = Some(self.decided(key, 1, decided));
+                }
+            }
+        }
 
-        let requests = limiter.requests.lock().unwrap();
-        assert!(requests.contains_key("user1"));
-        assert_eq!(requests["user1"].bucket_max, 5);
-        assert_eq!(requests["user1"].bucket, 5);
+        // Entities the limiter doesn't know yet, added with the default limit if it has one.
+        let decisions: Vec<Decision> = decisions
+            .into_iter()
+            .zip(entities)
+            .map(|(decision, entity)| {
+                decision.unwrap_or_else(|| self.consume_uncounted(*entity, 1))
+            })
+            .collect();
+        for decision in &decisions {
+            self.counters.record(decision);
+        }
+        decisions
     }
 
-    #[test]
-    fn test_limiter_refresh_rate() {
-        let mut limiter: Limiter<&str> = Limiter::new();
-        let refresh_rate = Duration::from_millis(500);
-        let max_requests = 3;
-
-        limiter.add_limited_entity("user1", max_requests, refresh_rate);
+    /// Consumes a request for `entity` like `check`, with anything but an allowed request
+    /// as an error, so it can be passed on with `?`.
+    pub fn try_consume<Q>(&self, entity: &Q) -> Result<Allowance, RateGateError>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        self.check(entity).into_result()
+    }
 
-        for _ in 0..max_requests {
-            assert_eq!(
-                limiter.is_entity_limited(&"user1"),
-                Some(true),
-                "Request should be allowed"
-            );
+    /// Consumes a request for `entity` for a scope of work, see `Permit`.
+    ///
+    /// Returns `None` if the entity is rate limited, banned or not found by the limiter.
+    pub fn acquire_permit<'a, Q>(&'a self, entity: &'a Q) -> Option<Permit<'a, T, Q, S>>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        match self.check(entity) {
+            Decision::Allowed {
+                remaining,
+                reset_in,
+            } => Some(Permit::new(self, entity, remaining, reset_in)),
+            _ => None,
         }
+    }
 
-        assert_eq!(
-            limiter.is_entity_limited(&"user1"),
-            Some(false),
-            "Request should be denied after limit is reached"
-        );
-
-        thread::sleep(refresh_rate + Duration::from_millis(50));
+    /// Caps how many requests of `entity` can be in progress at once, on top of its rate
+    /// limit, e.g. at most 4 simultaneous downloads per user. Requests are started with
+    /// `acquire_in_flight` and end when the returned guard is dropped.
+    ///
+    /// Changing the cap keeps the requests already in progress. Snapshots don't keep
+    /// track of the cap. Returns `false` if the entity was not found by the limiter.
+    pub fn limit_concurrency<Q>(&self, entity: &Q, max_in_flight: usize) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, _| {
+            entry.limit_in_flight(Some(max_in_flight))
+        })
+        .is_some()
+    }
 
-        // After refresh, we should be able to make max_requests again
-        for i in 0..max_requests {
-            assert_eq!(
-                limiter.is_entity_limited(&"user1"),
-                Some(true),
-                "Request {} should be allowed after refresh",
-                i + 1
-            );
+    /// Starts a request of `entity` under its concurrency limit, see `limit_concurrency`,
+    /// without consuming from its bucket. The request ends when the guard is dropped.
+    ///
+    /// Entities without a concurrency limit, and unlimited ones, always get a guard.
+    /// Returns `None` if the entity already has as many requests in progress as it may,
+    /// is banned, or was not found by the limiter.
+    pub fn acquire_in_flight<Q>(&self, entity: &Q) -> Option<InFlightGuard>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.overridden(entity, self.now()) {
+            Some(Decision::Banned { .. }) => return None,
+            Some(_) => return Some(InFlightGuard::unlimited()),
+            None => {}
+        }
+        match self.update(entity, |entry, _| entry.in_flight.clone())? {
+            Some(in_flight) => in_flight.acquire(),
+            None => Some(InFlightGuard::unlimited()),
         }
+    }
 
-        // The next request should be denied again
-        assert_eq!(
-            limiter.is_entity_limited(&"user1"),
-            Some(false),
-            "Request should be denied after refreshed limit is reached"
-        );
+    /// Consumes a request for `entity` and starts it under its concurrency limit in one
+    /// call, see `limit_concurrency`. The request ends when the guard is dropped, while
+    /// what it consumed from the bucket stays consumed.
+    ///
+    /// Nothing is consumed if the entity has too many requests in progress, and no
+    /// request is started if it is rate limited.
+    pub fn check_in_flight<Q>(&self, entity: &Q) -> Result<InFlightGuard, RateGateError>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let in_flight = self.update(entity, |entry, _| entry.in_flight.clone());
+        let guard = match in_flight.flatten() {
+            Some(in_flight) => in_flight.acquire().ok_or(RateGateError::TooManyInFlight)?,
+            None => InFlightGuard::unlimited(),
+        };
+        // Dropping the guard gives the slot back if the request is denied.
+        self.check(entity).into_result().map(|_| guard)
     }
 
-    #[test]
-    fn test_is_entity_limited_allows_requests() {
-        let mut limiter: Limiter<&str> = Limiter::new();
-        limiter.add_limited_entity("user1", 2, Duration::from_secs(60));
+    /// Adapts the limit of `entity` to the outcomes of its requests, reported with
+    /// `report_success` and `report_failure`, see `Aimd`. Its current limit is moved
+    /// into the bounds of `aimd`.
+    ///
+    /// Snapshots don't keep track of it. Returns `false` if the entity was not found
+    /// by the limiter.
+    pub fn make_adaptive<Q>(&self, entity: &Q, aimd: Aimd) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| entry.set_adaptive(now, aimd))
+            .is_some()
+    }
 
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false));
+    /// Lets `entity` make up to `max_debt` requests beyond its limit, repaid from its
+    /// next refills before they can be used, see `Policy::with_overdraft`. A debt it
+    /// already took on is kept, even above a lower `max_debt`; `0` stops any new debt.
+    ///
+    /// Returns `false` if the entity was not found by the limiter.
+    pub fn set_overdraft<Q>(&self, entity: &Q, max_debt: usize) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, _| entry.set_overdraft(max_debt))
+            .is_some()
     }
 
-    #[test]
-    fn test_is_entity_limited_refills_bucket() {
-        let mut limiter: Limiter<&str> = Limiter::new();
-        limiter.add_limited_entity("user1", 1, Duration::from_millis(10));
+    /// Denies every request of `entity` for `period` once it hits its limit, even if its
+    /// bucket refills sooner, see `Policy::with_cooldown`. `None` ends a cooldown in
+    /// progress and stops further ones.
+    ///
+    /// Returns `false` if the entity was not found by the limiter.
+    pub fn set_cooldown<Q>(&self, entity: &Q, period: Option<Duration>) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, _| entry.set_cooldown(period))
+            .is_some()
+    }
 
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false));
-        thread::sleep(Duration::from_millis(25));
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+    /// Returns how many requests `entity` made beyond its limit that weren't repaid yet,
+    /// see `set_overdraft`.
+    /// `None` -> entity was not found by the limiter.
+    pub fn debt<Q>(&self, entity: &Q) -> Option<usize>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.peek(entity, |entry, _| entry.debt)
     }
 
-    #[test]
-    fn test_is_entity_limited_not_found() {
-        let mut limiter: Limiter<&str> = Limiter::new();
-        assert_eq!(limiter.is_entity_limited(&"unknown_user"), None);
+    /// Reports that a request of an adaptive `entity` went through, raising its limit,
+    /// see `make_adaptive`. What it already consumed stays consumed.
+    ///
+    /// `None` -> entity was not found by the limiter, or isn't adaptive.
+    /// `Some(limit)` -> the limit of the entity from now on.
+    pub fn report_success<Q>(&self, entity: &Q) -> Option<usize>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| entry.adapt(now, true))?
     }
 
-    #[test]
-    fn test_multiple_entities() {
-        let mut limiter: Limiter<&str> = Limiter::new();
-        limiter.add_limited_entity("user1", 3, Duration::from_secs(60));
-        limiter.add_limited_entity("user2", 5, Duration::from_secs(60));
+    /// Reports that a request of an adaptive `entity` failed, e.g. timed out or was
+    /// throttled downstream, cutting its limit, see `make_adaptive`.
+    ///
+    /// `None` -> entity was not found by the limiter, or isn't adaptive.
+    /// `Some(limit)` -> the limit of the entity from now on.
+    pub fn report_failure<Q>(&self, entity: &Q) -> Option<usize>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, now| entry.adapt(now, false))?
+    }
 
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false)); // Now should be limited
+    /// How many requests of `entity` are in progress, see `limit_concurrency`.
+    ///
+    /// `None` -> entity was not found by the limiter, or has no concurrency limit.
+    pub fn in_flight<Q>(&self, entity: &Q) -> Option<usize>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(entity, |entry, _| {
+            entry.in_flight.as_ref().map(InFlight::count)
+        })?
+    }
 
-        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
-        assert_eq!(limiter.is_entity_limited(&"user2"), Some(false)); // Now should be limited
+    /// Takes a request from the bucket of `entity` that is given back unless it gets
+    /// committed, see `Reservation`.
+    ///
+    /// Returns `None` if the entity is rate limited or not found by the limiter.
+    pub fn reserve<'a, Q>(&'a self, entity: &'a Q) -> Option<Reservation<'a, T, Q, S>>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        self.reserve_many(entity, 1)
     }
 
-    #[test]
-    fn test_limiter_with_multiple_threads() {
-        let limiter = Arc::new(Mutex::new(Limiter::new()));
-        limiter
-            .lock()
-            .unwrap()
-            .add_limited_entity("user1", 5, Duration::from_secs(60));
+    /// Reserves `cost` requests at once, like `reserve`.
+    pub fn reserve_many<'a, Q>(
+        &'a self,
+        entity: &'a Q,
+        cost: usize,
+    ) -> Option<Reservation<'a, T, Q, S>>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        self.consume(entity, cost)
+            .is_allowed()
+            .then(|| Reservation::new(self, entity, cost))
+    }
 
-        let limiter_clone1 = Arc::clone(&limiter);
-        let limiter_clone2 = Arc::clone(&limiter);
-        let limiter_clone3 = Arc::clone(&limiter);
+    /// Consumes `cost` requests from the bucket of `entity` at once.
+    ///
+    /// Useful when some operations are more expensive than others, e.g. endpoints
+    /// that are charged different amounts of "points".
+    /// The request is denied, and nothing is consumed, if fewer than `cost` requests are left.
+    /// A `cost` above the size of the bucket is denied with a `retry_after` of `Duration::MAX`.
+    pub fn consume<Q>(&self, entity: &Q, cost: usize) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let decision = self.consume_uncounted(entity, cost);
+        self.counters.record(&decision);
+        decision
+    }
 
-        let thread1 = thread::spawn(move || {
-            for _ in 0..2 {
-                assert_eq!(
-                    limiter_clone1.lock().unwrap().is_entity_limited(&"user1"),
-                    Some(true)
-                );
+    /// Consumes the cost of every entity of `entities` if all of them have enough
+    /// requests left, and nothing at all if any of them doesn't.
+    ///
+    /// Meant for requests limited by several keys at once, e.g. an IP and an API key,
+    /// where a request denied for one key shouldn't use up the quota of the others.
+    /// Requests are taken from the entities in order and given back once one of them
+    /// turns out to be limited, so concurrent checks may briefly see them missing.
+    ///
+    /// ### returns:
+    ///
+    /// `Decision::Unknown` -> an entity was not found by the limiter and there is no
+    /// default limit, nothing was consumed.
+    ///
+    /// `Decision::Banned` -> an entity is banned, nothing was consumed.
+    ///
+    /// `Decision::Denied` -> an entity is rate limited, nothing was consumed.
+    ///
+    /// `Decision::Allowed` -> every cost was consumed, with what is left of the
+    /// entity with the fewest requests left.
+    pub fn consume_all<Q>(&self, entities: &[(&Q, usize)]) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let decision = self.consume_all_uncounted(entities);
+        self.counters.record(&decision);
+        decision
+    }
+
+    fn consume_all_uncounted<Q>(&self, entities: &[(&Q, usize)]) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let now = self.now();
+        let mut limited = Vec::with_capacity(entities.len());
+        let mut unlimited = Vec::new();
+        for &(entity, cost) in entities {
+            match self.overridden(entity, now) {
+                Some(banned @ Decision::Banned { .. }) => {
+                    return self.overruled(|key| key(&entity.to_owned()), cost, banned)
+                }
+                Some(decision) => unlimited.push((entity, cost, decision)),
+                None => limited.push((entity, cost)),
             }
+        }
+
+        let mut allowed = Decision::Allowed {
+            remaining: usize::MAX,
+            reset_in: Duration::ZERO,
+        };
+        for (i, &(entity, cost)) in limited.iter().enumerate() {
+            let mut decision = self.decide(entity, now, cost);
+            if decision.is_none() && self.add_default(entity, now) {
+                decision = self.decide(entity, now, cost);
+            }
+            match decision.unwrap_or(Decision::Unknown) {
+                Decision::Allowed {
+                    remaining,
+                    reset_in,
+                } => {
+                    if matches!(allowed, Decision::Allowed { remaining: least, .. } if remaining < least)
+                    {
+                        allowed = Decision::Allowed {
+                            remaining,
+                            reset_in,
+                        };
+                    }
+                }
+                denied => {
+                    for &(entity, cost) in &limited[..i] {
+                        if let Some(entry) = self.requests.read(entity).get(entity) {
+                            entry.give_back(now, cost);
+                        }
+                    }
+                    return denied;
+                }
+            }
+        }
+        // Only once the batch as a whole is allowed.
+        for (entity, cost, decision) in unlimited {
+            self.overruled(|key| key(&entity.to_owned()), cost, decision);
+        }
+        allowed
+    }
+
+    /// Adds `entity` with the policy of its tier, or the default limit, if the limiter
+    /// has either. Returns `false` if it has neither.
+    fn add_default<Q>(&self, entity: &Q, now: Instant) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let mut owned = None;
+        let tier = self.tiers.as_ref().and_then(|tiers| {
+            let name = tiers.0.tier(owned.insert(entity.to_owned()))?;
+            Some((self.policy(&name)?, name))
         });
+        let state = match (tier, self.default) {
+            (Some((policy, name)), _) => {
+                let mut state =
+                    AssociatedEntity::from_policy(&policy, entity, now, self.clock.system_time());
+                state.policy = Some(name.into());
+                state
+            }
+            (None, Some((max_limit, refresh_rate))) => {
+                AssociatedEntity::new(max_limit, refresh_rate, Algorithm::FixedWindow, now)
+            }
+            (None, None) => return false,
+        };
+        let mut requests = self.requests.write(entity);
+        let mut evicted = None;
+        if !requests.contains_key(entity) {
+            let entity = owned.unwrap_or_else(|| entity.to_owned());
+            #[cfg(feature = "tracing")]
+            trace::inserted(self.debug, &entity, state.bucket_max);
+            evicted = self
+                .requests
+                .insert(&mut requests, entity, Entry::new(state, now));
+        }
+        drop(requests);
+        self.evicted(evicted);
+        true
+    }
 
-        let thread2 = thread::spawn(move || {
-            for _ in 0..2 {
-                assert_eq!(
-                    limiter_clone2.lock().unwrap().is_entity_limited(&"user1"),
-                    Some(true)
-                );
+    fn consume_uncounted<Q>(&self, entity: &Q, cost: usize) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let now = self.now();
+        if let Some(decision) = self.overridden(entity, now) {
+            return self.overruled(|key| key(&entity.to_owned()), cost, decision);
+        }
+        self.consume_bucket(entity, now, cost)
+    }
+
+    /// Consumes `cost` requests of `entity` like `consume`, and once they are allowed
+    /// hands the decision to `confirm`, e.g. to consume from another limiter as well.
+    /// Whatever `confirm` returns is counted instead, a request it doesn't allow is
+    /// given back to the bucket, as in `consume_all`.
+    pub(crate) fn consume_confirmed<Q>(
+        &self,
+        entity: &Q,
+        cost: usize,
+        confirm: impl FnOnce(Decision) -> Decision,
+    ) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let now = self.now();
+        let decision = match self.overridden(entity, now) {
+            Some(decision) => {
+                let decision = self.overruled(|key| key(&entity.to_owned()), cost, decision);
+                match decision.is_allowed() {
+                    true => confirm(decision),
+                    false => decision,
+                }
+            }
+            None => match self.consume_bucket(entity, now, cost) {
+                allowed @ Decision::Allowed { .. } => {
+                    let decision = confirm(allowed);
+                    if !decision.is_allowed() {
+                        if let Some(entry) = self.requests.read(entity).get(entity) {
+                            entry.give_back(now, cost);
+                        }
+                    }
+                    decision
+                }
+                decision => decision,
+            },
+        };
+        self.counters.record(&decision);
+        decision
+    }
+
+    /// Consumes `cost` requests from the bucket of `entity`, adding it with its default
+    /// limit first if the limiter doesn't know it yet.
+    fn consume_bucket<Q>(&self, entity: &Q, now: Instant, cost: usize) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        if let Some(decision) = self.decide(entity, now, cost) {
+            return decision;
+        }
+
+        if !self.add_default(entity, now) {
+            return Decision::Unknown;
+        }
+        // Unknown if it was evicted right away.
+        self.decide(entity, now, cost).unwrap_or(Decision::Unknown)
+    }
+
+    /// Consumes `cost` requests of an entity the limiter tracks, `None` if it doesn't.
+    fn decide<Q>(&self, entity: &Q, now: Instant, cost: usize) -> Option<Decision>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let requests = self.requests.read(entity);
+        let (key, entry) = requests.get_key_value(entity)?;
+        let decided = entry.decide(now, cost, self.escalation.as_ref());
+        Some(self.decided(key, cost, decided))
+    }
+
+    /// Consumes a request for `entity`, adding it with `max_limit` and `refresh_rate` first
+    /// if the limiter doesn't know it yet.
+    ///
+    /// Both happen under one lock, so concurrent callers can't race each other into
+    /// adding the same entity twice. An entity that already exists keeps its own limits.
+    pub fn check_or_add(&self, entity: T, max_limit: usize, refresh_rate: Duration) -> Decision {
+        let decision = self.check_or_add_uncounted(entity, max_limit, refresh_rate);
+        self.counters.record(&decision);
+        decision
+    }
+
+    fn check_or_add_uncounted(
+        &self,
+        entity: T,
+        max_limit: usize,
+        refresh_rate: Duration,
+    ) -> Decision {
+        let now = self.now();
+        if let Some(decision) = self.overridden(&entity, now) {
+            return self.overruled(|key| key(&entity), 1, decision);
+        }
+        if let Some((key, entry)) = self.requests.read(&entity).get_key_value(&entity) {
+            let decided = entry.decide(now, 1, self.escalation.as_ref());
+            return self.decided(key, 1, decided);
+        }
+
+        let mut requests = self.requests.write(&entity);
+        if let Some((key, entry)) = requests.get_key_value(&entity) {
+            let decided = entry.decide(now, 1, self.escalation.as_ref());
+            return self.decided(key, 1, decided);
+        }
+        let entry = Entry::new(
+            AssociatedEntity::new(max_limit, refresh_rate, Algorithm::FixedWindow, now),
+            now,
+        );
+        let decided = entry.decide(now, 1, self.escalation.as_ref());
+        let decision = self.decided(&entity, 1, decided);
+        #[cfg(feature = "tracing")]
+        trace::inserted(self.debug, &entity, max_limit);
+        let evicted = self.requests.insert(&mut requests, entity, entry);
+        drop(requests);
+        self.evicted(evicted);
+        decision
+    }
+
+    /// Returns how many requests `entity` has left, without consuming anything.
+    ///
+    /// `None` -> entity was not found by the limiter, create one with `add_limited_entity`.
+    pub fn get_bucket_remaining<Q>(&self, entity: &Q) -> Option<usize>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.peek(entity, |entry, now| entry.total_available(now))
+    }
+
+    /// Returns the rate limit headers for `entity`, without consuming anything.
+    /// Meant to be added to responses after checking the entity, so clients can
+    /// pace themselves.
+    ///
+    /// With several windows, the limit of the tightest one is reported.
+    /// `None` -> entity was not found by the limiter, create one with `add_limited_entity`.
+    pub fn rate_limit_headers<Q>(&self, entity: &Q) -> Option<RateLimitHeaders>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let system_time = self.clock.system_time();
+        self.peek(entity, |entry, now| entry.headers(now, system_time))
+    }
+
+    /// Matches the bucket of `entity` to the rate limit headers of a server's response,
+    /// for limiters pacing outbound requests, whose local estimates drift from the
+    /// server's real counters.
+    ///
+    /// - `X-RateLimit-Remaining` or `RateLimit-Remaining` sets how many requests are left.
+    /// - `X-RateLimit-Reset` or `RateLimit-Reset` moves the end of a fixed window, read as
+    ///   Unix time in seconds if it's that large, otherwise as seconds from now.
+    /// - `Retry-After`, in seconds or as an HTTP date, or nothing remaining until the reset,
+    ///   empties the bucket until then.
+    ///
+    /// Only the entity's own bucket is synced, not its windows or parent.
+    /// Returns `false` if the entity was not found by the limiter.
+    #[cfg(feature = "http")]
+    pub fn sync_from_headers<Q>(&self, entity: &Q, headers: &http::HeaderMap) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let server = headers::ServerLimit::from_headers(headers, self.clock.system_time());
+        self.update(entity, |entry, now| entry.sync(now, &server))
+            .is_some()
+    }
+
+    /// Checks whether a request from `entity` would be allowed, without consuming anything.
+    ///
+    /// Useful for health checks or UI displays that should not distort the limits.
+    /// The bucket is refreshed first if its `refresh_rate` has passed.
+    ///
+    /// ### returns:
+    ///
+    /// `None` -> entity was not found by the limiter, create one with `add_limited_entity`.
+    ///
+    /// `Some(false)` -> the next request would be rate limited, or the entity is banned.
+    ///
+    /// `Some(true)` -> the next request would be allowed.
+    pub fn would_allow<Q>(&self, entity: &Q) -> Option<bool>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(decision) = self.overridden(entity, self.now()) {
+            return Some(decision.is_allowed());
+        }
+        self.peek(entity, |entry, now| entry.total_available(now) > 0)
+    }
+
+    /// Blocks the current thread until `entity` has a request left and consumes it.
+    ///
+    /// With a `timeout`, gives up once waiting any longer would exceed it. A timeout
+    /// too long to end, like `Duration::MAX`, waits as long as without one.
+    ///
+    /// ### returns:
+    ///
+    /// `Decision::Unknown` -> entity was not found by the limiter, create one with `add_limited_entity`.
+    ///
+    /// `Decision::Banned` -> entity is banned, waiting for the ban to expire is up to the caller.
+    ///
+    /// `Decision::Denied` -> no request could be consumed within `timeout`, or ever.
+    ///
+    /// `Decision::Allowed` -> a request was consumed.
+    pub fn acquire_blocking<Q>(&self, entity: &Q, timeout: Option<Duration>) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        // Real time, a manual clock doesn't move while this sleeps.
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        loop {
+            match self.check(entity) {
+                Decision::Denied { retry_after } => {
+                    let too_late = match deadline {
+                        Some(deadline) => Instant::now()
+                            .checked_add(retry_after)
+                            .is_none_or(|retry_at| retry_at > deadline),
+                        None => retry_after == Duration::MAX,
+                    };
+                    if too_late {
+                        return Decision::Denied { retry_after };
+                    }
+                    std::thread::sleep(retry_after);
+                }
+                decision => return decision,
+            }
+        }
+    }
+
+    /// Waits until `entity` has a request left and consumes it.
+    ///
+    /// Instead of returning `Decision::Denied`, this sleeps until the bucket refreshes,
+    /// which makes it handy for throttling outbound calls.
+    ///
+    /// Tasks waiting for the same entity are served in the order they started waiting,
+    /// so none of them starves while others keep getting lucky. Requests made with
+    /// `check` and the like don't queue, and still take what's left first.
+    ///
+    /// Cancel safe: a request is only consumed in the same poll that returns it, so
+    /// dropping the future before it completes, e.g. when another branch of
+    /// `tokio::select!` wins, consumes nothing and leaves the queue right away.
+    ///
+    /// ### returns:
+    ///
+    /// `Decision::Unknown` -> entity was not found by the limiter, create one with `add_limited_entity`.
+    ///
+    /// `Decision::Banned` -> entity is banned, waiting for the ban to expire is up to the caller.
+    ///
+    /// `Decision::Denied` -> no request will ever be allowed, as the bucket holds none.
+    ///
+    /// `Decision::Allowed` -> a request was consumed.
+    #[cfg(feature = "tokio")]
+    pub async fn acquire<Q>(&self, entity: &Q) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        self.acquire_until(entity, None).await
+    }
+
+    /// Waits until `entity` has a request left and consumes it, like `acquire`, but
+    /// gives up once waiting any longer would exceed `timeout`.
+    ///
+    /// Handy for request handlers with a latency budget of their own:
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use rate_gate::{Limiter, RateGateError};
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let limiter: Limiter<&str> = Limiter::new();
+    /// limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+    ///
+    /// assert!(limiter.acquire_timeout(&"user1", Duration::from_millis(50)).await.is_ok());
+    /// assert!(matches!(
+    ///     limiter.acquire_timeout(&"user1", Duration::from_millis(50)).await,
+    ///     Err(RateGateError::TimedOut { .. })
+    /// ));
+    /// # }
+    /// ```
+    ///
+    /// `RateGateError::EntityNotFound` and `RateGateError::Banned` are returned right
+    /// away, as for `Decision::into_result`. A timeout too long to end, like
+    /// `Duration::MAX`, waits like `acquire`. Cancel safe, like `acquire`.
+    #[cfg(feature = "tokio")]
+    pub async fn acquire_timeout<Q>(
+        &self,
+        entity: &Q,
+        timeout: Duration,
+    ) -> Result<Allowance, RateGateError>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let deadline = tokio::time::Instant::now().checked_add(timeout);
+        match self.acquire_until(entity, deadline).await {
+            Decision::Denied { retry_after } => Err(RateGateError::TimedOut { retry_after }),
+            decision => decision.into_result(),
+        }
+    }
+
+    /// Blocks the current thread until `entity` has a request left and consumes it,
+    /// like `acquire_timeout`.
+    pub fn acquire_timeout_blocking<Q>(
+        &self,
+        entity: &Q,
+        timeout: Duration,
+    ) -> Result<Allowance, RateGateError>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        match self.acquire_blocking(entity, Some(timeout)) {
+            Decision::Denied { retry_after } => Err(RateGateError::TimedOut { retry_after }),
+            decision => decision.into_result(),
+        }
+    }
+
+    /// Waits in the queue of `entity` until it has a request left, see `acquire`.
+    /// Returns `Decision::Denied` once waiting any longer would pass `deadline`.
+    #[cfg(feature = "tokio")]
+    async fn acquire_until<Q>(&self, entity: &Q, deadline: Option<tokio::time::Instant>) -> Decision
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        // Requests that are never allowed are too late even without a deadline.
+        let too_late = |retry_after| match deadline {
+            Some(deadline) => tokio::time::Instant::now()
+                .checked_add(retry_after)
+                .is_none_or(|retry_at| retry_at > deadline),
+            None => retry_after == Duration::MAX,
+        };
+        // Without anyone waiting, there is no queue to join unless denied.
+        if !self.waiters.is_waiting(entity) {
+            match self.check(entity) {
+                Decision::Denied { retry_after } if too_late(retry_after) => {
+                    return Decision::Denied { retry_after };
+                }
+                Decision::Denied { .. } => {}
+                decision => return decision,
+            }
+        }
+        let waiter = self.waiters.join(entity);
+        let _turn = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, waiter.turn()).await {
+                Ok(turn) => turn,
+                Err(_) => {
+                    let retry_after = self.retry_after(entity).unwrap_or_default();
+                    return Decision::Denied { retry_after };
+                }
+            },
+            None => waiter.turn().await,
+        };
+        loop {
+            match self.check(entity) {
+                Decision::Denied { retry_after } if too_late(retry_after) => {
+                    return Decision::Denied { retry_after };
+                }
+                Decision::Denied { retry_after } => tokio::time::sleep(retry_after).await,
+                decision => return decision,
+            }
+        }
+    }
+
+    /// Returns how many tasks are waiting in `acquire` for `entity`.
+    #[cfg(feature = "tokio")]
+    pub fn queue_depth<Q>(&self, entity: &Q) -> usize
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.waiters.depth(entity)
+    }
+
+    /// Returns how long `entity` has to wait before its next request is allowed.
+    ///
+    /// ### returns:
+    ///
+    /// `None` -> entity was not found by the limiter, create one with `add_limited_entity`.
+    ///
+    /// `Some(Duration::ZERO)` -> entity has requests left, no need to wait.
+    ///
+    /// `Some(duration)` -> entity is rate limited, the bucket refreshes in `duration`,
+    /// or the entity is banned and the ban expires in `duration`.
+    ///
+    /// Wrap it in `RetryAfter` to answer with a `Retry-After` header.
+    pub fn retry_after<Q>(&self, entity: &Q) -> Option<Duration>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.overridden(entity, self.now()) {
+            Some(Decision::Banned { retry_after }) => return Some(retry_after),
+            Some(_) => return Some(Duration::ZERO),
+            None => {}
+        }
+        self.peek(entity, |entry, now| entry.retry_after(now, 1))
+    }
+
+    /// Returns how often `entity` was allowed and denied, and when it was last seen.
+    ///
+    /// `None` -> entity was not found by the limiter.
+    pub fn stats<Q>(&self, entity: &Q) -> Option<EntityStats>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.requests.read(entity).get(entity).map(Entry::stats)
+    }
+
+    /// Returns what is left of the bucket of `entity` and when it refills, like
+    /// `snapshot` does for every entity.
+    ///
+    /// `None` -> entity was not found by the limiter.
+    pub fn state<Q>(&self, entity: &Q) -> Option<EntityState>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.now();
+        let requests = self.requests.read(entity);
+        let mut entry = requests.get(entity)?.lock();
+        entry.refresh(now);
+        let state = entry.state(now);
+        Some(state)
+    }
+
+    /// Returns how many requests were checked and denied over all entities,
+    /// since the limiter was created or `reset_global_stats` was called.
+    ///
+    /// Counting the tracked entities locks every shard for a moment,
+    /// so call this for dashboards rather than per request.
+    pub fn global_stats(&self) -> GlobalStats {
+        self.counters.load(self.len())
+    }
+
+    /// Starts counting the requests of `global_stats` from zero again.
+    pub fn reset_global_stats(&self) {
+        self.counters.reset();
+    }
+
+    /// Creates a Prometheus collector that exposes the counters of the limiter,
+    /// see `PrometheusCollector`.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_collector(&self) -> PrometheusCollector<T, S> {
+        PrometheusCollector::new(self.clone())
+    }
+
+    /// Takes a snapshot of every tracked entity, to be restored with `import_state`,
+    /// possibly by another process.
+    ///
+    /// Every shard is locked while it is read, like for `snapshot`.
+    pub fn export_state(&self) -> LimiterSnapshot<T>
+    where
+        T: Clone,
+    {
+        let taken_at = SystemTime::now();
+        let now = self.now();
+        let mut entities = Vec::new();
+        self.requests.for_each(|entity, entry| {
+            entities.push((entity.clone(), entry.lock().snapshot(now)));
+        });
+        LimiterSnapshot { taken_at, entities }
+    }
+
+    /// Adds every entity of `snapshot`, replacing entities the limiter already tracks.
+    ///
+    /// Time keeps passing between taking and restoring a snapshot, e.g. a window that
+    /// was about to end when the snapshot was taken has ended when it is restored.
+    pub fn import_state(&self, snapshot: LimiterSnapshot<T>) {
+        let now = self.now();
+        let taken_at = snapshot.taken_at_instant(now);
+        for (entity, state) in snapshot.entities {
+            let entry = Entry::new(AssociatedEntity::restore(&state, taken_at), now);
+            let mut requests = self.requests.write(&entity);
+            let evicted = self.requests.insert(&mut requests, entity, entry);
+            drop(requests);
+            self.evicted(evicted);
+        }
+    }
+
+    /// Combines the entities of `other`, taken from another limiter, with this one's.
+    /// Entities only `other` tracks are added as with `import_state`, entities both
+    /// track are combined according to `strategy`.
+    ///
+    /// Useful to reconcile limiters that served the same entities, e.g. after
+    /// consolidating two instances of a service.
+    pub fn merge(&self, other: LimiterSnapshot<T>, strategy: MergeStrategy) {
+        let now = self.now();
+        let taken_at = other.taken_at_instant(now);
+        for (entity, state) in other.entities {
+            let theirs = AssociatedEntity::restore(&state, taken_at);
+            let mut requests = self.requests.write(&entity);
+            if let Some(entry) = requests.get(&entity) {
+                entry.lock().merge(now, theirs, strategy);
+                continue;
+            }
+            let evicted = self
+                .requests
+                .insert(&mut requests, entity, Entry::new(theirs, now));
+            drop(requests);
+            self.evicted(evicted);
+        }
+    }
+
+    /// Returns every tracked entity along with its current state.
+    ///
+    /// Every shard is locked while it is read, so this is meant for admin dashboards
+    /// and the like rather than hot paths.
+    pub fn snapshot(&self) -> Vec<(T, EntityState)>
+    where
+        T: Clone,
+    {
+        let mut snapshot = Vec::new();
+        self.for_each(|entity, state| snapshot.push((entity.clone(), state)));
+        snapshot
+    }
+
+    /// Calls `f` with every tracked entity and its current state,
+    /// like `snapshot` but without cloning the entities.
+    pub fn for_each(&self, mut f: impl FnMut(&T, EntityState)) {
+        let now = self.now();
+        self.requests.for_each(|entity, entry| {
+            let mut entry = entry.lock();
+            entry.refresh(now);
+            f(entity, entry.state(now));
+        });
+    }
+
+    /// Returns the `n` entities that were denied the most requests along with their
+    /// stats, most denied first. Entities never denied aren't offenders.
+    ///
+    /// Goes through every entity like `snapshot`, so call this for dashboards rather
+    /// than per request.
+    pub fn top_offenders(&self, n: usize) -> Vec<(T, EntityStats)>
+    where
+        T: Clone,
+    {
+        let mut offenders = Vec::new();
+        self.requests.for_each(|entity, entry| {
+            let stats = entry.stats();
+            if stats.denied > 0 {
+                offenders.push((entity.clone(), stats));
             }
         });
+        offenders.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.denied));
+        offenders.truncate(n);
+        offenders
+    }
 
-        let thread3 = thread::spawn(move || {
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns the decision for `entity` if it is banned or unlimited,
+    /// which takes precedence over its bucket.
+    fn overridden<Q>(&self, entity: &Q, now: Instant) -> Option<Decision>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let unlimited = Decision::Allowed {
+            remaining: usize::MAX,
+            reset_in: Duration::ZERO,
+        };
+        let disabled = Decision::Banned {
+            retry_after: Duration::MAX,
+        };
+        match self.mode.get() {
+            Mode::Enforce => {}
+            Mode::AllowAll => return Some(unlimited),
+            Mode::DenyAll => return Some(disabled),
+        }
+        match self.access.get(entity, now)? {
+            Access::Banned { until } => Some(Decision::Banned {
+                retry_after: until
+                    .map_or(Duration::MAX, |until| until.saturating_duration_since(now)),
+            }),
+            Access::Unlimited => Some(unlimited),
+            Access::Disabled => Some(disabled),
+        }
+    }
+
+    /// Reports a decision made for `entity` to the hooks and the audit log.
+    fn decided(&self, entity: &T, cost: usize, decided: (Decision, bool)) -> Decision {
+        #[cfg(feature = "tracing")]
+        trace::decided(self.debug, entity, cost, &decided.0, decided.1);
+        let decision = self.hooks.decided(entity, cost, decided);
+        #[cfg(feature = "audit")]
+        if let Some(audited) = &self.audit {
+            let key = || (audited.key)(entity);
+            let timestamp = self.clock.system_time();
+            audited.audit.record(key, cost, &decision, timestamp);
+        }
+        decision
+    }
+
+    /// Reports a decision `overridden` made to the audit log, `_key` prints the entity
+    /// with the function it is given. The hooks only hear about decisions of buckets.
+    fn overruled(
+        &self,
+        _key: impl FnOnce(fn(&T) -> String) -> String,
+        _cost: usize,
+        decision: Decision,
+    ) -> Decision {
+        #[cfg(feature = "audit")]
+        if let Some(audited) = &self.audit {
+            let key = || _key(audited.key);
+            let timestamp = self.clock.system_time();
+            audited.audit.record(key, _cost, &decision, timestamp);
+        }
+        decision
+    }
+
+    /// Counts an entity the limiter removed by itself and tells the hooks about it.
+    fn evicted(&self, evicted: Option<(T, Entry)>) {
+        if let Some((_entity, _)) = &evicted {
+            #[cfg(feature = "tracing")]
+            trace::evicted(self.debug, _entity);
+            self.counters.record_evicted(1);
+        }
+        self.hooks.evicted(evicted);
+    }
+
+    /// Locks an existing entity and hands it to `update`.
+    fn update<Q, R>(
+        &self,
+        entity: &Q,
+        update: impl FnOnce(&mut AssociatedEntity, Instant) -> R,
+    ) -> Option<R>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let requests = self.requests.read(entity);
+        let mut entry = requests.get(entity)?.lock();
+        Some(update(&mut entry, self.now()))
+    }
+
+    /// Refreshes the bucket of `entity` and reads from it without consuming anything.
+    /// Entities covered by the default limit are read as if they were just added.
+    fn peek<Q, R>(
+        &self,
+        entity: &Q,
+        read: impl FnOnce(&AssociatedEntity, Instant) -> R,
+    ) -> Option<R>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let requests = self.requests.read(entity);
+        let now = self.now();
+
+        match requests.get(entity) {
+            Some(entry) => {
+                let mut entry = entry.lock();
+                entry.refresh(now);
+                Some(read(&entry, now))
+            }
+            None => {
+                let (max_limit, refresh_rate) = self.default?;
+                let entry =
+                    AssociatedEntity::new(max_limit, refresh_rate, Algorithm::FixedWindow, now);
+                Some(read(&entry, now))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_add_limited_entity() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+
+        let requests = limiter.requests.read("user1");
+        assert!(requests.contains_key("user1"));
+        assert_eq!(requests["user1"].lock().bucket_max, 5);
+        assert_eq!(requests["user1"].lock().bucket, 5);
+    }
+
+    #[test]
+    fn test_keys_without_clone() {
+        #[derive(Debug, PartialEq, Eq, Hash)]
+        struct Key(u32);
+
+        let limiter: Limiter<Key> = Limiter::new();
+        assert!(limiter
+            .check_or_add(Key(1), 1, Duration::from_secs(60))
+            .is_allowed());
+        assert!(!limiter
+            .check_or_add(Key(1), 1, Duration::from_secs(60))
+            .is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&Key(1)), Some(0));
+        assert!(limiter.remove_limited_entity(&Key(1)).is_some());
+    }
+
+    #[test]
+    fn test_limiter_refresh_rate() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        let refresh_rate = Duration::from_millis(500);
+        let max_requests = 3;
+
+        limiter.add_limited_entity("user1", max_requests, refresh_rate);
+
+        for _ in 0..max_requests {
             assert_eq!(
-                limiter_clone3.lock().unwrap().is_entity_limited(&"user1"),
-                Some(true)
+                limiter.is_entity_limited(&"user1"),
+                Some(true),
+                "Request should be allowed"
             );
-        });
+        }
 
-        thread1.join().unwrap();
-        thread2.join().unwrap();
-        thread3.join().unwrap();
+        assert_eq!(
+            limiter.is_entity_limited(&"user1"),
+            Some(false),
+            "Request should be denied after limit is reached"
+        );
+
+        clock.advance(refresh_rate + Duration::from_millis(50));
 
+        // After refresh, we should be able to make max_requests again
+        for i in 0..max_requests {
+            assert_eq!(
+                limiter.is_entity_limited(&"user1"),
+                Some(true),
+                "Request {} should be allowed after refresh",
+                i + 1
+            );
+        }
+
+        // The next request should be denied again
         assert_eq!(
-            limiter.lock().unwrap().is_entity_limited(&"user1"),
-            Some(false)
+            limiter.is_entity_limited(&"user1"),
+            Some(false),
+            "Request should be denied after refreshed limit is reached"
         );
     }
 
+    #[test]
+    fn test_is_entity_limited_allows_requests() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 2, Duration::from_secs(60));
+
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false));
+    }
+
+    #[test]
+    fn test_is_entity_limited_refills_bucket() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity("user1", 1, Duration::from_millis(10));
+
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false));
+        clock.advance(Duration::from_millis(9));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+    }
+
+    #[test]
+    fn test_is_entity_limited_not_found() {
+        let limiter: Limiter<&str> = Limiter::new();
+        assert_eq!(limiter.is_entity_limited(&"unknown_user"), None);
+    }
+
+    #[test]
+    fn test_would_allow_does_not_consume() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity("user1", 1, Duration::from_millis(10));
+
+        assert_eq!(limiter.would_allow(&"user1"), Some(true));
+        assert_eq!(limiter.would_allow(&"user1"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+        assert_eq!(limiter.would_allow(&"user1"), Some(false));
+
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(limiter.would_allow(&"user1"), Some(true));
+        assert_eq!(limiter.would_allow(&"unknown_user"), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        let refresh_rate = Duration::from_millis(200);
+        limiter.add_limited_entity("user1", 1, refresh_rate);
+
+        assert_eq!(limiter.retry_after(&"user1"), Some(Duration::ZERO));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+        assert_eq!(limiter.retry_after(&"user1"), Some(refresh_rate));
+
+        clock.advance(Duration::from_millis(150));
+        let wait = limiter.retry_after(&"user1").unwrap();
+        assert_eq!(wait, Duration::from_millis(50));
+
+        clock.advance(wait);
+        assert_eq!(limiter.retry_after(&"user1"), Some(Duration::ZERO));
+        assert_eq!(limiter.retry_after(&"unknown_user"), None);
+    }
+
+    #[test]
+    fn test_check_decision() {
+        let limiter: Limiter<&str> = Limiter::new();
+        let refresh_rate = Duration::from_secs(60);
+        limiter.add_limited_entity("user1", 2, refresh_rate);
+
+        match limiter.check(&"user1") {
+            Decision::Allowed {
+                remaining,
+                reset_in,
+            } => {
+                assert_eq!(remaining, 1);
+                assert!(reset_in <= refresh_rate);
+            }
+            other => panic!("expected Allowed, got {:?}", other),
+        }
+        assert!(limiter.check(&"user1").is_allowed());
+
+        match limiter.check(&"user1") {
+            Decision::Denied { retry_after } => assert!(retry_after <= refresh_rate),
+            other => panic!("expected Denied, got {:?}", other),
+        }
+        assert_eq!(limiter.check(&"unknown_user"), Decision::Unknown);
+    }
+
+    #[test]
+    fn test_consume_with_cost() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 10, Duration::from_secs(60));
+
+        assert!(matches!(
+            limiter.consume(&"user1", 4),
+            Decision::Allowed { remaining: 6, .. }
+        ));
+        assert!(matches!(
+            limiter.consume(&"user1", 6),
+            Decision::Allowed { remaining: 0, .. }
+        ));
+        assert!(matches!(
+            limiter.consume(&"user1", 1),
+            Decision::Denied { .. }
+        ));
+        assert_eq!(limiter.consume(&"unknown_user", 1), Decision::Unknown);
+    }
+
+    #[test]
+    fn test_consume_denied_does_not_drain() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+
+        assert!(!limiter.consume(&"user1", 6).is_allowed());
+        assert!(matches!(
+            limiter.consume(&"user1", 5),
+            Decision::Allowed { remaining: 0, .. }
+        ));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_acquire_waits_for_refresh() {
+        let limiter: Limiter<&str> = Limiter::new();
+        let refresh_rate = Duration::from_millis(100);
+        limiter.add_limited_entity("user1", 1, refresh_rate);
+
+        let start = std::time::Instant::now();
+        assert!(limiter.acquire(&"user1").await.is_allowed());
+        assert!(limiter.acquire(&"user1").await.is_allowed());
+        assert!(start.elapsed() >= refresh_rate);
+
+        assert_eq!(limiter.acquire(&"unknown_user").await, Decision::Unknown);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_is_cancel_safe() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(1));
+        assert!(limiter.check(&"user1").is_allowed());
+
+        // Cancelled while at the front of the queue, waiting for the bucket.
+        tokio::select! {
+            _ = limiter.acquire(&"user1") => panic!("the bucket is empty"),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+        }
+        assert_eq!(limiter.queue_depth(&"user1"), 0);
+
+        // Cancelled while waiting behind another task, which is served as usual.
+        let first = limiter.clone();
+        let waiting = tokio::spawn(async move { first.acquire(&"user1").await });
+        tokio::task::yield_now().await;
+        tokio::select! {
+            _ = limiter.acquire(&"user1") => panic!("another task is first"),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+        }
+        assert_eq!(limiter.queue_depth(&"user1"), 1);
+        assert!(waiting.await.unwrap().is_allowed());
+        assert_eq!(limiter.queue_depth(&"user1"), 0);
+
+        // Nothing was consumed by the cancelled futures.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_timeout() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(1));
+        assert!(limiter.check(&"user1").is_allowed());
+
+        let timeout = Duration::from_millis(500);
+        let start = tokio::time::Instant::now();
+        assert!(matches!(
+            limiter.acquire_timeout(&"user1", timeout).await,
+            Err(RateGateError::TimedOut { .. })
+        ));
+        // Gave up right away instead of waiting out the timeout.
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(limiter
+            .acquire_timeout(&"user1", Duration::from_secs(2))
+            .await
+            .is_ok());
+        assert!(matches!(
+            limiter.acquire_timeout(&"unknown_user", timeout).await,
+            Err(RateGateError::EntityNotFound)
+        ));
+
+        // Waiting behind another task counts against the timeout too.
+        let first = limiter.clone();
+        let waiting = tokio::spawn(async move { first.acquire(&"user1").await });
+        tokio::task::yield_now().await;
+        assert!(matches!(
+            limiter.acquire_timeout(&"user1", timeout).await,
+            Err(RateGateError::TimedOut { .. })
+        ));
+        assert!(waiting.await.unwrap().is_allowed());
+        assert!(limiter
+            .acquire_timeout(&"user1", Duration::MAX)
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_acquire_timeout_blocking() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+        assert!(limiter
+            .acquire_timeout_blocking(&"user1", Duration::from_millis(10))
+            .is_ok());
+        let err = limiter
+            .acquire_timeout_blocking(&"user1", Duration::from_millis(10))
+            .unwrap_err();
+        assert!(matches!(err, RateGateError::TimedOut { .. }));
+        assert!(err.retry_after().unwrap() > Duration::from_secs(59));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_is_fifo() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_millis(100));
+        assert!(limiter.check(&"user1").is_allowed());
+
+        let served = Arc::new(crate::sync::Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for i in 0..5 {
+            let (limiter, served) = (limiter.clone(), served.clone());
+            tasks.push(tokio::spawn(async move {
+                assert!(limiter.acquire(&"user1").await.is_allowed());
+                served.lock().push(i);
+            }));
+            // Lets the task join the queue before the next one starts.
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limiter.queue_depth(&"user1"), 5);
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(*served.lock(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(limiter.queue_depth(&"user1"), 0);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_paused_time() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(limiter.check(&"user1").is_allowed());
+
+        // Sleeping auto-advances the paused clock, so this doesn't take a minute.
+        let start = tokio::time::Instant::now();
+        assert!(limiter.acquire(&"user1").await.is_allowed());
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_acquire_blocking() {
+        let limiter: Limiter<&str> = Limiter::new();
+        let refresh_rate = Duration::from_millis(100);
+        limiter.add_limited_entity("user1", 1, refresh_rate);
+
+        let start = std::time::Instant::now();
+        assert!(limiter.acquire_blocking(&"user1", None).is_allowed());
+        assert!(limiter.acquire_blocking(&"user1", None).is_allowed());
+        assert!(start.elapsed() >= refresh_rate);
+
+        assert_eq!(
+            limiter.acquire_blocking(&"unknown_user", None),
+            Decision::Unknown
+        );
+    }
+
+    #[test]
+    fn test_acquire_blocking_timeout() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+
+        assert!(limiter
+            .acquire_blocking(&"user1", Some(Duration::from_millis(10)))
+            .is_allowed());
+        assert!(matches!(
+            limiter.acquire_blocking(&"user1", Some(Duration::from_millis(10))),
+            Decision::Denied { .. }
+        ));
+
+        limiter.add_limited_entity("user2", 1, Duration::from_secs(60));
+        assert!(limiter
+            .acquire_blocking(&"user2", Some(Duration::MAX))
+            .is_allowed());
+        assert_eq!(
+            limiter.acquire_blocking(&"unknown_user", Some(Duration::MAX)),
+            Decision::Unknown
+        );
+    }
+
+    #[test]
+    fn test_token_bucket_entity() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity_with_algorithm(
+            "user1",
+            2,
+            Duration::from_millis(100),
+            Algorithm::TokenBucket,
+        );
+
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+
+        // Half the refresh rate gives back one token, not the whole bucket.
+        clock.advance(Duration::from_millis(60));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_gcra_entity() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity_with_algorithm(
+            "user1",
+            2,
+            Duration::from_secs(60),
+            Algorithm::Gcra,
+        );
+
+        assert!(matches!(
+            limiter.check(&"user1"),
+            Decision::Allowed { remaining: 1, .. }
+        ));
+        assert!(limiter.check(&"user1").is_allowed());
+        match limiter.check(&"user1") {
+            Decision::Denied { retry_after } => {
+                assert!(retry_after > Duration::from_secs(29));
+                assert!(retry_after <= Duration::from_secs(30));
+            }
+            other => panic!("expected Denied, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sliding_window_log_entity() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity_with_algorithm(
+            "user1",
+            2,
+            Duration::from_millis(100),
+            Algorithm::SlidingWindowLog,
+        );
+
+        assert!(limiter.check(&"user1").is_allowed());
+        clock.advance(Duration::from_millis(60));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+
+        // Only the first request has left the window.
+        clock.advance(Duration::from_millis(50));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_multiple_entities() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 3, Duration::from_secs(60));
+        limiter.add_limited_entity("user2", 5, Duration::from_secs(60));
+
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false)); // Now should be limited
+
+        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user2"), Some(true));
+        assert_eq!(limiter.is_entity_limited(&"user2"), Some(false)); // Now should be limited
+    }
+
+    #[test]
+    fn test_limiter_with_multiple_threads() {
+        let limiter = Arc::new(Mutex::new(Limiter::new()));
+        limiter
+            .lock()
+            .unwrap()
+            .add_limited_entity("user1", 5, Duration::from_secs(60));
+
+        let limiter_clone1 = Arc::clone(&limiter);
+        let limiter_clone2 = Arc::clone(&limiter);
+        let limiter_clone3 = Arc::clone(&limiter);
+
+        let thread1 = thread::spawn(move || {
+            for _ in 0..2 {
+                assert_eq!(
+                    limiter_clone1.lock().unwrap().is_entity_limited(&"user1"),
+                    Some(true)
+                );
+            }
+        });
+
+        let thread2 = thread::spawn(move || {
+            for _ in 0..2 {
+                assert_eq!(
+                    limiter_clone2.lock().unwrap().is_entity_limited(&"user1"),
+                    Some(true)
+                );
+            }
+        });
+
+        let thread3 = thread::spawn(move || {
+            assert_eq!(
+                limiter_clone3.lock().unwrap().is_entity_limited(&"user1"),
+                Some(true)
+            );
+        });
+
+        thread1.join().unwrap();
+        thread2.join().unwrap();
+        thread3.join().unwrap();
+
+        assert_eq!(
+            limiter.lock().unwrap().is_entity_limited(&"user1"),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_limiter_shared_by_clone() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 6, Duration::from_secs(60));
+
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    for _ in 0..2 {
+                        assert_eq!(limiter.is_entity_limited(&"user1"), Some(true));
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false));
+    }
+
+    #[test]
+    fn test_borrowed_key_lookups() {
+        let limiter: Limiter<String> = Limiter::new();
+        limiter.add_limited_entity("user1".to_string(), 2, Duration::from_secs(60));
+
+        assert_eq!(limiter.is_entity_limited("user1"), Some(true));
+        assert!(limiter.check("user1").is_allowed());
+        assert_eq!(limiter.get_bucket_remaining("user1"), Some(0));
+        assert_eq!(limiter.would_allow("user1"), Some(false));
+        assert_eq!(limiter.get_bucket_remaining("unknown_user"), None);
+
+        let removed = limiter.remove_limited_entity("user1").unwrap();
+        assert_eq!(removed.bucket_max(), 2);
+        assert_eq!(removed.remaining(), 0);
+    }
+
+    #[test]
+    fn test_try_consume() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+
+        let allowance = limiter.try_consume(&"user1").unwrap();
+        assert_eq!(allowance.remaining, 0);
+        match limiter.try_consume(&"user1") {
+            Err(RateGateError::LimitExceeded { retry_after }) => {
+                assert!(retry_after <= Duration::from_secs(60))
+            }
+            other => panic!("expected LimitExceeded, got {:?}", other),
+        }
+        assert!(matches!(
+            limiter.try_consume(&"unknown_user"),
+            Err(RateGateError::EntityNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_permit() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 2, Duration::from_secs(60));
+
+        let permit = limiter.acquire_permit(&"user1").unwrap();
+        assert_eq!(permit.remaining(), 1);
+        drop(permit);
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+
+        drop(limiter.acquire_permit(&"user1").unwrap().refund_on_drop());
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+        limiter.acquire_permit(&"user1").unwrap().refund();
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+
+        limiter
+            .acquire_permit(&"user1")
+            .unwrap()
+            .refund_on_drop()
+            .commit();
+        assert!(limiter.acquire_permit(&"user1").is_none());
+        assert!(limiter.acquire_permit(&"unknown_user").is_none());
+    }
+
+    #[test]
+    fn test_limit_concurrency() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 100, Duration::from_secs(60));
+        assert!(limiter.acquire_in_flight(&"user1").is_some());
+        assert!(limiter.limit_concurrency(&"user1", 2));
+        assert!(!limiter.limit_concurrency(&"unknown_user", 2));
+
+        let first = limiter.acquire_in_flight(&"user1").unwrap();
+        let second = limiter.acquire_in_flight(&"user1").unwrap();
+        assert!(limiter.acquire_in_flight(&"user1").is_none());
+        assert_eq!(limiter.in_flight(&"user1"), Some(2));
+
+        drop(first);
+        assert!(limiter.limit_concurrency(&"user1", 1));
+        assert!(limiter.acquire_in_flight(&"user1").is_none());
+        drop(second);
+        assert!(limiter.acquire_in_flight(&"user1").is_some());
+        // Only the in flight requests were counted, not the rate.
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(100));
+        assert!(limiter.acquire_in_flight(&"unknown_user").is_none());
+    }
+
+    #[test]
+    fn test_check_in_flight() {
+        let limiter: Limiter<&str> = Limiter::new();
+        let policy = Policy::new(2, Duration::from_secs(60)).with_max_in_flight(1);
+        limiter.add_limited_entity_with_policy("user1", policy);
+
+        let guard = limiter.check_in_flight(&"user1").unwrap();
+        assert!(matches!(
+            limiter.check_in_flight(&"user1"),
+            Err(RateGateError::TooManyInFlight)
+        ));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+        drop(guard);
+
+        // The token stays consumed after the request ends.
+        let guard = limiter.check_in_flight(&"user1").unwrap();
+        drop(guard);
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(0));
+        assert!(matches!(
+            limiter.check_in_flight(&"user1"),
+            Err(RateGateError::LimitExceeded { .. })
+        ));
+        assert_eq!(limiter.in_flight(&"user1"), Some(0));
+    }
+
+    #[test]
+    fn test_burst() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        let policy = Policy::new(5, Duration::from_secs(1)).with_burst(20);
+        limiter.add_limited_entity_with_policy("user1", policy);
+
+        for _ in 0..20 {
+            assert!(limiter.check(&"user1").is_allowed());
+        }
+        assert!(!limiter.check(&"user1").is_allowed());
+
+        // Refilled at the sustained rate, not the burst.
+        clock.advance(Duration::from_secs(1));
+        for _ in 0..5 {
+            assert!(limiter.check(&"user1").is_allowed());
+        }
+        assert!(!limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        let policy = Policy::new(30, Duration::from_secs(1)).with_warm_up(Duration::from_secs(10));
+        limiter.add_limited_entity_with_policy("user1", policy);
+        let allowed = || {
+            std::iter::from_fn(|| Some(limiter.check(&"user1").is_allowed()))
+                .take_while(|&allowed| allowed)
+                .count()
+        };
+
+        // Cold, a third of the bucket.
+        assert_eq!(allowed(), 10);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(allowed(), 20);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(allowed(), 30);
+
+        // Idle for the warm-up period, cold again.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(allowed(), 10);
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("api", 10, Duration::from_secs(60));
+        assert_eq!(limiter.report_success(&"api"), None);
+        assert!(limiter.make_adaptive(&"api", Aimd::new(2, 12).increase(2)));
+        assert!(!limiter.make_adaptive(&"unknown", Aimd::new(2, 12)));
+
+        assert!(limiter.check(&"api").is_allowed());
+        assert_eq!(limiter.report_success(&"api"), Some(12));
+        assert_eq!(limiter.report_success(&"api"), Some(12));
+        assert_eq!(limiter.get_bucket_remaining(&"api"), Some(11));
+
+        assert_eq!(limiter.report_failure(&"api"), Some(6));
+        assert_eq!(limiter.get_bucket_remaining(&"api"), Some(5));
+        assert_eq!(limiter.report_failure(&"api"), Some(3));
+        assert_eq!(limiter.report_failure(&"api"), Some(2));
+        assert_eq!(limiter.get_bucket_remaining(&"api"), Some(1));
+
+        let policy = Policy::new(50, Duration::from_secs(60)).with_adaptive(Aimd::new(1, 20));
+        limiter.add_limited_entity_with_policy("pooled", policy);
+        assert_eq!(limiter.get_bucket_remaining(&"pooled"), Some(20));
+        assert_eq!(limiter.report_failure(&"pooled"), Some(10));
+    }
+
+    #[test]
+    fn test_aligned_windows() {
+        let clock = ManualClock::new();
+        let day = Duration::from_secs(86_400);
+        // 23:59:30 UTC
+        clock.set_system_time(SystemTime::UNIX_EPOCH + day * 20_000 - Duration::from_secs(30));
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        let policy = Policy::new(2, day).with_aligned_windows();
+        limiter.add_limited_entity_with_policy("user1", policy);
+
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(limiter.check(&"user1").is_allowed());
+        assert_eq!(
+            limiter.check(&"user1"),
+            Decision::Denied {
+                retry_after: Duration::from_secs(30)
+            }
+        );
+
+        // A new calendar day, with a full day ahead.
+        clock.advance(Duration::from_secs(30));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert_eq!(limiter.retry_after(&"user1"), Some(Duration::ZERO));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert_eq!(limiter.retry_after(&"user1"), Some(day));
+    }
+
+    #[test]
+    fn test_tier_resolver() {
+        let resolver = |key: &String| match key.as_str() {
+            "pro-key" => Some("pro".to_string()),
+            "lost-key" => Some("legacy".to_string()),
+            _ => None,
+        };
+        let limiter: Limiter<String> = LimiterBuilder::new()
+            .policy("pro", Policy::new(3, Duration::from_secs(60)))
+            .build()
+            .with_tier_resolver(resolver);
+
+        assert!(limiter.consume("pro-key", 3).is_allowed());
+        assert!(!limiter.check("pro-key").is_allowed());
+        // Without a policy for the tier or a default limit, unknown.
+        assert_eq!(limiter.check("lost-key"), Decision::Unknown);
+        assert_eq!(limiter.check("free-key"), Decision::Unknown);
+
+        let limiter: Limiter<String> = LimiterBuilder::new()
+            .default_limit(1, Duration::from_secs(60))
+            .policy("pro", Policy::new(3, Duration::from_secs(60)))
+            .build()
+            .with_tier_resolver(resolver);
+        assert!(limiter.check("pro-key").is_allowed());
+        assert_eq!(limiter.get_bucket_remaining("pro-key"), Some(2));
+        assert!(limiter.check("lost-key").is_allowed());
+        assert!(!limiter.check("lost-key").is_allowed());
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        let clock = ManualClock::new();
+        let hour = Duration::from_secs(3600);
+        let minute = Duration::from_secs(60);
+        // 08:59:30 UTC
+        let day = Duration::from_secs(86_400);
+        clock.set_system_time(SystemTime::UNIX_EPOCH + day * 20_000 + hour * 9 - minute / 2);
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        let policy = Policy::new(2, minute).during(hour * 9..hour * 17, 5, minute);
+        limiter.add_limited_entity_with_policy("user1", policy);
+
+        assert!(limiter.consume(&"user1", 2).is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+        assert_eq!(limiter.rate_limit_headers(&"user1").unwrap().limit, 2);
+
+        // Business hours, what was consumed is kept.
+        clock.advance(minute / 2);
+        assert_eq!(limiter.rate_limit_headers(&"user1").unwrap().limit, 5);
+        assert!(limiter.consume(&"user1", 3).is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+
+        clock.advance(hour * 8);
+        assert_eq!(limiter.rate_limit_headers(&"user1").unwrap().limit, 2);
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let clock = ManualClock::new();
+        let second = Duration::from_secs(1);
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        let policy = Policy::new(2, second).with_cooldown(second * 10);
+        limiter.add_limited_entity_with_policy("user1", policy);
+
+        assert!(limiter.consume(&"user1", 2).is_allowed());
+        assert_eq!(limiter.check(&"user1").retry_after(), Some(second * 10));
+
+        // Refilled, but still cooling down.
+        clock.advance(second * 5);
+        assert!(!limiter.check(&"user1").is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(0));
+        assert_eq!(limiter.retry_after(&"user1"), Some(second * 5));
+
+        clock.advance(second * 5);
+        assert!(limiter.check(&"user1").is_allowed());
+
+        assert!(limiter.set_cooldown(&"user1", None));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+        clock.advance(second);
+        assert!(limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_jitter() {
+        let clock = ManualClock::new();
+        let minute = Duration::from_secs(60);
+        let policy = Policy::new(1, minute).with_jitter(10).with_jitter_seed(7);
+        let retries = || {
+            // Every user gets the one policy on their first request.
+            let limiter: Limiter<usize> = LimiterBuilder::new()
+                .clock(clock.clone())
+                .policy("free", policy.clone())
+                .build()
+                .with_tier_resolver(|_: &usize| Some("free".to_string()));
+            // Denied at the same instant, told to come back at different ones.
+            let retries: Vec<_> = (0..20)
+                .map(|user| {
+                    assert!(limiter.check(&user).is_allowed());
+                    limiter.check(&user).retry_after().unwrap()
+                })
+                .collect();
+            (limiter, retries)
+        };
+        let (limiter, first) = retries();
+        let distinct: std::collections::HashSet<_> = first.iter().collect();
+        assert!(distinct.len() > 10);
+        for &retry_after in &first {
+            assert!(retry_after >= minute * 9 / 10 && retry_after <= minute * 11 / 10);
+        }
+        // The same windows with the same seed.
+        assert_eq!(retries().1, first);
+
+        clock.advance(minute * 11 / 10);
+        assert!((0..20).all(|user| limiter.check(&user).is_allowed()));
+    }
+
+    #[test]
+    fn test_overdraft() {
+        let clock = ManualClock::new();
+        let minute = Duration::from_secs(60);
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity_with_policy("user1", Policy::new(2, minute).with_overdraft(3));
+
+        // Beyond the limit up to the ceiling.
+        assert!(limiter.consume(&"user1", 2).is_allowed());
+        assert!(limiter.consume(&"user1", 3).is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+        assert_eq!(limiter.debt(&"user1"), Some(3));
+
+        // The refill goes to the debt first.
+        clock.advance(minute);
+        assert_eq!(limiter.debt(&"user1"), Some(1));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(0));
+        assert!(limiter.consume(&"user1", 2).is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+
+        // Without an overdraft, the debt is still repaid.
+        assert!(limiter.set_overdraft(&"user1", 0));
+        clock.advance(minute);
+        assert_eq!(limiter.debt(&"user1"), Some(1));
+        assert!(!limiter.check(&"user1").is_allowed());
+        clock.advance(minute);
+        assert!(limiter.check(&"user1").is_allowed());
+        assert_eq!(limiter.debt(&"user1"), Some(0));
+    }
+
+    #[cfg(feature = "cron")]
+    #[test]
+    fn test_reset_schedule() {
+        let clock = ManualClock::new();
+        let day = Duration::from_secs(86_400);
+        // 23:59:30 UTC
+        clock.set_system_time(SystemTime::UNIX_EPOCH + day * 20_000 - Duration::from_secs(30));
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        let midnight = ResetSchedule::parse("0 0 * * *").unwrap();
+        let policy = Policy::new(2, day).with_reset_schedule(midnight);
+        limiter.add_limited_entity_with_policy("user1", policy);
+
+        assert!(limiter.consume(&"user1", 2).is_allowed());
+        assert_eq!(limiter.retry_after(&"user1"), Some(Duration::from_secs(30)));
+
+        // Reset at midnight, the next window lasts until the following one.
+        clock.advance(Duration::from_secs(30));
+        assert!(limiter.consume(&"user1", 2).is_allowed());
+        assert_eq!(limiter.retry_after(&"user1"), Some(day));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_sync_from_headers() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity("api", 10, Duration::from_secs(60));
+        limiter.add_limited_entity_with_algorithm(
+            "bucket",
+            10,
+            Duration::from_secs(10),
+            Algorithm::TokenBucket,
+        );
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = http::HeaderMap::new();
+            for &(name, value) in pairs {
+                headers.insert(name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        let synced = headers(&[("x-ratelimit-remaining", "3"), ("x-ratelimit-reset", "20")]);
+        assert!(limiter.sync_from_headers(&"api", &synced));
+        assert!(!limiter.sync_from_headers(&"unknown", &synced));
+        assert_eq!(limiter.get_bucket_remaining(&"api"), Some(3));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(limiter.get_bucket_remaining(&"api"), Some(10));
+
+        let throttled = headers(&[("retry-after", "30")]);
+        for entity in ["api", "bucket"] {
+            assert!(limiter.sync_from_headers(&entity, &throttled));
+            assert_eq!(
+                limiter.check(&entity),
+                Decision::Denied {
+                    retry_after: Duration::from_secs(30)
+                }
+            );
+        }
+        clock.advance(Duration::from_secs(30));
+        assert!(limiter.check(&"api").is_allowed());
+        assert!(limiter.check(&"bucket").is_allowed());
+        assert!(!limiter.check(&"bucket").is_allowed());
+
+        assert!(limiter.sync_from_headers(&"bucket", &headers(&[("ratelimit-remaining", "5")])));
+        assert_eq!(limiter.get_bucket_remaining(&"bucket"), Some(5));
+
+        // A server can't push the bucket past what an `Instant` holds.
+        let bogus = headers(&[("retry-after", "18446744073709551615")]);
+        assert!(limiter.sync_from_headers(&"api", &bogus));
+        assert!(!limiter.check(&"api").is_allowed());
+    }
+
+    #[test]
+    fn test_check_many() {
+        let limiter: Limiter<&str> = LimiterBuilder::new()
+            .shards(4)
+            .default_limit(1, Duration::from_secs(60))
+            .build();
+        limiter.add_limited_entity("ip", 2, Duration::from_secs(60));
+        limiter.add_limited_entity("user", 1, Duration::from_secs(60));
+        limiter.ban("banned", Duration::from_secs(60));
+
+        let decisions = limiter.check_many(&[&"ip", &"user", &"key", &"banned"]);
+        assert!(decisions[0].is_allowed());
+        assert!(decisions[1].is_allowed());
+        assert!(decisions[2].is_allowed()); // added with the default limit
+        assert!(matches!(decisions[3], Decision::Banned { .. }));
+
+        let decisions = limiter.check_many(&[&"ip", &"user", &"key"]);
+        assert_eq!(
+            decisions
+                .iter()
+                .map(Decision::is_allowed)
+                .collect::<Vec<_>>(),
+            [true, false, false]
+        );
+        assert!(limiter.check_many::<&str>(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_consume_all() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("ip", 10, Duration::from_secs(60));
+        limiter.add_limited_entity("key", 3, Duration::from_secs(60));
+
+        assert!(matches!(
+            limiter.consume_all(&[(&"ip", 2), (&"key", 2)]),
+            Decision::Allowed { remaining: 1, .. }
+        ));
+        // The key is out of requests, the IP keeps its own.
+        assert!(matches!(
+            limiter.consume_all(&[(&"ip", 2), (&"key", 2)]),
+            Decision::Denied { .. }
+        ));
+        assert_eq!(limiter.get_bucket_remaining(&"ip"), Some(8));
+        assert_eq!(limiter.get_bucket_remaining(&"key"), Some(1));
+
+        assert_eq!(
+            limiter.consume_all(&[(&"ip", 1), (&"unknown", 1)]),
+            Decision::Unknown
+        );
+        assert_eq!(limiter.get_bucket_remaining(&"ip"), Some(8));
+
+        limiter.ban("key", Duration::from_secs(60));
+        assert!(matches!(
+            limiter.consume_all(&[(&"ip", 1), (&"key", 1)]),
+            Decision::Banned { .. }
+        ));
+        assert_eq!(limiter.get_bucket_remaining(&"ip"), Some(8));
+    }
+
+    #[test]
+    fn test_consume_all_gives_back_to_parents() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("org", 10, Duration::from_secs(60));
+        assert!(limiter.add_child_entity(&"org", "user", 5, Duration::from_secs(60)));
+        limiter.add_limited_entity("ip", 1, Duration::from_secs(60));
+        assert!(limiter.check(&"ip").is_allowed());
+
+        assert!(!limiter
+            .consume_all(&[(&"user", 2), (&"ip", 1)])
+            .is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&"org"), Some(10));
+        assert_eq!(limiter.get_bucket_remaining(&"user"), Some(5));
+    }
+
+    #[test]
+    fn test_consume_all_counts_as_access() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new()
+            .clock(clock.clone())
+            .idle_ttl(Duration::from_secs(10))
+            .build();
+        limiter.add_limited_entity("ip", 10, Duration::from_secs(60));
+        limiter.add_limited_entity("key", 1, Duration::from_secs(60));
+
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(5));
+            limiter.consume_all(&[(&"ip", 1), (&"key", 1)]);
+            assert_eq!(limiter.evict_idle(), 0);
+        }
+        // Given back when the key was denied, so not allowed either.
+        let ip = limiter.stats(&"ip").unwrap();
+        assert_eq!((ip.allowed, ip.denied), (1, 0));
+        let key = limiter.stats(&"key").unwrap();
+        assert_eq!((key.allowed, key.denied), (1, 2));
+        assert_eq!(limiter.get_bucket_remaining(&"ip"), Some(9));
+    }
+
+    #[test]
+    fn test_add_limited_entities() {
+        let limiter: Limiter<u32> = LimiterBuilder::new().shards(4).build();
+        let policy = Policy::new(2, Duration::from_secs(60));
+        limiter.add_limited_entities((0..1000).map(|key| (key, policy.clone())));
+
+        assert_eq!(limiter.len(), 1000);
+        assert_eq!(limiter.get_bucket_remaining(&999), Some(2));
+        assert!(limiter.check(&0).is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&0), Some(1));
+    }
+
+    #[test]
+    fn test_check_or_add() {
+        let limiter: Limiter<&str> = Limiter::new();
+
+        assert!(matches!(
+            limiter.check_or_add("user1", 2, Duration::from_secs(60)),
+            Decision::Allowed { remaining: 1, .. }
+        ));
+        // Existing entities keep their limits.
+        assert!(matches!(
+            limiter.check_or_add("user1", 10, Duration::from_secs(60)),
+            Decision::Allowed { remaining: 0, .. }
+        ));
+        assert!(!limiter
+            .check_or_add("user1", 10, Duration::from_secs(60))
+            .is_allowed());
+        assert_eq!(limiter.get_bucket_remaining("user2"), None);
+    }
+
+    #[test]
+    fn test_check_or_add_concurrently() {
+        let limiter: Limiter<&str> = Limiter::new();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    (0..5)
+                        .filter(|_| {
+                            limiter
+                                .check_or_add("user1", 10, Duration::from_secs(60))
+                                .is_allowed()
+                        })
+                        .count()
+                })
+            })
+            .collect();
+
+        let allowed: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(allowed, 10);
+    }
+
+    #[test]
+    fn test_default_limit_for_unknown_entities() {
+        let limiter: Limiter<String> = Limiter::with_default(2, Duration::from_secs(60));
+        limiter.add_limited_entity("user1".to_string(), 5, Duration::from_secs(60));
+
+        assert_eq!(limiter.get_bucket_remaining("user2"), Some(2));
+        assert_eq!(limiter.would_allow("user2"), Some(true));
+        assert_eq!(limiter.is_entity_limited("user2"), Some(true));
+        assert_eq!(limiter.is_entity_limited("user2"), Some(true));
+        assert_eq!(limiter.is_entity_limited("user2"), Some(false));
+        assert!(limiter.retry_after("user2").unwrap() > Duration::ZERO);
+
+        // Explicitly added entities keep their own limits.
+        assert_eq!(limiter.get_bucket_remaining("user1"), Some(5));
+    }
+
+    #[test]
+    fn test_sharded_limiter_with_many_entities() {
+        let limiter: Limiter<u32> = LimiterBuilder::new().shards(8).build();
+        for entity in 0..64 {
+            limiter.add_limited_entity(entity, 100, Duration::from_secs(60));
+        }
+
+        let handles: Vec<_> = (0..8)
+            .map(|thread| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    // Every thread hammers its own entities and a shared one.
+                    for entity in (thread..64).step_by(8) {
+                        for _ in 0..100 {
+                            assert!(limiter.check(&entity).is_allowed());
+                        }
+                        assert!(!limiter.check(&entity).is_allowed());
+                        limiter.check(&0);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(limiter.get_bucket_remaining(&0), Some(0));
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new()
+            .default_limit(5, Duration::from_secs(60))
+            .idle_ttl(Duration::from_millis(50))
+            .clock(clock.clone())
+            .build();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+        limiter.check(&"user2");
+
+        assert_eq!(limiter.evict_idle(), 0);
+        clock.advance(Duration::from_millis(40));
+        limiter.check(&"user2");
+        clock.advance(Duration::from_millis(20));
+
+        assert_eq!(limiter.evict_idle(), 1);
+        assert!(!limiter.requests.read("user1").contains_key("user1"));
+        assert_eq!(limiter.get_bucket_remaining("user2"), Some(3));
+    }
+
+    #[test]
+    fn test_evict_idle_keeps_new_aligned_entities() {
+        let clock = ManualClock::new();
+        let day = Duration::from_secs(86_400);
+        // Noon UTC, half a day into the aligned window.
+        clock.set_system_time(SystemTime::UNIX_EPOCH + day * 20_000 + day / 2);
+        let builder = LimiterBuilder::new()
+            .idle_ttl(Duration::from_secs(60))
+            .clock(clock.clone());
+        let limiter: Limiter<&str> = builder.clone().build();
+        let policy = Policy::new(2, day).with_aligned_windows();
+        limiter.add_limited_entity_with_policy("user1", policy);
+
+        assert_eq!(limiter.evict_idle(), 0);
+        assert_eq!(limiter.stats(&"user1").unwrap().last_access, clock.now());
+
+        let restored: Limiter<&str> = builder.build();
+        restored.import_state(limiter.export_state());
+        assert_eq!(restored.evict_idle(), 0);
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(limiter.evict_idle(), 1);
+        assert_eq!(restored.evict_idle(), 1);
+    }
+
+    #[test]
+    fn test_evict_idle_without_ttl() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 5, Duration::ZERO);
+        assert_eq!(limiter.evict_idle(), 0);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn test_spawn_cleanup() {
+        let limiter: Limiter<&str> = LimiterBuilder::new()
+            .idle_ttl(Duration::from_millis(20))
+            .build();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+        let cleanup = limiter.spawn_cleanup(Duration::from_millis(10));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(limiter.get_bucket_remaining("user1"), None);
+
+        // The task stops once the limiter is dropped.
+        drop(limiter);
+        tokio::time::timeout(Duration::from_secs(1), cleanup)
+            .await
+            .expect("cleanup task should stop")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_max_entities_evicts_least_recently_used() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new()
+            .shards(1)
+            .max_entities(2)
+            .clock(clock.clone())
+            .build();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+        limiter.add_limited_entity("user2", 5, Duration::from_secs(60));
+        clock.advance(Duration::from_millis(5));
+        limiter.check(&"user1");
+
+        limiter.check_or_add("user3", 5, Duration::from_secs(60));
+        assert!(limiter.get_bucket_remaining("user1").is_some());
+        assert!(limiter.get_bucket_remaining("user2").is_none());
+        assert!(limiter.get_bucket_remaining("user3").is_some());
+    }
+
+    #[test]
+    fn test_max_entities_bounds_memory() {
+        let limiter: Limiter<u32> = Limiter::with_max_entities(64);
+        for entity in 0..10_000 {
+            limiter.check_or_add(entity, 5, Duration::from_secs(60));
+        }
+
+        let shards = shards::default_shard_count().next_power_of_two();
+        assert!(limiter.len() <= 64usize.next_multiple_of(shards));
+    }
+
+    #[cfg(feature = "dashmap")]
+    #[test]
+    fn test_dashmap_storage() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<String> = LimiterBuilder::new()
+            .storage(Storage::DashMap)
+            .shards(4)
+            .max_entities(64)
+            .idle_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .build();
+        for entity in 0..1000 {
+            limiter.check_or_add(entity.to_string(), 2, Duration::from_secs(60));
+        }
+        assert!(limiter.len() <= 64);
+
+        limiter.add_limited_entity("user1".to_string(), 2, Duration::from_secs(60));
+        assert!(limiter.consume("user1", 2).is_allowed());
+        assert!(!limiter.check("user1").is_allowed());
+        assert_eq!(limiter.get_bucket_remaining("user1"), Some(0));
+        assert!(limiter
+            .snapshot()
+            .iter()
+            .any(|(entity, _)| entity == "user1"));
+
+        clock.advance(Duration::from_secs(61));
+        assert!(limiter.check("user1").is_allowed());
+        let tracked = limiter.len();
+        assert_eq!(limiter.evict_idle(), tracked - 1);
+        assert_eq!(limiter.len(), 1);
+        assert!(limiter.remove_limited_entity("user1").is_some());
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn test_hasher_and_capacity() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        let hasher = BuildHasherDefault::<DefaultHasher>::default();
+        let limiter: Limiter<u32, _> = Limiter::with_hasher_and_capacity(hasher, 1000);
+        let capacity = |limiter: &Limiter<u32, _>| -> usize {
+            (0..limiter.requests.count())
+                .map(|index| limiter.requests.write_shard(index).capacity())
+                .sum()
+        };
+        assert!(capacity(&limiter) >= 1000);
+
+        for entity in 0..100 {
+            limiter.check_or_add(entity, 1, Duration::from_secs(60));
+        }
+        assert!(!limiter.check(&7).is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&8), Some(0));
+
+        limiter.shrink_to_fit();
+        assert!(capacity(&limiter) < 1000);
+        limiter.reserve_capacity(5000);
+        assert!(capacity(&limiter) >= 5000);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 2, Duration::from_secs(60));
+        limiter.add_limited_entity_with_algorithm(
+            "user2",
+            1,
+            Duration::from_secs(30),
+            Algorithm::TokenBucket,
+        );
+        limiter.check(&"user1");
+        limiter.check(&"user2");
+
+        let mut snapshot = limiter.snapshot();
+        snapshot.sort_by_key(|(entity, _)| *entity);
+        assert_eq!(snapshot.len(), 2);
+
+        let (entity, user1) = snapshot[0];
+        assert_eq!(entity, "user1");
+        assert_eq!((user1.remaining, user1.max), (1, 2));
+        assert!(!user1.is_limited());
+        assert!(user1.reset_in <= Duration::from_secs(60));
+
+        let (entity, user2) = snapshot[1];
+        assert_eq!(entity, "user2");
+        assert_eq!(user2.algorithm, Algorithm::TokenBucket);
+        assert_eq!(user2.remaining, 0);
+        assert!(user2.is_limited());
+        assert!(user2.retry_after > Duration::from_secs(29));
+    }
+
+    #[test]
+    fn test_len_is_empty_and_clear() {
+        let limiter: Limiter<&str> = Limiter::new();
+        assert!(limiter.is_empty());
+        assert_eq!(limiter.len(), 0);
+
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+        limiter.add_limited_entity("user2", 5, Duration::from_secs(60));
+        limiter.add_limited_entity("user2", 10, Duration::from_secs(60));
+        assert!(!limiter.is_empty());
+        assert_eq!(limiter.len(), 2);
+
+        limiter.clear();
+        assert!(limiter.is_empty());
+        assert_eq!(limiter.is_entity_limited(&"user1"), None);
+    }
+
+    #[test]
+    fn test_update_limit() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+        limiter.consume(&"user1", 3);
+
+        assert!(limiter.update_limit(&"user1", 10, Duration::from_secs(60)));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(7));
+
+        assert!(limiter.update_limit_scaled(&"user1", 5, Duration::from_secs(60)));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(3));
+
+        assert!(!limiter.update_limit(&"unknown_user", 10, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_reset_bucket_and_penalize() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+
+        assert!(limiter.penalize(&"user1", 4));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+        assert!(limiter.penalize(&"user1", 4));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(0));
+
+        assert!(limiter.reset_bucket(&"user1"));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(5));
+
+        assert!(!limiter.reset_bucket(&"unknown_user"));
+        assert!(!limiter.penalize(&"unknown_user", 1));
+    }
+
+    #[test]
+    fn test_refund() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 2, Duration::from_secs(60));
+        limiter.consume(&"user1", 2);
+        assert!(!limiter.check(&"user1").is_allowed());
+
+        assert!(limiter.refund(&"user1", 1));
+        assert!(limiter.check(&"user1").is_allowed());
+
+        assert!(limiter.refund(&"user1", 10));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(2));
+        assert!(!limiter.refund(&"unknown_user", 1));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 2, Duration::from_secs(60));
+
+        let reservation = limiter.reserve(&"user1").unwrap();
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+        drop(reservation);
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(2));
+
+        limiter.reserve_many(&"user1", 2).unwrap().rollback();
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(2));
+
+        limiter.reserve(&"user1").unwrap().commit();
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+
+        assert!(limiter.reserve_many(&"user1", 2).is_none());
+        assert!(limiter.reserve(&"unknown_user").is_none());
+    }
+
+    #[test]
+    fn test_ban() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+
+        limiter.ban("user1", Duration::from_millis(100));
+        assert!(matches!(limiter.check(&"user1"), Decision::Banned { .. }));
+        assert_eq!(limiter.is_entity_limited(&"user1"), Some(false));
+        assert_eq!(limiter.would_allow(&"user1"), Some(false));
+        assert!(limiter.retry_after(&"user1").unwrap() > Duration::ZERO);
+        // The bucket is left alone while banned.
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(5));
+
+        clock.advance(Duration::from_millis(150));
+        assert_eq!(limiter.banned_for(&"user1"), None);
+        assert!(limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_ban_forever() {
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+
+        limiter.ban("user1", Duration::MAX);
+        clock.advance(Duration::from_secs(86_400 * 365));
+        assert_eq!(limiter.banned_for(&"user1"), Some(Duration::MAX));
+        assert_eq!(
+            limiter.check(&"user1"),
+            Decision::Banned {
+                retry_after: Duration::MAX
+            }
+        );
+        assert!(limiter.unban(&"user1"));
+        assert!(limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_unban() {
+        let limiter: Limiter<&str> = Limiter::with_default(5, Duration::from_secs(60));
+
+        limiter.ban("user1", Duration::from_secs(60));
+        assert!(limiter.banned_for(&"user1").is_some());
+        assert!(matches!(limiter.check(&"user1"), Decision::Banned { .. }));
+
+        assert!(limiter.unban(&"user1"));
+        assert!(!limiter.unban(&"user1"));
+        assert!(limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_disable() {
+        let limiter: Limiter<&str> = Limiter::with_default(5, Duration::from_secs(60));
+        limiter.disable("user1");
+
+        assert!(limiter.is_disabled(&"user1"));
+        assert_eq!(
+            limiter.check(&"user1"),
+            Decision::Banned {
+                retry_after: Duration::MAX
+            }
+        );
+        assert_eq!(limiter.retry_after(&"user1"), Some(Duration::MAX));
+        assert_eq!(limiter.banned_for(&"user1"), None);
+
+        assert!(limiter.enable(&"user1"));
+        assert!(!limiter.enable(&"user1"));
+        assert!(limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_mode() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+        limiter.ban("user2", Duration::from_secs(60));
+        let clone = limiter.clone();
+
+        clone.set_mode(Mode::AllowAll);
+        assert_eq!(limiter.mode(), Mode::AllowAll);
+        for _ in 0..3 {
+            assert!(limiter.check(&"user1").is_allowed());
+            assert!(limiter.check(&"user2").is_allowed());
+        }
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+
+        limiter.set_mode(Mode::DenyAll);
+        assert!(matches!(limiter.check(&"user1"), Decision::Banned { .. }));
+
+        limiter.set_mode(Mode::Enforce);
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+        assert!(matches!(limiter.check(&"user2"), Decision::Banned { .. }));
+    }
+
+    #[test]
+    fn test_allow_unlimited() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+        limiter.allow_unlimited("user1");
+        limiter.allow_unlimited("health_checker");
+
+        for _ in 0..10 {
+            assert!(limiter.check(&"user1").is_allowed());
+            assert_eq!(limiter.is_entity_limited(&"health_checker"), Some(true));
+        }
+        assert!(limiter.is_unlimited(&"user1"));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+        assert_eq!(limiter.would_allow(&"health_checker"), Some(true));
+
+        assert!(limiter.revoke_unlimited(&"user1"));
+        assert!(!limiter.revoke_unlimited(&"user1"));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+    }
+
+    #[test]
+    fn test_escalation() {
+        let limiter: Limiter<&str> = LimiterBuilder::new()
+            .escalation(Escalation::new(2, Duration::from_secs(600)))
+            .build();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+
+        assert!(limiter.check(&"user1").is_allowed());
+        let Decision::Denied { retry_after } = limiter.check(&"user1") else {
+            panic!("expected a denied request");
+        };
+        assert!(retry_after <= Duration::from_secs(60));
+        let Decision::Denied { retry_after } = limiter.check(&"user1") else {
+            panic!("expected a denied request");
+        };
+        assert!(retry_after > Duration::from_secs(60));
+
+        let requests = limiter.requests.read("user1");
+        assert_eq!(requests["user1"].lock().penalty_level(), 1);
+        assert_eq!(
+            requests["user1"].lock().refresh_rate(),
+            Duration::from_secs(120)
+        );
+        drop(requests);
+
+        assert!(limiter.reset_bucket(&"user1"));
+        assert_eq!(limiter.retry_after(&"user1"), Some(Duration::ZERO));
+        let requests = limiter.requests.read("user1");
+        assert_eq!(requests["user1"].lock().penalty_level(), 0);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (denied, refilled, evicted) = (events.clone(), events.clone(), events.clone());
+        let clock = ManualClock::new();
+        let limiter: Limiter<&str> = LimiterBuilder::new()
+            .shards(1)
+            .max_entities(1)
+            .clock(clock.clone())
+            .build()
+            .with_hooks(
+                Hooks::new()
+                    .on_denied(move |entity, info| {
+                        assert_eq!(info.cost, 1);
+                        denied.lock().unwrap().push(format!("denied {}", entity));
+                    })
+                    .on_refill(move |entity| {
+                        refilled
+                            .lock()
+                            .unwrap()
+                            .push(format!("refilled {}", entity))
+                    })
+                    .on_evicted(move |entity, _| {
+                        evicted.lock().unwrap().push(format!("evicted {}", entity))
+                    }),
+            );
+        limiter.add_limited_entity("user1", 1, Duration::from_millis(50));
+
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+        clock.advance(Duration::from_millis(60));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+        limiter.add_limited_entity("user2", 1, Duration::from_secs(60));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "denied user1",
+                "refilled user1",
+                "denied user1",
+                "evicted user1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 2, Duration::from_secs(60));
+        let added = limiter.stats(&"user1").unwrap().last_access;
+
+        for _ in 0..5 {
+            limiter.check(&"user1");
+        }
+        let stats = limiter.stats(&"user1").unwrap();
+        assert_eq!(stats.allowed, 2);
+        assert_eq!(stats.denied, 3);
+        assert!(stats.last_access >= added);
+        assert!(limiter.stats(&"unknown_user").is_none());
+    }
+
+    #[test]
+    fn test_state() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 2, Duration::from_secs(60));
+        limiter.consume(&"user1", 2);
+
+        let state = limiter.state(&"user1").unwrap();
+        assert_eq!((state.remaining, state.max), (0, 2));
+        assert!(state.is_limited());
+        assert!(limiter.state(&"unknown_user").is_none());
+    }
+
+    #[test]
+    fn test_top_offenders() {
+        let limiter: Limiter<&str> = Limiter::with_default(1, Duration::from_secs(60));
+        for (user, checks) in [("user1", 3), ("user2", 5), ("user3", 1)] {
+            for _ in 0..checks {
+                limiter.check(&user);
+            }
+        }
+
+        let offenders = limiter.top_offenders(5);
+        let denied: Vec<_> = offenders
+            .iter()
+            .map(|(user, stats)| (*user, stats.denied))
+            .collect();
+        assert_eq!(denied, vec![("user2", 4), ("user1", 2)]);
+        assert_eq!(limiter.top_offenders(1).len(), 1);
+    }
+
+    #[test]
+    fn test_global_stats() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+        limiter.ban("user2", Duration::from_secs(60));
+
+        limiter.check(&"user1");
+        limiter.check(&"user1");
+        limiter.check(&"user2");
+        limiter.check_or_add("user3", 1, Duration::from_secs(60));
+
+        let stats = limiter.global_stats();
+        assert_eq!(stats.checks, 4);
+        assert_eq!(stats.allowed, 2);
+        assert_eq!(stats.denied, 2);
+        assert_eq!(stats.entities, 2);
+        assert_eq!(stats.denial_rate(), 0.5);
+
+        limiter.reset_global_stats();
+        let stats = limiter.global_stats();
+        assert_eq!((stats.checks, stats.denied, stats.entities), (0, 0, 2));
+    }
+
+    #[test]
+    fn test_export_and_import_state() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+        limiter.add_limited_entity_with_algorithm(
+            "user2",
+            5,
+            Duration::from_secs(60),
+            Algorithm::Gcra,
+        );
+        limiter.consume(&"user1", 3);
+        limiter.consume(&"user2", 5);
+
+        let snapshot = limiter.export_state();
+        assert_eq!(snapshot.entities.len(), 2);
+
+        let restored: Limiter<&str> = Limiter::new();
+        restored.import_state(snapshot);
+        assert_eq!(restored.get_bucket_remaining(&"user1"), Some(2));
+        assert!(!restored.check(&"user2").is_allowed());
+        assert!(restored.retry_after(&"user1").unwrap() == Duration::ZERO);
+        assert!(restored.retry_after(&"user2").unwrap() > Duration::from_secs(10));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_state_survives_json() {
+        let limiter: Limiter<String> = Limiter::new();
+        limiter.add_limited_entity_with_algorithm(
+            "user1".to_string(),
+            5,
+            Duration::from_secs(60),
+            Algorithm::SlidingWindowLog,
+        );
+        limiter.consume("user1", 2);
+
+        let json = serde_json::to_string(&limiter.export_state()).unwrap();
+        let restored: Limiter<String> = Limiter::new();
+        restored.import_state(serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.get_bucket_remaining("user1"), Some(3));
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let clock = ManualClock::new();
+        clock.set_system_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_760_000_000));
+        let limiter: Limiter<&str> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
+        limiter.consume(&"user1", 2);
+
+        let headers = limiter.rate_limit_headers(&"user1").unwrap();
+        assert_eq!(headers.limit, 5);
+        assert_eq!(headers.remaining, 3);
+        assert_eq!(headers.window, Duration::from_secs(60));
+        assert_eq!(headers.reset, Duration::from_secs(60));
+        assert_eq!(headers.reset_at, 1_760_000_060);
+        assert_eq!(limiter.rate_limit_headers(&"unknown_user"), None);
+    }
+
+    #[test]
+    fn test_named_policies() {
+        let limiter: Limiter<&str> = LimiterBuilder::new()
+            .policy("free_tier", Policy::new(1, Duration::from_secs(60)))
+            .build();
+        limiter.define_policy(
+            "pro_tier",
+            Policy::new(2, Duration::from_secs(60)).with_algorithm(Algorithm::Gcra),
+        );
+
+        assert!(limiter.add_limited_entity_with_named_policy("user1", "free_tier"));
+        assert!(limiter.add_limited_entity_with_named_policy("user2", "pro_tier"));
+        assert!(!limiter.add_limited_entity_with_named_policy("user3", "unknown_tier"));
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+        assert_eq!(limiter.get_bucket_remaining(&"user2"), Some(2));
+        assert_eq!(limiter.get_bucket_remaining(&"user3"), None);
+        let (_, state) = limiter
+            .snapshot()
+            .into_iter()
+            .find(|(entity, _)| *entity == "user2")
+            .unwrap();
+        assert_eq!(state.algorithm, Algorithm::Gcra);
+    }
+
+    #[test]
+    fn test_add_child_entity() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("org1", 3, Duration::from_secs(60));
+        assert!(limiter.add_child_entity(&"org1", "key1", 2, Duration::from_secs(60)));
+        assert!(limiter.add_child_entity(&"org1", "key2", 2, Duration::from_secs(60)));
+        assert!(!limiter.add_child_entity(&"unknown_org", "key3", 2, Duration::from_secs(60)));
+
+        assert!(limiter.consume(&"key1", 2).is_allowed());
+        assert!(!limiter.check(&"key1").is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&"key2"), Some(1));
+        assert!(limiter.check(&"key2").is_allowed());
+
+        // The organization's quota is used up by its keys.
+        assert!(!limiter.check(&"key2").is_allowed());
+        assert!(!limiter.check(&"org1").is_allowed());
+        assert!(limiter.retry_after(&"key2").unwrap() > Duration::from_secs(50));
+        assert!(limiter.reset_bucket(&"org1"));
+        assert!(limiter.check(&"key2").is_allowed());
+    }
+
+    #[test]
+    fn test_add_group() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_group(["session1", "session2"], 3, Duration::from_secs(60));
+
+        assert!(limiter.consume(&"session1", 2).is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&"session2"), Some(1));
+        assert!(limiter.check(&"session2").is_allowed());
+        assert!(!limiter.check(&"session1").is_allowed());
+        assert_eq!(limiter.stats(&"session2").unwrap().allowed, 1);
+
+        // Removing one doesn't give the others their requests back.
+        limiter.remove_limited_entity(&"session1");
+        assert!(!limiter.check(&"session2").is_allowed());
+    }
+
+    #[test]
+    fn test_add_window() {
+        let limiter: Limiter<&str> = Limiter::new();
+        limiter.add_limited_entity("user1", 5, Duration::from_secs(1));
+        assert!(limiter.add_window(&"user1", 2, Duration::from_secs(60)));
+        assert!(!limiter.add_window(&"unknown_user", 2, Duration::from_secs(60)));
+
+        assert!(limiter.check(&"user1").is_allowed());
+        assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+        assert!(limiter.check(&"user1").is_allowed());
+        assert!(!limiter.check(&"user1").is_allowed());
+        assert!(limiter.retry_after(&"user1").unwrap() > Duration::from_secs(50));
+    }
+
+    #[test]
+    fn test_merge() {
+        let limiter = |consumed| {
+            let limiter: Limiter<&str> = Limiter::new();
+            limiter.add_limited_entity("user1", 10, Duration::from_secs(60));
+            limiter.consume(&"user1", consumed);
+            limiter
+        };
+        let other = limiter(4);
+        other.add_limited_entity("user2", 5, Duration::from_secs(60));
+
+        let ours = limiter(3);
+        ours.merge(other.export_state(), MergeStrategy::MinRemaining);
+        assert_eq!(ours.get_bucket_remaining(&"user1"), Some(6));
+        assert_eq!(ours.get_bucket_remaining(&"user2"), Some(5));
+
+        let ours = limiter(3);
+        ours.merge(other.export_state(), MergeStrategy::SumConsumption);
+        assert_eq!(ours.get_bucket_remaining(&"user1"), Some(3));
+
+        let ours = limiter(8);
+        ours.merge(other.export_state(), MergeStrategy::SumConsumption);
+        assert_eq!(ours.get_bucket_remaining(&"user1"), Some(0));
+    }
+
     #[test]
     fn test_remove_limited_entity() {
-        let mut limiter: Limiter<&str> = Limiter::new();
+        let limiter: Limiter<&str> = Limiter::new();
         limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
 
         {
-            let requests = limiter.requests.lock().unwrap();
+            let requests = limiter.requests.read("user1");
             assert!(requests.contains_key("user1"));
         }
 
@@ -263,7 +3885,7 @@ mod tests {
         assert_eq!(removed_entity_exact.unwrap().bucket_max, 5);
 
         {
-            let requests = limiter.requests.lock().unwrap();
+            let requests = limiter.requests.read("user1");
             assert!(!requests.contains_key("user1"));
         }
 
@@ -280,7 +3902,7 @@ mod tests {
         assert_eq!(removed_entity_borrowed.unwrap().bucket_max, 5);
 
         {
-            let requests = limiter.requests.lock().unwrap();
+            let requests = limiter.requests.read("user2");
             assert!(!requests.contains_key("user2"));
         }
     }
@@ -292,7 +3914,7 @@ mod tests {
         limiter.add_limited_entity("user1", 5, Duration::from_secs(60));
 
         {
-            let requests = limiter.requests.lock().unwrap();
+            let requests = limiter.requests.read("user1");
             assert!(requests.contains_key("user1"));
         }
 
@@ -300,17 +3922,17 @@ mod tests {
         assert!(removed_entity.is_some());
 
         {
-            let requests = limiter.requests.lock().unwrap();
+            let requests = limiter.requests.read("user1");
             assert!(!requests.contains_key("user1"));
         }
 
         limiter.add_limited_entity("user1", 10, Duration::from_secs(120));
 
         {
-            let requests = limiter.requests.lock().unwrap();
+            let requests = limiter.requests.read("user1");
             assert!(requests.contains_key("user1"));
-            assert_eq!(requests["user1"].bucket_max, 10);
-            assert_eq!(requests["user1"].bucket, 10); // Should reflect the new bucket max
+            assert_eq!(requests["user1"].lock().bucket_max, 10);
+            assert_eq!(requests["user1"].lock().bucket, 10); // Should reflect the new bucket max
         }
 
         let removed_entity_after_reuse = limiter.remove_limited_entity("user1");
@@ -318,7 +3940,7 @@ mod tests {
         assert_eq!(removed_entity_after_reuse.unwrap().bucket_max, 10);
 
         {
-            let requests = limiter.requests.lock().unwrap();
+            let requests = limiter.requests.read("user1");
             assert!(!requests.contains_key("user1"));
         }
     }
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Opts};
+
+use crate::sync::Mutex;
+use crate::Limiter;
+
+type Label<T> = Box<dyn Fn(&T) -> String + Send + Sync>;
+
+/// Exposes the counters of a limiter as Prometheus metrics, created with
+/// `Limiter::prometheus_collector` and registered like any other collector:
+///
+/// ```
+/// # use rate_gate::Limiter;
+/// let limiter: Limiter<String> = Limiter::new();
+/// let registry = prometheus::Registry::new();
+/// registry.register(Box::new(limiter.prometheus_collector())).unwrap();
+/// ```
+///
+/// The values are read from the limiter on every scrape, so checks don't pay for them.
+/// Collects
+/// - `rate_gate_requests_total{decision="allowed|denied"}`, see `Limiter::global_stats`
+/// - `rate_gate_evicted_total`
+/// - `rate_gate_entities`
+/// - `rate_gate_key_requests_total{key, decision}` with `with_label`
+pub struct PrometheusCollector<T, S = DefaultHashBuilder>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    limiter: Limiter<T, S>,
+    label: Option<Label<T>>,
+    requests: IntCounterVec,
+    evicted: IntCounter,
+    entities: IntGauge,
+    key_requests: IntCounterVec,
+    scrape: Mutex<()>, // Counters are rebuilt on every scrape, one at a time
+}
+
+impl<T, S> PrometheusCollector<T, S>
+where
+    T: Hash + Eq + Send + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    pub(crate) fn new(limiter: Limiter<T, S>) -> Self {
+        PrometheusCollector {
+            limiter,
+            label: None,
+            requests: IntCounterVec::new(
+                Opts::new(
+                    "rate_gate_requests_total",
+                    "Requests checked by the limiter",
+                ),
+                &["decision"],
+            )
+            .unwrap(),
+            evicted: IntCounter::new(
+                "rate_gate_evicted_total",
+                "Entities the limiter removed by itself",
+            )
+            .unwrap(),
+            entities: IntGauge::new("rate_gate_entities", "Entities tracked by the limiter")
+                .unwrap(),
+            key_requests: IntCounterVec::new(
+                Opts::new(
+                    "rate_gate_key_requests_total",
+                    "Requests of the tracked entities, by key",
+                ),
+                &["key", "decision"],
+            )
+            .unwrap(),
+            scrape: Mutex::new(()),
+        }
+    }
+
+    /// Also collects the allowed and denied requests per entity, labeled with the
+    /// `key` that `label` returns for it. Entities with the same key are added up.
+    ///
+    /// Keep the number of distinct keys small, e.g. a customer tier rather than an IP.
+    /// Only tracked entities are counted, their requests drop out once they are evicted.
+    pub fn with_label(mut self, label: impl Fn(&T) -> String + Send + Sync + 'static) -> Self {
+        self.label = Some(Box::new(label));
+        self
+    }
+}
+
+impl<T, S> Collector for PrometheusCollector<T, S>
+where
+    T: Hash + Eq + Send + Sync + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.requests.desc();
+        descs.extend(self.evicted.desc());
+        descs.extend(self.entities.desc());
+        if self.label.is_some() {
+            descs.extend(self.key_requests.desc());
+        }
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _scrape = self.scrape.lock();
+        let stats = self.limiter.global_stats();
+        set_counter(
+            &self.requests.with_label_values(&["allowed"]),
+            stats.allowed,
+        );
+        set_counter(&self.requests.with_label_values(&["denied"]), stats.denied);
+        set_counter(&self.evicted, stats.evicted);
+        self.entities.set(stats.entities as i64);
+
+        let mut families = self.requests.collect();
+        families.extend(self.evicted.collect());
+        families.extend(self.entities.collect());
+
+        if let Some(label) = &self.label {
+            let mut keys: HashMap<String, (u64, u64)> = HashMap::new();
+            self.limiter.requests.for_each(|entity, entry| {
+                let stats = entry.stats();
+                let key = keys.entry(label(entity)).or_default();
+                key.0 += stats.allowed;
+                key.1 += stats.denied;
+            });
+            // Drops the keys that are gone since the last scrape.
+            self.key_requests.reset();
+            for (key, (allowed, denied)) in keys {
+                let with = |decision| {
+                    self.key_requests
+                        .with_label_values(&[key.as_str(), decision])
+                };
+                with("allowed").inc_by(allowed);
+                with("denied").inc_by(denied);
+            }
+            families.extend(self.key_requests.collect());
+        }
+        families
+    }
+}
+
+fn set_counter(counter: &IntCounter, value: u64) {
+    counter.reset();
+    counter.inc_by(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_collect() {
+        let limiter: Limiter<String> = Limiter::new();
+        limiter.add_limited_entity("free:user1".to_string(), 1, Duration::from_secs(60));
+        limiter.add_limited_entity("free:user2".to_string(), 1, Duration::from_secs(60));
+        for _ in 0..2 {
+            limiter.check("free:user1");
+            limiter.check("free:user2");
+        }
+
+        let registry = prometheus::Registry::new();
+        let collector = limiter
+            .prometheus_collector()
+            .with_label(|entity| entity.split(':').next().unwrap().to_string());
+        registry.register(Box::new(collector)).unwrap();
+
+        let families = registry.gather();
+        let value = |name: &str, labels: &[&str]| {
+            let family = families.iter().find(|f| f.name() == name).unwrap();
+            let metric = family
+                .get_metric()
+                .iter()
+                .find(|m| {
+                    let values: Vec<_> = m.get_label().iter().map(|l| l.value()).collect();
+                    values == labels
+                })
+                .unwrap();
+            if name.ends_with("_total") {
+                metric.get_counter().get_value()
+            } else {
+                metric.get_gauge().get_value()
+            }
+        };
+        assert_eq!(value("rate_gate_requests_total", &["allowed"]), 2.0);
+        assert_eq!(value("rate_gate_requests_total", &["denied"]), 2.0);
+        assert_eq!(value("rate_gate_entities", &[]), 2.0);
+        assert_eq!(value("rate_gate_evicted_total", &[]), 0.0);
+        assert_eq!(
+            value("rate_gate_key_requests_total", &["denied", "free"]),
+            2.0
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How a limiter decides every request, switched at runtime with `Limiter::set_mode`.
+///
+/// Meant for incidents: failing open or closed in one call, without tearing down the
+/// limiter or losing what its entities consumed.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, Mode};
+/// let limiter: Limiter<&str> = Limiter::new();
+/// limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+///
+/// limiter.set_mode(Mode::AllowAll);
+/// assert!(limiter.check(&"user1").is_allowed());
+/// assert!(limiter.check(&"user1").is_allowed());
+///
+/// limiter.set_mode(Mode::Enforce);
+/// assert!(limiter.check(&"user1").is_allowed());
+/// assert!(!limiter.check(&"user1").is_allowed());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Mode {
+    /// Requests are decided by bans, exemptions and buckets, as usual.
+    #[default]
+    Enforce,
+    /// Every request is allowed without consuming anything.
+    AllowAll,
+    /// Every request is answered with `Decision::Banned`.
+    DenyAll,
+}
+
+/// The `Mode` of a limiter, shared by its clones.
+#[derive(Debug, Default)]
+pub(crate) struct ModeSwitch(AtomicU8);
+
+impl ModeSwitch {
+    pub(crate) fn get(&self) -> Mode {
+        match self.0.load(Ordering::Relaxed) {
+            1 => Mode::AllowAll,
+            2 => Mode::DenyAll,
+            _ => Mode::Enforce,
+        }
+    }
+
+    pub(crate) fn set(&self, mode: Mode) {
+        let value = match mode {
+            Mode::Enforce => 0,
+            Mode::AllowAll => 1,
+            Mode::DenyAll => 2,
+        };
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,349 @@
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hashbrown::HashMap;
+
+use crate::stats::Counters;
+use crate::sync::RwLock;
+use crate::{Decision, GlobalStats, Limiter, Policy};
+
+/// Limits the entities of many tenants in one limiter, every tenant with a key space,
+/// default limit, stats and idle TTL of its own.
+///
+/// Entities of different namespaces never share a bucket, even with the same key.
+/// Underneath, buckets are tracked by a single `Limiter<(Arc<str>, T)>` of the
+/// namespace and the entity, available through `limiter` for everything else, like
+/// bans or snapshots, so thousands of tenants don't need thousands of limiters:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{NamespacedLimiter, Policy};
+/// let limiter: NamespacedLimiter<String> = NamespacedLimiter::new();
+/// let minute = Duration::from_secs(60);
+/// limiter.namespace("tenant-a").set_default(Policy::new(100, minute));
+/// limiter.namespace("tenant-b").set_default(Policy::new(1, minute));
+///
+/// assert!(limiter.namespace("tenant-b").check("alice".to_string()).is_allowed());
+/// assert!(!limiter.namespace("tenant-b").check("alice".to_string()).is_allowed());
+/// assert!(limiter.namespace("tenant-a").check("alice".to_string()).is_allowed());
+/// assert_eq!(limiter.namespace("tenant-b").stats().denied, 1);
+/// ```
+#[derive(Debug)]
+pub struct NamespacedLimiter<T>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    limiter: Limiter<(Arc<str>, T)>,
+    namespaces: Arc<RwLock<HashMap<Arc<str>, Arc<NamespaceState>>>>,
+}
+
+/// What a namespace doesn't share with the others.
+#[derive(Debug)]
+struct NamespaceState {
+    idle_ttl: RwLock<Option<Duration>>,
+    counters: Counters,
+}
+
+impl NamespaceState {
+    fn new() -> Self {
+        NamespaceState {
+            idle_ttl: RwLock::new(None),
+            counters: Counters::new(1),
+        }
+    }
+}
+
+// Not derived, clones share the buckets so `T` doesn't need to be `Clone`.
+impl<T> Clone for NamespacedLimiter<T>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        NamespacedLimiter {
+            limiter: self.limiter.clone(),
+            namespaces: Arc::clone(&self.namespaces),
+        }
+    }
+}
+
+impl<T> Default for NamespacedLimiter<T>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> NamespacedLimiter<T>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::from_limiter(Limiter::new())
+    }
+
+    /// Tracks the buckets in `limiter`, e.g. one configured with `LimiterBuilder`.
+    /// Entities of namespaces without a default of their own get its default limit,
+    /// and are `Decision::Unknown` without one.
+    ///
+    /// The namespace defaults are resolved with the limiter's `TierResolver`,
+    /// replacing any it had.
+    pub fn from_limiter(limiter: Limiter<(Arc<str>, T)>) -> Self {
+        let limiter = limiter
+            .with_tier_resolver(|(namespace, _): &(Arc<str>, T)| Some(default_policy(namespace)));
+        NamespacedLimiter {
+            limiter,
+            namespaces: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// The namespace called `name`, created on first use.
+    pub fn namespace(&self, name: &str) -> Namespace<'_, T> {
+        let found = self
+            .namespaces
+            .read()
+            .get_key_value(name)
+            .map(|(name, state)| (Arc::clone(name), Arc::clone(state)));
+        let (name, state) = found.unwrap_or_else(|| {
+            // Someone may have created it in the meantime.
+            let name: Arc<str> = Arc::from(name);
+            let mut namespaces = self.namespaces.write();
+            let state = namespaces
+                .entry(Arc::clone(&name))
+                .or_insert_with(|| Arc::new(NamespaceState::new()));
+            (name, Arc::clone(state))
+        });
+        Namespace {
+            limiter: &self.limiter,
+            name,
+            state,
+        }
+    }
+
+    /// The names of every namespace in use.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.namespaces
+            .read()
+            .keys()
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    /// Removes the namespace called `name` along with its entities, default and
+    /// stats. Returns `false` if there was no such namespace.
+    pub fn remove_namespace(&self, name: &str) -> bool {
+        let removed = self.namespaces.write().remove(name).is_some();
+        self.limiter
+            .remove_where(|(namespace, _)| &**namespace == name);
+        self.limiter.policies.write().remove(&default_policy(name));
+        removed
+    }
+
+    /// Removes every entity idle for longer than the idle TTL of its namespace, or
+    /// else the one of the limiter, see `Namespace::set_idle_ttl`. Returns how many
+    /// were removed.
+    pub fn evict_idle(&self) -> usize {
+        let namespaces = self.namespaces.read().clone();
+        let idle_ttl = |(namespace, _): &(Arc<str>, T)| {
+            let own = namespaces
+                .get(namespace)
+                .and_then(|state| *state.idle_ttl.read());
+            own.or(self.limiter.idle_ttl)
+        };
+        self.limiter.evict_idle_by(idle_ttl, |(namespace, _)| {
+            if let Some(state) = namespaces.get(namespace) {
+                state.counters.record_evicted(1);
+            }
+        })
+    }
+
+    /// The limiter tracking a bucket per namespace and entity.
+    pub fn limiter(&self) -> &Limiter<(Arc<str>, T)> {
+        &self.limiter
+    }
+}
+
+/// A namespace of a `NamespacedLimiter`, see `NamespacedLimiter::namespace`.
+#[derive(Debug)]
+pub struct Namespace<'a, T>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    limiter: &'a Limiter<(Arc<str>, T)>,
+    name: Arc<str>,
+    state: Arc<NamespaceState>,
+}
+
+impl<T> Namespace<'_, T>
+where
+    T: Hash + Eq + Clone + Send + 'static,
+{
+    /// The name of the namespace.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Limits the entities of the namespace that were never added with `policy`.
+    ///
+    /// The policy is registered with the limiter as `ns:` followed by the name of the
+    /// namespace, so it doesn't replace a policy named like the namespace.
+    /// Entities already tracked keep the limits they started with, until they are
+    /// evicted or removed.
+    pub fn set_default(&self, policy: Policy) {
+        self.limiter
+            .define_policy(default_policy(&self.name), policy);
+    }
+
+    /// Removes entities of the namespace idle for longer than `idle_ttl` on
+    /// `NamespacedLimiter::evict_idle`, `None` falls back to the idle TTL of the limiter.
+    pub fn set_idle_ttl(&self, idle_ttl: Option<Duration>) {
+        *self.state.idle_ttl.write() = idle_ttl;
+    }
+
+    /// Adds `entity` to the namespace with `policy`, like `Limiter::add_limited_entity_with_policy`.
+    pub fn add_limited_entity(&self, entity: T, policy: Policy) {
+        self.limiter
+            .add_limited_entity_with_policy(self.key(entity), policy);
+    }
+
+    /// Consumes a request of `entity`, like `Limiter::check`.
+    pub fn check(&self, entity: T) -> Decision {
+        self.consume(entity, 1)
+    }
+
+    /// Consumes `cost` requests of `entity`, like `Limiter::consume`.
+    pub fn consume(&self, entity: T, cost: usize) -> Decision {
+        let decision = self.limiter.consume(&self.key(entity), cost);
+        self.state.counters.record(&decision);
+        decision
+    }
+
+    /// Returns how many requests `entity` has left, like `Limiter::get_bucket_remaining`.
+    pub fn get_bucket_remaining(&self, entity: T) -> Option<usize> {
+        self.limiter.get_bucket_remaining(&self.key(entity))
+    }
+
+    /// Removes `entity` from the namespace. Returns `false` if it wasn't tracked.
+    pub fn remove(&self, entity: T) -> bool {
+        self.limiter
+            .remove_limited_entity(&self.key(entity))
+            .is_some()
+    }
+
+    /// Returns how many requests of the namespace were checked and denied, like
+    /// `Limiter::global_stats`.
+    ///
+    /// Counting the tracked entities goes through every entity of the limiter, so
+    /// call this for dashboards rather than per request.
+    pub fn stats(&self) -> GlobalStats {
+        let mut entities = 0;
+        self.limiter.for_each(|(namespace, _), _| {
+            if *namespace == self.name {
+                entities += 1;
+            }
+        });
+        self.state.counters.load(entities)
+    }
+
+    /// Starts counting the requests of `stats` from zero again.
+    pub fn reset_stats(&self) {
+        self.state.counters.reset();
+    }
+
+    /// Removes every entity of the namespace, keeping its default and stats.
+    pub fn clear(&self) {
+        self.limiter
+            .remove_where(|(namespace, _)| *namespace == self.name);
+    }
+
+    fn key(&self, entity: T) -> (Arc<str>, T) {
+        (Arc::clone(&self.name), entity)
+    }
+}
+
+/// The name the default policy of `namespace` is registered under with the limiter.
+fn default_policy(namespace: &str) -> String {
+    format!("ns:{}", namespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LimiterBuilder, ManualClock};
+
+    #[test]
+    fn test_namespaces_are_isolated() {
+        let minute = Duration::from_secs(60);
+        let limiter =
+            NamespacedLimiter::from_limiter(LimiterBuilder::new().default_limit(1, minute).build());
+        let tenant_a = limiter.namespace("tenant-a");
+        tenant_a.set_default(Policy::new(3, minute));
+        let tenant_b = limiter.namespace("tenant-b");
+
+        for _ in 0..3 {
+            assert!(tenant_a.check("alice").is_allowed());
+        }
+        assert!(!tenant_a.check("alice").is_allowed());
+        assert!(tenant_b.check("alice").is_allowed());
+        assert!(!tenant_b.check("alice").is_allowed());
+
+        let stats = tenant_a.stats();
+        assert_eq!((stats.allowed, stats.denied, stats.entities), (3, 1, 1));
+
+        tenant_b.clear();
+        assert!(tenant_b.check("alice").is_allowed());
+        assert_eq!(tenant_a.get_bucket_remaining("alice"), Some(0));
+
+        assert!(limiter.remove_namespace("tenant-a"));
+        assert_eq!(limiter.limiter().len(), 1);
+        assert_eq!(limiter.namespaces(), vec!["tenant-b".to_string()]);
+    }
+
+    #[test]
+    fn test_defaults_keep_policies_of_the_same_name() {
+        let minute = Duration::from_secs(60);
+        let limiter = NamespacedLimiter::from_limiter(
+            LimiterBuilder::new()
+                .policy("premium", Policy::new(100, minute))
+                .build(),
+        );
+        limiter
+            .namespace("premium")
+            .set_default(Policy::new(1, minute));
+
+        assert!(limiter.namespace("premium").check("alice").is_allowed());
+        assert!(!limiter.namespace("premium").check("alice").is_allowed());
+        assert_eq!(limiter.limiter().policy("premium").unwrap().max_limit, 100);
+
+        assert!(limiter.remove_namespace("premium"));
+        assert!(limiter.limiter().policy("premium").is_some());
+        assert!(limiter.limiter().policy("ns:premium").is_none());
+    }
+
+    #[test]
+    fn test_idle_ttl_per_namespace() {
+        let minute = Duration::from_secs(60);
+        let clock = ManualClock::new();
+        let limiter = NamespacedLimiter::from_limiter(
+            LimiterBuilder::new()
+                .default_limit(5, minute)
+                .clock(clock.clone())
+                .build(),
+        );
+        limiter
+            .namespace("short")
+            .set_idle_ttl(Some(Duration::from_millis(10)));
+        limiter.namespace("short").check("alice");
+        limiter.namespace("long").check("alice");
+        clock.advance(Duration::from_millis(20));
+
+        assert_eq!(limiter.evict_idle(), 1);
+        assert_eq!(limiter.namespace("short").stats().evicted, 1);
+        assert_eq!(
+            limiter.namespace("long").get_bucket_remaining("alice"),
+            Some(4)
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+
+use crate::{Policy, TierResolver};
+
+/// Which keys a rule of `PatternRules` applies to.
+#[derive(Debug, Clone)]
+pub enum KeyPattern {
+    /// Only the key itself.
+    Exact(String),
+    /// Every key starting with the prefix.
+    Prefix(String),
+    /// Every key matching the glob, where `*` stands for any run of characters
+    /// and `?` for a single one.
+    Glob(String),
+    /// Every key the regex finds a match in, anchor it with `^` and `$` to match
+    /// whole keys.
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+impl KeyPattern {
+    /// Reads `bot-*` as a prefix, patterns with other `*` or `?` as globs, and
+    /// anything else as an exact key.
+    pub fn parse(pattern: &str) -> Self {
+        match pattern.strip_suffix('*') {
+            Some(prefix) if !prefix.contains(['*', '?']) => KeyPattern::Prefix(prefix.to_string()),
+            _ if pattern.contains(['*', '?']) => KeyPattern::Glob(pattern.to_string()),
+            _ => KeyPattern::Exact(pattern.to_string()),
+        }
+    }
+
+    /// Returns `true` if the pattern applies to `key`.
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::Exact(exact) => key == exact,
+            KeyPattern::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            KeyPattern::Glob(glob) => glob_matches(glob, key),
+            #[cfg(feature = "regex")]
+            KeyPattern::Regex(regex) => regex.is_match(key),
+        }
+    }
+}
+
+impl From<&str> for KeyPattern {
+    fn from(pattern: &str) -> Self {
+        KeyPattern::parse(pattern)
+    }
+}
+
+#[cfg(feature = "regex")]
+impl From<regex::Regex> for KeyPattern {
+    fn from(regex: regex::Regex) -> Self {
+        KeyPattern::Regex(regex)
+    }
+}
+
+impl fmt::Display for KeyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyPattern::Exact(exact) => f.write_str(exact),
+            KeyPattern::Prefix(prefix) => write!(f, "{}*", prefix),
+            KeyPattern::Glob(glob) => f.write_str(glob),
+            #[cfg(feature = "regex")]
+            KeyPattern::Regex(regex) => write!(f, "/{}/", regex),
+        }
+    }
+}
+
+/// Matches `key` against `glob` with a single backtracking point, the last `*`.
+fn glob_matches(glob: &str, key: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut g, mut k) = (0, 0);
+    let mut star = None; // Position after the last `*`, and where its run ends in the key
+    while k < key.len() {
+        match glob.get(g) {
+            Some('*') => {
+                g += 1;
+                star = Some((g, k));
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                g += 1;
+                k += 1;
+            }
+            _ => match star {
+                // The `*` takes one more character.
+                Some((after, run_end)) => {
+                    g = after;
+                    k = run_end + 1;
+                    star = Some((after, k));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+/// Policies for keys that can't all be registered up front, picked by pattern:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, PatternRules, Policy};
+/// let minute = Duration::from_secs(60);
+/// let limiter: Limiter<String> = Limiter::new().with_pattern_rules(
+///     PatternRules::new()
+///         .rule("bot-*", Policy::new(10, minute))
+///         .rule("*", Policy::new(100, minute)),
+/// );
+///
+/// assert!(limiter.check("bot-crawler").is_allowed());
+/// assert_eq!(limiter.get_bucket_remaining("bot-crawler"), Some(9));
+/// assert!(limiter.check("alice").is_allowed());
+/// assert_eq!(limiter.get_bucket_remaining("alice"), Some(99));
+/// ```
+///
+/// Rules take precedence in the order they were added, the first one whose pattern
+/// matches applies, so add the narrowest patterns first. Keys without a matching
+/// rule get the default limit of the limiter if it has one.
+#[derive(Debug, Clone, Default)]
+pub struct PatternRules {
+    rules: Vec<(KeyPattern, Policy)>,
+}
+
+impl PatternRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits keys matching `pattern` with `policy`, unless an earlier rule matches.
+    /// Strings are read with `KeyPattern::parse`.
+    pub fn rule(mut self, pattern: impl Into<KeyPattern>, policy: Policy) -> Self {
+        self.rules.push((pattern.into(), policy));
+        self
+    }
+
+    /// The first rule matching `key`, if any.
+    pub fn find(&self, key: &str) -> Option<(&KeyPattern, &Policy)> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(key))
+            .map(|(pattern, policy)| (pattern, policy))
+    }
+
+    /// The rules in order of precedence.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(KeyPattern, Policy)> {
+        self.rules.iter()
+    }
+}
+
+/// Resolves keys to the name of their first matching pattern, the name their
+/// policy is registered under by `Limiter::with_pattern_rules`.
+impl<T> TierResolver<T> for PatternRules
+where
+    T: AsRef<str>,
+{
+    fn tier(&self, entity: &T) -> Option<String> {
+        let (pattern, _) = self.find(entity.as_ref())?;
+        Some(pattern.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        assert!(matches!(KeyPattern::parse("bot-*"), KeyPattern::Prefix(_)));
+        assert!(matches!(
+            KeyPattern::parse("*.internal"),
+            KeyPattern::Glob(_)
+        ));
+        assert!(matches!(KeyPattern::parse("alice"), KeyPattern::Exact(_)));
+
+        let glob = KeyPattern::parse("api-*-v?");
+        assert!(glob.matches("api-search-v2"));
+        assert!(glob.matches("api--v1"));
+        assert!(!glob.matches("api-search-v10"));
+        assert!(KeyPattern::parse("*a*b").matches("xaxxab"));
+        assert!(!KeyPattern::parse("*a*b").matches("xaxxa"));
+        assert!(KeyPattern::parse("*").matches(""));
+        assert_eq!(KeyPattern::parse("bot-*").to_string(), "bot-*");
+    }
+
+    #[test]
+    fn test_precedence() {
+        let minute = std::time::Duration::from_secs(60);
+        let rules = PatternRules::new()
+            .rule("bot-good", Policy::new(50, minute))
+            .rule("bot-*", Policy::new(10, minute));
+        let limit = |key: &str| rules.find(key).map(|(_, policy)| policy.max_limit);
+        assert_eq!(limit("bot-good"), Some(50));
+        assert_eq!(limit("bot-bad"), Some(10));
+        assert_eq!(limit("alice"), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex() {
+        let pattern = KeyPattern::from(regex::Regex::new(r"^user-\d+$").unwrap());
+        assert!(pattern.matches("user-42"));
+        assert!(!pattern.matches("user-bob"));
+        assert_eq!(pattern.to_string(), r"/^user-\d+$/");
+    }
+}
@@ -0,0 +1,103 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::Limiter;
+
+/// A request consumed from the bucket of an entity for a scope of work, created with
+/// `Limiter::acquire_permit`.
+///
+/// The request stays consumed when the permit is dropped, unless the permit was set to
+/// `refund_on_drop`, in which case only `commit` keeps it:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::Limiter;
+/// # fn call_downstream() -> Result<(), ()> { Err(()) }
+/// # let limiter: Limiter<&str> = Limiter::new();
+/// # limiter.add_limited_entity("user1", 1, Duration::from_secs(60));
+/// fn handle(limiter: &Limiter<&str>) -> Result<(), ()> {
+///     let permit = limiter.acquire_permit(&"user1").ok_or(())?.refund_on_drop();
+///     call_downstream()?; // a failed call gives the request back
+///     permit.commit();
+///     Ok(())
+/// }
+/// # assert!(handle(&limiter).is_err());
+/// # assert_eq!(limiter.get_bucket_remaining(&"user1"), Some(1));
+/// ```
+#[derive(Debug)]
+pub struct Permit<'a, T, Q, S = DefaultHashBuilder>
+where
+    T: Hash + Eq + Send + Borrow<Q> + 'static,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    limiter: &'a Limiter<T, S>,
+    entity: &'a Q,
+    remaining: usize,
+    reset_in: Duration,
+    refund_on_drop: bool,
+}
+
+impl<'a, T, Q, S> Permit<'a, T, Q, S>
+where
+    T: Hash + Eq + Send + Borrow<Q> + 'static,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    pub(crate) fn new(
+        limiter: &'a Limiter<T, S>,
+        entity: &'a Q,
+        remaining: usize,
+        reset_in: Duration,
+    ) -> Self {
+        Permit {
+            limiter,
+            entity,
+            remaining,
+            reset_in,
+            refund_on_drop: false,
+        }
+    }
+
+    /// Gives the request back when the permit is dropped without calling `commit`.
+    pub fn refund_on_drop(mut self) -> Self {
+        self.refund_on_drop = true;
+        self
+    }
+
+    /// How many requests were left in the bucket after taking this one.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Time until the bucket gets refilled, as of taking the request.
+    pub fn reset_in(&self) -> Duration {
+        self.reset_in
+    }
+
+    /// Keeps the request consumed, even if the permit was set to `refund_on_drop`.
+    pub fn commit(mut self) {
+        self.refund_on_drop = false;
+    }
+
+    /// Gives the request back right away.
+    pub fn refund(mut self) {
+        self.refund_on_drop = true;
+    }
+}
+
+impl<T, Q, S> Drop for Permit<'_, T, Q, S>
+where
+    T: Hash + Eq + Send + Borrow<Q> + 'static,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        if self.refund_on_drop {
+            self.limiter.refund(self.entity, 1);
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(feature = "cron")]
+use crate::ResetSchedule;
+use crate::{Aimd, Algorithm, TimeOfDayLimit};
+
+/// The limits of an entity: `max_limit` requests every `refresh_rate`, refilled
+/// according to `algorithm`.
+///
+/// Policies can be registered under a name with `Limiter::define_policy`, so every
+/// entity on e.g. a free tier is added with the same limits:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, Policy};
+/// let limiter: Limiter<&str> = Limiter::new();
+/// limiter.define_policy("free_tier", Policy::new(100, Duration::from_secs(60)));
+/// assert!(limiter.add_limited_entity_with_named_policy("user1", "free_tier"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Policy {
+    /// The value the bucket gets refilled with.
+    pub max_limit: usize,
+    /// The timeframe after which the entity gets a renewed limit.
+    pub refresh_rate: Duration,
+    /// How the bucket gets refilled.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub algorithm: Algorithm,
+    /// How many requests can be in progress at once, see `Limiter::limit_concurrency`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_in_flight: Option<usize>,
+    /// How many requests can be made at once after idling, while the rate stays
+    /// `max_limit` per `refresh_rate`, see `with_burst`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub burst: Option<usize>,
+    /// How long an idle entity takes to get back to the full rate, see `with_warm_up`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub warm_up: Option<Duration>,
+    /// How `max_limit` adapts to reported outcomes, see `Aimd`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub adaptive: Option<Aimd>,
+    /// Do windows start on wall-clock boundaries, see `with_aligned_windows`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub aligned: bool,
+    /// When windows reset instead of every `refresh_rate`, see `with_reset_schedule`.
+    #[cfg(feature = "cron")]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reset: Option<ResetSchedule>,
+    /// Limits replacing `max_limit` and `refresh_rate` during parts of the day, see `during`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub time_of_day: Vec<TimeOfDayLimit>,
+    /// How many requests can be allowed beyond the limit as a debt, see `with_overdraft`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overdraft: usize,
+    /// How long every request is denied once the limit is hit, see `with_cooldown`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cooldown: Option<Duration>,
+    /// How far fixed windows stray from `refresh_rate`, in percent of it, see `with_jitter`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub jitter: u8,
+    /// Where the random window lengths of `jitter` come from, random if `None`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub jitter_seed: Option<u64>,
+}
+
+impl Policy {
+    /// `max_limit` requests every `refresh_rate`, with `Algorithm::FixedWindow`.
+    pub fn new(max_limit: usize, refresh_rate: Duration) -> Self {
+        Policy {
+            max_limit,
+            refresh_rate,
+            algorithm: Algorithm::FixedWindow,
+            max_in_flight: None,
+            burst: None,
+            warm_up: None,
+            adaptive: None,
+            aligned: false,
+            #[cfg(feature = "cron")]
+            reset: None,
+            time_of_day: Vec::new(),
+            overdraft: 0,
+            cooldown: None,
+            jitter: 0,
+            jitter_seed: None,
+        }
+    }
+
+    /// A budget of `bytes` every `refresh_rate` rather than requests, for costs that are
+    /// byte counts like those of `Throttled`. Refills continuously with
+    /// `Algorithm::TokenBucket`, so throughput stays smooth.
+    pub fn bandwidth(bytes: usize, refresh_rate: Duration) -> Self {
+        Policy::new(bytes, refresh_rate).with_algorithm(Algorithm::TokenBucket)
+    }
+
+    /// Sets how the bucket gets refilled.
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Caps how many requests can be in progress at once, on top of the rate.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    /// Allows bursts of `burst` requests on top of the sustained rate, like a classic
+    /// token bucket: `Policy::new(5, Duration::from_secs(1)).with_burst(20)` allows
+    /// 20 requests at once, refilled at 5 per second.
+    ///
+    /// Only algorithms refilling continuously can tell the two apart, so a
+    /// `FixedWindow` or sliding window policy is switched to `Algorithm::TokenBucket`.
+    /// The bucket of an entity added with the policy holds `burst` requests.
+    pub fn with_burst(mut self, burst: usize) -> Self {
+        if !self.algorithm.is_continuous() {
+            self.algorithm = Algorithm::TokenBucket;
+        }
+        self.burst = Some(burst);
+        self
+    }
+
+    /// Ramps an idle entity up to its full rate over `period`, like a slow start,
+    /// so keys that were quiet for a while don't hit cold downstream caches at full burst.
+    ///
+    /// A cold entity holds and refills a third of its bucket, growing linearly to the
+    /// full bucket and rate over `period` of activity. Entities start out cold, and
+    /// go cold again once they made no requests for `period`.
+    /// Only token buckets warm up, so any other algorithm is switched to
+    /// `Algorithm::TokenBucket`.
+    pub fn with_warm_up(mut self, period: Duration) -> Self {
+        self.algorithm = Algorithm::TokenBucket;
+        self.warm_up = Some(period);
+        self
+    }
+
+    /// Adapts the limit to the outcomes reported with `Limiter::report_success` and
+    /// `Limiter::report_failure`, starting out at `max_limit` within the bounds of `aimd`.
+    pub fn with_adaptive(mut self, aimd: Aimd) -> Self {
+        self.adaptive = Some(aimd);
+        self
+    }
+
+    /// Starts windows on wall-clock boundaries instead of when the entity was added:
+    /// at multiples of `refresh_rate` since the Unix epoch. A window of a minute starts
+    /// at :00, of an hour on the hour and of a day at midnight UTC, so
+    /// `Policy::parse("1000/day")?.with_aligned_windows()` is 1000 requests per calendar day.
+    ///
+    /// Only fixed windows have a start, so the algorithm is switched to
+    /// `Algorithm::FixedWindow`. The wall-clock time is read from the limiter's `Clock`.
+    pub fn with_aligned_windows(mut self) -> Self {
+        self.algorithm = Algorithm::FixedWindow;
+        self.aligned = true;
+        self
+    }
+
+    /// Resets windows whenever `schedule` fires, e.g. at midnight UTC with `0 0 * * *`,
+    /// so a quota resets exactly when the one of an upstream provider does.
+    ///
+    /// Every window lasts from one firing to the next, and `refresh_rate` is only used
+    /// if the schedule never fires again. The algorithm is switched to
+    /// `Algorithm::FixedWindow`, and the wall-clock time is read from the limiter's `Clock`.
+    #[cfg(feature = "cron")]
+    pub fn with_reset_schedule(mut self, schedule: ResetSchedule) -> Self {
+        self.algorithm = Algorithm::FixedWindow;
+        self.reset = Some(schedule);
+        self
+    }
+
+    /// Allows `max_limit` requests every `refresh_rate` during `hours` of every day,
+    /// given as the time since midnight UTC, instead of the policy's own limits:
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use rate_gate::Policy;
+    /// let hour = Duration::from_secs(3600);
+    /// let minute = Duration::from_secs(60);
+    /// // 100/min during business hours, 20/min overnight.
+    /// let policy = Policy::new(20, minute).during(hour * 9..hour * 17, 100, minute);
+    /// ```
+    ///
+    /// Hours ending before they start span midnight, like `hour * 22..hour * 6`. If the
+    /// hours of several calls overlap, the first one applies. Entities switch limits
+    /// as their time comes, keeping what they consumed. The wall-clock time is read from
+    /// the limiter's `Clock`.
+    pub fn during(
+        mut self,
+        hours: Range<Duration>,
+        max_limit: usize,
+        refresh_rate: Duration,
+    ) -> Self {
+        self.time_of_day.push(TimeOfDayLimit {
+            hours,
+            max_limit,
+            refresh_rate,
+        });
+        self
+    }
+
+    /// Keeps allowing requests once the limit is reached, up to `max_debt` more, so a
+    /// short spike isn't denied outright. The debt is repaid from the next refills before
+    /// their requests can be used, so the rate evens out over time.
+    pub fn with_overdraft(mut self, max_debt: usize) -> Self {
+        self.overdraft = max_debt;
+        self
+    }
+
+    /// Denies every request for `period` once the limit is hit, even if the bucket
+    /// refills sooner, so clients hammering right at the reset don't get through first.
+    pub fn with_cooldown(mut self, period: Duration) -> Self {
+        self.cooldown = Some(period);
+        self
+    }
+
+    /// Makes every fixed window up to `percent` of `refresh_rate` longer or shorter,
+    /// at random, so clients denied at the same instant don't all come back at the
+    /// same instant either. Windows last `refresh_rate` on average. Capped at 100.
+    ///
+    /// Aligned and scheduled windows, and the other algorithms, aren't jittered.
+    pub fn with_jitter(mut self, percent: u8) -> Self {
+        self.jitter = percent.min(100);
+        self
+    }
+
+    /// Draws the random window lengths of `with_jitter` from `seed` and the hash of each
+    /// entity, so tests see the same windows on every run while every entity added with
+    /// the policy still draws windows of its own.
+    pub fn with_jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// The `bucket_max` and `refresh_rate` of entities added with the policy, which
+    /// refill one request every `refresh_rate / max_limit` even with a burst.
+    pub(crate) fn bucket(&self) -> (usize, Duration) {
+        let (max_limit, refresh_rate) = self.sustained();
+        (
+            self.adaptive
+                .map_or(max_limit, |aimd| aimd.clamp(max_limit)),
+            refresh_rate,
+        )
+    }
+
+    fn sustained(&self) -> (usize, Duration) {
+        match self.burst {
+            Some(burst) if self.algorithm.is_continuous() && self.max_limit > 0 => {
+                let nanos = self.refresh_rate.as_nanos() * burst as u128 / self.max_limit as u128;
+                let nanos = u64::try_from(nanos).unwrap_or(u64::MAX);
+                (burst, Duration::from_nanos(nanos))
+            }
+            _ => (self.max_limit, self.refresh_rate),
+        }
+    }
+
+    /// Parses a rate like `5/s`, `300/5m` or `10k/day` into a fixed window policy.
+    ///
+    /// The limit takes an optional `k` (thousand) or `M` (million) suffix. The window is
+    /// an optional count followed by a unit: `ms`, `s`, `m`, `h` or `d`, or spelled out
+    /// like `sec`, `min`, `hour` or `day`.
+    pub fn parse(rate: &str) -> Result<Self, ParsePolicyError> {
+        let (limit, window) = rate
+            .split_once('/')
+            .ok_or_else(|| ParsePolicyError::MissingSlash(rate.to_string()))?;
+        let max_limit = parse_limit(limit.trim())
+            .ok_or_else(|| ParsePolicyError::InvalidLimit(limit.to_string()))?;
+        let refresh_rate = parse_window(window.trim())
+            .ok_or_else(|| ParsePolicyError::InvalidWindow(window.to_string()))?;
+        Ok(Policy::new(max_limit, refresh_rate))
+    }
+}
+
+impl FromStr for Policy {
+    type Err = ParsePolicyError;
+
+    fn from_str(rate: &str) -> Result<Self, Self::Err> {
+        Policy::parse(rate)
+    }
+}
+
+/// Why a rate couldn't be parsed by `Policy::parse`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsePolicyError {
+    /// The rate has no `/` between the limit and the window.
+    MissingSlash(String),
+    /// The part before the `/` is not a number of requests.
+    InvalidLimit(String),
+    /// The part after the `/` is not a window.
+    InvalidWindow(String),
+    /// The reset schedule is not a cron expression.
+    InvalidSchedule(String),
+}
+
+impl fmt::Display for ParsePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParsePolicyError::MissingSlash(rate) => {
+                write!(f, "expected a rate like `100/min`, got `{}`", rate)
+            }
+            ParsePolicyError::InvalidLimit(limit) => write!(f, "invalid limit `{}`", limit),
+            ParsePolicyError::InvalidWindow(window) => write!(f, "invalid window `{}`", window),
+            ParsePolicyError::InvalidSchedule(schedule) => {
+                write!(f, "invalid cron schedule `{}`", schedule)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParsePolicyError {}
+
+fn parse_limit(limit: &str) -> Option<usize> {
+    let (digits, scale) = match limit.strip_suffix(['k', 'K']) {
+        Some(digits) => (digits, 1_000),
+        None => match limit.strip_suffix('M') {
+            Some(digits) => (digits, 1_000_000),
+            None => (limit, 1),
+        },
+    };
+    digits.parse::<usize>().ok()?.checked_mul(scale)
+}
+
+pub(crate) fn parse_window(window: &str) -> Option<Duration> {
+    let split = window
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(window.len());
+    let (count, unit) = window.split_at(split);
+    let count = match count {
+        "" => 1,
+        count => count.parse::<u32>().ok()?,
+    };
+    let unit = match unit.trim() {
+        "ms" | "millis" => Duration::from_millis(1),
+        "s" | "sec" | "second" | "seconds" => Duration::from_secs(1),
+        "m" | "min" | "minute" | "minutes" => Duration::from_secs(60),
+        "h" | "hr" | "hour" | "hours" => Duration::from_secs(60 * 60),
+        "d" | "day" | "days" => Duration::from_secs(24 * 60 * 60),
+        _ => return None,
+    };
+    unit.checked_mul(count).filter(|window| !window.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let parse = |rate: &str| {
+            rate.parse::<Policy>()
+                .map(|p| (p.max_limit, p.refresh_rate))
+        };
+        assert_eq!(parse("5/s"), Ok((5, Duration::from_secs(1))));
+        assert_eq!(parse("100/min"), Ok((100, Duration::from_secs(60))));
+        assert_eq!(parse("300/5m"), Ok((300, Duration::from_secs(300))));
+        assert_eq!(parse("10k/day"), Ok((10_000, Duration::from_secs(86_400))));
+        assert_eq!(
+            parse(" 2M / 2 hours "),
+            Ok((2_000_000, Duration::from_secs(7_200)))
+        );
+        assert_eq!(parse("50/250ms"), Ok((50, Duration::from_millis(250))));
+
+        assert!(matches!(
+            parse("100"),
+            Err(ParsePolicyError::MissingSlash(_))
+        ));
+        assert!(matches!(
+            parse("ten/s"),
+            Err(ParsePolicyError::InvalidLimit(_))
+        ));
+        assert!(matches!(
+            parse("10/fortnight"),
+            Err(ParsePolicyError::InvalidWindow(_))
+        ));
+        assert!(matches!(
+            parse("10/0s"),
+            Err(ParsePolicyError::InvalidWindow(_))
+        ));
+    }
+
+    #[test]
+    fn test_burst() {
+        let policy = Policy::new(5, Duration::from_secs(1)).with_burst(20);
+        assert_eq!(policy.algorithm, Algorithm::TokenBucket);
+        assert_eq!(policy.bucket(), (20, Duration::from_secs(4)));
+
+        let gcra = Policy::new(5, Duration::from_secs(1))
+            .with_algorithm(Algorithm::Gcra)
+            .with_burst(1);
+        assert_eq!(gcra.algorithm, Algorithm::Gcra);
+        assert_eq!(gcra.bucket(), (1, Duration::from_millis(200)));
+    }
+}
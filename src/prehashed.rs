@@ -0,0 +1,235 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::{Arc, OnceLock};
+
+use hashbrown::HashSet;
+
+use crate::sync::RwLock;
+
+/// A key hashed once up front, so limiters don't hash long keys again on every call.
+///
+/// Meant for keys like JWT subjects or API tokens, hundreds of bytes long, that are
+/// looked up several times per request. Hash them into a `PrehashedKey` once, and
+/// use a limiter hashing with `PrehashedState`, which takes the hash as it is:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, LimiterBuilder, PrehashedKey, PrehashedState};
+/// let limiter: Limiter<PrehashedKey, PrehashedState> = LimiterBuilder::new()
+///     .default_limit(100, Duration::from_secs(60))
+///     .build_with_hasher(PrehashedState);
+///
+/// let subject = PrehashedKey::new("eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiIxMjM0NTY3ODkwIn0");
+/// assert!(limiter.check(&subject).is_allowed());
+/// assert_eq!(limiter.get_bucket_remaining(&subject), Some(99));
+/// ```
+///
+/// Keys are compared by their hash alone. Strings are hashed with SipHash keyed at
+/// random once per process, so clients can't craft keys that collide, and two
+/// different keys share a hash with a chance of about 2^-64. Hashes don't carry over
+/// to other processes, persist the original instead.
+#[derive(Clone)]
+pub struct PrehashedKey {
+    hash: u64,
+    original: Option<Arc<str>>, // Kept for hooks, snapshots and debugging, never compared
+}
+
+impl PrehashedKey {
+    /// Hashes `key`, keeping it as the original.
+    pub fn new(key: &str) -> Self {
+        PrehashedKey {
+            hash: hash_str(key),
+            original: Some(key.into()),
+        }
+    }
+
+    /// A key that is nothing but `hash`, e.g. one computed by a proxy in front.
+    pub fn from_hash(hash: u64) -> Self {
+        PrehashedKey {
+            hash,
+            original: None,
+        }
+    }
+
+    /// The hash the key is looked up by.
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// The key that was hashed, `None` for keys created with `from_hash`.
+    pub fn original(&self) -> Option<&str> {
+        self.original.as_deref()
+    }
+}
+
+fn hash_str(key: &str) -> u64 {
+    static STATE: OnceLock<RandomState> = OnceLock::new();
+    STATE.get_or_init(RandomState::new).hash_one(key)
+}
+
+impl PartialEq for PrehashedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash
+    }
+}
+
+impl Eq for PrehashedKey {}
+
+impl Hash for PrehashedKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl fmt::Debug for PrehashedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.original {
+            Some(original) => write!(f, "PrehashedKey({:?})", original),
+            None => write!(f, "PrehashedKey({:#018x})", self.hash),
+        }
+    }
+}
+
+impl fmt::Display for PrehashedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.original {
+            Some(original) => f.write_str(original),
+            None => write!(f, "{:#018x}", self.hash),
+        }
+    }
+}
+
+impl From<&str> for PrehashedKey {
+    fn from(key: &str) -> Self {
+        PrehashedKey::new(key)
+    }
+}
+
+/// Hashes `PrehashedKey`s to the hash they carry, without hashing anything again,
+/// see `LimiterBuilder::build_with_hasher`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrehashedState;
+
+impl BuildHasher for PrehashedState {
+    type Hasher = PrehashedHasher;
+
+    fn build_hasher(&self) -> PrehashedHasher {
+        PrehashedHasher(0)
+    }
+}
+
+/// The `Hasher` of `PrehashedState`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrehashedHasher(u64);
+
+impl Hasher for PrehashedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+
+    /// Only `PrehashedKey`s are meant to be hashed, anything else is mixed in
+    /// with FNV-1a so it still works, if slowly.
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Shares one allocation between every `PrehashedKey` of the same original.
+///
+/// Hashing a key into a `PrehashedKey` copies it, so the same subject seen on
+/// thousands of requests would be copied as often. Interned keys point to a single
+/// copy, which lives until `clear`, e.g. after `Limiter::evict_idle` ran:
+///
+/// ```
+/// # use rate_gate::KeyInterner;
+/// let interner = KeyInterner::new();
+/// let first = interner.intern("user1");
+/// let second = interner.intern("user1");
+/// assert_eq!(first, second);
+/// assert_eq!(interner.len(), 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct KeyInterner {
+    keys: RwLock<HashSet<PrehashedKey, PrehashedState>>,
+}
+
+impl KeyInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hashes `key` and returns it as a `PrehashedKey`, sharing the original with
+    /// the keys interned before.
+    pub fn intern(&self, key: &str) -> PrehashedKey {
+        let hashed = PrehashedKey::from_hash(hash_str(key));
+        if let Some(interned) = self.keys.read().get(&hashed) {
+            return interned.clone();
+        }
+        // Someone may have interned it in the meantime.
+        self.keys
+            .write()
+            .get_or_insert_with(&hashed, |hashed| PrehashedKey {
+                hash: hashed.hash,
+                original: Some(key.into()),
+            })
+            .clone()
+    }
+
+    /// How many originals are interned.
+    pub fn len(&self) -> usize {
+        self.keys.read().len()
+    }
+
+    /// Returns `true` if nothing is interned.
+    pub fn is_empty(&self) -> bool {
+        self.keys.read().is_empty()
+    }
+
+    /// Forgets every interned original, keys handed out keep theirs.
+    pub fn clear(&self) {
+        self.keys.write().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prehashed_key() {
+        let key = PrehashedKey::new("user1");
+        assert_eq!(key, PrehashedKey::new("user1"));
+        assert_eq!(key, PrehashedKey::from_hash(key.hash()));
+        assert_ne!(key, PrehashedKey::new("user2"));
+        assert_eq!(PrehashedState.hash_one(&key), key.hash());
+        assert_eq!(key.to_string(), "user1");
+        assert_eq!(
+            PrehashedKey::from_hash(255).to_string(),
+            "0x00000000000000ff"
+        );
+    }
+
+    #[test]
+    fn test_interner_shares_originals() {
+        let interner = KeyInterner::new();
+        let first = interner.intern("user1");
+        let second = interner.intern("user1");
+        assert!(Arc::ptr_eq(
+            first.original.as_ref().unwrap(),
+            second.original.as_ref().unwrap()
+        ));
+        assert_eq!(second.original(), Some("user1"));
+
+        interner.intern("user2");
+        assert_eq!(interner.len(), 2);
+        interner.clear();
+        assert!(interner.is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashMap;
+
+use crate::sync::Mutex;
+
+/// The tasks waiting in `Limiter::acquire`, a queue per entity.
+///
+/// Only the task at the front of a queue waits for the bucket, the others wait for
+/// their turn, so they are served in the order they arrived instead of whoever
+/// happens to wake up first after a refill.
+///
+/// The queues are spread over shards like the entities, so tasks waiting for entities
+/// of different shards never wait on each other to join or leave.
+#[derive(Debug)]
+pub(crate) struct WaitQueues<T> {
+    shards: Box<[Mutex<Queues<T>>]>,
+    hasher: DefaultHashBuilder,
+}
+
+/// The queues of the entities of a shard with waiters.
+type Queues<T> = HashMap<T, Arc<WaitQueue>>;
+
+/// Cloned and dropped under the lock of its shard only, so its strong count is
+/// how many tasks wait, plus one for the map.
+#[derive(Debug, Default)]
+struct WaitQueue {
+    turn: tokio::sync::Mutex<()>, // Fair, handed to waiters in the order they asked
+}
+
+impl<T> WaitQueues<T>
+where
+    T: Hash + Eq,
+{
+    /// Creates `count` shards of queues, rounded up to the next power of two.
+    pub(crate) fn new(count: usize) -> Self {
+        let count = count.max(1).next_power_of_two();
+        WaitQueues {
+            shards: (0..count).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: DefaultHashBuilder::default(),
+        }
+    }
+
+    /// Returns `true` if tasks are waiting for `entity`.
+    pub(crate) fn is_waiting<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.depth(entity) > 0
+    }
+
+    /// How many tasks are waiting for `entity`, including the one at the front.
+    pub(crate) fn depth<Q>(&self, entity: &Q) -> usize
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(entity)
+            .lock()
+            .get(entity)
+            .map_or(0, |queue| Arc::strong_count(queue) - 1)
+    }
+
+    /// Joins the back of the queue of `entity`, leaving it when the waiter is dropped.
+    pub(crate) fn join<'a, Q>(&'a self, entity: &'a Q) -> Waiter<'a, T, Q>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = T> + ?Sized,
+    {
+        let mut queues = self.shard(entity).lock();
+        let queue = match queues.get(entity) {
+            Some(queue) => Arc::clone(queue),
+            None => {
+                let queue = Arc::new(WaitQueue::default());
+                queues.insert(entity.to_owned(), Arc::clone(&queue));
+                queue
+            }
+        };
+        Waiter {
+            queues: self,
+            entity,
+            queue: Some(queue),
+        }
+    }
+
+    /// The shard of the queue of `entity`.
+    fn shard<Q>(&self, entity: &Q) -> &Mutex<Queues<T>>
+    where
+        Q: Hash + ?Sized,
+    {
+        let index = self.hasher.hash_one(entity) as usize & (self.shards.len() - 1);
+        &self.shards[index]
+    }
+}
+
+/// A task waiting in a queue of `WaitQueues`.
+#[derive(Debug)]
+pub(crate) struct Waiter<'a, T, Q>
+where
+    T: Borrow<Q> + Hash + Eq,
+    Q: Hash + Eq + ?Sized,
+{
+    queues: &'a WaitQueues<T>,
+    entity: &'a Q,
+    queue: Option<Arc<WaitQueue>>, // Taken on drop
+}
+
+impl<T, Q> Waiter<'_, T, Q>
+where
+    T: Borrow<Q> + Hash + Eq,
+    Q: Hash + Eq + ?Sized,
+{
+    /// Waits until every task that joined the queue before is done.
+    pub(crate) async fn turn(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.queue
+            .as_ref()
+            .expect("queue taken on drop")
+            .turn
+            .lock()
+            .await
+    }
+}
+
+impl<T, Q> Drop for Waiter<'_, T, Q>
+where
+    T: Borrow<Q> + Hash + Eq,
+    Q: Hash + Eq + ?Sized,
+{
+    fn drop(&mut self) {
+        let Some(queue) = self.queue.take() else {
+            return;
+        };
+        let mut queues = self.queues.shard(self.entity).lock();
+        drop(queue);
+        // The map holds the last reference once nobody waits anymore.
+        if queues
+            .get(self.entity)
+            .is_some_and(|queue| Arc::strong_count(queue) == 1)
+        {
+            queues.remove(self.entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leaving_removes_only_empty_queues() {
+        let queues: WaitQueues<String> = WaitQueues::new(4);
+        let first = queues.join("user1");
+        let second = queues.join("user1");
+        let other = queues.join("user2");
+        assert_eq!((queues.depth("user1"), queues.depth("user2")), (2, 1));
+
+        drop(first);
+        assert_eq!(queues.depth("user1"), 1);
+        drop(second);
+        assert!(!queues.is_waiting("user1"));
+        assert!(queues.is_waiting("user2"));
+        drop(other);
+        assert!(queues.shards.iter().all(|shard| shard.lock().is_empty()));
+    }
+}
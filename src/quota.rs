@@ -0,0 +1,377 @@
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::headers::{civil_from_days, days_from_civil};
+use crate::store::{AsyncStore, Store};
+use crate::{Clock, Decision, Limiter, SystemClock};
+
+/// A calendar period quotas are counted in, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Period {
+    /// From midnight to midnight.
+    Day,
+    /// From the first of the month to the first of the next, however many days it has.
+    Month,
+}
+
+impl Period {
+    /// The period `since_epoch` falls into: its name, e.g. `2024-02-29` or `2024-02`,
+    /// and when it starts and ends in seconds since the Unix epoch.
+    fn current(self, since_epoch: Duration) -> (String, u64, u64) {
+        let day = since_epoch.as_secs() / 86_400;
+        let (year, month, date) = civil_from_days(day);
+        match self {
+            Period::Day => (
+                format!("{:04}-{:02}-{:02}", year, month, date),
+                day * 86_400,
+                (day + 1) * 86_400,
+            ),
+            Period::Month => {
+                let (next_year, next_month) = match month {
+                    12 => (year + 1, 1),
+                    month => (year, month + 1),
+                };
+                let start = days_from_civil(year, month, 1).unwrap_or(day);
+                let end = days_from_civil(next_year, next_month, 1).unwrap_or(day + 1);
+                (
+                    format!("{:04}-{:02}", year, month),
+                    start * 86_400,
+                    end * 86_400,
+                )
+            }
+        }
+    }
+}
+
+/// `limit` requests per calendar `period`, e.g. the 10k API calls a month of a billing plan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quota {
+    /// How many requests a period allows.
+    pub limit: usize,
+    /// The period the requests are counted in.
+    pub period: Period,
+}
+
+impl Quota {
+    /// `limit` requests per calendar day.
+    pub fn per_day(limit: usize) -> Self {
+        Quota {
+            limit,
+            period: Period::Day,
+        }
+    }
+
+    /// `limit` requests per calendar month.
+    pub fn per_month(limit: usize) -> Self {
+        Quota {
+            limit,
+            period: Period::Month,
+        }
+    }
+}
+
+/// Counts requests against a calendar `Quota` in a `Store`, so e.g. a monthly
+/// quota survives restarts when kept in a `SledStore` or shared `RedisStore`.
+///
+/// Every key gets a counter per period, stored as `<key>:<period>`, e.g. `user1:2024-02`
+/// for a monthly quota. A new period starts with a new counter, and the decisions tell
+/// the time until the period ends, so a client out of its monthly quota is told to
+/// come back on the first of the next month.
+///
+/// Quotas are for billing, not bursts: layer one over the short-term limits of a
+/// `Limiter` with `consume_layered`.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, Quota, QuotaLimiter, Store, Decision};
+/// # struct MemoryStore;
+/// # impl Store for MemoryStore {
+/// #     type Error = std::convert::Infallible;
+/// #     fn consume(&self, _: &str, _: usize, reset_in: Duration, _: usize) -> Result<Decision, Self::Error> {
+/// #         Ok(Decision::Allowed { remaining: 0, reset_in })
+/// #     }
+/// # }
+/// let quota = QuotaLimiter::new(MemoryStore, Quota::per_month(10_000));
+/// let limiter: Limiter<String> = Limiter::with_default(10, Duration::from_secs(1));
+///
+/// assert!(quota.consume_layered(&limiter, "user1", 1).unwrap().is_allowed());
+/// ```
+#[derive(Debug, Clone)]
+pub struct QuotaLimiter<S> {
+    store: S,
+    quota: Quota,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S> QuotaLimiter<S> {
+    /// Counts the requests of every key against `quota` in `store`.
+    pub fn new(store: S, quota: Quota) -> Self {
+        QuotaLimiter {
+            store,
+            quota,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Sets where the calendar date is read from, e.g. a `ManualClock` in tests.
+    /// Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The store the counters are kept in.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The quota requests are counted against.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// The stored key of `key`'s counter in the current period, the length of
+    /// the period and the time until it ends.
+    fn period(&self, key: &str) -> (String, Duration, Duration) {
+        let since_epoch = self
+            .clock
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let (name, start, end) = self.quota.period.current(since_epoch);
+        (
+            format!("{}:{}", key, name),
+            Duration::from_secs(end - start),
+            Duration::from_secs(end).saturating_sub(since_epoch),
+        )
+    }
+}
+
+/// Points the decision of the store at the end of the period. The store's window
+/// started with the period's first request and may outlast it, the next period
+/// counts under another key anyway.
+fn until_period_end(decision: Decision, ends_in: Duration) -> Decision {
+    match decision {
+        Decision::Allowed { remaining, .. } => Decision::Allowed {
+            remaining,
+            reset_in: ends_in,
+        },
+        Decision::Denied { .. } => Decision::Denied {
+            retry_after: ends_in,
+        },
+        decision => decision,
+    }
+}
+
+impl<S> QuotaLimiter<S>
+where
+    S: Store,
+{
+    /// Counts a request of `key` against its quota.
+    pub fn check(&self, key: &str) -> Result<Decision, S::Error> {
+        self.consume(key, 1)
+    }
+
+    /// Counts `cost` requests of `key` against its quota at once.
+    /// The request is denied, and nothing is counted, if fewer than `cost` requests are left.
+    pub fn consume(&self, key: &str, cost: usize) -> Result<Decision, S::Error> {
+        let (stored, length, ends_in) = self.period(key);
+        let decision = self
+            .store
+            .consume(&stored, self.quota.limit, length, cost)?;
+        Ok(until_period_end(decision, ends_in))
+    }
+
+    /// Consumes `cost` requests of `key` from `limiter` first, and counts them against
+    /// the quota only once the short-term limits allowed them. Requests the quota
+    /// denies are refunded to `limiter`, so they don't use up its bucket either.
+    ///
+    /// Keys unknown to `limiter` are only limited by the quota. An allowed request
+    /// reports whichever of the two has fewer requests left.
+    pub fn consume_layered(
+        &self,
+        limiter: &Limiter<String>,
+        key: &str,
+        cost: usize,
+    ) -> Result<Decision, S::Error> {
+        let short_term = limiter.consume(key, cost);
+        if !matches!(short_term, Decision::Allowed { .. } | Decision::Unknown) {
+            return Ok(short_term);
+        }
+        let quota = self.consume(key, cost);
+        let refund = !matches!(quota, Ok(Decision::Allowed { .. }));
+        if refund && short_term.is_allowed() {
+            limiter.refund(key, cost);
+        }
+        Ok(tightest(short_term, quota?))
+    }
+}
+
+impl<S> QuotaLimiter<S>
+where
+    S: AsyncStore,
+{
+    /// Counts a request of `key` against its quota in an `AsyncStore`.
+    pub async fn check_async(&self, key: &str) -> Result<Decision, S::Error> {
+        self.consume_async(key, 1).await
+    }
+
+    /// Counts `cost` requests of `key` against its quota in an `AsyncStore` at once.
+    pub async fn consume_async(&self, key: &str, cost: usize) -> Result<Decision, S::Error> {
+        let (stored, length, ends_in) = self.period(key);
+        let decision = self
+            .store
+            .consume(&stored, self.quota.limit, length, cost)
+            .await?;
+        Ok(until_period_end(decision, ends_in))
+    }
+}
+
+/// The decision of a request allowed by the short-term limits, if any, and the quota.
+fn tightest(short_term: Decision, quota: Decision) -> Decision {
+    match (short_term, quota) {
+        (
+            Decision::Allowed { remaining, .. },
+            Decision::Allowed {
+                remaining: quota_remaining,
+                ..
+            },
+        ) if remaining < quota_remaining => short_term,
+        (_, quota) => quota,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LimiterBuilder, ManualClock};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+    use std::time::SystemTime;
+
+    /// Counts requests without ever resetting, periods roll over by key.
+    #[derive(Default)]
+    struct CountingStore(Mutex<HashMap<String, usize>>);
+
+    impl Store for CountingStore {
+        type Error = Infallible;
+
+        fn consume(
+            &self,
+            key: &str,
+            max_limit: usize,
+            refresh_rate: Duration,
+            cost: usize,
+        ) -> Result<Decision, Infallible> {
+            let mut counts = self.0.lock().unwrap();
+            let count = counts.entry(key.to_string()).or_default();
+            if *count + cost > max_limit {
+                return Ok(Decision::Denied {
+                    retry_after: refresh_rate,
+                });
+            }
+            *count += cost;
+            Ok(Decision::Allowed {
+                remaining: max_limit - *count,
+                reset_in: refresh_rate,
+            })
+        }
+    }
+
+    /// 2024-02-29, a leap day, at `time` seconds after midnight UTC.
+    fn leap_day(time: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(19_782 * 86_400 + time)
+    }
+
+    #[test]
+    fn test_periods() {
+        let since_epoch = |time| leap_day(time).duration_since(UNIX_EPOCH).unwrap();
+        let (day, start, end) = Period::Day.current(since_epoch(3600));
+        assert_eq!(day, "2024-02-29");
+        assert_eq!(end - start, 86_400);
+
+        let (month, start, end) = Period::Month.current(since_epoch(3600));
+        assert_eq!(month, "2024-02");
+        assert_eq!(end - start, 29 * 86_400);
+        let (month, ..) = Period::Month.current(since_epoch(86_400));
+        assert_eq!(month, "2024-03");
+    }
+
+    #[test]
+    fn test_quota_rolls_over() {
+        let clock = ManualClock::new();
+        clock.set_system_time(leap_day(86_400 - 10));
+        let quota =
+            QuotaLimiter::new(CountingStore::default(), Quota::per_month(2)).clock(clock.clone());
+
+        assert!(quota.check("user1").unwrap().is_allowed());
+        assert!(!quota.consume("user1", 2).unwrap().is_allowed());
+        assert!(quota.check("user1").unwrap().is_allowed());
+        assert_eq!(
+            quota.check("user1").unwrap(),
+            Decision::Denied {
+                retry_after: Duration::from_secs(10)
+            }
+        );
+        assert!(quota.check("user2").unwrap().is_allowed());
+
+        // March, a new counter.
+        clock.advance(Duration::from_secs(10));
+        assert!(quota.check("user1").unwrap().is_allowed());
+        let counts = quota.store().0.lock().unwrap();
+        assert_eq!(counts.get("user1:2024-02"), Some(&2));
+        assert_eq!(counts.get("user1:2024-03"), Some(&1));
+    }
+
+    #[test]
+    fn test_consume_layered() {
+        let clock = ManualClock::new();
+        clock.set_system_time(leap_day(0));
+        let limiter: Limiter<String> = LimiterBuilder::new().clock(clock.clone()).build();
+        limiter.add_limited_entity("user1".to_string(), 2, Duration::from_secs(60));
+        let quota =
+            QuotaLimiter::new(CountingStore::default(), Quota::per_day(3)).clock(clock.clone());
+
+        assert!(quota
+            .consume_layered(&limiter, "user1", 1)
+            .unwrap()
+            .is_allowed());
+        assert_eq!(
+            quota.consume_layered(&limiter, "user1", 1).unwrap(),
+            Decision::Allowed {
+                remaining: 0,
+                reset_in: Duration::from_secs(60)
+            }
+        );
+        // Denied by the short-term limit, the quota isn't touched.
+        assert!(!quota
+            .consume_layered(&limiter, "user1", 1)
+            .unwrap()
+            .is_allowed());
+
+        clock.advance(Duration::from_secs(60));
+        assert!(quota
+            .consume_layered(&limiter, "user1", 1)
+            .unwrap()
+            .is_allowed());
+        // Denied by the quota, refunded to the short-term limit.
+        assert!(!quota
+            .consume_layered(&limiter, "user1", 1)
+            .unwrap()
+            .is_allowed());
+        assert_eq!(limiter.get_bucket_remaining("user1"), Some(1));
+
+        // Unknown to the limiter, only the quota applies.
+        assert!(quota
+            .consume_layered(&limiter, "user2", 3)
+            .unwrap()
+            .is_allowed());
+        assert!(!quota
+            .consume_layered(&limiter, "user2", 1)
+            .unwrap()
+            .is_allowed());
+    }
+}
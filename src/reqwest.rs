@@ -0,0 +1,220 @@
+//! Client side rate limiting for `reqwest`, see `RateLimitedClient`.
+
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use ::reqwest::{Client, Request, RequestBuilder, Response, StatusCode};
+
+use crate::{Limiter, RateGateError};
+
+/// Finds the entity a request counts against, `None` sends it unlimited.
+type KeyFn<T> = Arc<dyn Fn(&Request) -> Option<T> + Send + Sync>;
+
+/// A `reqwest::Client` that waits for its `Limiter` before sending a request,
+/// to stay under the limits of the APIs it talks to.
+///
+/// Requests count against their host, unless keyed otherwise with `with_key`.
+/// Hosts the limiter doesn't know are sent right away, so give it a default limit
+/// or add every host to pace:
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use rate_gate::{reqwest::RateLimitedClient, Limiter};
+/// # async fn run() -> Result<(), rate_gate::reqwest::Error> {
+/// let limiter = Limiter::with_default(10, Duration::from_secs(1));
+/// let client = RateLimitedClient::new(reqwest::Client::new(), limiter)
+///     .honor_too_many_requests();
+///
+/// let response = client.send(client.client().get("http://example.com")).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RateLimitedClient<T = String>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    client: Client,
+    limiter: Limiter<T>,
+    key: KeyFn<T>,
+    honor_too_many_requests: bool,
+}
+
+impl RateLimitedClient {
+    /// Paces requests by the host of their URL.
+    pub fn new(client: Client, limiter: Limiter<String>) -> Self {
+        RateLimitedClient::with_key(client, limiter, |request: &Request| {
+            request.url().host_str().map(str::to_owned)
+        })
+    }
+}
+
+impl<T> RateLimitedClient<T>
+where
+    T: Hash + Eq + Clone + Send + 'static,
+{
+    /// Paces requests by what `key` returns for them, e.g. the API token they carry.
+    /// Requests it returns `None` for are sent right away.
+    pub fn with_key(
+        client: Client,
+        limiter: Limiter<T>,
+        key: impl Fn(&Request) -> Option<T> + Send + Sync + 'static,
+    ) -> Self {
+        RateLimitedClient {
+            client,
+            limiter,
+            key: Arc::new(key),
+            honor_too_many_requests: false,
+        }
+    }
+
+    /// Backs off once a server answers with `429 Too Many Requests`: the entity is
+    /// out of requests until its `Retry-After`, or its next refill without one.
+    /// See `Limiter::sync_from_headers`.
+    pub fn honor_too_many_requests(mut self) -> Self {
+        self.honor_too_many_requests = true;
+        self
+    }
+
+    /// The client requests are sent with, e.g. to build them.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The limiter requests wait for.
+    pub fn limiter(&self) -> &Limiter<T> {
+        &self.limiter
+    }
+
+    /// Builds the request and sends it once the limiter allows it, see `execute`.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, Error> {
+        self.execute(request.build()?).await
+    }
+
+    /// Waits until the limiter allows `request`, then sends it.
+    ///
+    /// Fails without sending if the entity of the request is banned.
+    pub async fn execute(&self, request: Request) -> Result<Response, Error> {
+        let Some(key) = (self.key)(&request) else {
+            return Ok(self.client.execute(request).await?);
+        };
+        match self.limiter.acquire(&key).await.into_result() {
+            Ok(_) | Err(RateGateError::EntityNotFound) => {}
+            Err(err) => return Err(Error::Limited(err)),
+        }
+        let response = self.client.execute(request).await?;
+        if self.honor_too_many_requests && response.status() == StatusCode::TOO_MANY_REQUESTS {
+            self.limiter.penalize(&key, usize::MAX);
+            self.limiter.sync_from_headers(&key, response.headers());
+        }
+        Ok(response)
+    }
+}
+
+impl<T> fmt::Debug for RateLimitedClient<T>
+where
+    T: Hash + Eq + Send + fmt::Debug + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitedClient")
+            .field("client", &self.client)
+            .field("limiter", &self.limiter)
+            .field("honor_too_many_requests", &self.honor_too_many_requests)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Why `RateLimitedClient` couldn't send a request.
+#[derive(Debug)]
+pub enum Error {
+    /// The limiter doesn't let the request through, e.g. its entity is banned.
+    Limited(RateGateError),
+    /// The request failed.
+    Request(::reqwest::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Limited(err) => write!(f, "request not sent: {}", err),
+            Error::Request(err) => write!(f, "request failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Limited(err) => Some(err),
+            Error::Request(err) => Some(err),
+        }
+    }
+}
+
+impl From<::reqwest::Error> for Error {
+    fn from(err: ::reqwest::Error) -> Self {
+        Error::Request(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers every connection with `response`, returns the address to send to.
+    async fn serve(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_waits_for_limiter() {
+        let url = serve("HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+        let limiter = Limiter::new();
+        limiter.add_limited_entity("127.0.0.1".to_string(), 1, Duration::from_secs(60));
+        let client = RateLimitedClient::new(Client::new(), limiter);
+
+        let response = client.send(client.client().get(&url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let start = tokio::time::Instant::now();
+        client.send(client.client().get(&url)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(59));
+
+        client
+            .limiter()
+            .ban("127.0.0.1".to_string(), Duration::from_secs(60));
+        assert!(matches!(
+            client.send(client.client().get(&url)).await,
+            Err(Error::Limited(RateGateError::Banned { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_honor_too_many_requests() {
+        let url = serve(
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 30\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        )
+        .await;
+        let limiter = Limiter::new();
+        limiter.add_limited_entity("127.0.0.1".to_string(), 5, Duration::from_secs(1));
+        let client = RateLimitedClient::new(Client::new(), limiter).honor_too_many_requests();
+
+        let response = client.send(client.client().get(&url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = client.limiter().retry_after("127.0.0.1").unwrap();
+        assert!(retry_after > Duration::from_secs(29));
+    }
+}
@@ -0,0 +1,67 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::Limiter;
+
+/// A request taken from the bucket of an entity that isn't final yet, created with
+/// `Limiter::reserve`.
+///
+/// Dropping the reservation without calling `commit` gives the request back, so work
+/// that gets cancelled or fails halfway doesn't cost the entity any quota.
+#[must_use = "dropping a Reservation refunds it right away"]
+#[derive(Debug)]
+pub struct Reservation<'a, T, Q, S = DefaultHashBuilder>
+where
+    T: Hash + Eq + Send + Borrow<Q> + 'static,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    limiter: &'a Limiter<T, S>,
+    entity: &'a Q,
+    cost: usize,
+    committed: bool,
+}
+
+impl<'a, T, Q, S> Reservation<'a, T, Q, S>
+where
+    T: Hash + Eq + Send + Borrow<Q> + 'static,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    pub(crate) fn new(limiter: &'a Limiter<T, S>, entity: &'a Q, cost: usize) -> Self {
+        Reservation {
+            limiter,
+            entity,
+            cost,
+            committed: false,
+        }
+    }
+
+    /// The number of requests held by the reservation.
+    pub fn cost(&self) -> usize {
+        self.cost
+    }
+
+    /// Keeps the reserved requests consumed.
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+
+    /// Gives the reserved requests back right away, same as dropping the reservation.
+    pub fn rollback(self) {}
+}
+
+impl<T, Q, S> Drop for Reservation<'_, T, Q, S>
+where
+    T: Hash + Eq + Send + Borrow<Q> + 'static,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        if !self.committed {
+            self.limiter.refund(self.entity, self.cost);
+        }
+    }
+}
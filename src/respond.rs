@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use http::{header, response, Response, StatusCode};
+
+use crate::{Decision, RetryAfter};
+
+/// Builds the response to requests denied by the HTTP middleware, e.g. `RateLimitLayer`.
+///
+/// Implemented by closures taking the `Decision` the request was denied with, so the
+/// body can be anything from JSON to HTML:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, RateLimitLayer, TooManyRequests};
+/// # let limiter: Limiter<String> = Limiter::with_default(100, Duration::from_secs(60));
+/// # let key = |request: &http::Request<()>| None::<String>;
+/// let layer = RateLimitLayer::new(limiter, key).responder(|decision: &rate_gate::Decision| {
+///     let retry_after = decision.retry_after().unwrap_or_default();
+///     TooManyRequests::builder(retry_after)
+///         .header("content-type", "text/html")
+///         .body("<h1>Slow down</h1>".to_string())
+///         .unwrap()
+/// });
+/// ```
+pub trait DeniedResponder<B> {
+    /// The response to a request denied with `decision`, a `Decision::Denied` or
+    /// `Decision::Banned`.
+    fn respond(&self, decision: &Decision) -> Response<B>;
+}
+
+impl<B, F> DeniedResponder<B> for F
+where
+    F: Fn(&Decision) -> Response<B>,
+{
+    fn respond(&self, decision: &Decision) -> Response<B> {
+        self(decision)
+    }
+}
+
+/// An empty `429 Too Many Requests` with a `Retry-After` header, the default response
+/// of the middleware.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TooManyRequests;
+
+impl TooManyRequests {
+    /// A `429 Too Many Requests` telling the client to retry after `retry_after`,
+    /// for responders to add a body to.
+    pub fn builder(retry_after: Duration) -> response::Builder {
+        Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, RetryAfter(retry_after).header_value())
+    }
+}
+
+impl<B: Default> DeniedResponder<B> for TooManyRequests {
+    fn respond(&self, decision: &Decision) -> Response<B> {
+        let retry_after = decision.retry_after().unwrap_or_default();
+        let mut response = Response::new(B::default());
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, RetryAfter(retry_after).header_value());
+        response
+    }
+}
+
+/// A `429 Too Many Requests` with an RFC 9457 `application/problem+json` body.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProblemJson;
+
+impl<B: From<String>> DeniedResponder<B> for ProblemJson {
+    fn respond(&self, decision: &Decision) -> Response<B> {
+        let retry_after = RetryAfter(decision.retry_after().unwrap_or_default());
+        let detail = match decision {
+            Decision::Banned { .. } => "banned",
+            _ => "rate limit exceeded",
+        };
+        let body = format!(
+            r#"{{"type":"about:blank","title":"Too Many Requests","status":429,"detail":"{}, retry after {} seconds"}}"#,
+            detail,
+            retry_after.seconds()
+        );
+        let mut response = Response::new(B::from(body));
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        let headers = response.headers_mut();
+        headers.insert(header::RETRY_AFTER, retry_after.header_value());
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/problem+json"),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_responders() {
+        let denied = Decision::Denied {
+            retry_after: Duration::from_millis(1500),
+        };
+
+        let response: Response<String> = TooManyRequests.respond(&denied);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+        assert!(response.body().is_empty());
+
+        let response: Response<String> = ProblemJson.respond(&denied);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert!(response.body().contains(r#""status":429"#));
+        assert!(response.body().contains("retry after 2 seconds"));
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+
+use crate::clock::WallTime;
+use crate::ParsePolicyError;
+
+/// When the windows of a policy reset, as a cron expression in UTC,
+/// see `Policy::with_reset_schedule`.
+///
+/// Takes the five fields of crontab, `minute hour day-of-month month day-of-week`,
+/// or the `cron` crate's six or seven with seconds first and an optional year:
+///
+/// ```
+/// # use rate_gate::ResetSchedule;
+/// let midnight: ResetSchedule = "0 0 * * *".parse().unwrap();
+/// let first_of_month = ResetSchedule::parse("0 0 0 1 * *").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct ResetSchedule {
+    expression: Arc<str>,
+    schedule: Arc<Schedule>,
+}
+
+impl ResetSchedule {
+    /// Parses a cron expression.
+    pub fn parse(expression: &str) -> Result<Self, ParsePolicyError> {
+        let trimmed = expression.trim();
+        let full = match trimmed.split_whitespace().count() {
+            5 => format!("0 {}", trimmed), // crontab has no seconds
+            _ => trimmed.to_string(),
+        };
+        let schedule = Schedule::from_str(&full)
+            .map_err(|_| ParsePolicyError::InvalidSchedule(expression.to_string()))?;
+        Ok(ResetSchedule {
+            expression: trimmed.into(),
+            schedule: Arc::new(schedule),
+        })
+    }
+
+    /// The expression the schedule was parsed from.
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    /// The window `at` falls into: the time since the schedule last fired, and until
+    /// it fires next. `None` if it doesn't fire on both sides of `at`.
+    fn window(&self, at: SystemTime) -> Option<(Duration, Duration)> {
+        let at = DateTime::<Utc>::from(at);
+        let next = self.schedule.after(&at).next()?;
+        let last = self.schedule.after(&next).next_back()?;
+        let since = (at - last).to_std().ok()?;
+        let until = (next - at).to_std().ok()?;
+        Some((since, until))
+    }
+}
+
+/// A `ResetSchedule` an entity follows, and how to tell the wall-clock time
+/// of its instants.
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct ScheduledReset {
+    schedule: ResetSchedule,
+    wall: WallTime,
+}
+
+impl ScheduledReset {
+    pub(crate) fn new(schedule: ResetSchedule, now: Instant, system_time: SystemTime) -> Self {
+        ScheduledReset {
+            schedule,
+            wall: WallTime::new(now, system_time),
+        }
+    }
+
+    /// The fixed window `now` falls into, as its start and length.
+    pub(crate) fn window(&self, now: Instant) -> Option<(Instant, Duration)> {
+        let (since, until) = self.schedule.window(self.wall.at(now))?;
+        Some((now.checked_sub(since)?, since + until))
+    }
+}
+
+impl PartialEq for ResetSchedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression
+    }
+}
+
+impl Eq for ResetSchedule {}
+
+impl Hash for ResetSchedule {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.expression.hash(state);
+    }
+}
+
+impl fmt::Display for ResetSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl FromStr for ResetSchedule {
+    type Err = ParsePolicyError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        ResetSchedule::parse(expression)
+    }
+}
+
+impl TryFrom<String> for ResetSchedule {
+    type Error = ParsePolicyError;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        ResetSchedule::parse(&expression)
+    }
+}
+
+impl From<ResetSchedule> for String {
+    fn from(schedule: ResetSchedule) -> Self {
+        schedule.expression.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let midnight = ResetSchedule::parse("0 0 * * *").unwrap();
+        let noon = SystemTime::UNIX_EPOCH + Duration::from_secs(20_000 * 86_400 + 12 * 3600);
+        let half_day = Duration::from_secs(12 * 3600);
+        assert_eq!(midnight.window(noon), Some((half_day, half_day)));
+
+        // On the dot, a new window starts.
+        let (since, until) = midnight.window(noon + half_day).unwrap();
+        assert_eq!((since, until), (Duration::ZERO, half_day * 2));
+
+        let monthly: ResetSchedule = "0 0 0 1 * *".parse().unwrap();
+        let (since, until) = monthly.window(noon).unwrap();
+        assert_eq!((since + until).as_secs() % 86_400, 0);
+        assert!(ResetSchedule::parse("every day").is_err());
+    }
+}
@@ -0,0 +1,813 @@
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::num::NonZeroUsize;
+use std::ops::Index;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "dashmap")]
+use dashmap::{DashMap, SharedValue};
+use hashbrown::hash_map::{DefaultHashBuilder, RawEntryMut};
+#[cfg(feature = "dashmap")]
+use hashbrown::raw::RawTable;
+use hashbrown::HashMap;
+
+use crate::entry::Entry;
+use crate::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// How a limiter stores its entities, see `LimiterBuilder::storage`.
+///
+/// Either way the entities are spread over shards that are locked independently,
+/// so checks and updates of entities in different shards proceed in parallel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Storage {
+    /// Maps behind the locks of the crate, `parking_lot`'s with the `parking_lot` feature.
+    #[default]
+    Sharded,
+    /// A `DashMap`, behind its own locks. Takes at least two shards.
+    #[cfg(feature = "dashmap")]
+    DashMap,
+}
+
+/// The entities of a limiter, spread over several independently locked maps.
+///
+/// Every entity lives in the shard picked by its hash, so checks on entities
+/// in different shards never wait on each other. Lookups only take a shared lock,
+/// a shard is locked exclusively only to insert or remove entities.
+#[derive(Debug)]
+pub(crate) struct Shards<T, S = DefaultHashBuilder> {
+    maps: Maps<T, S>,
+    evictions: Box<[Mutex<EvictionQueue>]>, // One per shard, only used while it's write locked
+    hasher: S,
+    capacity: Option<usize>, // Max entities per shard
+}
+
+/// The shards of a `Storage`.
+enum Maps<T, S> {
+    Sharded(Box<[RwLock<HashMap<T, Entry, S>>]>),
+    #[cfg(feature = "dashmap")]
+    DashMap(DashMap<T, Entry, S>),
+}
+
+/// A shard of a `DashMap`, the entities along with their hash.
+#[cfg(feature = "dashmap")]
+type DashShard<T> = RawTable<(T, SharedValue<Entry>)>;
+
+impl<T, S> Shards<T, S>
+where
+    T: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    /// Creates `count` shards, rounded up to the next power of two, hashing with `hasher`
+    /// and with room for `capacity` entities in total.
+    ///
+    /// With `max_entities`, every shard holds at most its share of them,
+    /// so the total is `max_entities` rounded up to a multiple of the shard count.
+    pub(crate) fn new(
+        count: usize,
+        max_entities: Option<usize>,
+        capacity: usize,
+        hasher: S,
+        storage: Storage,
+    ) -> Self {
+        let maps = match storage {
+            Storage::Sharded => {
+                let count = count.max(1).next_power_of_two();
+                let per_shard = capacity.div_ceil(count);
+                Maps::Sharded(
+                    (0..count)
+                        .map(|_| {
+                            let map = HashMap::with_capacity_and_hasher(per_shard, hasher.clone());
+                            RwLock::new(map)
+                        })
+                        .collect(),
+                )
+            }
+            #[cfg(feature = "dashmap")]
+            Storage::DashMap => {
+                let count = count.max(2).next_power_of_two();
+                Maps::DashMap(DashMap::with_capacity_and_hasher_and_shard_amount(
+                    capacity,
+                    hasher.clone(),
+                    count,
+                ))
+            }
+        };
+        let count = maps.count();
+        Shards {
+            maps,
+            evictions: (0..count)
+                .map(|_| Mutex::new(EvictionQueue::default()))
+                .collect(),
+            hasher,
+            capacity: max_entities.map(|max| max.div_ceil(count).max(1)),
+        }
+    }
+
+    /// Locks the shard `entity` belongs to for lookups.
+    pub(crate) fn read<Q>(&self, entity: &Q) -> ShardReadGuard<'_, T, S>
+    where
+        Q: Hash + ?Sized,
+    {
+        self.read_shard(self.index(entity))
+    }
+
+    /// Locks the shard `entity` belongs to for inserting or removing entities.
+    pub(crate) fn write<Q>(&self, entity: &Q) -> ShardWriteGuard<'_, T, S>
+    where
+        Q: Hash + ?Sized,
+    {
+        self.write_shard(self.index(entity))
+    }
+
+    /// Inserts `entity` into its (write locked) shard, evicting about the least recently
+    /// used entity of the shard first if it is full, see `EvictionQueue`.
+    /// Returns the evicted entity.
+    pub(crate) fn insert(
+        &self,
+        requests: &mut ShardWriteGuard<'_, T, S>,
+        entity: T,
+        entry: Entry,
+    ) -> Option<(T, Entry)> {
+        let Some(capacity) = self.capacity else {
+            requests.insert(entity, entry);
+            return None;
+        };
+        if requests.contains_key(&entity) {
+            requests.insert(entity, entry);
+            return None;
+        }
+        let hash = self.hasher.hash_one(&entity);
+        let mut queue = self.evictions[self.index_of(hash)].lock();
+        let evicted = match requests.len() >= capacity {
+            true => queue.evict(requests),
+            false => None,
+        };
+        queue.push(hash, &entry);
+        requests.insert(entity, entry);
+        queue.compact(requests, capacity);
+        evicted
+    }
+
+    /// Removes every entity idle for longer than its `idle_ttl`, if it has one, and
+    /// hands it to `evicted` once its shard is unlocked again, returns how many were removed.
+    pub(crate) fn evict_idle(
+        &self,
+        idle_ttl: impl Fn(&T) -> Option<Duration>,
+        now: Instant,
+        mut evicted: impl FnMut(T, Entry),
+    ) -> usize {
+        let mut count = 0;
+        for index in 0..self.count() {
+            let removed = self.write_shard(index).extract_if(|entity, entry| {
+                idle_ttl(entity).is_some_and(|idle_ttl| entry.idle_for(now) > idle_ttl)
+            });
+            count += removed.len();
+            for (entity, entry) in removed {
+                evicted(entity, entry);
+            }
+        }
+        count
+    }
+
+    /// Removes every entity `remove` returns `true` for, returns how many were removed.
+    pub(crate) fn remove_where(&self, mut remove: impl FnMut(&T) -> bool) -> usize {
+        (0..self.count())
+            .map(|index| {
+                self.write_shard(index)
+                    .extract_if(|entity, _| remove(entity))
+                    .len()
+            })
+            .sum()
+    }
+
+    /// Calls `f` with every entity, locking one shard at a time.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&T, &Entry)) {
+        match &self.maps {
+            Maps::Sharded(shards) => {
+                for shard in shards.iter() {
+                    for (entity, entry) in shard.read().iter() {
+                        f(entity, entry);
+                    }
+                }
+            }
+            #[cfg(feature = "dashmap")]
+            Maps::DashMap(map) => {
+                for pair in map.iter() {
+                    f(pair.key(), pair.value());
+                }
+            }
+        }
+    }
+
+    /// How many entities there are, locking one shard at a time.
+    pub(crate) fn len(&self) -> usize {
+        (0..self.count())
+            .map(|index| self.read_shard(index).len())
+            .sum()
+    }
+
+    /// Returns `true` if there are no entities.
+    pub(crate) fn is_empty(&self) -> bool {
+        (0..self.count()).all(|index| self.read_shard(index).is_empty())
+    }
+
+    /// Removes every entity, keeping the allocated memory.
+    pub(crate) fn clear(&self) {
+        for index in 0..self.count() {
+            self.write_shard(index).clear();
+        }
+    }
+
+    /// Locks the shard at `index` for inserting or removing entities, see `index`.
+    pub(crate) fn write_shard(&self, index: usize) -> ShardWriteGuard<'_, T, S> {
+        match &self.maps {
+            Maps::Sharded(shards) => ShardWriteGuard::Sharded(shards[index].write()),
+            #[cfg(feature = "dashmap")]
+            Maps::DashMap(map) => {
+                ShardWriteGuard::DashMap(map.shards()[index].write(), &self.hasher)
+            }
+        }
+    }
+
+    /// How many shards there are.
+    pub(crate) fn count(&self) -> usize {
+        self.maps.count()
+    }
+
+    /// Locks the shard at `index`, see `index`.
+    pub(crate) fn read_shard(&self, index: usize) -> ShardReadGuard<'_, T, S> {
+        match &self.maps {
+            Maps::Sharded(shards) => ShardReadGuard::Sharded(shards[index].read()),
+            #[cfg(feature = "dashmap")]
+            Maps::DashMap(map) => ShardReadGuard::DashMap(map.shards()[index].read(), &self.hasher),
+        }
+    }
+
+    /// Makes room for at least `additional` more entities, spread evenly over the shards.
+    pub(crate) fn reserve(&self, additional: usize) {
+        let per_shard = additional.div_ceil(self.count());
+        for index in 0..self.count() {
+            self.write_shard(index).reserve(per_shard);
+        }
+    }
+
+    /// Frees the memory of every shard that its entities don't need.
+    pub(crate) fn shrink_to_fit(&self) {
+        for index in 0..self.count() {
+            self.write_shard(index).shrink_to_fit();
+        }
+    }
+
+    /// The shard `entity` belongs to.
+    pub(crate) fn index<Q>(&self, entity: &Q) -> usize
+    where
+        Q: Hash + ?Sized,
+    {
+        if self.count() == 1 {
+            return 0;
+        }
+        self.index_of(self.hasher.hash_one(entity))
+    }
+
+    /// The shard of an entity with the hash `hash`.
+    fn index_of(&self, hash: u64) -> usize {
+        match &self.maps {
+            Maps::Sharded(shards) => {
+                // The maps hash with the same hasher, indexing by the low bits and tagging
+                // entries with the top 7, so pick the shard with the bits right below those.
+                let bits = shards.len().trailing_zeros();
+                let mask = shards.len() - 1;
+                (hash >> (u64::BITS - 7 - bits)) as usize & mask
+            }
+            #[cfg(feature = "dashmap")]
+            Maps::DashMap(map) => map.determine_shard(hash as usize),
+        }
+    }
+}
+
+impl<T, S> Maps<T, S>
+where
+    T: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn count(&self) -> usize {
+        match self {
+            Maps::Sharded(shards) => shards.len(),
+            #[cfg(feature = "dashmap")]
+            Maps::DashMap(map) => map.shards().len(),
+        }
+    }
+}
+
+// Not derived, a `DashMap` is only `Debug` with the bounds it is used with.
+impl<T: fmt::Debug, S> fmt::Debug for Maps<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Maps::Sharded(shards) => f.debug_tuple("Sharded").field(shards).finish(),
+            #[cfg(feature = "dashmap")]
+            Maps::DashMap(_) => f.debug_tuple("DashMap").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// A shard locked for lookups, see `Shards::read`.
+pub(crate) enum ShardReadGuard<'a, T, S> {
+    Sharded(RwLockReadGuard<'a, HashMap<T, Entry, S>>),
+    #[cfg(feature = "dashmap")]
+    DashMap(dashmap::RwLockReadGuard<'a, DashShard<T>>, &'a S),
+}
+
+/// A shard locked for inserting or removing entities, see `Shards::write`.
+pub(crate) enum ShardWriteGuard<'a, T, S> {
+    Sharded(RwLockWriteGuard<'a, HashMap<T, Entry, S>>),
+    #[cfg(feature = "dashmap")]
+    DashMap(dashmap::RwLockWriteGuard<'a, DashShard<T>>, &'a S),
+}
+
+/// The entities of a locked shard, whichever the storage.
+enum Shard<'a, T, S> {
+    Sharded(&'a HashMap<T, Entry, S>),
+    #[cfg(feature = "dashmap")]
+    DashMap(&'a DashShard<T>, &'a S),
+}
+
+impl<'a, T, S> Shard<'a, T, S>
+where
+    T: Hash + Eq,
+    S: BuildHasher,
+{
+    fn get_key_value<Q>(self, entity: &Q) -> Option<(&'a T, &'a Entry)>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self {
+            Shard::Sharded(map) => map.get_key_value(entity),
+            #[cfg(feature = "dashmap")]
+            Shard::DashMap(shard, hasher) => shard
+                .get(hasher.hash_one(entity), |(key, _)| key.borrow() == entity)
+                .map(|(key, entry)| (key, entry.get())),
+        }
+    }
+
+    /// The entity with the hash `hash`, for entities that are only known by it.
+    fn get_by_hash(self, hash: u64) -> Option<&'a Entry> {
+        match self {
+            Shard::Sharded(map) => map
+                .raw_entry()
+                .from_hash(hash, |entity| map.hasher().hash_one(entity) == hash)
+                .map(|(_, entry)| entry),
+            #[cfg(feature = "dashmap")]
+            Shard::DashMap(shard, hasher) => shard
+                .get(hash, |(entity, _)| hasher.hash_one(entity) == hash)
+                .map(|(_, entry)| entry.get()),
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            Shard::Sharded(map) => map.len(),
+            #[cfg(feature = "dashmap")]
+            Shard::DashMap(shard, _) => shard.len(),
+        }
+    }
+}
+
+impl<T, S> ShardReadGuard<'_, T, S>
+where
+    T: Hash + Eq,
+    S: BuildHasher,
+{
+    pub(crate) fn get<Q>(&self, entity: &Q) -> Option<&Entry>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_key_value(entity).map(|(_, entry)| entry)
+    }
+
+    pub(crate) fn get_key_value<Q>(&self, entity: &Q) -> Option<(&T, &Entry)>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard().get_key_value(entity)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn contains_key<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_key_value(entity).is_some()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shard().len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self) -> Shard<'_, T, S> {
+        match self {
+            ShardReadGuard::Sharded(map) => Shard::Sharded(map),
+            #[cfg(feature = "dashmap")]
+            ShardReadGuard::DashMap(shard, hasher) => Shard::DashMap(shard, *hasher),
+        }
+    }
+}
+
+impl<T, S, Q> Index<&Q> for ShardReadGuard<'_, T, S>
+where
+    T: Hash + Eq + Borrow<Q>,
+    S: BuildHasher,
+    Q: Hash + Eq + ?Sized,
+{
+    type Output = Entry;
+
+    fn index(&self, entity: &Q) -> &Entry {
+        self.get(entity).expect("entity not found")
+    }
+}
+
+impl<T, S> ShardWriteGuard<'_, T, S>
+where
+    T: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn get<Q>(&self, entity: &Q) -> Option<&Entry>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_key_value(entity).map(|(_, entry)| entry)
+    }
+
+    pub(crate) fn get_key_value<Q>(&self, entity: &Q) -> Option<(&T, &Entry)>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard().get_key_value(entity)
+    }
+
+    pub(crate) fn contains_key<Q>(&self, entity: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_key_value(entity).is_some()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shard().len()
+    }
+
+    /// Inserts `entity`, returning the entry it replaces. Use `Shards::insert` to respect
+    /// the capacity of the shard.
+    pub(crate) fn insert(&mut self, entity: T, entry: Entry) -> Option<Entry> {
+        match self {
+            ShardWriteGuard::Sharded(map) => map.insert(entity, entry),
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, hasher) => {
+                let hash = hasher.hash_one(&entity);
+                if let Some((_, old)) = shard.get_mut(hash, |(key, _)| *key == entity) {
+                    return Some(std::mem::replace(old.get_mut(), entry));
+                }
+                let value = (entity, SharedValue::new(entry));
+                shard.insert(hash, value, |(key, _)| hasher.hash_one(key));
+                None
+            }
+        }
+    }
+
+    pub(crate) fn remove<Q>(&mut self, entity: &Q) -> Option<Entry>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self {
+            ShardWriteGuard::Sharded(map) => map.remove(entity),
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, hasher) => shard
+                .remove_entry(hasher.hash_one(entity), |(key, _)| key.borrow() == entity)
+                .map(|(_, entry)| entry.into_inner()),
+        }
+    }
+
+    /// Removes the entity with the hash `hash`, for entities that are only known by it.
+    fn remove_by_hash(&mut self, hash: u64) -> Option<(T, Entry)> {
+        match self {
+            ShardWriteGuard::Sharded(map) => {
+                let hasher = map.hasher().clone();
+                match map
+                    .raw_entry_mut()
+                    .from_hash(hash, |entity| hasher.hash_one(entity) == hash)
+                {
+                    RawEntryMut::Occupied(found) => Some(found.remove_entry()),
+                    RawEntryMut::Vacant(_) => None,
+                }
+            }
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, hasher) => shard
+                .remove_entry(hash, |(entity, _)| hasher.hash_one(entity) == hash)
+                .map(|(entity, entry)| (entity, entry.into_inner())),
+        }
+    }
+
+    /// Removes every entity `remove` returns `true` for, and returns them.
+    fn extract_if(&mut self, mut remove: impl FnMut(&T, &Entry) -> bool) -> Vec<(T, Entry)> {
+        match self {
+            ShardWriteGuard::Sharded(map) => map
+                .extract_if(|entity, entry| remove(entity, entry))
+                .collect(),
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, hasher) => {
+                // Iterating the shard in place takes unsafe code, draining it keeps its memory.
+                let (removed, kept): (Vec<_>, Vec<_>) = shard
+                    .drain()
+                    .partition(|(entity, entry)| remove(entity, entry.get()));
+                for value in kept {
+                    shard.insert(hasher.hash_one(&value.0), value, |(entity, _)| {
+                        hasher.hash_one(entity)
+                    });
+                }
+                removed
+                    .into_iter()
+                    .map(|(entity, entry)| (entity, entry.into_inner()))
+                    .collect()
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            ShardWriteGuard::Sharded(map) => map.clear(),
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, _) => shard.clear(),
+        }
+    }
+
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        match self {
+            ShardWriteGuard::Sharded(map) => map.reserve(additional),
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, hasher) => {
+                shard.reserve(additional, |(entity, _)| hasher.hash_one(entity))
+            }
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        match self {
+            ShardWriteGuard::Sharded(map) => map.shrink_to_fit(),
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, hasher) => {
+                shard.shrink_to(0, |(entity, _)| hasher.hash_one(entity))
+            }
+        }
+    }
+
+    /// How many entities the shard has room for without growing.
+    #[cfg(test)]
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            ShardWriteGuard::Sharded(map) => map.capacity(),
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, _) => shard.capacity(),
+        }
+    }
+
+    fn shard(&self) -> Shard<'_, T, S> {
+        match self {
+            ShardWriteGuard::Sharded(map) => Shard::Sharded(map),
+            #[cfg(feature = "dashmap")]
+            ShardWriteGuard::DashMap(shard, hasher) => Shard::DashMap(shard, *hasher),
+        }
+    }
+}
+
+/// The entities of a shard in the order they were added, for picking the one to evict
+/// once it is full in constant time, rather than looking for the least recently used
+/// one among all of them.
+///
+/// Approximates LRU like CLOCK does: the entity added first is evicted, unless it
+/// consumed since it was queued, which gets it queued again as a second chance.
+/// Entities are queued by hash, those removed some other way are skipped when they
+/// come up.
+#[derive(Debug, Default)]
+struct EvictionQueue {
+    queue: VecDeque<(u64, u64)>, // Hashes, and when the entity last consumed as of being queued
+}
+
+impl EvictionQueue {
+    fn push(&mut self, hash: u64, entry: &Entry) {
+        self.queue.push_back((hash, entry.accessed()));
+    }
+
+    /// Removes the first queued entity that hasn't consumed since it was queued.
+    ///
+    /// Nothing can consume while the shard is write locked, so every entity gets at most
+    /// one second chance before it comes up again.
+    fn evict<T, S>(&mut self, requests: &mut ShardWriteGuard<'_, T, S>) -> Option<(T, Entry)>
+    where
+        T: Hash + Eq,
+        S: BuildHasher + Clone,
+    {
+        while let Some((hash, accessed)) = self.queue.pop_front() {
+            let Some(found) = requests.shard().get_by_hash(hash) else {
+                continue; // removed since
+            };
+            let now_accessed = found.accessed();
+            if now_accessed == accessed {
+                return requests.remove_by_hash(hash);
+            }
+            self.queue.push_back((hash, now_accessed));
+        }
+        None
+    }
+
+    /// Drops the hashes of removed entities once they make up most of the queue,
+    /// which takes as long as there are entities but only happens every so often.
+    fn compact<T, S>(&mut self, requests: &ShardWriteGuard<'_, T, S>, capacity: usize)
+    where
+        T: Hash + Eq,
+        S: BuildHasher + Clone,
+    {
+        if self.queue.len() <= 2 * capacity {
+            return;
+        }
+        self.queue
+            .retain(|&(hash, _)| requests.shard().get_by_hash(hash).is_some());
+    }
+}
+
+/// A shard count that scales with the available cores.
+pub(crate) fn default_shard_count() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get) * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AssociatedEntity;
+
+    /// Every storage the enabled features offer.
+    const STORAGES: &[Storage] = &[
+        Storage::Sharded,
+        #[cfg(feature = "dashmap")]
+        Storage::DashMap,
+    ];
+
+    fn new_shards<T: Hash + Eq>(
+        count: usize,
+        max_entities: Option<usize>,
+        storage: Storage,
+    ) -> Shards<T> {
+        Shards::new(
+            count,
+            max_entities,
+            0,
+            DefaultHashBuilder::default(),
+            storage,
+        )
+    }
+
+    fn entry() -> Entry {
+        let now = Instant::now();
+        Entry::new(
+            AssociatedEntity::new(1, Duration::from_secs(1), Default::default(), now),
+            now,
+        )
+    }
+
+    #[test]
+    fn test_shard_count_is_power_of_two() {
+        let count = |count| new_shards::<u32>(count, None, Storage::Sharded).count();
+        assert_eq!(count(0), 1);
+        assert_eq!(count(1), 1);
+        assert_eq!(count(6), 8);
+    }
+
+    #[test]
+    fn test_entities_spread_over_shards() {
+        for &storage in STORAGES {
+            let shards: Shards<u32> = new_shards(8, None, storage);
+            for key in 0..1000 {
+                shards.write(&key).insert(key, entry());
+            }
+
+            for index in 0..shards.count() {
+                assert!(!shards.read_shard(index).is_empty(), "{:?}", storage);
+            }
+            assert_eq!(shards.len(), 1000);
+            // Borrowed forms of a key land in the same shard.
+            let owned: Shards<String> = new_shards(8, None, storage);
+            owned.write("user1").insert("user1".to_string(), entry());
+            assert!(owned.read(&"user1".to_string()).contains_key("user1"));
+        }
+    }
+
+    #[test]
+    fn test_full_shard_evicts_least_recently_used() {
+        for &storage in STORAGES {
+            let shards: Shards<u32> = new_shards(1, Some(2 * shards_of(storage)), storage);
+            let start = Instant::now();
+            // Keys of the same shard, with room for two.
+            let keys = same_shard(&shards, 3);
+            let mut requests = shards.write(&keys[0]);
+
+            shards.insert(&mut requests, keys[0], entry());
+            shards.insert(&mut requests, keys[1], entry());
+            requests
+                .get(&keys[0])
+                .unwrap()
+                .decide(start + Duration::from_secs(1), 1, None);
+
+            let evicted = shards.insert(&mut requests, keys[2], entry());
+            assert_eq!(evicted.map(|(entity, _)| entity), Some(keys[1]));
+            assert!(requests.contains_key(&keys[0]));
+            assert!(!requests.contains_key(&keys[1]));
+            assert!(requests.contains_key(&keys[2]));
+
+            // Replacing an existing entity doesn't evict anything.
+            let evicted = shards.insert(&mut requests, keys[2], entry());
+            assert!(evicted.is_none());
+            assert_eq!(requests.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_eviction_skips_removed_entities() {
+        for &storage in STORAGES {
+            let shards: Shards<u32> = new_shards(1, Some(3 * shards_of(storage)), storage);
+            let keys = same_shard(&shards, 100);
+            let mut requests = shards.write(&keys[0]);
+            for key in &keys[..3] {
+                shards.insert(&mut requests, *key, entry());
+            }
+            requests.remove(&keys[0]);
+            shards.insert(&mut requests, keys[3], entry());
+
+            let evicted = shards.insert(&mut requests, keys[4], entry());
+            assert_eq!(evicted.map(|(entity, _)| entity), Some(keys[1]));
+            assert_eq!(requests.len(), 3);
+
+            // Churn that never fills the shard doesn't grow the queue without bound.
+            for i in 5..100 {
+                requests.remove(&keys[i - 3]);
+                shards.insert(&mut requests, keys[i], entry());
+            }
+            let index = shards.index(&keys[0]);
+            assert!(shards.evictions[index].lock().queue.len() <= 6);
+        }
+    }
+
+    #[test]
+    fn test_evict_idle_keeps_active_entities() {
+        for &storage in STORAGES {
+            let shards: Shards<u32> = new_shards(4, None, storage);
+            let start = Instant::now();
+            for key in 0..100 {
+                shards.write(&key).insert(key, entry());
+            }
+            for key in (0..100).step_by(2) {
+                let requests = shards.read(&key);
+                requests[&key].decide(start + Duration::from_secs(30), 1, None);
+            }
+
+            let mut evicted = Vec::new();
+            let idle_ttl = |_: &u32| Some(Duration::from_secs(45));
+            let now = start + Duration::from_secs(60);
+            let count = shards.evict_idle(idle_ttl, now, |entity, _| evicted.push(entity));
+            evicted.sort_unstable();
+            assert_eq!(count, 50);
+            assert_eq!(evicted, (1..100).step_by(2).collect::<Vec<_>>());
+            assert_eq!(shards.len(), 50);
+            assert!(shards.read(&2).contains_key(&2));
+
+            assert_eq!(shards.remove_where(|key| key % 4 == 0), 25);
+            shards.clear();
+            assert!(shards.is_empty());
+        }
+    }
+
+    /// How many shards `storage` has at least.
+    fn shards_of(storage: Storage) -> usize {
+        new_shards::<u32>(1, None, storage).count()
+    }
+
+    /// The first `n` keys that fall into the shard of key 0.
+    fn same_shard(shards: &Shards<u32>, n: usize) -> Vec<u32> {
+        let index = shards.index(&0);
+        (0..)
+            .filter(|key| shards.index(key) == index)
+            .take(n)
+            .collect()
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::Algorithm;
+
+/// The entities of a limiter at one point in time, see `Limiter::export_state`.
+///
+/// Points in time are kept relative to `taken_at`, which is wall clock time,
+/// so a snapshot can be restored by another process, e.g. during a deploy.
+/// With the `serde` feature, snapshots can be written to any serde format.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LimiterSnapshot<T> {
+    /// When the snapshot was taken.
+    pub taken_at: SystemTime,
+    /// Every entity the limiter tracked.
+    pub entities: Vec<(T, EntitySnapshot)>,
+}
+
+/// How `Limiter::merge` combines an entity tracked by both limiters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Keeps whichever of the two has fewer requests left, along with its limits.
+    #[default]
+    MinRemaining,
+    /// Adds what the other entity consumed in its window to ours, as if both
+    /// had served their requests from one bucket.
+    SumConsumption,
+}
+
+/// The state of one entity in a `LimiterSnapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntitySnapshot {
+    pub(crate) bucket: usize,
+    pub(crate) bucket_age: Duration, // How long before `taken_at` bucket_init was
+    pub(crate) bucket_max: usize,
+    pub(crate) refresh_rate: Duration,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) state: StateSnapshot,
+    pub(crate) violations: Option<ViolationsSnapshot>,
+    pub(crate) limited: bool,
+    pub(crate) windows: Vec<EntitySnapshot>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) aligned: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) debt: usize,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) cooldown_in: Option<Duration>, // How long after `taken_at` the cooldown ends
+}
+
+/// `entity::State` with ages instead of instants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum StateSnapshot {
+    Bucket,
+    Gcra { tat_in: Duration }, // How long after `taken_at` the TAT is
+    Log(Vec<(Duration, usize)>),
+    Counter { previous: usize, current: usize },
+    Leaky { level: usize },
+}
+
+/// `escalation::Violations` with ages instead of instants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ViolationsSnapshot {
+    pub(crate) count: usize,
+    pub(crate) level: u32,
+    pub(crate) last_age: Duration,
+    pub(crate) base_rate: Duration,
+}
+
+impl EntitySnapshot {
+    /// How many requests were left in the bucket when the snapshot was taken.
+    /// With several windows, what was left in the tightest one.
+    pub fn remaining(&self) -> usize {
+        self.windows
+            .iter()
+            .map(|window| window.bucket)
+            .fold(self.bucket, usize::min)
+    }
+
+    /// The value the bucket gets refilled with.
+    pub fn bucket_max(&self) -> usize {
+        self.bucket_max
+    }
+
+    /// The timeframe after which the entity gets a renewed limit.
+    pub fn refresh_rate(&self) -> Duration {
+        self.refresh_rate
+    }
+
+    /// How the bucket gets refilled.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+}
+
+impl<T> LimiterSnapshot<T> {
+    /// The instant of a clock standing at `now` that corresponds to `taken_at`.
+    pub(crate) fn taken_at_instant(&self, now: Instant) -> Instant {
+        let since = SystemTime::now()
+            .duration_since(self.taken_at)
+            .unwrap_or_default();
+        now.checked_sub(since).unwrap_or(now)
+    }
+}
+
+/// The instant `age` before `at`, or `at` itself if the clock doesn't go back that far.
+pub(crate) fn before(at: Instant, age: Duration) -> Instant {
+    at.checked_sub(age).unwrap_or(at)
+}
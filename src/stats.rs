@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
+use crate::sync::Mutex;
+use crate::Decision;
+
+/// Aggregate counts over every entity of a limiter, see `Limiter::global_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlobalStats {
+    /// Requests checked since `since`.
+    pub checks: u64,
+    /// Requests allowed since `since`.
+    pub allowed: u64,
+    /// Requests denied since `since`, for hitting the limit or by a ban.
+    pub denied: u64,
+    /// Entities the limiter removed by itself since `since`, because they were idle
+    /// or to make room for others.
+    pub evicted: u64,
+    /// How many entities the limiter tracks right now.
+    pub entities: usize,
+    /// When the limiter was created, or its stats last reset.
+    pub since: Instant,
+}
+
+impl GlobalStats {
+    /// The share of checked requests that got denied, between 0 and 1.
+    pub fn denial_rate(&self) -> f64 {
+        if self.checks == 0 {
+            return 0.0;
+        }
+        self.denied as f64 / self.checks as f64
+    }
+}
+
+/// Check and denial counters, striped so threads don't fight over one cache line.
+#[derive(Debug)]
+pub(crate) struct Counters {
+    stripes: Box<[Stripe]>,
+    evicted: AtomicU64, // Evictions are rare, no need to stripe them
+    since: Mutex<Instant>,
+}
+
+#[derive(Debug, Default)]
+#[repr(align(128))]
+struct Stripe {
+    checks: AtomicU64,
+    allowed: AtomicU64,
+    denied: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn new(stripes: usize) -> Self {
+        Counters {
+            stripes: (0..stripes.max(1)).map(|_| Stripe::default()).collect(),
+            evicted: AtomicU64::new(0),
+            since: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Counts a checked request and whether it was allowed or denied.
+    pub(crate) fn record(&self, decision: &Decision) {
+        let stripe = &self.stripes[stripe_index() % self.stripes.len()];
+        stripe.checks.fetch_add(1, Ordering::Relaxed);
+        match decision {
+            Decision::Allowed { .. } => stripe.allowed.fetch_add(1, Ordering::Relaxed),
+            Decision::Denied { .. } | Decision::Banned { .. } => {
+                stripe.denied.fetch_add(1, Ordering::Relaxed)
+            }
+            Decision::Unknown => 0,
+        };
+    }
+
+    /// Counts `count` evicted entities.
+    pub(crate) fn record_evicted(&self, count: usize) {
+        self.evicted.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Sums up the stripes, `entities` is filled in by the caller.
+    pub(crate) fn load(&self, entities: usize) -> GlobalStats {
+        let since = *self.since.lock();
+        let mut stats = GlobalStats {
+            checks: 0,
+            allowed: 0,
+            denied: 0,
+            evicted: self.evicted.load(Ordering::Relaxed),
+            entities,
+            since,
+        };
+        for stripe in self.stripes.iter() {
+            stats.checks += stripe.checks.load(Ordering::Relaxed);
+            stats.allowed += stripe.allowed.load(Ordering::Relaxed);
+            stats.denied += stripe.denied.load(Ordering::Relaxed);
+        }
+        stats
+    }
+
+    /// Starts counting from zero again.
+    pub(crate) fn reset(&self) {
+        let mut since = self.since.lock();
+        for stripe in self.stripes.iter() {
+            stripe.checks.store(0, Ordering::Relaxed);
+            stripe.allowed.store(0, Ordering::Relaxed);
+            stripe.denied.store(0, Ordering::Relaxed);
+        }
+        self.evicted.store(0, Ordering::Relaxed);
+        *since = Instant::now();
+    }
+}
+
+/// Every thread sticks to one stripe, handed out round robin.
+fn stripe_index() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static STRIPE: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    STRIPE.with(|stripe| *stripe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_counters_sum_over_threads() {
+        let counters = Counters::new(4);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        counters.record(&Decision::Allowed {
+                            remaining: 0,
+                            reset_in: Duration::ZERO,
+                        });
+                        counters.record(&Decision::Denied {
+                            retry_after: Duration::ZERO,
+                        });
+                    }
+                });
+            }
+        });
+
+        counters.record(&Decision::Unknown);
+        counters.record_evicted(3);
+
+        let stats = counters.load(0);
+        assert_eq!(stats.checks, 1601);
+        assert_eq!(stats.allowed, 800);
+        assert_eq!(stats.evicted, 3);
+        assert_eq!(stats.denied, 800);
+
+        counters.reset();
+        assert_eq!(counters.load(0).checks, 0);
+        assert!(counters.load(0).since > stats.since);
+    }
+}
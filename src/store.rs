@@ -0,0 +1,175 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::{Allowance, Decision, RateGateError};
+
+#[cfg(feature = "sqlx")]
+mod postgres;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "sled")]
+mod sled;
+
+#[cfg(feature = "sqlx")]
+pub use self::postgres::PostgresStore;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+#[cfg(feature = "sled")]
+pub use self::sled::SledStore;
+
+/// Keeps the buckets of entities outside of the limiter's memory, e.g. to share them
+/// between processes or to keep them across restarts. See `StoreLimiter`.
+///
+/// Stores only know fixed windows, and key their buckets by strings.
+pub trait Store {
+    type Error;
+
+    /// Consumes `cost` requests from the bucket of `key`, which allows `max_limit`
+    /// requests per `refresh_rate`. Has to be atomic, concurrent callers may share the store.
+    ///
+    /// The request is denied, and nothing is consumed, if fewer than `cost` requests are left.
+    fn consume(
+        &self,
+        key: &str,
+        max_limit: usize,
+        refresh_rate: Duration,
+        cost: usize,
+    ) -> Result<Decision, Self::Error>;
+}
+
+/// A `Store` that has to be awaited, e.g. one that talks to a database with an async driver.
+pub trait AsyncStore {
+    type Error;
+
+    /// Consumes `cost` requests from the bucket of `key`, see `Store::consume`.
+    fn consume(
+        &self,
+        key: &str,
+        max_limit: usize,
+        refresh_rate: Duration,
+        cost: usize,
+    ) -> impl Future<Output = Result<Decision, Self::Error>> + Send;
+}
+
+/// A limiter that keeps its buckets in a `Store`, limiting every key with the same limit.
+///
+/// Unlike `Limiter`, every check goes to the store and may fail, so the decisions
+/// come wrapped in a `Result`.
+#[derive(Debug, Clone)]
+pub struct StoreLimiter<S> {
+    store: S,
+    max_limit: usize,
+    refresh_rate: Duration,
+}
+
+impl<S> StoreLimiter<S> {
+    /// Limits every key to `max_limit` requests per `refresh_rate`.
+    pub fn new(store: S, max_limit: usize, refresh_rate: Duration) -> Self {
+        StoreLimiter {
+            store,
+            max_limit,
+            refresh_rate,
+        }
+    }
+
+    /// The store the buckets are kept in.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+impl<S> StoreLimiter<S>
+where
+    S: Store,
+{
+    /// Consumes a request for `key`.
+    pub fn check(&self, key: &str) -> Result<Decision, S::Error> {
+        self.consume(key, 1)
+    }
+
+    /// Consumes `cost` requests for `key` at once, see `Limiter::consume`.
+    pub fn consume(&self, key: &str, cost: usize) -> Result<Decision, S::Error> {
+        self.store
+            .consume(key, self.max_limit, self.refresh_rate, cost)
+    }
+
+    /// Consumes a request for `key`, with a failing store and a denied request
+    /// both as a `RateGateError`, see `Limiter::try_consume`.
+    pub fn try_consume(&self, key: &str) -> Result<Allowance, RateGateError>
+    where
+        S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.check(key)
+            .map_err(|err| RateGateError::StoreUnavailable(Box::new(err)))?
+            .into_result()
+    }
+}
+
+impl<S> StoreLimiter<S>
+where
+    S: AsyncStore,
+{
+    /// Consumes a request for `key` in an `AsyncStore`.
+    pub async fn check_async(&self, key: &str) -> Result<Decision, S::Error> {
+        self.consume_async(key, 1).await
+    }
+
+    /// Consumes `cost` requests for `key` at once in an `AsyncStore`.
+    pub async fn consume_async(&self, key: &str, cost: usize) -> Result<Decision, S::Error> {
+        self.store
+            .consume(key, self.max_limit, self.refresh_rate, cost)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    /// Counts requests without ever resetting, enough to check the plumbing.
+    #[derive(Default)]
+    struct CountingStore(Mutex<HashMap<String, usize>>);
+
+    impl Store for CountingStore {
+        type Error = Infallible;
+
+        fn consume(
+            &self,
+            key: &str,
+            max_limit: usize,
+            refresh_rate: Duration,
+            cost: usize,
+        ) -> Result<Decision, Infallible> {
+            let mut counts = self.0.lock().unwrap();
+            let count = counts.entry(key.to_string()).or_default();
+            if *count + cost > max_limit {
+                return Ok(Decision::Denied {
+                    retry_after: refresh_rate,
+                });
+            }
+            *count += cost;
+            Ok(Decision::Allowed {
+                remaining: max_limit - *count,
+                reset_in: refresh_rate,
+            })
+        }
+    }
+
+    #[test]
+    fn test_store_limiter() {
+        let limiter = StoreLimiter::new(CountingStore::default(), 2, Duration::from_secs(60));
+
+        assert!(limiter.check("user1").unwrap().is_allowed());
+        assert!(!limiter.consume("user1", 2).unwrap().is_allowed());
+        assert!(limiter.check("user1").unwrap().is_allowed());
+        assert!(!limiter.check("user1").unwrap().is_allowed());
+        assert!(limiter.check("user2").unwrap().is_allowed());
+        assert!(matches!(
+            limiter.try_consume("user1"),
+            Err(RateGateError::LimitExceeded { .. })
+        ));
+    }
+}
@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use ::sqlx::{PgPool, Row};
+
+use crate::store::AsyncStore;
+use crate::Decision;
+
+/// An `AsyncStore` in a PostgreSQL table, for when the database is the only thing
+/// the processes share.
+///
+/// Every check is a single upsert, which locks just the row of its key,
+/// and windows follow the database's clock so the processes agree on them.
+/// Create the table with `create_table` first.
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+    pool: PgPool,
+    table: String,
+    consume: String,
+}
+
+impl PostgresStore {
+    /// Keeps the buckets in the `rate_gate` table.
+    pub fn new(pool: PgPool) -> Self {
+        PostgresStore::with_table(pool, "rate_gate")
+    }
+
+    /// Keeps the buckets in `table`, which is put into the statements as is.
+    pub fn with_table(pool: PgPool, table: &str) -> Self {
+        PostgresStore {
+            pool,
+            table: table.to_string(),
+            consume: consume_statement(table),
+        }
+    }
+
+    /// Creates the table if it doesn't exist yet.
+    pub async fn create_table(&self) -> ::sqlx::Result<()> {
+        let statement = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                key TEXT PRIMARY KEY,
+                window_start BIGINT NOT NULL,
+                count BIGINT NOT NULL,
+                allowed BOOLEAN NOT NULL
+            )",
+            self.table
+        );
+        ::sqlx::query(&statement).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Deletes the buckets whose window started more than `age` ago,
+    /// returns how many were deleted. Keys come and go, so call this every now and then
+    /// with the longest refresh rate in use.
+    pub async fn delete_older_than(&self, age: Duration) -> ::sqlx::Result<u64> {
+        let statement = format!(
+            "DELETE FROM {} WHERE window_start < {} - $1",
+            self.table, NOW_MS
+        );
+        let result = ::sqlx::query(&statement)
+            .bind(age.as_millis() as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// The database's clock, in ms since the Unix epoch.
+const NOW_MS: &str = "(extract(epoch FROM clock_timestamp()) * 1000)::bigint";
+
+/// Starts a new window when the old one is over, and consumes `$3` of `$4` requests
+/// if they fit. `allowed` records whether they did, as the count alone can't tell.
+fn consume_statement(table: &str) -> String {
+    format!(
+        "WITH now AS (SELECT {NOW_MS} AS ms)
+        INSERT INTO {table} AS bucket (key, window_start, count, allowed)
+        SELECT $1, now.ms, CASE WHEN $3 <= $4 THEN $3 ELSE 0 END, $3 <= $4 FROM now
+        ON CONFLICT (key) DO UPDATE SET
+            window_start = CASE
+                WHEN EXCLUDED.window_start - bucket.window_start >= $2 THEN EXCLUDED.window_start
+                ELSE bucket.window_start END,
+            count = CASE
+                WHEN EXCLUDED.window_start - bucket.window_start >= $2 THEN EXCLUDED.count
+                WHEN bucket.count + $3 <= $4 THEN bucket.count + $3
+                ELSE bucket.count END,
+            allowed = CASE
+                WHEN EXCLUDED.window_start - bucket.window_start >= $2 THEN EXCLUDED.allowed
+                ELSE bucket.count + $3 <= $4 END
+        RETURNING count, allowed, window_start + $2 - (SELECT ms FROM now) AS reset_in"
+    )
+}
+
+impl AsyncStore for PostgresStore {
+    type Error = ::sqlx::Error;
+
+    async fn consume(
+        &self,
+        key: &str,
+        max_limit: usize,
+        refresh_rate: Duration,
+        cost: usize,
+    ) -> ::sqlx::Result<Decision> {
+        let row = ::sqlx::query(&self.consume)
+            .bind(key)
+            .bind(refresh_rate.as_millis().max(1) as i64)
+            .bind(cost as i64)
+            .bind(max_limit as i64)
+            .fetch_one(&self.pool)
+            .await?;
+
+        let count: i64 = row.try_get("count")?;
+        let allowed: bool = row.try_get("allowed")?;
+        let reset_in: i64 = row.try_get("reset_in")?;
+        let reset_in = Duration::from_millis(reset_in.max(0) as u64);
+        Ok(if allowed {
+            Decision::Allowed {
+                remaining: (max_limit as i64 - count).max(0) as usize,
+                reset_in,
+            }
+        } else {
+            Decision::Denied {
+                retry_after: reset_in,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StoreLimiter;
+
+    /// Needs a server, run with `DATABASE_URL=postgres://... cargo test --features sqlx -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_postgres_store() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let pool = PgPool::connect(&url).await.unwrap();
+        let table = format!("rate_gate_test_{}", std::process::id());
+        let store = PostgresStore::with_table(pool.clone(), &table);
+        store.create_table().await.unwrap();
+        let limiter = StoreLimiter::new(store, 2, Duration::from_millis(500));
+
+        assert!(limiter.check_async("user1").await.unwrap().is_allowed());
+        assert!(!limiter
+            .consume_async("user1", 2)
+            .await
+            .unwrap()
+            .is_allowed());
+        assert!(limiter.check_async("user1").await.unwrap().is_allowed());
+        assert!(!limiter.check_async("user1").await.unwrap().is_allowed());
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(limiter.check_async("user1").await.unwrap().is_allowed());
+
+        ::sqlx::query(&format!("DROP TABLE {}", table))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use ::redis::{Connection, ConnectionLike, RedisResult, Script};
+
+use crate::store::Store;
+use crate::sync::Mutex;
+use crate::Decision;
+
+/// Counts the requests of a window and lets the key expire with it, all in one go.
+/// Returns whether the request was allowed, the requests left, and the ms left in the window.
+const FIXED_WINDOW: &str = r#"
+local max_limit = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+
+local count = tonumber(redis.call('GET', KEYS[1]) or '0')
+if count + cost > max_limit then
+    local ttl = redis.call('PTTL', KEYS[1])
+    if ttl < 0 then ttl = window end
+    return {0, max_limit - count, ttl}
+end
+
+count = redis.call('INCRBY', KEYS[1], cost)
+if count == cost then
+    redis.call('PEXPIRE', KEYS[1], window)
+end
+return {1, max_limit - count, redis.call('PTTL', KEYS[1])}
+"#;
+
+/// A `Store` on a Redis server, so every process that uses the same server
+/// shares the buckets.
+///
+/// Every check runs a Lua script, which Redis executes atomically.
+/// Keys are prefixed, `rate-gate:` by default, and expire along with their window.
+pub struct RedisStore<C = Connection> {
+    connection: Mutex<C>,
+    prefix: String,
+    script: Script,
+}
+
+impl RedisStore {
+    /// Connects to the Redis server at `url`, e.g. `redis://127.0.0.1/`.
+    pub fn open(url: &str) -> RedisResult<Self> {
+        let connection = ::redis::Client::open(url)?.get_connection()?;
+        Ok(RedisStore::new(connection))
+    }
+}
+
+impl<C> RedisStore<C>
+where
+    C: ConnectionLike,
+{
+    /// Uses an established connection, checks wait on each other for it.
+    pub fn new(connection: C) -> Self {
+        RedisStore {
+            connection: Mutex::new(connection),
+            prefix: "rate-gate:".to_string(),
+            script: Script::new(FIXED_WINDOW),
+        }
+    }
+
+    /// Sets what the Redis keys of the buckets start with, so several limiters
+    /// can share a server.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+impl<C> Store for RedisStore<C>
+where
+    C: ConnectionLike,
+{
+    type Error = ::redis::RedisError;
+
+    fn consume(
+        &self,
+        key: &str,
+        max_limit: usize,
+        refresh_rate: Duration,
+        cost: usize,
+    ) -> RedisResult<Decision> {
+        let window = refresh_rate.as_millis().max(1) as u64;
+        let mut connection = self.connection.lock();
+        let (allowed, remaining, ttl): (bool, i64, i64) = self
+            .script
+            .key(format!("{}{}", self.prefix, key))
+            .arg(max_limit)
+            .arg(window)
+            .arg(cost)
+            .invoke(&mut *connection)?;
+
+        let ttl = Duration::from_millis(ttl.max(0) as u64);
+        Ok(if allowed {
+            Decision::Allowed {
+                remaining: remaining.max(0) as usize,
+                reset_in: ttl,
+            }
+        } else {
+            Decision::Denied { retry_after: ttl }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StoreLimiter;
+
+    /// Needs a server, run with `REDIS_URL=redis://127.0.0.1/ cargo test --features redis -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_redis_store() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string());
+        let prefix = format!("rate-gate-test-{}:", std::process::id());
+        let store = RedisStore::open(&url).unwrap().prefix(prefix);
+        let limiter = StoreLimiter::new(store, 2, Duration::from_millis(200));
+
+        assert!(limiter.check("user1").unwrap().is_allowed());
+        assert!(limiter.check("user1").unwrap().is_allowed());
+        let Decision::Denied { retry_after } = limiter.check("user1").unwrap() else {
+            panic!("expected a denied request");
+        };
+        assert!(retry_after <= Duration::from_millis(200));
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(limiter.check("user1").unwrap().is_allowed());
+    }
+}
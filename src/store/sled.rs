@@ -0,0 +1,137 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::store::Store;
+use crate::Decision;
+
+/// A `Store` in an embedded sled database, so buckets survive restarts
+/// without running a server.
+///
+/// Windows are kept in wall clock time, a restarted process picks up the
+/// windows where the previous one left them.
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    tree: ::sled::Tree,
+}
+
+impl SledStore {
+    /// Opens, or creates, the database at `path`.
+    pub fn open(path: impl AsRef<Path>) -> ::sled::Result<Self> {
+        let db = ::sled::open(path)?;
+        Ok(SledStore::new(db.open_tree("rate-gate")?))
+    }
+
+    /// Keeps the buckets in `tree` of an already opened database.
+    pub fn new(tree: ::sled::Tree) -> Self {
+        SledStore { tree }
+    }
+
+    /// Writes everything to disk. sled does this every so often by itself,
+    /// call this before exiting to not lose the latest requests.
+    pub fn flush(&self) -> ::sled::Result<()> {
+        self.tree.flush().map(|_| ())
+    }
+}
+
+impl Store for SledStore {
+    type Error = ::sled::Error;
+
+    fn consume(
+        &self,
+        key: &str,
+        max_limit: usize,
+        refresh_rate: Duration,
+        cost: usize,
+    ) -> ::sled::Result<Decision> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let window = refresh_rate.as_millis().max(1) as u64;
+
+        let mut decision = Decision::Unknown;
+        // Retried until no one else updated the key in between, the last run decides.
+        self.tree.update_and_fetch(key, |old| {
+            let (mut start, mut count) = old.map(decode).unwrap_or((now, 0));
+            if now.saturating_sub(start) >= window {
+                (start, count) = (now, 0);
+            }
+            let reset_in = Duration::from_millis((start + window).saturating_sub(now));
+            decision = if count + cost as u64 > max_limit as u64 {
+                Decision::Denied {
+                    retry_after: reset_in,
+                }
+            } else {
+                count += cost as u64;
+                Decision::Allowed {
+                    remaining: max_limit - count as usize,
+                    reset_in,
+                }
+            };
+            Some(encode(start, count).to_vec())
+        })?;
+        Ok(decision)
+    }
+}
+
+/// Window start in ms since the Unix epoch, and requests consumed in it.
+fn encode(start: u64, count: u64) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&start.to_be_bytes());
+    bytes[8..].copy_from_slice(&count.to_be_bytes());
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> (u64, u64) {
+    let start = bytes.get(..8).and_then(|b| b.try_into().ok());
+    let count = bytes.get(8..16).and_then(|b| b.try_into().ok());
+    match (start, count) {
+        (Some(start), Some(count)) => (u64::from_be_bytes(start), u64::from_be_bytes(count)),
+        _ => (0, 0), // Not ours, start over
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StoreLimiter;
+
+    #[test]
+    fn test_buckets_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("rate-gate-sled-{}", std::process::id()));
+
+        let limiter =
+            StoreLimiter::new(SledStore::open(&path).unwrap(), 3, Duration::from_secs(60));
+        assert!(limiter.consume("user1", 2).unwrap().is_allowed());
+        limiter.store().flush().unwrap();
+        drop(limiter);
+
+        // sled's flusher thread lets go of the lock shortly after the drop.
+        let store = (0..50)
+            .find_map(|_| {
+                SledStore::open(&path)
+                    .inspect_err(|_| std::thread::sleep(Duration::from_millis(20)))
+                    .ok()
+            })
+            .unwrap();
+        let limiter = StoreLimiter::new(store, 3, Duration::from_secs(60));
+        assert!(limiter.check("user1").unwrap().is_allowed());
+        assert!(!limiter.check("user1").unwrap().is_allowed());
+        assert!(limiter.check("user2").unwrap().is_allowed());
+        drop(limiter);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_window_restarts() {
+        let db = ::sled::Config::new().temporary(true).open().unwrap();
+        let store = SledStore::new(db.open_tree("rate-gate").unwrap());
+        let limiter = StoreLimiter::new(store, 1, Duration::from_millis(50));
+
+        assert!(limiter.check("user1").unwrap().is_allowed());
+        assert!(!limiter.check("user1").unwrap().is_allowed());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check("user1").unwrap().is_allowed());
+    }
+}
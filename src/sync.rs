@@ -0,0 +1,78 @@
+//! The locks used throughout the crate, `parking_lot`'s with the `parking_lot` feature.
+//!
+//! Neither kind is poisoned: a thread panicking while holding a lock, e.g. in a hook,
+//! leaves the limiter usable for every other thread instead of making them panic too.
+
+#[cfg(feature = "parking_lot")]
+pub(crate) use parking_lot::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) use self::std_locks::{Mutex, RwLock};
+#[cfg(not(feature = "parking_lot"))]
+pub(crate) use std::sync::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+/// `std` locks with the API of `parking_lot`'s, ignoring poisoning.
+#[cfg(not(feature = "parking_lot"))]
+mod std_locks {
+    use std::sync::{self, MutexGuard, PoisonError, RwLockReadGuard, RwLockWriteGuard};
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Mutex<T>(sync::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        pub(crate) fn new(value: T) -> Self {
+            Mutex(sync::Mutex::new(value))
+        }
+
+        pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub(crate) fn into_inner(self) -> T {
+            self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub(crate) struct RwLock<T>(sync::RwLock<T>);
+
+    impl<T> RwLock<T> {
+        pub(crate) fn new(value: T) -> Self {
+            RwLock(sync::RwLock::new(value))
+        }
+
+        pub(crate) fn read(&self) -> RwLockReadGuard<'_, T> {
+            self.0.read().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub(crate) fn write(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_panics_dont_poison() {
+        let lock = Arc::new(RwLock::new(0));
+        let mutex = Arc::new(Mutex::new(0));
+        let (lock2, mutex2) = (lock.clone(), mutex.clone());
+        let panicked = thread::spawn(move || {
+            let _write = lock2.write();
+            let _guard = mutex2.lock();
+            panic!("poison");
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        *lock.write() += 1;
+        *mutex.lock() += 1;
+        assert_eq!(*lock.read(), 1);
+        assert_eq!(*mutex.lock(), 1);
+    }
+}
@@ -0,0 +1,533 @@
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::{Decision, Limiter};
+
+/// Paces an iterator by a limiter, see `rate_limit_blocking`.
+pub trait RateLimitIteratorExt: Iterator + Sized {
+    /// Yields the items no faster than `key` is allowed requests by `limiter`, one
+    /// request per item, e.g. to drain a queue against an API with a quota.
+    ///
+    /// Blocks the current thread while `key` is out of requests, or banned.
+    /// Keys the limiter doesn't know are not paced. While `key` is banned without
+    /// an end, e.g. disabled, the iterator returns `None` and keeps the item for when
+    /// it's called again.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use rate_gate::{Limiter, RateLimitIteratorExt};
+    /// let limiter = Limiter::new();
+    /// limiter.add_limited_entity("api", 100, Duration::from_secs(1));
+    /// for job in ["a", "b", "c"].into_iter().rate_limit_blocking(&limiter, "api") {
+    ///     // call the API with job
+    /// }
+    /// ```
+    fn rate_limit_blocking<T>(self, limiter: &Limiter<T>, key: T) -> RateLimitedIter<Self, T>
+    where
+        T: Hash + Eq + Clone + Send + 'static,
+    {
+        RateLimitedIter {
+            iter: self,
+            limiter: limiter.clone(),
+            key,
+            item: None,
+        }
+    }
+}
+
+impl<I: Iterator> RateLimitIteratorExt for I {}
+
+/// An iterator paced by a limiter, see `RateLimitIteratorExt::rate_limit_blocking`.
+#[derive(Debug, Clone)]
+pub struct RateLimitedIter<I, T>
+where
+    I: Iterator,
+    T: Hash + Eq + Send + 'static,
+{
+    iter: I,
+    limiter: Limiter<T>,
+    key: T,
+    item: Option<I::Item>, // Taken from the iterator while the key was disabled
+}
+
+impl<I, T> Iterator for RateLimitedIter<I, T>
+where
+    I: Iterator,
+    T: Hash + Eq + Clone + Send + 'static,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        // Nothing is consumed for the end of the iterator.
+        let item = match self.item.take() {
+            Some(item) => item,
+            None => self.iter.next()?,
+        };
+        loop {
+            match self.limiter.check(&self.key) {
+                Decision::Denied {
+                    retry_after: Duration::MAX,
+                }
+                | Decision::Banned {
+                    retry_after: Duration::MAX,
+                } => {
+                    self.item = Some(item);
+                    return None;
+                }
+                Decision::Denied { retry_after } | Decision::Banned { retry_after } => {
+                    std::thread::sleep(retry_after)
+                }
+                Decision::Allowed { .. } | Decision::Unknown => return Some(item),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pending = usize::from(self.item.is_some());
+        let (lower, upper) = self.iter.size_hint();
+        (
+            lower.saturating_add(pending),
+            upper.and_then(|upper| upper.checked_add(pending)),
+        )
+    }
+}
+
+#[cfg(feature = "stream")]
+pub use self::stream::{RateLimitStreamExt, RateLimitedStream};
+
+#[cfg(feature = "stream")]
+mod stream {
+    use std::future::Future;
+    use std::hash::Hash;
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+    use std::time::Duration;
+
+    use futures_core::Stream;
+    use pin_project_lite::pin_project;
+    use tokio::time::Sleep;
+
+    use crate::{Decision, Limiter};
+
+    /// Paces a stream by a limiter, see `rate_limit`.
+    pub trait RateLimitStreamExt: Stream + Sized {
+        /// Yields the items no faster than `key` is allowed requests by `limiter`, one
+        /// request per item, waiting with `tokio::time::sleep` while it's out of requests,
+        /// or banned. Keys the limiter doesn't know are not paced. While `key` is banned
+        /// without an end, e.g. disabled, the stream returns `None` and keeps the item
+        /// for when it's polled again.
+        fn rate_limit<T>(self, limiter: &Limiter<T>, key: T) -> RateLimitedStream<Self, T>
+        where
+            T: Hash + Eq + Clone + Send + 'static,
+        {
+            RateLimitedStream {
+                stream: self,
+                limiter: limiter.clone(),
+                key,
+                item: None,
+                sleep: None,
+            }
+        }
+    }
+
+    impl<S: Stream> RateLimitStreamExt for S {}
+
+    pin_project! {
+        /// A stream paced by a limiter, see `RateLimitStreamExt::rate_limit`.
+        #[derive(Debug)]
+        pub struct RateLimitedStream<S, T>
+        where
+            S: Stream,
+            T: Hash,
+            T: Eq,
+            T: Send,
+            T: 'static,
+        {
+            #[pin]
+            stream: S,
+            limiter: Limiter<T>,
+            key: T,
+            item: Option<S::Item>, // Taken from the stream, waiting for a request
+            #[pin]
+            sleep: Option<Sleep>,
+        }
+    }
+
+    impl<S, T> Stream for RateLimitedStream<S, T>
+    where
+        S: Stream,
+        T: Hash + Eq + Clone + Send + 'static,
+    {
+        type Item = S::Item;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+            let mut this = self.project();
+            loop {
+                if let Some(sleep) = this.sleep.as_mut().as_pin_mut() {
+                    ready!(sleep.poll(cx));
+                    this.sleep.set(None);
+                }
+                // Nothing is consumed for the end of the stream.
+                if this.item.is_none() {
+                    match ready!(this.stream.as_mut().poll_next(cx)) {
+                        Some(item) => *this.item = Some(item),
+                        None => return Poll::Ready(None),
+                    }
+                }
+                match this.limiter.check(this.key) {
+                    Decision::Denied {
+                        retry_after: Duration::MAX,
+                    }
+                    | Decision::Banned {
+                        retry_after: Duration::MAX,
+                    } => return Poll::Ready(None),
+                    Decision::Denied { retry_after } | Decision::Banned { retry_after } => {
+                        this.sleep.set(Some(tokio::time::sleep(retry_after)))
+                    }
+                    Decision::Allowed { .. } | Decision::Unknown => {
+                        return Poll::Ready(this.item.take())
+                    }
+                }
+            }
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            let pending = usize::from(self.item.is_some());
+            let (lower, upper) = self.stream.size_hint();
+            (
+                lower.saturating_add(pending),
+                upper.and_then(|upper| upper.checked_add(pending)),
+            )
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::time::Duration;
+
+        /// The items of an iterator as a stream.
+        struct Iter<I>(I);
+
+        impl<I: Iterator + Unpin> Stream for Iter<I> {
+            type Item = I::Item;
+
+            fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<I::Item>> {
+                Poll::Ready(self.0.next())
+            }
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn test_rate_limit() {
+            let limiter = Limiter::new();
+            limiter.add_limited_entity("api", 2, Duration::from_secs(1));
+            let start = tokio::time::Instant::now();
+            let mut stream = std::pin::pin!(Iter(0..5).rate_limit(&limiter, "api"));
+
+            let mut items = Vec::new();
+            while let Some(item) = std::future::poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+                items.push((item, start.elapsed().as_secs()));
+            }
+            assert_eq!(items, [(0, 0), (1, 0), (2, 1), (3, 1), (4, 2)]);
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub use self::io::Throttled;
+
+#[cfg(feature = "tokio")]
+mod io {
+    use std::future::Future;
+    use std::hash::Hash;
+    use std::io::{self, ErrorKind};
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::time::Sleep;
+
+    use crate::{Decision, Limiter, RateGateError};
+
+    /// Throttles the bytes read from and written to `S` by a limiter, every byte
+    /// costing one request of `key`, e.g. to cap the upload bandwidth of a user.
+    ///
+    /// Pair it with a byte budget like `Policy::bandwidth`. Reads and writes count
+    /// against the same key, wrap the halves of `tokio::io::split` to limit them
+    /// apart. Keys the limiter doesn't know are not throttled, reads and writes of keys
+    /// banned without an end, e.g. disabled, fail with `ErrorKind::PermissionDenied`.
+    ///
+    /// Readers and writers that aren't `Unpin` can be wrapped in `Box::pin` first.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use rate_gate::{Limiter, Policy, Throttled};
+    /// # use tokio::io::AsyncReadExt;
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let limiter = Limiter::new();
+    /// let one_mb = 1024 * 1024;
+    /// limiter.add_limited_entity_with_policy("user1", Policy::bandwidth(one_mb, Duration::from_secs(1)));
+    ///
+    /// let upload: &[u8] = b"file contents";
+    /// let mut body = Vec::new();
+    /// Throttled::new(upload, &limiter, "user1").read_to_end(&mut body).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[derive(Debug)]
+    pub struct Throttled<S, T>
+    where
+        T: Hash + Eq + Send + 'static,
+    {
+        inner: S,
+        limiter: Limiter<T>,
+        key: T,
+        read: Budget,
+        write: Budget,
+    }
+
+    /// Bytes consumed for one direction, but not read or written yet.
+    #[derive(Debug, Default)]
+    struct Budget {
+        credit: usize,
+        sleep: Option<Pin<Box<Sleep>>>, // Until the key has bytes left
+    }
+
+    impl<S, T> Throttled<S, T>
+    where
+        T: Hash + Eq + Clone + Send + 'static,
+    {
+        /// Throttles `inner` to the bytes `key` is allowed by `limiter`.
+        pub fn new(inner: S, limiter: &Limiter<T>, key: T) -> Self {
+            Throttled {
+                inner,
+                limiter: limiter.clone(),
+                key,
+                read: Budget::default(),
+                write: Budget::default(),
+            }
+        }
+
+        /// The wrapped reader or writer.
+        pub fn get_ref(&self) -> &S {
+            &self.inner
+        }
+
+        /// The wrapped reader or writer, reading or writing it directly isn't throttled.
+        pub fn get_mut(&mut self) -> &mut S {
+            &mut self.inner
+        }
+    }
+
+    impl Budget {
+        /// Consumes up to `want` bytes unless some are left over, waiting while the key
+        /// has none left. Returns how many can be read or written, `None` for all of them.
+        fn poll_take<T>(
+            &mut self,
+            cx: &mut Context<'_>,
+            limiter: &Limiter<T>,
+            key: &T,
+            want: usize,
+        ) -> Poll<io::Result<Option<usize>>>
+        where
+            T: Hash + Eq + Clone + Send + 'static,
+        {
+            loop {
+                if self.credit > 0 {
+                    return Poll::Ready(Ok(Some(self.credit.min(want))));
+                }
+                if let Some(sleep) = &mut self.sleep {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.sleep = None;
+                }
+                let Some(available) = limiter.get_bucket_remaining(key) else {
+                    return Poll::Ready(Ok(None));
+                };
+                // Asking for at least one byte tells how long to wait when there are none.
+                let cost = want.min(available.max(1));
+                match limiter.consume(key, cost) {
+                    Decision::Allowed { .. } => self.credit = cost,
+                    Decision::Banned {
+                        retry_after: retry_after @ Duration::MAX,
+                    } => {
+                        let banned = RateGateError::Banned { retry_after };
+                        return Poll::Ready(Err(io::Error::new(
+                            ErrorKind::PermissionDenied,
+                            banned,
+                        )));
+                    }
+                    Decision::Denied { retry_after } | Decision::Banned { retry_after } => {
+                        self.sleep = Some(Box::pin(tokio::time::sleep(retry_after)));
+                    }
+                    Decision::Unknown => return Poll::Ready(Ok(None)),
+                }
+            }
+        }
+    }
+
+    // The key is never pinned, only the reader or writer needs to be `Unpin`.
+    impl<S: Unpin, T> Unpin for Throttled<S, T> where T: Hash + Eq + Send + 'static {}
+
+    impl<S, T> AsyncRead for Throttled<S, T>
+    where
+        S: AsyncRead + Unpin,
+        T: Hash + Eq + Clone + Send + 'static,
+    {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            if buf.remaining() == 0 {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            let budget =
+                ready!(this
+                    .read
+                    .poll_take(cx, &this.limiter, &this.key, buf.remaining()))?;
+            let Some(budget) = budget else {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            };
+            let mut limited = ReadBuf::new(buf.initialize_unfilled_to(budget));
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            let read = limited.filled().len();
+            buf.advance(read);
+            this.read.credit -= read;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<S, T> AsyncWrite for Throttled<S, T>
+    where
+        S: AsyncWrite + Unpin,
+        T: Hash + Eq + Clone + Send + 'static,
+    {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if buf.is_empty() {
+                return Pin::new(&mut this.inner).poll_write(cx, buf);
+            }
+            let budget = ready!(this
+                .write
+                .poll_take(cx, &this.limiter, &this.key, buf.len()))?;
+            let Some(budget) = budget else {
+                return Pin::new(&mut this.inner).poll_write(cx, buf);
+            };
+            let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..budget]))?;
+            this.write.credit -= written;
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        }
+    }
+
+    impl<S, T> Drop for Throttled<S, T>
+    where
+        T: Hash + Eq + Send + 'static,
+    {
+        fn drop(&mut self) {
+            // Bytes consumed but never read or written are given back.
+            let unused = self.read.credit + self.write.credit;
+            if unused > 0 {
+                self.limiter.refund(&self.key, unused);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::Policy;
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        #[tokio::test(start_paused = true)]
+        async fn test_throttled() {
+            let limiter = Limiter::new();
+            let policy = Policy::bandwidth(1000, Duration::from_secs(1));
+            limiter.add_limited_entity_with_policy("user1", policy);
+            let start = tokio::time::Instant::now();
+
+            let (client, mut server) = tokio::io::duplex(64 * 1024);
+            let mut upload = Throttled::new(client, &limiter, "user1");
+            upload.write_all(&[7; 2500]).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(1500));
+            drop(upload);
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, [7; 2500]);
+
+            let start = tokio::time::Instant::now();
+            let download: &[u8] = &[1; 2000];
+            let mut read = Vec::new();
+            Throttled::new(download, &limiter, "user1")
+                .read_to_end(&mut read)
+                .await
+                .unwrap();
+            assert_eq!(read.len(), 2000);
+            assert!(start.elapsed() >= Duration::from_millis(1500));
+
+            let unknown: &[u8] = &[1; 2000];
+            let mut read = Vec::new();
+            Throttled::new(unknown, &limiter, "unknown")
+                .read_to_end(&mut read)
+                .await
+                .unwrap();
+            assert_eq!(read.len(), 2000);
+
+            limiter.set_mode(crate::Mode::DenyAll);
+            let download: &[u8] = &[1; 10];
+            let err = Throttled::new(download, &limiter, "user1")
+                .read_to_end(&mut read)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_rate_limit_blocking() {
+        let limiter = Limiter::new();
+        limiter.add_limited_entity("api", 2, Duration::from_millis(50));
+        let start = Instant::now();
+        let items: Vec<_> = (0..5).rate_limit_blocking(&limiter, "api").collect();
+        assert_eq!(items, [0, 1, 2, 3, 4]);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let unknown: Vec<_> = (0..3).rate_limit_blocking(&limiter, "unknown").collect();
+        assert_eq!(unknown, [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_rate_limit_blocking_disabled() {
+        let limiter = Limiter::new();
+        limiter.add_limited_entity("api", 10, Duration::from_secs(1));
+        let mut items = (0..3).rate_limit_blocking(&limiter, "api");
+        assert_eq!(items.next(), Some(0));
+
+        limiter.disable("api");
+        assert_eq!(items.next(), None);
+        assert_eq!(items.size_hint(), (2, Some(2)));
+        limiter.enable(&"api");
+        assert_eq!(items.collect::<Vec<_>>(), [1, 2]);
+    }
+}
@@ -0,0 +1,61 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Finds the tier of entities the limiter doesn't know yet, set with
+/// `Limiter::with_tier_resolver`.
+///
+/// A tier, like `free`, `pro` or `enterprise`, is the name of a policy registered with
+/// `Limiter::define_policy` or `LimiterBuilder::policy`. Unknown entities are added with
+/// the policy of their tier on their first request, so e.g. API keys get the limits of
+/// their plan without registering every key up front:
+///
+/// ```
+/// # use std::collections::HashMap;
+/// # use std::time::Duration;
+/// # use rate_gate::{Limiter, LimiterBuilder, Policy};
+/// let plans: HashMap<String, String> =
+///     HashMap::from([("key-abc".to_string(), "pro".to_string())]);
+/// let limiter: Limiter<String> = LimiterBuilder::new()
+///     .default_limit(10, Duration::from_secs(60))
+///     .policy("pro", Policy::new(1000, Duration::from_secs(60)))
+///     .build()
+///     .with_tier_resolver(move |key: &String| plans.get(key).cloned());
+///
+/// assert!(limiter.check("key-abc").is_allowed());
+/// assert_eq!(limiter.get_bucket_remaining("key-abc"), Some(999));
+/// ```
+///
+/// Closures taking the entity and returning the name of its tier are resolvers.
+/// Entities without a tier, or with one that has no policy, get the default limit
+/// of the limiter if it has one, and are unknown otherwise.
+pub trait TierResolver<T>: Send + Sync {
+    /// The tier of `entity`, if it has one.
+    ///
+    /// Called without any lock of the limiter held, on the first request of the entity
+    /// and again once it was removed, e.g. for idling.
+    fn tier(&self, entity: &T) -> Option<String>;
+}
+
+impl<T, F> TierResolver<T> for F
+where
+    F: Fn(&T) -> Option<String> + Send + Sync,
+{
+    fn tier(&self, entity: &T) -> Option<String> {
+        self(entity)
+    }
+}
+
+/// The `TierResolver` of a limiter, shared by its clones.
+pub(crate) struct Tiers<T>(pub(crate) Arc<dyn TierResolver<T>>);
+
+impl<T> Clone for Tiers<T> {
+    fn clone(&self) -> Self {
+        Tiers(self.0.clone())
+    }
+}
+
+impl<T> fmt::Debug for Tiers<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TierResolver")
+    }
+}
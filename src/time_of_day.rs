@@ -0,0 +1,117 @@
+use std::ops::Range;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::WallTime;
+
+const DAY: u64 = 86_400;
+
+/// Limits that replace those of a policy during part of every day, see `Policy::during`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimeOfDayLimit {
+    /// When the limits apply, as the time since midnight UTC. Hours ending before
+    /// they start span midnight, like `22:00..06:00`.
+    pub hours: Range<Duration>,
+    /// The value the bucket gets refilled with meanwhile.
+    pub max_limit: usize,
+    /// The timeframe after which the entity gets a renewed limit meanwhile.
+    pub refresh_rate: Duration,
+}
+
+impl TimeOfDayLimit {
+    /// Does `since_midnight` fall into the hours of the limit.
+    fn contains(&self, since_midnight: Duration) -> bool {
+        let Range { start, end } = self.hours;
+        match start <= end {
+            true => start <= since_midnight && since_midnight < end,
+            false => start <= since_midnight || since_midnight < end,
+        }
+    }
+}
+
+/// The limits an entity switches between over the day.
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct TimeOfDay {
+    limits: Vec<TimeOfDayLimit>,
+    base: (usize, Duration), // The limits outside of every hours
+    wall: WallTime,
+    active: Option<usize>, // The index of the limit in force, if any
+}
+
+impl TimeOfDay {
+    /// Starts out with the `base` limits, `system_time` being the wall-clock time at `now`.
+    pub(crate) fn new(
+        limits: Vec<TimeOfDayLimit>,
+        base: (usize, Duration),
+        now: Instant,
+        system_time: SystemTime,
+    ) -> Self {
+        TimeOfDay {
+            limits,
+            base,
+            wall: WallTime::new(now, system_time),
+            active: None,
+        }
+    }
+
+    /// The limits to switch to if they changed since the last call, the first one
+    /// whose hours include `now` or the base limits.
+    pub(crate) fn switch(&mut self, now: Instant) -> Option<(usize, Duration)> {
+        let since_epoch = self
+            .wall
+            .at(now)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let since_midnight = Duration::new(since_epoch.as_secs() % DAY, since_epoch.subsec_nanos());
+        let active = self
+            .limits
+            .iter()
+            .position(|limit| limit.contains(since_midnight));
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        Some(active.map_or(self.base, |index| {
+            let limit = &self.limits[index];
+            (limit.max_limit, limit.refresh_rate)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hours(hours: u64) -> Duration {
+        Duration::from_secs(hours * 3600)
+    }
+
+    #[test]
+    fn test_switch() {
+        let minute = Duration::from_secs(60);
+        let limits = vec![
+            TimeOfDayLimit {
+                hours: hours(9)..hours(17),
+                max_limit: 100,
+                refresh_rate: minute,
+            },
+            TimeOfDayLimit {
+                hours: hours(22)..hours(6),
+                max_limit: 5,
+                refresh_rate: minute,
+            },
+        ];
+        let now = Instant::now();
+        // 08:00 UTC
+        let morning = SystemTime::UNIX_EPOCH + Duration::from_secs(20_000 * DAY) + hours(8);
+        let mut time_of_day = TimeOfDay::new(limits, (20, minute), now, morning);
+
+        assert_eq!(time_of_day.switch(now), None);
+        assert_eq!(time_of_day.switch(now + hours(1)), Some((100, minute)));
+        assert_eq!(time_of_day.switch(now + hours(2)), None);
+        assert_eq!(time_of_day.switch(now + hours(9)), Some((20, minute)));
+        // Past midnight.
+        assert_eq!(time_of_day.switch(now + hours(17)), Some((5, minute)));
+        assert_eq!(time_of_day.switch(now + hours(22)), Some((20, minute)));
+    }
+}
@@ -0,0 +1,161 @@
+//! Rate limiting for `tonic` gRPC services, see `RateLimitInterceptor`.
+
+use std::hash::Hash;
+use std::time::Duration;
+
+use ::tonic::metadata::MetadataValue;
+use ::tonic::service::Interceptor;
+use ::tonic::{Request, Status};
+
+use http::HeaderName;
+
+use crate::extract::{Header, KeyExtractor};
+use crate::{Decision, Limiter};
+
+/// Limits the calls of every client by a metadata key, `x-api-key` by default,
+/// answering with `RESOURCE_EXHAUSTED` once it is out of requests.
+///
+/// Denied calls carry a `retry-delay` metadata entry, the time until the call would be
+/// allowed like `1.500s`. Calls without the key, and keys the limiter doesn't know,
+/// are let through. Any other `KeyExtractor` can be used with `with_extractor`.
+///
+/// ```ignore
+/// let interceptor = RateLimitInterceptor::new(limiter);
+/// Server::builder()
+///     .add_service(GreeterServer::with_interceptor(greeter, interceptor))
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimitInterceptor<T = String, E = Header>
+where
+    T: Hash + Eq + Send + 'static,
+{
+    limiter: Limiter<T>,
+    extractor: E,
+}
+
+impl RateLimitInterceptor {
+    pub fn new(limiter: Limiter<String>) -> Self {
+        RateLimitInterceptor {
+            limiter,
+            extractor: Header(HeaderName::from_static("x-api-key")),
+        }
+    }
+
+    /// Sets the metadata key clients are limited by.
+    ///
+    /// # Panics
+    ///
+    /// If `key` is not a valid metadata key.
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        let key = HeaderName::try_from(key.into()).expect("invalid metadata key");
+        self.extractor = Header(key);
+        self
+    }
+}
+
+impl<T, E> RateLimitInterceptor<T, E>
+where
+    T: Hash + Eq + Send + 'static,
+    E: KeyExtractor<(), Key = T>,
+{
+    /// Limits calls by the key `extractor` finds, e.g. `PeerIp`.
+    ///
+    /// The extractor gets a copy of the metadata of a call as headers, and of its
+    /// extensions.
+    pub fn with_extractor(limiter: Limiter<T>, extractor: E) -> Self {
+        RateLimitInterceptor { limiter, extractor }
+    }
+}
+
+impl<T, E> Interceptor for RateLimitInterceptor<T, E>
+where
+    T: Hash + Eq + Send + Clone + 'static,
+    E: KeyExtractor<(), Key = T>,
+{
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let mut call = http::Request::new(());
+        *call.headers_mut() = request.metadata().clone().into_headers();
+        *call.extensions_mut() = request.extensions().clone();
+        let Some(key) = self.extractor.extract(&call) else {
+            return Ok(request);
+        };
+        match self.limiter.check(&key) {
+            Decision::Denied { retry_after } | Decision::Banned { retry_after } => {
+                let mut status = Status::resource_exhausted("rate limit exceeded");
+                if let Ok(delay) = MetadataValue::try_from(retry_delay(retry_after)) {
+                    status.metadata_mut().insert("retry-delay", delay);
+                }
+                Err(status)
+            }
+            Decision::Allowed { .. } | Decision::Unknown => Ok(request),
+        }
+    }
+}
+
+/// Formats `retry_after` like a protobuf `Duration` in JSON, rounded up to milliseconds.
+fn retry_delay(retry_after: Duration) -> String {
+    let millis = retry_after.as_nanos().div_ceil(1_000_000);
+    format!("{}.{:03}s", millis / 1000, millis % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::tonic::Code;
+
+    #[test]
+    fn test_interceptor() {
+        let limiter: Limiter<String> = Limiter::new();
+        limiter.add_limited_entity("key1".to_string(), 1, Duration::from_millis(1500));
+        let mut interceptor = RateLimitInterceptor::new(limiter);
+        let request = |key: &str| {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert("x-api-key", key.parse().unwrap());
+            request
+        };
+
+        assert!(interceptor.call(request("key1")).is_ok());
+        let status = interceptor.call(request("key1")).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        let delay = status
+            .metadata()
+            .get("retry-delay")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(delay.starts_with("1.") && delay.ends_with('s'), "{}", delay);
+
+        assert!(interceptor.call(request("key2")).is_ok());
+        assert!(interceptor.call(Request::new(())).is_ok());
+    }
+
+    #[test]
+    fn test_with_extractor() {
+        let limiter = Limiter::with_default(1, Duration::from_secs(60));
+        let tenant = |call: &http::Request<()>| {
+            let tenant = call.headers().get("x-tenant")?.to_str().ok()?;
+            tenant.parse::<u32>().ok()
+        };
+        let mut interceptor = RateLimitInterceptor::with_extractor(limiter, tenant);
+        let request = |tenant: &str| {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert("x-tenant", tenant.parse().unwrap());
+            request
+        };
+
+        assert!(interceptor.call(request("1")).is_ok());
+        assert!(interceptor.call(request("1")).is_err());
+        assert!(interceptor.call(request("2")).is_ok());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(Duration::from_millis(1500)), "1.500s");
+        assert_eq!(retry_delay(Duration::from_nanos(1)), "0.001s");
+        assert_eq!(retry_delay(Duration::from_secs(60)), "60.000s");
+    }
+}
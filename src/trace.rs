@@ -0,0 +1,68 @@
+use std::fmt;
+
+use crate::Decision;
+
+/// Turns an entity into something printable, set with `Limiter::trace_entities`.
+pub(crate) type DebugFn<T> = fn(&T) -> &dyn fmt::Debug;
+
+pub(crate) fn as_debug<T: fmt::Debug>(entity: &T) -> &dyn fmt::Debug {
+    entity
+}
+
+/// An entity in an event, printed as `_` unless the limiter knows how to print it.
+struct Entity<'a, T>(&'a T, Option<DebugFn<T>>);
+
+impl<T> fmt::Debug for Entity<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.1 {
+            Some(debug) => debug(self.0).fmt(f),
+            None => f.write_str("_"),
+        }
+    }
+}
+
+/// Emits an event for a decision made for `entity`, see `Entry::decide`.
+pub(crate) fn decided<T>(
+    printer: Option<DebugFn<T>>,
+    entity: &T,
+    cost: usize,
+    decision: &Decision,
+    refilled: bool,
+) {
+    let entity = Entity(entity, printer);
+    if refilled {
+        tracing::debug!(entity = ?entity, "entity refilled");
+    }
+    match decision {
+        Decision::Allowed { remaining, .. } => {
+            tracing::trace!(entity = ?entity, cost, remaining, "request allowed")
+        }
+        Decision::Denied { retry_after } => {
+            tracing::debug!(entity = ?entity, cost, ?retry_after, "request denied")
+        }
+        // Decided before the entity's bucket is looked at.
+        Decision::Banned { .. } | Decision::Unknown => {}
+    }
+}
+
+pub(crate) fn inserted<T>(printer: Option<DebugFn<T>>, entity: &T, max_limit: usize) {
+    tracing::trace!(entity = ?Entity(entity, printer), remaining = max_limit, "entity added");
+}
+
+pub(crate) fn evicted<T>(printer: Option<DebugFn<T>>, entity: &T) {
+    tracing::debug!(entity = ?Entity(entity, printer), "entity evicted");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entity_formatting() {
+        assert_eq!(
+            format!("{:?}", Entity(&"user1", Some(as_debug))),
+            "\"user1\""
+        );
+        assert_eq!(format!("{:?}", Entity(&"user1", None)), "_");
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+/// How much slower a cold entity refills than a warm one.
+const COLD_FACTOR: u128 = 3;
+
+/// Slow start of an entity that was idle, see `Policy::with_warm_up`.
+///
+/// A cold entity can hold and refill a third of its bucket. Both grow linearly
+/// to the full bucket and rate over `period` of activity, and an entity without
+/// requests for `period` is cold again.
+#[derive(Debug, Clone, Hash)]
+pub(crate) struct WarmUp {
+    pub(crate) period: Duration,
+    warm_since: Instant,   // When the entity last became active after idling
+    last_request: Instant, // When requests were last consumed
+}
+
+impl WarmUp {
+    /// Starts out cold, like an entity that idled for `period`.
+    pub(crate) fn new(period: Duration, now: Instant) -> Self {
+        WarmUp {
+            period,
+            warm_since: now,
+            last_request: now,
+        }
+    }
+
+    /// Goes cold again if the entity idled for `period`.
+    pub(crate) fn cool(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_request) >= self.period {
+            self.warm_since = now;
+        }
+    }
+
+    pub(crate) fn record(&mut self, now: Instant) {
+        self.last_request = self.last_request.max(now);
+    }
+
+    /// The share of the full rate at `at`, as a numerator over a denominator.
+    fn warmth(&self, at: Instant) -> (u128, u128) {
+        let period = self.period.as_nanos();
+        if period == 0 {
+            return (1, 1);
+        }
+        let elapsed = at.saturating_duration_since(self.warm_since).as_nanos();
+        (
+            period + (COLD_FACTOR - 1) * elapsed.min(period),
+            COLD_FACTOR * period,
+        )
+    }
+
+    /// How many of `bucket_max` requests the bucket can hold at `now`, at least one.
+    pub(crate) fn capacity(&self, bucket_max: usize, now: Instant) -> usize {
+        let (warm, full) = self.warmth(now);
+        let capacity = (bucket_max as u128 * warm).div_ceil(full) as usize;
+        capacity.clamp(bucket_max.min(1), bucket_max)
+    }
+
+    /// `interval` between two tokens, stretched by the warmth at `at`.
+    pub(crate) fn interval(&self, interval: Duration, at: Instant) -> u128 {
+        let (warm, full) = self.warmth(at);
+        interval.as_nanos().saturating_mul(full) / warm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warms_up_and_cools_down() {
+        let start = Instant::now();
+        let mut warm_up = WarmUp::new(Duration::from_secs(10), start);
+        assert_eq!(warm_up.capacity(30, start), 10);
+        assert_eq!(warm_up.capacity(1, start), 1);
+        assert_eq!(
+            warm_up.interval(Duration::from_millis(100), start),
+            300_000_000
+        );
+
+        let halfway = start + Duration::from_secs(5);
+        warm_up.record(halfway);
+        assert_eq!(warm_up.capacity(30, halfway), 20);
+        let warm = start + Duration::from_secs(12);
+        warm_up.cool(warm);
+        assert_eq!(warm_up.capacity(30, warm), 30);
+        assert_eq!(
+            warm_up.interval(Duration::from_millis(100), warm),
+            100_000_000
+        );
+
+        // Idle for the whole period.
+        let idle = halfway + Duration::from_secs(10);
+        warm_up.cool(idle);
+        assert_eq!(warm_up.capacity(30, idle), 10);
+    }
+}
@@ -0,0 +1,179 @@
+//! Rate limiting for `warp`, see `limit`.
+
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use ::warp::http::{header, HeaderMap, Method, StatusCode};
+use ::warp::path::FullPath;
+use ::warp::reject::{Reject, Rejection};
+use ::warp::reply::{self, Reply};
+use ::warp::Filter;
+
+use crate::extract::KeyExtractor;
+use crate::{Decision, Limiter, RetryAfter};
+
+/// Rejects requests whose key, extracted by the `key` filter, is out of requests,
+/// with `RateLimited`. Keys the limiter doesn't know are let through.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use warp::Filter;
+/// # use rate_gate::Limiter;
+/// let limiter: Limiter<String> = Limiter::with_default(100, Duration::from_secs(60));
+/// let api_key = warp::header::<String>("x-api-key");
+///
+/// let routes = warp::path("hello")
+///     .and(rate_gate::warp::limit(limiter, api_key))
+///     .map(|| "hello")
+///     .recover(rate_gate::warp::recover);
+/// ```
+pub fn limit<T, F>(
+    limiter: Limiter<T>,
+    key: F,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone
+where
+    T: Hash + Eq + Send + Sync + Clone + 'static,
+    F: Filter<Extract = (T,), Error = Rejection> + Clone,
+{
+    key.and_then(move |key: T| {
+        let checked = check(&limiter, Some(key));
+        async move { checked }
+    })
+    .untuple_one()
+}
+
+/// Like `limit`, but finds the key with a `KeyExtractor`, e.g. `ForwardedFor`.
+/// Requests it finds no key for are let through.
+///
+/// warp is built on `http` 0.2, so the extractor gets a copy of the method, path,
+/// headers and remote address of the request, with the address as a `SocketAddr`
+/// extension.
+pub fn limit_by<T, E>(
+    limiter: Limiter<T>,
+    extractor: E,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone
+where
+    T: Hash + Eq + Send + Sync + Clone + 'static,
+    E: KeyExtractor<(), Key = T> + Clone + Send + Sync + 'static,
+{
+    ::warp::method()
+        .and(::warp::path::full())
+        .and(::warp::header::headers_cloned())
+        .and(::warp::addr::remote())
+        .and_then(
+            move |method: Method, path: FullPath, headers: HeaderMap, addr: Option<SocketAddr>| {
+                let key = to_request(method, path, headers, addr)
+                    .and_then(|request| extractor.extract(&request));
+                let checked = check(&limiter, key);
+                async move { checked }
+            },
+        )
+        .untuple_one()
+}
+
+fn check<T: Hash + Eq + Send + Clone>(
+    limiter: &Limiter<T>,
+    key: Option<T>,
+) -> Result<(), Rejection> {
+    let decision = match key {
+        Some(key) => limiter.check(&key),
+        None => Decision::Unknown,
+    };
+    match decision {
+        Decision::Denied { retry_after } | Decision::Banned { retry_after } => {
+            Err(::warp::reject::custom(RateLimited { retry_after }))
+        }
+        Decision::Allowed { .. } | Decision::Unknown => Ok(()),
+    }
+}
+
+/// Rebuilds the request as an `http` 1 request for `KeyExtractor`s.
+fn to_request(
+    method: Method,
+    path: FullPath,
+    headers: HeaderMap,
+    addr: Option<SocketAddr>,
+) -> Option<http::Request<()>> {
+    let mut request = http::Request::builder()
+        .method(method.as_str())
+        .uri(path.as_str());
+    for (name, value) in &headers {
+        request = request.header(name.as_str(), value.as_bytes());
+    }
+    if let Some(addr) = addr {
+        request = request.extension(addr);
+    }
+    request.body(()).ok()
+}
+
+/// The rejection of requests denied by `limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    /// Time until the request would be allowed.
+    pub retry_after: Duration,
+}
+
+impl Reject for RateLimited {}
+
+/// Answers `RateLimited` rejections with `429 Too Many Requests` and a `Retry-After`
+/// header, passing on every other rejection. Meant for `Filter::recover`.
+pub async fn recover(rejection: Rejection) -> Result<reply::Response, Rejection> {
+    let Some(RateLimited { retry_after }) = rejection.find::<RateLimited>() else {
+        return Err(rejection);
+    };
+    let retry_after = RetryAfter(*retry_after).to_string();
+    let response = reply::with_status(reply::reply(), StatusCode::TOO_MANY_REQUESTS);
+    Ok(reply::with_header(response, header::RETRY_AFTER, retry_after).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limit() {
+        let limiter: Limiter<String> = Limiter::new();
+        limiter.add_limited_entity("key1".to_string(), 1, Duration::from_secs(60));
+        let routes = limit(limiter, ::warp::header::<String>("x-api-key"))
+            .map(|| "hello")
+            .recover(recover);
+        let request = |key: &str| ::warp::test::request().header("x-api-key", key);
+
+        let response = request("key1").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = request("key1").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+
+        let response = request("key2").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_limit_by() {
+        use crate::extract::ForwardedFor;
+
+        let limiter = Limiter::with_default(1, Duration::from_secs(60));
+        let routes = limit_by(limiter, ForwardedFor { proxies: 1 })
+            .map(|| "hello")
+            .recover(recover);
+        let request = |ip: &str| {
+            ::warp::test::request()
+                .header("x-forwarded-for", ip)
+                .remote_addr(SocketAddr::from(([10, 0, 0, 1], 1234)))
+        };
+
+        assert_eq!(
+            request("1.1.1.1").reply(&routes).await.status(),
+            StatusCode::OK
+        );
+        let response = request("1.1.1.1").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            request("2.2.2.2").reply(&routes).await.status(),
+            StatusCode::OK
+        );
+    }
+}